@@ -0,0 +1,45 @@
+//! Generates the gRPC service stubs when the `grpc` feature is enabled
+//!
+//! The service is described in Rust rather than compiled from
+//! `proto/raft.proto`, so building doesn't need `protoc`. The message types
+//! live in `src/transport/grpc.rs` and carry the same field tags as the
+//! `.proto` file, keeping the wire format identical.
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    grpc::compile();
+}
+
+#[cfg(feature = "grpc")]
+mod grpc {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    const CODEC: &str = "tonic::codec::ProstCodec";
+
+    fn method(name: &str, route: &str, message: &str) -> Method {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("super::{}Request", message))
+            .output_type(format!("super::{}Response", message))
+            .codec_path(CODEC)
+            .build()
+    }
+
+    pub fn compile() {
+        let service = Service::builder()
+            .name("Raft")
+            .package("objectbox.raft")
+            .method(method("request_vote", "RequestVote", "RequestVote"))
+            .method(method("append_entries", "AppendEntries", "AppendEntries"))
+            .method(method(
+                "install_snapshot",
+                "InstallSnapshot",
+                "InstallSnapshot",
+            ))
+            .method(method("ping", "Ping", "Ping"))
+            .build();
+
+        Builder::new().compile(&[service]);
+    }
+}
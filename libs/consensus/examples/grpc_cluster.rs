@@ -0,0 +1,123 @@
+//! Three Raft nodes talking to each other over gRPC
+//!
+//! Each node listens on its own localhost port and reaches the other two
+//! through a `GrpcTransport`, exactly as separate machines would.
+//!
+//! Run with: cargo run --example grpc_cluster --features grpc
+
+use objectbox_consensus::{
+    serve, GrpcTransport, NodeId, RaftConfig, RaftNodeBuilder, RaftRole, StateMachine,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// State machine that just counts applied commands
+struct Counter {
+    applied: u64,
+}
+
+impl StateMachine for Counter {
+    fn apply(&mut self, _command: &[u8]) -> Vec<u8> {
+        self.applied += 1;
+        self.applied.to_le_bytes().to_vec()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.applied.to_le_bytes().to_vec()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&snapshot[..8]);
+        self.applied = u64::from_le_bytes(bytes);
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
+
+    println!("=== ObjectBox Raft over gRPC ===\n");
+
+    let addrs: HashMap<NodeId, SocketAddr> = [
+        (NodeId(1), "127.0.0.1:50051".parse()?),
+        (NodeId(2), "127.0.0.1:50052".parse()?),
+        (NodeId(3), "127.0.0.1:50053".parse()?),
+    ]
+    .into_iter()
+    .collect();
+    let node_ids: Vec<NodeId> = addrs.keys().copied().collect();
+
+    let config = RaftConfig {
+        election_timeout_min: Duration::from_millis(300),
+        election_timeout_max: Duration::from_millis(600),
+        heartbeat_interval: Duration::from_millis(100),
+        ..Default::default()
+    };
+
+    let mut nodes = Vec::new();
+    let mut servers = Vec::new();
+    for (&id, &addr) in &addrs {
+        let peers = addrs
+            .iter()
+            .filter(|(peer, _)| **peer != id)
+            .map(|(peer, peer_addr)| (*peer, format!("http://{}", peer_addr)));
+        let transport = GrpcTransport::new(peers)?;
+
+        let node = RaftNodeBuilder::new(id, node_ids.clone(), Counter { applied: 0 })
+            .config(config.clone())
+            .transport(Arc::new(transport))
+            .build()
+            .await?;
+        let node = Arc::new(node);
+
+        servers.push(tokio::spawn(serve(Arc::clone(&node), addr)));
+        println!("  ✓ Node {} listening on {}", id.0, addr);
+        nodes.push(node);
+    }
+
+    println!("\nWaiting for leader election...");
+    let leader = loop {
+        let mut leader = None;
+        for node in &nodes {
+            if node.metrics().await?.role == RaftRole::Leader {
+                leader = Some(Arc::clone(node));
+            }
+        }
+        if let Some(leader) = leader {
+            break leader;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    let metrics = leader.metrics().await?;
+    println!(
+        "  ✓ Node {} elected leader for term {}\n",
+        metrics.id.0, metrics.current_term.0
+    );
+
+    for i in 1..=3 {
+        let index = leader
+            .propose_no_wait(format!("command {}", i).into_bytes())
+            .await?;
+        println!("  ✓ Command {} appended at index {}", i, index.0);
+    }
+
+    println!("\nShutting down cluster...");
+    for server in servers {
+        server.abort();
+        let _ = server.await;
+    }
+    drop(leader);
+    for node in nodes {
+        if let Ok(node) = Arc::try_unwrap(node) {
+            node.shutdown().await;
+        }
+    }
+    println!("  ✓ All nodes stopped\n");
+
+    Ok(())
+}
@@ -1,13 +1,18 @@
 //! Simple distributed key-value store using Raft consensus
 //!
 //! This example demonstrates how to build a strongly consistent distributed
-//! key-value store on top of the Raft consensus library.
+//! key-value store on top of the Raft consensus library. The three nodes run
+//! in one process, wired together by an in-process transport.
 //!
 //! Run with: cargo run --example simple_kv
 
-use objectbox_consensus::{NodeId, RaftConfig, RaftNode, StateMachine};
+use objectbox_consensus::{
+    ChannelTransport, NodeId, RaftConfig, RaftNode, RaftNodeBuilder, RaftRole, StateMachine,
+    Transport,
+};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 
 /// Simple key-value state machine
@@ -56,6 +61,24 @@ impl StateMachine for KvStore {
     }
 }
 
+/// Wait until one of `nodes` has won an election
+async fn wait_for_leader(
+    nodes: &[Arc<RaftNode>],
+) -> Result<Arc<RaftNode>, Box<dyn std::error::Error>> {
+    let leader = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            for node in nodes {
+                if node.metrics().await?.role == RaftRole::Leader {
+                    return Ok::<_, objectbox_consensus::RaftError>(Arc::clone(node));
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await??;
+    Ok(leader)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize tracing
@@ -79,86 +102,92 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Starting 3-node Raft cluster...");
 
-    // Create three nodes
-    let node1 = RaftNode::new(
-        NodeId(1),
-        node_ids.clone(),
-        config.clone(),
-        KvStore::new(),
-    )
-    .await?;
-
-    let node2 = RaftNode::new(
-        NodeId(2),
-        node_ids.clone(),
-        config.clone(),
-        KvStore::new(),
-    )
-    .await?;
-
-    let node3 = RaftNode::new(NodeId(3), node_ids.clone(), config, KvStore::new()).await?;
-
-    println!("  ✓ Node 1 started");
-    println!("  ✓ Node 2 started");
-    println!("  ✓ Node 3 started\n");
-
-    // Wait for leader election (in real impl, we'd detect this)
-    println!("Waiting for leader election...");
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    println!("  ✓ Leader elected (Node 1 in this simplified demo)\n");
-
-    // Simulate some operations
-    println!("Proposing commands to the cluster...\n");
-
-    // Set some keys
-    let set_cmd = Command::Set {
-        key: "username".to_string(),
-        value: "alice".to_string(),
-    };
-
-    println!("Command 1: SET username = alice");
-    match node1.propose(serde_json::to_vec(&set_cmd)?).await {
-        Ok(_) => println!("  ✓ Command committed\n"),
-        Err(e) => println!("  ✗ Error: {}\n", e),
+    // Each node gets its own handle on the in-process network and registers
+    // itself there so the others can reach it
+    let transports = ChannelTransport::cluster(&node_ids);
+    let mut nodes = Vec::new();
+    for (&id, transport) in node_ids.iter().zip(&transports) {
+        let node = RaftNodeBuilder::new(id, node_ids.clone(), KvStore::new())
+            .config(config.clone())
+            .transport(Arc::clone(transport) as Arc<dyn Transport>)
+            .build()
+            .await?;
+        let node = Arc::new(node);
+        transport.register(Arc::clone(&node));
+        nodes.push(node);
+        println!("  ✓ Node {} started", id.0);
     }
 
-    let set_cmd2 = Command::Set {
-        key: "role".to_string(),
-        value: "admin".to_string(),
-    };
-
-    println!("Command 2: SET role = admin");
-    match node1.propose(serde_json::to_vec(&set_cmd2)?).await {
-        Ok(_) => println!("  ✓ Command committed\n"),
-        Err(e) => println!("  ✗ Error: {}\n", e),
+    println!("\nWaiting for leader election...");
+    let leader = wait_for_leader(&nodes).await?;
+    let term = leader.metrics().await?.current_term;
+    println!(
+        "  ✓ Node {} elected leader in term {}\n",
+        leader.id().0,
+        term.0
+    );
+
+    println!("Proposing commands to the leader...\n");
+    let commands = [
+        Command::Set {
+            key: "username".to_string(),
+            value: "alice".to_string(),
+        },
+        Command::Set {
+            key: "role".to_string(),
+            value: "admin".to_string(),
+        },
+        Command::Delete {
+            key: "username".to_string(),
+        },
+    ];
+    for (i, command) in commands.iter().enumerate() {
+        println!("Command {}: {:?}", i + 1, command);
+        match leader.propose(serde_json::to_vec(command)?).await {
+            Ok(_) => println!("  ✓ Command committed\n"),
+            Err(e) => println!("  ✗ Error: {}\n", e),
+        }
     }
 
-    // Delete a key
-    let del_cmd = Command::Delete {
-        key: "username".to_string(),
-    };
-
-    println!("Command 3: DELETE username");
-    match node1.propose(serde_json::to_vec(&del_cmd)?).await {
-        Ok(_) => println!("  ✓ Command committed\n"),
-        Err(e) => println!("  ✗ Error: {}\n", e),
+    // Every node applies the same commands in the same order
+    let last_index = leader.metrics().await?.commit_index;
+    for node in &nodes {
+        while node.metrics().await?.last_applied < last_index {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    let data = leader
+        .linearizable_read(|kv: &KvStore| {
+            let mut data: Vec<_> = kv.data.iter().collect();
+            data.sort();
+            format!("{:?}", data)
+        })
+        .await?;
+    println!("Leader's store after replication: {}", data);
+    for node in &nodes {
+        let metrics = node.metrics().await?;
+        println!(
+            "  Node {}: {:?}, applied through {}",
+            node.id().0,
+            metrics.role,
+            metrics.last_applied.0
+        );
     }
 
     println!("\n=== Demo Summary ===");
     println!("✓ Raft consensus ensures all nodes have the same log");
     println!("✓ Commands are committed only after majority replication");
     println!("✓ State machine applies commands in the same order on all nodes");
-    println!("\nIn a real implementation:");
-    println!("  - Nodes would communicate over the network (gRPC)");
-    println!("  - Leader election would be automatic and dynamic");
-    println!("  - Log would be persisted to disk");
-    println!("  - Snapshots would be taken for log compaction");
 
     // Cleanup
     println!("\nShutting down cluster...");
-    node1.shutdown().await;
-    node2.shutdown().await;
-    node3.shutdown().await;
+    drop(leader);
+    for (node, transport) in nodes.into_iter().zip(&transports) {
+        transport.unregister(node.id());
+        if let Ok(node) = Arc::try_unwrap(node) {
+            node.shutdown().await;
+        }
+    }
     println!("  ✓ All nodes stopped\n");
 
     Ok(())
@@ -8,7 +8,8 @@ pub struct RaftConfig {
     /// Minimum election timeout in milliseconds
     ///
     /// This is the minimum time a follower waits before starting an election.
-    /// The actual timeout is randomized between min and max to avoid split votes.
+    /// The actual timeout is randomized between min and max to avoid split votes,
+    /// unless the node is built with its own `ElectionTimeoutStrategy`.
     pub election_timeout_min: Duration,
 
     /// Maximum election timeout in milliseconds
@@ -26,7 +27,10 @@ pub struct RaftConfig {
     /// can cause longer RPC latencies.
     pub max_append_entries: usize,
 
-    /// Maximum number of bytes in a single AppendEntries RPC
+    /// Maximum number of entry or snapshot bytes in a single RPC
+    ///
+    /// Lowered to the transport's `max_message_size` if that is smaller. An
+    /// entry bigger than this on its own is still sent, alone.
     pub max_append_bytes: usize,
 
     /// Snapshot threshold - create snapshot after this many log entries
@@ -42,6 +46,166 @@ pub struct RaftConfig {
     /// When enabled, leader sends multiple AppendEntries without waiting
     /// for responses (improves throughput but can waste bandwidth on retry)
     pub enable_pipelining: bool,
+
+    /// Consecutive election timeouts without leader contact before a node
+    /// reports a suspected partition
+    ///
+    /// A leader reports one instead after a single election timeout without
+    /// acknowledgements from a quorum. Set to 0 to disable partition
+    /// detection
+    pub partition_detection_timeouts: u32,
+
+    /// Verify log continuity before the node starts
+    ///
+    /// Checks that indices are contiguous and terms never decrease, refusing
+    /// to start on a corrupt log. This reads the whole log, so it's off by
+    /// default.
+    pub verify_log_on_startup: bool,
+
+    /// Pass leader no-op entries to the state machine
+    ///
+    /// When enabled, `StateMachine::apply_noop` is called for every no-op so
+    /// the state machine observes every log index. Off by default: no-ops
+    /// are skipped.
+    pub deliver_noops_to_state_machine: bool,
+
+    /// How long `propose` waits for the cluster's first leader election
+    ///
+    /// With `None`, proposing before any leader is known fails immediately
+    /// with `NotLeader(None)`. Once a leader has been seen this has no
+    /// effect.
+    pub initial_leader_timeout: Option<Duration>,
+
+    /// Shortest time allowed between two elections started by this node
+    ///
+    /// Keeps a node that keeps timing out from inflating the term. `None`
+    /// leaves elections to the election timeout alone.
+    pub min_election_interval: Option<Duration>,
+
+    /// Elections started within a minute before the node reports an
+    /// election storm
+    ///
+    /// Set to 0 to disable storm detection
+    pub election_storm_threshold: u32,
+
+    /// How far any node's clock may run ahead of the leader's over one
+    /// minimum election timeout
+    ///
+    /// `RaftNode::lease_read_index` relies on followers refusing votes for
+    /// `election_timeout_min` after hearing from the leader. If a follower's
+    /// clock runs fast, that wait ends early by its measure, so the leader
+    /// takes this much off its lease. Clocks are assumed to drift by no more
+    /// than this; a larger drift can make lease reads stale. A drift at or
+    /// above `election_timeout_min` leaves no lease at all.
+    pub max_clock_drift: Duration,
+
+    /// Passed to the node's election timeout strategy
+    ///
+    /// The default randomized strategy ignores it; a custom
+    /// `ElectionTimeoutStrategy` can use it to let favoured nodes time out
+    /// first.
+    pub election_priority: u32,
+
+    /// Seed for the node's randomness, such as election timeouts
+    ///
+    /// `None` seeds from the OS. Set it to replay a simulated cluster
+    /// exactly.
+    pub random_seed: Option<u64>,
+
+    /// Apply committed entries on a dedicated thread
+    ///
+    /// By default the state machine runs on the consensus loop, so a slow
+    /// `apply` delays heartbeats and votes. With this set, committed entries
+    /// are handed to an apply thread in order and the loop keeps going.
+    pub separate_apply_thread: bool,
+
+    /// Leave applying committed entries to the caller
+    ///
+    /// The node stops feeding its state machine. Instead
+    /// `RaftNode::apply_ready` hands out committed commands and
+    /// `RaftNode::confirm_applied` advances `last_applied` once the caller
+    /// has applied them. Proposals are answered with an empty output on
+    /// confirmation, and no snapshots are taken automatically, since the
+    /// node's own state machine never sees the commands.
+    pub manual_apply: bool,
+
+    /// How many times a leader retries a failed InstallSnapshot transfer
+    /// before reporting `RaftEvent::SnapshotTransferFailed`
+    ///
+    /// Each retry starts the transfer over after a backoff that doubles from
+    /// the heartbeat interval. Set to 0 to report the first failure.
+    pub snapshot_transfer_retries: u32,
+
+    /// Most commands the consensus loop handles while a linearizable read
+    /// waits to be served
+    ///
+    /// Reads queue apart from writes, so a burst of proposals can't push
+    /// them to the back of a long line. Lower values favour reads; 0 leaves
+    /// them to compete with other commands on equal terms.
+    pub read_priority: u32,
+
+    /// How many reads can wait for a leadership confirmation round before
+    /// one is started for them alongside the round in flight
+    ///
+    /// Reads that arrive while a round is out wait for the next one and
+    /// share it, so a burst of reads costs a few heartbeat rounds rather
+    /// than one each. 0 gives every read its own round.
+    pub read_batch_size: usize,
+
+    /// Have followers forward proposals to the leader
+    ///
+    /// `propose` then works on any node that knows the leader, at the cost
+    /// of an extra hop. A forwarded proposal is never forwarded again, so a
+    /// stale leader hint fails with `NotLeader` instead of looping.
+    pub forward_proposals: bool,
+
+    /// Accept proposals with an empty command
+    ///
+    /// Off by default, so an accidentally empty proposal fails with
+    /// `EmptyCommand` instead of reaching the state machine. Turn it on for
+    /// state machines that give the empty command a meaning of their own.
+    pub allow_empty_commands: bool,
+
+    /// Have a leader that fails to write to its own log step down
+    ///
+    /// A leader whose storage has broken (a full disk, say) can't commit
+    /// anything, and would otherwise fail every proposal until the
+    /// operator notices. On a storage error it reports
+    /// `RaftEvent::StorageFailure` and becomes a follower, so a healthy
+    /// node can take over.
+    pub step_down_on_append_failure: bool,
+
+    /// How far a node's term may run ahead of the cluster's before it is
+    /// reported as `RaftEvent::TermGapDetected`
+    ///
+    /// A node cut off for a long time keeps starting elections and comes
+    /// back with an inflated term. With a limit set, a leader also refuses
+    /// votes to candidates that far ahead, so a returning node can't unseat
+    /// it before hearing its heartbeat. `None` disables the check.
+    pub max_term_gap: Option<u64>,
+
+    /// Have a node past `max_term_gap` fall back to the leader's term
+    ///
+    /// The node follows the first leader it hears from instead of rejecting
+    /// it and dragging the cluster up to its own term. It records its vote
+    /// in that term as cast for the leader, which can't change the outcome
+    /// of an election the leader has already won.
+    pub reset_term_on_gap: bool,
+
+    /// Ask peers whether they would vote before starting an election
+    ///
+    /// The node only bumps its term once a majority says it could win, so a
+    /// node that can't reach the leader but also can't win, e.g. behind a
+    /// flaky link, doesn't keep unseating it.
+    pub pre_vote: bool,
+
+    /// Election timeouts a follower sits through without hearing from a
+    /// leader before it campaigns
+    ///
+    /// With more than 1, a leader that misses a heartbeat or two to jitter
+    /// costs nothing, not even a pre-vote round; the node only moves once
+    /// the silence has lasted long enough to mean the leader is gone.
+    pub election_confirmation_timeouts: u32,
 }
 
 impl Default for RaftConfig {
@@ -68,6 +232,66 @@ impl Default for RaftConfig {
 
             // Disable pipelining by default (simpler, more predictable)
             enable_pipelining: false,
+
+            // Suspect a partition after 3 silent election cycles
+            partition_detection_timeouts: 3,
+
+            // Startup integrity check is O(n), opt in
+            verify_log_on_startup: false,
+
+            // Hide no-ops from the state machine
+            deliver_noops_to_state_machine: false,
+
+            // Fail fast when there's no leader yet
+            initial_leader_timeout: None,
+
+            // No throttling beyond the election timeout itself
+            min_election_interval: None,
+
+            // One election every six seconds is already unhealthy
+            election_storm_threshold: 10,
+
+            // A tenth of the minimum election timeout, far beyond what
+            // NTP-disciplined clocks drift in that time
+            max_clock_drift: Duration::from_millis(15),
+
+            // Every node is equally eager to lead
+            election_priority: 0,
+
+            // Fresh randomness on every start
+            random_seed: None,
+
+            // Apply inline; cheap state machines don't need the extra thread
+            separate_apply_thread: false,
+
+            // The node applies what it commits
+            manual_apply: false,
+
+            // Ride out a follower restart or a brief network blip
+            snapshot_transfer_retries: 3,
+
+            // Keep read latency bounded without stalling replication
+            read_priority: 4,
+
+            // Share confirmation rounds without letting a burst wait long
+            read_batch_size: 128,
+
+            // Clients follow NotLeader hints themselves
+            forward_proposals: false,
+
+            // An empty command is almost always a client bug
+            allow_empty_commands: false,
+
+            // A leader that can't persist its log is no use to anyone
+            step_down_on_append_failure: true,
+
+            // Every term bump is honoured, as plain Raft does
+            max_term_gap: None,
+            reset_term_on_gap: false,
+
+            // Campaign on the first timeout, as plain Raft does
+            pre_vote: false,
+            election_confirmation_timeouts: 1,
         }
     }
 }
@@ -84,6 +308,18 @@ impl RaftConfigBuilder {
         }
     }
 
+    /// Start from timeouts scaled to a measured round-trip time
+    ///
+    /// Heartbeats go out every 2×RTT and elections time out after 10–20×RTT,
+    /// keeping the default ratios for clusters spread over a WAN. An RTT
+    /// under a millisecond is treated as one.
+    pub fn for_wan(rtt: Duration) -> Self {
+        let rtt = rtt.max(Duration::from_millis(1));
+        Self::new()
+            .heartbeat_interval(rtt * 2)
+            .election_timeout(rtt * 10, rtt * 20)
+    }
+
     pub fn election_timeout(mut self, min: Duration, max: Duration) -> Self {
         self.config.election_timeout_min = min;
         self.config.election_timeout_max = max;
@@ -120,6 +356,111 @@ impl RaftConfigBuilder {
         self
     }
 
+    pub fn partition_detection_timeouts(mut self, timeouts: u32) -> Self {
+        self.config.partition_detection_timeouts = timeouts;
+        self
+    }
+
+    pub fn verify_log_on_startup(mut self, verify: bool) -> Self {
+        self.config.verify_log_on_startup = verify;
+        self
+    }
+
+    pub fn deliver_noops_to_state_machine(mut self, deliver: bool) -> Self {
+        self.config.deliver_noops_to_state_machine = deliver;
+        self
+    }
+
+    pub fn initial_leader_timeout(mut self, timeout: Duration) -> Self {
+        self.config.initial_leader_timeout = Some(timeout);
+        self
+    }
+
+    pub fn min_election_interval(mut self, interval: Duration) -> Self {
+        self.config.min_election_interval = Some(interval);
+        self
+    }
+
+    pub fn election_storm_threshold(mut self, threshold: u32) -> Self {
+        self.config.election_storm_threshold = threshold;
+        self
+    }
+
+    pub fn max_clock_drift(mut self, drift: Duration) -> Self {
+        self.config.max_clock_drift = drift;
+        self
+    }
+
+    pub fn election_priority(mut self, priority: u32) -> Self {
+        self.config.election_priority = priority;
+        self
+    }
+
+    pub fn random_seed(mut self, seed: u64) -> Self {
+        self.config.random_seed = Some(seed);
+        self
+    }
+
+    pub fn separate_apply_thread(mut self, separate: bool) -> Self {
+        self.config.separate_apply_thread = separate;
+        self
+    }
+
+    pub fn manual_apply(mut self, manual: bool) -> Self {
+        self.config.manual_apply = manual;
+        self
+    }
+
+    pub fn snapshot_transfer_retries(mut self, retries: u32) -> Self {
+        self.config.snapshot_transfer_retries = retries;
+        self
+    }
+
+    pub fn read_priority(mut self, priority: u32) -> Self {
+        self.config.read_priority = priority;
+        self
+    }
+
+    pub fn read_batch_size(mut self, size: usize) -> Self {
+        self.config.read_batch_size = size;
+        self
+    }
+
+    pub fn forward_proposals(mut self, forward: bool) -> Self {
+        self.config.forward_proposals = forward;
+        self
+    }
+
+    pub fn allow_empty_commands(mut self, allow: bool) -> Self {
+        self.config.allow_empty_commands = allow;
+        self
+    }
+
+    pub fn step_down_on_append_failure(mut self, step_down: bool) -> Self {
+        self.config.step_down_on_append_failure = step_down;
+        self
+    }
+
+    pub fn max_term_gap(mut self, gap: u64) -> Self {
+        self.config.max_term_gap = Some(gap);
+        self
+    }
+
+    pub fn reset_term_on_gap(mut self, reset: bool) -> Self {
+        self.config.reset_term_on_gap = reset;
+        self
+    }
+
+    pub fn pre_vote(mut self, enable: bool) -> Self {
+        self.config.pre_vote = enable;
+        self
+    }
+
+    pub fn election_confirmation_timeouts(mut self, timeouts: u32) -> Self {
+        self.config.election_confirmation_timeouts = timeouts;
+        self
+    }
+
     pub fn build(self) -> RaftConfig {
         // Validate configuration
         assert!(
@@ -170,6 +511,20 @@ mod tests {
         assert!(config.enable_pipelining);
     }
 
+    #[test]
+    fn test_for_wan() {
+        let config = RaftConfigBuilder::for_wan(Duration::from_millis(50)).build();
+
+        assert_eq!(config.heartbeat_interval, Duration::from_millis(100));
+        assert_eq!(config.election_timeout_min, Duration::from_millis(500));
+        assert_eq!(config.election_timeout_max, Duration::from_millis(1000));
+        assert!(config.heartbeat_interval < config.election_timeout_min);
+        assert!(config.election_timeout_min < config.election_timeout_max);
+
+        // Degenerate RTTs still give a valid config
+        RaftConfigBuilder::for_wan(Duration::ZERO).build();
+    }
+
     #[test]
     #[should_panic(expected = "heartbeat_interval must be less than election_timeout_min")]
     fn test_invalid_heartbeat() {
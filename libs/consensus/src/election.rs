@@ -0,0 +1,106 @@
+//! Choosing how long a node waits for a leader before standing for election
+//!
+//! A node draws a fresh timeout from its [`ElectionTimeoutStrategy`] each
+//! time its election timer is reset. Unless one is set with
+//! [`RaftNodeBuilder::election_timeout_strategy`](crate::RaftNodeBuilder::election_timeout_strategy),
+//! nodes use [`RandomizedTimeout`] over the configured range.
+
+use crate::config::RaftConfig;
+
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::Duration;
+
+/// Decides each election timeout a node waits out
+///
+/// `attempt` counts the election timeouts in a row that passed without
+/// hearing from a leader: 0 while a leader is known, 1 after the first
+/// silent timeout, and so on, so a strategy can back off on repeated
+/// failure. `priority` is the node's
+/// [`RaftConfig::election_priority`].
+///
+/// Called on the node's main loop: keep it cheap.
+pub trait ElectionTimeoutStrategy: Send + Sync + 'static {
+    fn next_timeout(&self, attempt: u32, priority: u32) -> Duration;
+}
+
+impl<F> ElectionTimeoutStrategy for F
+where
+    F: Fn(u32, u32) -> Duration + Send + Sync + 'static,
+{
+    fn next_timeout(&self, attempt: u32, priority: u32) -> Duration {
+        self(attempt, priority)
+    }
+}
+
+/// A timeout drawn uniformly from `[min, max)`, ignoring attempt and priority
+///
+/// The randomness keeps nodes from timing out together and splitting the
+/// vote.
+pub struct RandomizedTimeout {
+    min: Duration,
+    max: Duration,
+    rng: Mutex<StdRng>,
+}
+
+impl RandomizedTimeout {
+    /// Draw from `[min, max)` with randomness seeded from the OS
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self::with_rng(min, max, StdRng::from_entropy())
+    }
+
+    /// Draw from `[min, max)` with randomness seeded from `seed`
+    pub fn seeded(min: Duration, max: Duration, seed: u64) -> Self {
+        Self::with_rng(min, max, StdRng::seed_from_u64(seed))
+    }
+
+    /// The strategy a node uses by default: the configured range, seeded
+    /// from [`RaftConfig::random_seed`] if set
+    pub fn from_config(config: &RaftConfig) -> Self {
+        let (min, max) = (config.election_timeout_min, config.election_timeout_max);
+        match config.random_seed {
+            Some(seed) => Self::seeded(min, max, seed),
+            None => Self::new(min, max),
+        }
+    }
+
+    fn with_rng(min: Duration, max: Duration, rng: StdRng) -> Self {
+        Self {
+            min,
+            max,
+            rng: Mutex::new(rng),
+        }
+    }
+}
+
+impl ElectionTimeoutStrategy for RandomizedTimeout {
+    fn next_timeout(&self, _attempt: u32, _priority: u32) -> Duration {
+        if self.max <= self.min {
+            return self.min;
+        }
+        self.rng.lock().gen_range(self.min..self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_randomized_timeout_stays_in_range() {
+        let (min, max) = (Duration::from_millis(150), Duration::from_millis(300));
+        let strategy = RandomizedTimeout::seeded(min, max, 7);
+        for attempt in 0..100 {
+            let timeout = strategy.next_timeout(attempt, 0);
+            assert!(min <= timeout && timeout < max);
+        }
+
+        // The same seed draws the same timeouts
+        let replay = RandomizedTimeout::seeded(min, max, 7);
+        let again = RandomizedTimeout::seeded(min, max, 7);
+        for _ in 0..10 {
+            assert_eq!(replay.next_timeout(0, 0), again.next_timeout(0, 0));
+        }
+    }
+}
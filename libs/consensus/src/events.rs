@@ -0,0 +1,114 @@
+//! Events emitted by a Raft node for operational awareness
+//!
+//! Events are delivered over a broadcast channel obtained from
+//! [`RaftNode::subscribe_events`](crate::RaftNode::subscribe_events). They are
+//! advisory: a slow subscriber that falls behind misses events rather than
+//! stalling the node.
+
+use crate::types::{LogIndex, NodeId, Term};
+
+/// Capacity of the per-node event broadcast channel
+pub(crate) const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Something noteworthy that happened on a Raft node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RaftEvent {
+    /// This node won an election and is now leader
+    BecameLeader { term: Term },
+
+    /// A snapshot of the state machine was taken and the log compacted
+    SnapshotCreated { last_included_index: LogIndex },
+
+    /// The node suspects it has been cut off from the rest of the cluster
+    ///
+    /// This is a heuristic signal, not a guarantee: a slow network can look
+    /// exactly like a partition.
+    SuspectedPartition {
+        /// Term the node was in when the suspicion was raised
+        term: Term,
+        /// Why the node thinks it is isolated
+        reason: PartitionReason,
+    },
+
+    /// This node started more elections in the last minute than
+    /// `RaftConfig::election_storm_threshold` allows
+    ///
+    /// Reported once per storm; the node reports again only after its
+    /// election rate has dropped back below the threshold.
+    ElectionStorm {
+        /// Term of the election that crossed the threshold
+        term: Term,
+        /// Elections started in the last minute
+        elections_per_minute: u32,
+    },
+
+    /// The leader gave up sending its snapshot to a follower
+    ///
+    /// Every attempt allowed by `RaftConfig::snapshot_transfer_retries`
+    /// failed. The follower stays behind until a later heartbeat starts a
+    /// fresh transfer.
+    SnapshotTransferFailed {
+        /// Follower the snapshot was for
+        peer: NodeId,
+        /// Transfers attempted, the first included
+        attempts: u32,
+    },
+
+    /// This node's term is further ahead of a live leader's than
+    /// `RaftConfig::max_term_gap` allows
+    ///
+    /// Usually a node returning from a long partition. Reported once per
+    /// term of this node.
+    TermGapDetected {
+        /// This node's term
+        term: Term,
+        /// Term of the leader that reached it
+        leader_term: Term,
+        /// The leader
+        leader: NodeId,
+        /// Whether the node fell back to the leader's term, as
+        /// `RaftConfig::reset_term_on_gap` asks
+        reset: bool,
+    },
+
+    /// The leader failed to write to its own log and stepped down
+    ///
+    /// Only reported with `RaftConfig::step_down_on_append_failure` set.
+    StorageFailure {
+        /// Term the node was leading
+        term: Term,
+        /// The storage error
+        error: String,
+    },
+
+    /// A Raft safety invariant was observed to be broken
+    ///
+    /// This should be impossible in a correct cluster and points to a bug or
+    /// a misconfiguration such as two nodes sharing a `NodeId`.
+    SafetyViolation {
+        /// Term at which the violation was observed
+        term: Term,
+        /// Which invariant was broken
+        violation: SafetyViolation,
+    },
+}
+
+/// Why a node suspects it has been partitioned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionReason {
+    /// A follower or candidate has gone this many consecutive election
+    /// timeouts without hearing from a leader or winning an election
+    NoLeaderContact { election_timeouts: u32 },
+
+    /// A leader has gone an election timeout without acknowledgements from
+    /// a quorum of voters; `acknowledged` of `voters` answered in that time,
+    /// counting the leader itself if it votes
+    LeaderLostQuorum { acknowledged: usize, voters: usize },
+}
+
+/// A broken Raft safety invariant
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafetyViolation {
+    /// Another node claimed leadership for a term this node is leading
+    ConflictingLeader { other_leader: NodeId },
+}
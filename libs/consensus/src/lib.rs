@@ -14,16 +14,17 @@
 //! # Example
 //!
 //! ```no_run
-//! use objectbox_consensus::{RaftNode, RaftConfig, StateMachine};
+//! use objectbox_consensus::{NodeId, RaftConfig, RaftNode, StateMachine, Transport};
 //! use std::sync::Arc;
 //!
-//! # async fn example() -> anyhow::Result<()> {
-//! // Create a Raft node
+//! # async fn example<SM: StateMachine>(
+//! #     state_machine: SM,
+//! #     transport: Arc<dyn Transport>,
+//! # ) -> anyhow::Result<()> {
+//! // Create and start a Raft node that reaches its peers through `transport`
+//! let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
 //! let config = RaftConfig::default();
-//! let node = RaftNode::new(1, config).await?;
-//!
-//! // Start the node
-//! node.start().await?;
+//! let node = RaftNode::new(NodeId(1), peers, config, state_machine, transport).await?;
 //!
 //! // Propose a command (only works on leader)
 //! let result = node.propose(b"SET key value".to_vec()).await?;
@@ -32,17 +33,52 @@
 //! ```
 
 mod config;
+mod election;
+mod events;
 mod log;
+mod metrics;
 mod node;
+mod observer;
 mod rpc;
 mod state;
+mod state_machine;
+mod state_storage;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod transport;
 mod types;
 
 pub use config::{RaftConfig, RaftConfigBuilder};
-pub use node::{RaftNode, StateMachine};
-pub use rpc::{AppendEntriesRequest, AppendEntriesResponse, RequestVoteRequest, RequestVoteResponse};
-pub use state::{NodeState, RaftRole};
-pub use types::{Entry, LogIndex, NodeId, Term};
+pub use election::{ElectionTimeoutStrategy, RandomizedTimeout};
+pub use events::{PartitionReason, RaftEvent, SafetyViolation};
+#[cfg(feature = "sled")]
+pub use log::KvLogStorage;
+pub use log::{
+    CachedLogStorage, Codec, FileLogConfig, FileLogStorage, LogCacheConfig, LogStorage,
+    MemoryLogStorage, RaftLog,
+};
+pub use metrics::RaftMetrics;
+pub use node::{RaftNode, RaftNodeBuilder, StateMachine};
+pub use observer::{RpcDirection, RpcKind, RpcObserver, RpcSummary};
+pub use rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, ForwardRequest, ForwardResponse,
+    InstallSnapshotRequest, InstallSnapshotResponse, JoinRequest, JoinResponse, PingRequest,
+    PingResponse, RequestVoteRequest, RequestVoteResponse, VoteDenialReason,
+};
+pub use state::{
+    MemberInfo, MemberRelation, MemberRole, NodeState, PeerProgress, PersistentState, RaftRole,
+};
+pub use state_machine::{AsyncStateMachine, BlockingStateMachine};
+pub use state_storage::{FileStateStorage, MemoryStateStorage, StateStorage};
+#[cfg(feature = "grpc")]
+pub use transport::{serve, GrpcTransport};
+#[cfg(feature = "tcp")]
+pub use transport::{serve_tcp, serve_tcp_with, TcpTransport, UnknownRpc};
+pub use transport::{ChannelTransport, NoopTransport, Transport};
+pub use types::{
+    payload_redaction, set_payload_redaction, ClusterConfig, Entry, EntryKind, LogIndex,
+    LogPosition, NodeId, Redacted, Snapshot, SnapshotMetadata, StateBundle, Term,
+};
 
 /// Result type for Raft operations
 pub type Result<T> = std::result::Result<T, RaftError>;
@@ -50,8 +86,8 @@ pub type Result<T> = std::result::Result<T, RaftError>;
 /// Errors that can occur during Raft operations
 #[derive(Debug, thiserror::Error)]
 pub enum RaftError {
-    #[error("Not the leader (current leader: {0:?})")]
-    NotLeader(Option<NodeId>),
+    #[error("Not the leader (current leader: {:?})", .0.leader)]
+    NotLeader(NotLeaderInfo),
 
     #[error("Node is shutting down")]
     ShuttingDown,
@@ -59,12 +95,73 @@ pub enum RaftError {
     #[error("Log index out of range: {0}")]
     LogIndexOutOfRange(LogIndex),
 
+    #[error("Invalid entry: {0}")]
+    InvalidEntry(String),
+
+    #[error("Non-contiguous append: expected entry {expected}, got {got}")]
+    NonContiguousAppend { expected: LogIndex, got: LogIndex },
+
+    #[error("Refusing to truncate committed entry {index} (commit index: {commit_index})")]
+    TruncateCommitted {
+        index: LogIndex,
+        commit_index: LogIndex,
+    },
+
+    #[error("Corrupt log: {0}")]
+    CorruptLog(String),
+
     #[error("Storage error: {0}")]
     Storage(#[from] std::io::Error),
 
     #[error("RPC error: {0}")]
     Rpc(String),
 
+    #[error("Invalid state bundle: {0}")]
+    InvalidBundle(String),
+
+    #[error("Invalid configuration change: {0}")]
+    InvalidConfiguration(String),
+
+    #[error("Operation timed out")]
+    Timeout,
+
+    #[error("Leader lease expired (see RaftConfig::max_clock_drift)")]
+    LeaseExpired,
+
+    #[error("Empty command rejected (see RaftConfig::allow_empty_commands)")]
+    EmptyCommand,
+
+    #[error("The node applies entries itself (see RaftConfig::manual_apply)")]
+    ManualApplyDisabled,
+
     #[error("Internal error: {0}")]
     Internal(String),
 }
+
+impl RaftError {
+    /// `NotLeader` carrying just a leader hint
+    pub fn not_leader(leader: Option<NodeId>) -> Self {
+        RaftError::NotLeader(NotLeaderInfo {
+            leader,
+            ..Default::default()
+        })
+    }
+}
+
+/// What a node that isn't leading knows about the cluster
+///
+/// Lets a client refresh its view of the cluster from the error alone. Only
+/// `leader` survives forwarding; `term` and `voters` are filled in by the
+/// node that refused the request, and left empty where it had nothing to
+/// add.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NotLeaderInfo {
+    /// The leader this node last heard from
+    pub leader: Option<NodeId>,
+
+    /// This node's current term
+    pub term: Option<Term>,
+
+    /// The voting membership this node is running with
+    pub voters: Vec<NodeId>,
+}
@@ -3,11 +3,24 @@
 //! The log is the source of truth for all commands that have been proposed.
 //! It must be persisted to stable storage to survive crashes.
 
-use crate::types::{Entry, LogIndex, Snapshot, SnapshotMetadata, Term};
+use crate::types::{Entry, LogIndex, LogPosition, Snapshot, Term};
 use crate::{Result, RaftError};
 use parking_lot::RwLock;
-use std::path::PathBuf;
 use std::sync::Arc;
+use tracing::error;
+
+mod cache;
+mod codec;
+mod file;
+#[cfg(feature = "sled")]
+mod kv;
+
+pub use cache::{CachedLogStorage, LogCacheConfig};
+pub use codec::Codec;
+pub(crate) use file::sync_dir;
+pub use file::{FileLogConfig, FileLogStorage};
+#[cfg(feature = "sled")]
+pub use kv::KvLogStorage;
 
 /// Trait for log storage backends
 ///
@@ -16,10 +29,24 @@ pub trait LogStorage: Send + Sync {
     /// Append entries to the log
     fn append(&mut self, entries: Vec<Entry>) -> Result<()>;
 
+    /// Append entries and make them durable before returning
+    ///
+    /// Used for entries the node can't go on without, like configuration
+    /// changes, even from a backend that batches the syncs of plain
+    /// appends. One call is one sync. Backends that sync every `append`
+    /// anyway can leave this as it is.
+    fn append_sync(&mut self, entries: Vec<Entry>) -> Result<()> {
+        self.append(entries)
+    }
+
     /// Get an entry at a specific index
     fn get(&self, index: LogIndex) -> Result<Option<Entry>>;
 
     /// Get a range of entries [start, end)
+    ///
+    /// A range with `end <= start` is empty: it returns no entries and no
+    /// error wherever it lies, so callers needn't check before asking for
+    /// everything from a peer's next index on.
     fn get_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Entry>>;
 
     /// Get all entries from start index onwards
@@ -53,6 +80,12 @@ pub trait LogStorage: Send + Sync {
 pub struct MemoryLogStorage {
     entries: Vec<Entry>,
     snapshot: Option<Snapshot>,
+
+    /// Log index of `entries[0]`
+    ///
+    /// Tracked separately from the snapshot so that installing a snapshot
+    /// doesn't shift the mapping of entries that haven't been compacted yet.
+    first_index: LogIndex,
 }
 
 impl MemoryLogStorage {
@@ -60,15 +93,13 @@ impl MemoryLogStorage {
         Self {
             entries: vec![],
             snapshot: None,
+            first_index: LogIndex(1),
         }
     }
 
     /// Get the offset caused by log compaction
     fn offset(&self) -> LogIndex {
-        self.snapshot
-            .as_ref()
-            .map(|s| s.metadata.last_included_index + 1)
-            .unwrap_or(LogIndex(1))
+        self.first_index
     }
 
     /// Convert a log index to an array index
@@ -95,26 +126,51 @@ impl Default for MemoryLogStorage {
 
 impl LogStorage for MemoryLogStorage {
     fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let Some(first) = entries.first().map(|e| e.index) else {
+            return Ok(());
+        };
+        for (entry, expected) in entries.iter().zip((first.0..).map(LogIndex)) {
+            if entry.index != expected {
+                return Err(RaftError::NonContiguousAppend {
+                    expected,
+                    got: entry.index,
+                });
+            }
+        }
+
+        if self.entries.is_empty() {
+            // Nothing left to line up with (empty or fully compacted), so the
+            // log may restart at any index
+            self.first_index = first;
+        } else if first != self.last_index() + 1 {
+            return Err(RaftError::NonContiguousAppend {
+                expected: self.last_index() + 1,
+                got: first,
+            });
+        }
+
         self.entries.extend(entries);
         Ok(())
     }
 
     fn get(&self, index: LogIndex) -> Result<Option<Entry>> {
-        if let Some(snapshot) = &self.snapshot {
-            if index <= snapshot.metadata.last_included_index {
-                return Ok(None); // Entry is in snapshot
-            }
-        }
-
+        // Entries compacted into the snapshot fall below `first_index`;
+        // trailing entries kept after a snapshot are still served
         Ok(self
             .to_array_index(index)
             .and_then(|idx| self.entries.get(idx).cloned()))
     }
 
     fn get_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Entry>> {
+        if end <= start {
+            return Ok(Vec::new());
+        }
+        // Starting past the last entry (e.g. right after a snapshot that
+        // covers the whole log) just finds nothing
         let start_idx = self
             .to_array_index(start)
-            .ok_or(RaftError::LogIndexOutOfRange(start))?;
+            .ok_or(RaftError::LogIndexOutOfRange(start))?
+            .min(self.entries.len());
         let end_idx = self
             .to_array_index(end)
             .unwrap_or(self.entries.len())
@@ -126,7 +182,8 @@ impl LogStorage for MemoryLogStorage {
     fn get_from(&self, start: LogIndex) -> Result<Vec<Entry>> {
         let start_idx = self
             .to_array_index(start)
-            .ok_or(RaftError::LogIndexOutOfRange(start))?;
+            .ok_or(RaftError::LogIndexOutOfRange(start))?
+            .min(self.entries.len());
 
         Ok(self.entries[start_idx..].to_vec())
     }
@@ -164,12 +221,12 @@ impl LogStorage for MemoryLogStorage {
             if index == snapshot.metadata.last_included_index {
                 return Ok(Some(snapshot.metadata.last_included_term));
             }
-            if index < snapshot.metadata.last_included_index {
-                return Ok(None);
-            }
         }
 
-        Ok(self.get(index)?.map(|e| e.term))
+        Ok(self
+            .to_array_index(index)
+            .and_then(|i| self.entries.get(i))
+            .map(|e| e.term))
     }
 
     fn set_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
@@ -184,7 +241,9 @@ impl LogStorage for MemoryLogStorage {
     fn compact(&mut self, through_index: LogIndex) -> Result<()> {
         if let Some(idx) = self.to_array_index(through_index) {
             // Remove entries up to through_index
-            self.entries.drain(0..=idx);
+            let drain_to = (idx + 1).min(self.entries.len());
+            self.entries.drain(0..drain_to);
+            self.first_index = through_index + 1;
         }
         Ok(())
     }
@@ -210,6 +269,10 @@ impl RaftLog {
         self.storage.write().append(entries)
     }
 
+    pub fn append_sync(&self, entries: Vec<Entry>) -> Result<()> {
+        self.storage.write().append_sync(entries)
+    }
+
     pub fn get(&self, index: LogIndex) -> Result<Option<Entry>> {
         self.storage.read().get(index)
     }
@@ -226,6 +289,30 @@ impl RaftLog {
         self.storage.write().delete_from(index)
     }
 
+    /// Delete entries from `index` onwards, refusing to touch committed entries
+    ///
+    /// Committed entries must never be removed. Returns
+    /// [`RaftError::TruncateCommitted`] without modifying the log if `index`
+    /// is at or below `commit_index`.
+    pub fn truncate_suffix_if_uncommitted(
+        &self,
+        index: LogIndex,
+        commit_index: LogIndex,
+    ) -> Result<()> {
+        if index <= commit_index {
+            error!(
+                "Refusing to truncate log at {}: entries through {} are committed",
+                index, commit_index
+            );
+            return Err(RaftError::TruncateCommitted {
+                index,
+                commit_index,
+            });
+        }
+
+        self.delete_from(index)
+    }
+
     pub fn last_index(&self) -> LogIndex {
         self.storage.read().last_index()
     }
@@ -234,6 +321,13 @@ impl RaftLog {
         self.storage.read().last_term()
     }
 
+    /// Position of the last entry, or of the snapshot if the log is empty
+    /// behind it
+    pub fn last_position(&self) -> LogPosition {
+        let storage = self.storage.read();
+        LogPosition::new(storage.last_term(), storage.last_index())
+    }
+
     pub fn get_term(&self, index: LogIndex) -> Result<Option<Term>> {
         self.storage.read().get_term(index)
     }
@@ -249,6 +343,52 @@ impl RaftLog {
     pub fn compact(&self, through_index: LogIndex) -> Result<()> {
         self.storage.write().compact(through_index)
     }
+
+    /// Check that the stored log is internally consistent
+    ///
+    /// Verifies that indices run contiguously from just after the snapshot up
+    /// to `last_index` and that terms never decrease. This reads every entry,
+    /// so it's meant for startup rather than the hot path.
+    pub fn verify_integrity(&self) -> Result<()> {
+        let storage = self.storage.read();
+
+        let (mut expected_index, mut prev_term) = match storage.get_snapshot() {
+            Some(snapshot) => (
+                snapshot.metadata.last_included_index + 1,
+                snapshot.metadata.last_included_term,
+            ),
+            None => (LogIndex(1), Term(0)),
+        };
+
+        for entry in storage.get_from(expected_index)? {
+            if entry.index != expected_index {
+                return Err(RaftError::CorruptLog(format!(
+                    "expected entry {} but found {}",
+                    expected_index, entry.index
+                )));
+            }
+            if entry.term < prev_term {
+                return Err(RaftError::CorruptLog(format!(
+                    "entry {} has {} which is lower than the preceding {}",
+                    entry.index, entry.term, prev_term
+                )));
+            }
+
+            prev_term = entry.term;
+            expected_index.increment();
+        }
+
+        let last_index = storage.last_index();
+        if last_index + 1 != expected_index {
+            return Err(RaftError::CorruptLog(format!(
+                "last index is {} but entries end at {}",
+                last_index,
+                expected_index - 1
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 impl Clone for RaftLog {
@@ -262,6 +402,7 @@ impl Clone for RaftLog {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::{ClusterConfig, SnapshotMetadata};
 
     #[test]
     fn test_append_and_get() {
@@ -318,6 +459,173 @@ mod tests {
         assert_eq!(range[1].command, b"cmd2");
     }
 
+    #[test]
+    fn test_truncate_suffix_refuses_committed_entries() {
+        let log = RaftLog::new_memory();
+        log.append(vec![
+            Entry::new(Term(1), LogIndex(1), b"cmd1".to_vec()),
+            Entry::new(Term(1), LogIndex(2), b"cmd2".to_vec()),
+            Entry::new(Term(2), LogIndex(3), b"cmd3".to_vec()),
+        ])
+        .unwrap();
+
+        let err = log
+            .truncate_suffix_if_uncommitted(LogIndex(2), LogIndex(2))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            RaftError::TruncateCommitted {
+                index: LogIndex(2),
+                commit_index: LogIndex(2)
+            }
+        ));
+        assert_eq!(log.last_index(), LogIndex(3));
+
+        log.truncate_suffix_if_uncommitted(LogIndex(3), LogIndex(2))
+            .unwrap();
+        assert_eq!(log.last_index(), LogIndex(2));
+    }
+
+    #[test]
+    fn test_verify_integrity() {
+        let entries = vec![
+            Entry::new(Term(1), LogIndex(1), b"cmd1".to_vec()),
+            Entry::new(Term(1), LogIndex(2), b"cmd2".to_vec()),
+            Entry::new(Term(2), LogIndex(3), b"cmd3".to_vec()),
+        ];
+        let log = RaftLog::new_memory();
+        log.append(entries.clone()).unwrap();
+        log.verify_integrity().unwrap();
+
+        // Index gap, which append itself refuses to create
+        let mut storage = MemoryLogStorage::new();
+        storage.append(entries).unwrap();
+        storage
+            .entries
+            .push(Entry::new(Term(2), LogIndex(5), b"cmd5".to_vec()));
+        let log = RaftLog::new(Box::new(storage));
+        assert!(matches!(
+            log.verify_integrity(),
+            Err(RaftError::CorruptLog(_))
+        ));
+
+        // Term regression
+        let log = RaftLog::new_memory();
+        log.append(vec![
+            Entry::new(Term(2), LogIndex(1), b"cmd1".to_vec()),
+            Entry::new(Term(1), LogIndex(2), b"cmd2".to_vec()),
+        ])
+        .unwrap();
+        assert!(matches!(
+            log.verify_integrity(),
+            Err(RaftError::CorruptLog(_))
+        ));
+    }
+
+    /// A backend's name, the backend, and the directory it lives in if any
+    type Backend = (&'static str, Box<dyn LogStorage>, Option<tempfile::TempDir>);
+
+    /// Every backend, freshly created
+    fn every_backend() -> Vec<Backend> {
+        let file_dir = tempfile::tempdir().unwrap();
+        let file = FileLogStorage::open(file_dir.path(), Default::default()).unwrap();
+        #[cfg_attr(not(feature = "sled"), allow(unused_mut))]
+        let mut backends: Vec<Backend> = vec![
+            ("memory", Box::new(MemoryLogStorage::new()), None),
+            ("file", Box::new(file), Some(file_dir)),
+            (
+                "cached",
+                Box::new(CachedLogStorage::new(
+                    Box::new(MemoryLogStorage::new()),
+                    LogCacheConfig::default(),
+                )),
+                None,
+            ),
+        ];
+        #[cfg(feature = "sled")]
+        {
+            let kv_dir = tempfile::tempdir().unwrap();
+            let kv = KvLogStorage::open(kv_dir.path()).unwrap();
+            backends.push(("sled", Box::new(kv), Some(kv_dir)));
+        }
+        backends
+    }
+
+    #[test]
+    fn test_empty_ranges_on_every_backend() {
+        for (name, mut log, _dir) in every_backend() {
+            let empty = |log: &dyn LogStorage, start, end| {
+                let range = log.get_range(LogIndex(start), LogIndex(end));
+                assert!(
+                    matches!(&range, Ok(entries) if entries.is_empty()),
+                    "{}: get_range({}, {}) gave {:?}",
+                    name,
+                    start,
+                    end,
+                    range
+                );
+            };
+
+            empty(&*log, 1, 1);
+            empty(&*log, 3, 1);
+            log.append(
+                (1..=5)
+                    .map(|i| Entry::new(Term(1), LogIndex(i), vec![i as u8]))
+                    .collect(),
+            )
+            .unwrap();
+            for (start, end) in [(3, 3), (4, 2), (6, 6), (9, 6), (6, 1)] {
+                empty(&*log, start, end);
+            }
+            assert_eq!(log.get_range(LogIndex(2), LogIndex(4)).unwrap().len(), 2);
+
+            // Even below the compacted prefix
+            log.set_snapshot(Snapshot {
+                metadata: SnapshotMetadata {
+                    last_included_index: LogIndex(3),
+                    last_included_term: Term(1),
+                    configuration: ClusterConfig::default(),
+                },
+                data: vec![],
+            })
+            .unwrap();
+            log.compact(LogIndex(3)).unwrap();
+            empty(&*log, 2, 2);
+            empty(&*log, 3, 1);
+            empty(&*log, 6, 6);
+        }
+    }
+
+    #[test]
+    fn test_append_must_follow_on() {
+        let entry = |index| Entry::new(Term(1), LogIndex(index), vec![]);
+
+        // An empty log takes any starting index
+        let mut log = MemoryLogStorage::new();
+        log.append(vec![entry(5), entry(6)]).unwrap();
+        assert_eq!(log.last_index(), LogIndex(6));
+        assert_eq!(log.get(LogIndex(5)).unwrap().unwrap().index, LogIndex(5));
+
+        // After that, only the next index
+        log.append(vec![entry(7)]).unwrap();
+        assert_eq!(log.last_index(), LogIndex(7));
+        for (batch, expected, got) in [
+            (vec![entry(9)], 8, 9),
+            (vec![entry(7)], 8, 7),
+            (vec![entry(8), entry(10)], 9, 10),
+        ] {
+            match log.append(batch) {
+                Err(RaftError::NonContiguousAppend {
+                    expected: e,
+                    got: g,
+                }) => assert_eq!((e, g), (LogIndex(expected), LogIndex(got))),
+                other => panic!("expected a non-contiguous append, got {:?}", other),
+            }
+        }
+        assert_eq!(log.last_index(), LogIndex(7));
+        assert!(log.get(LogIndex(8)).unwrap().is_none());
+    }
+
     #[test]
     fn test_snapshot_compaction() {
         let mut log = MemoryLogStorage::new();
@@ -335,7 +643,7 @@ mod tests {
             metadata: SnapshotMetadata {
                 last_included_index: LogIndex(2),
                 last_included_term: Term(1),
-                configuration: vec![],
+                configuration: ClusterConfig::default(),
             },
             data: b"snapshot_data".to_vec(),
         };
@@ -348,4 +656,54 @@ mod tests {
         assert!(log.get(LogIndex(1)).unwrap().is_none()); // In snapshot
         assert_eq!(log.get(LogIndex(3)).unwrap().unwrap().command, b"cmd3");
     }
+
+    #[test]
+    fn test_snapshot_covering_whole_log() {
+        let mut log = MemoryLogStorage::new();
+        log.append(vec![
+            Entry::new(Term(1), LogIndex(1), b"cmd1".to_vec()),
+            Entry::new(Term(2), LogIndex(2), b"cmd2".to_vec()),
+            Entry::new(Term(2), LogIndex(3), b"cmd3".to_vec()),
+        ])
+        .unwrap();
+        log.set_snapshot(Snapshot {
+            metadata: SnapshotMetadata {
+                last_included_index: LogIndex(3),
+                last_included_term: Term(2),
+                configuration: ClusterConfig::default(),
+            },
+            data: vec![],
+        })
+        .unwrap();
+        log.compact(LogIndex(3)).unwrap();
+
+        // The snapshot stands in for the last entry
+        assert_eq!(log.last_index(), LogIndex(3));
+        assert_eq!(log.last_term(), Term(2));
+        assert_eq!(log.get_term(LogIndex(3)).unwrap(), Some(Term(2)));
+        assert_eq!(log.get_term(LogIndex(2)).unwrap(), None);
+        assert_eq!(log.get_term(LogIndex(4)).unwrap(), None);
+
+        // But its entries are gone
+        for index in 1..=4 {
+            assert!(log.get(LogIndex(index)).unwrap().is_none());
+        }
+        assert!(matches!(
+            log.get_range(LogIndex(3), LogIndex(4)),
+            Err(RaftError::LogIndexOutOfRange(LogIndex(3)))
+        ));
+        assert!(log.get_range(LogIndex(4), LogIndex(4)).unwrap().is_empty());
+        assert!(log.get_range(LogIndex(4), LogIndex(10)).unwrap().is_empty());
+        assert!(log.get_range(LogIndex(6), LogIndex(10)).unwrap().is_empty());
+        assert!(log.get_from(LogIndex(4)).unwrap().is_empty());
+        assert!(log.get_from(LogIndex(6)).unwrap().is_empty());
+
+        // The log carries on right after the snapshot
+        log.append(vec![Entry::new(Term(3), LogIndex(4), b"cmd4".to_vec())])
+            .unwrap();
+        assert_eq!(log.last_index(), LogIndex(4));
+        assert_eq!(log.get(LogIndex(4)).unwrap().unwrap().command, b"cmd4");
+        assert_eq!(log.get_range(LogIndex(4), LogIndex(10)).unwrap().len(), 1);
+        assert_eq!(log.get_term(LogIndex(3)).unwrap(), Some(Term(2)));
+    }
 }
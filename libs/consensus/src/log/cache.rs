@@ -0,0 +1,384 @@
+//! In-memory cache in front of a [`LogStorage`]
+//!
+//! A leader catching up several followers reads the same recent entries over
+//! and over. [`CachedLogStorage`] keeps the most recently used ones in memory
+//! so only the first of those reads reaches the backend.
+
+use crate::log::LogStorage;
+use crate::types::{Entry, LogIndex, Snapshot, Term};
+use crate::Result;
+
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+
+/// Limits for [`CachedLogStorage`]
+///
+/// The least recently used entries are evicted once either limit is
+/// exceeded.
+#[derive(Debug, Clone)]
+pub struct LogCacheConfig {
+    /// Most entries kept in memory
+    pub max_entries: usize,
+
+    /// Most bytes of entries kept in memory, counting each entry's command
+    /// plus its fixed-size fields
+    pub max_bytes: usize,
+}
+
+impl Default for LogCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 4096,
+
+            // 16 MiB
+            max_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Least recently used entries, keyed by index
+#[derive(Default)]
+struct EntryCache {
+    entries: BTreeMap<LogIndex, (Entry, u64)>,
+
+    /// Index of each cached entry by the tick it was last used at
+    recency: BTreeMap<u64, LogIndex>,
+
+    /// Advances on every use
+    tick: u64,
+    bytes: usize,
+}
+
+impl EntryCache {
+    fn size_of(entry: &Entry) -> usize {
+        std::mem::size_of::<Entry>() + entry.command.len()
+    }
+
+    fn get(&mut self, index: LogIndex) -> Option<Entry> {
+        self.tick += 1;
+        let (entry, last_used) = self.entries.get_mut(&index)?;
+        self.recency.remove(last_used);
+        *last_used = self.tick;
+        self.recency.insert(self.tick, index);
+        Some(entry.clone())
+    }
+
+    /// Every entry in `[start, end)`, or nothing unless all of them are
+    /// cached
+    fn get_range(&mut self, start: LogIndex, end: LogIndex) -> Option<Vec<Entry>> {
+        if start >= end || (start.0..end.0).any(|i| !self.entries.contains_key(&LogIndex(i))) {
+            return None;
+        }
+        (start.0..end.0).map(|i| self.get(LogIndex(i))).collect()
+    }
+
+    fn insert(&mut self, entry: Entry, limits: &LogCacheConfig) {
+        self.remove(entry.index);
+        self.tick += 1;
+        self.bytes += Self::size_of(&entry);
+        self.recency.insert(self.tick, entry.index);
+        self.entries.insert(entry.index, (entry, self.tick));
+
+        while self.entries.len() > limits.max_entries || self.bytes > limits.max_bytes {
+            let Some((_, index)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some((entry, _)) = self.entries.remove(&index) {
+                self.bytes -= Self::size_of(&entry);
+            }
+        }
+    }
+
+    fn remove(&mut self, index: LogIndex) {
+        if let Some((entry, last_used)) = self.entries.remove(&index) {
+            self.recency.remove(&last_used);
+            self.bytes -= Self::size_of(&entry);
+        }
+    }
+
+    /// Drop every cached entry `keep` returns false for
+    fn retain(&mut self, keep: impl Fn(LogIndex) -> bool) {
+        let dropped: Vec<_> = self.entries.keys().copied().filter(|&i| !keep(i)).collect();
+        for index in dropped {
+            self.remove(index);
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Wraps a [`LogStorage`], serving reads of recently used entries from
+/// memory
+///
+/// Appended entries are cached as they're written, since they're the ones
+/// about to be replicated. Truncation, compaction and installing a snapshot
+/// drop the entries they affect, so the cache never serves an entry the
+/// backend no longer has.
+pub struct CachedLogStorage {
+    inner: Box<dyn LogStorage>,
+    config: LogCacheConfig,
+    cache: Mutex<EntryCache>,
+}
+
+impl CachedLogStorage {
+    pub fn new(inner: Box<dyn LogStorage>, config: LogCacheConfig) -> Self {
+        Self {
+            inner,
+            config,
+            cache: Mutex::new(EntryCache::default()),
+        }
+    }
+
+    /// Unwrap the cache, returning the storage it reads from
+    pub fn into_inner(self) -> Box<dyn LogStorage> {
+        self.inner
+    }
+
+    /// Number of entries currently cached
+    pub fn cached_entries(&self) -> usize {
+        self.cache.lock().entries.len()
+    }
+
+    /// Append through the backend with `append`, caching what it accepts
+    fn append_with(
+        &mut self,
+        entries: Vec<Entry>,
+        append: impl FnOnce(&mut dyn LogStorage, Vec<Entry>) -> Result<()>,
+    ) -> Result<()> {
+        let Some(first) = entries.first().map(|e| e.index) else {
+            return append(&mut *self.inner, entries);
+        };
+        // Whatever sat at these indices before is being replaced
+        self.cache.get_mut().retain(|i| i < first);
+        append(&mut *self.inner, entries.clone())?;
+        self.cache_all(&entries);
+        Ok(())
+    }
+
+    fn cache_all(&self, entries: &[Entry]) {
+        let mut cache = self.cache.lock();
+        for entry in entries {
+            cache.insert(entry.clone(), &self.config);
+        }
+    }
+}
+
+impl LogStorage for CachedLogStorage {
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        self.append_with(entries, |inner, entries| inner.append(entries))
+    }
+
+    fn append_sync(&mut self, entries: Vec<Entry>) -> Result<()> {
+        self.append_with(entries, |inner, entries| inner.append_sync(entries))
+    }
+
+    fn get(&self, index: LogIndex) -> Result<Option<Entry>> {
+        if let Some(entry) = self.cache.lock().get(index) {
+            return Ok(Some(entry));
+        }
+        let entry = self.inner.get(index)?;
+        if let Some(entry) = &entry {
+            self.cache.lock().insert(entry.clone(), &self.config);
+        }
+        Ok(entry)
+    }
+
+    fn get_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Entry>> {
+        if let Some(entries) = self.cache.lock().get_range(start, end) {
+            return Ok(entries);
+        }
+        let entries = self.inner.get_range(start, end)?;
+        self.cache_all(&entries);
+        Ok(entries)
+    }
+
+    fn get_from(&self, start: LogIndex) -> Result<Vec<Entry>> {
+        let end = self.inner.last_index() + 1;
+        if let Some(entries) = self.cache.lock().get_range(start, end) {
+            return Ok(entries);
+        }
+        let entries = self.inner.get_from(start)?;
+        self.cache_all(&entries);
+        Ok(entries)
+    }
+
+    fn delete_from(&mut self, index: LogIndex) -> Result<()> {
+        self.cache.get_mut().retain(|i| i < index);
+        self.inner.delete_from(index)
+    }
+
+    fn last_index(&self) -> LogIndex {
+        self.inner.last_index()
+    }
+
+    fn last_term(&self) -> Term {
+        self.inner.last_term()
+    }
+
+    fn get_term(&self, index: LogIndex) -> Result<Option<Term>> {
+        if let Some(entry) = self.cache.lock().get(index) {
+            return Ok(Some(entry.term));
+        }
+        self.inner.get_term(index)
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        // Backends may drop or replace entries when a snapshot is installed
+        self.cache.get_mut().clear();
+        self.inner.set_snapshot(snapshot)
+    }
+
+    fn get_snapshot(&self) -> Option<Snapshot> {
+        self.inner.get_snapshot()
+    }
+
+    fn compact(&mut self, through_index: LogIndex) -> Result<()> {
+        self.cache.get_mut().retain(|i| i > through_index);
+        self.inner.compact(through_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::MemoryLogStorage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Counts the entry reads that reach the wrapped storage
+    struct CountingStorage {
+        inner: MemoryLogStorage,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl LogStorage for CountingStorage {
+        fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+            self.inner.append(entries)
+        }
+        fn get(&self, index: LogIndex) -> Result<Option<Entry>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.get(index)
+        }
+        fn get_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Entry>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.get_range(start, end)
+        }
+        fn get_from(&self, start: LogIndex) -> Result<Vec<Entry>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.get_from(start)
+        }
+        fn delete_from(&mut self, index: LogIndex) -> Result<()> {
+            self.inner.delete_from(index)
+        }
+        fn last_index(&self) -> LogIndex {
+            self.inner.last_index()
+        }
+        fn last_term(&self) -> Term {
+            self.inner.last_term()
+        }
+        fn get_term(&self, index: LogIndex) -> Result<Option<Term>> {
+            self.inner.get_term(index)
+        }
+        fn set_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+            self.inner.set_snapshot(snapshot)
+        }
+        fn get_snapshot(&self) -> Option<Snapshot> {
+            self.inner.get_snapshot()
+        }
+        fn compact(&mut self, through_index: LogIndex) -> Result<()> {
+            self.inner.compact(through_index)
+        }
+    }
+
+    /// Ten entries written straight to the backend, so none start out cached
+    fn cached_log(config: LogCacheConfig) -> (CachedLogStorage, Arc<AtomicUsize>) {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let mut inner = MemoryLogStorage::new();
+        inner
+            .append(
+                (1..=10)
+                    .map(|i| Entry::new(Term(1), LogIndex(i), vec![i as u8; 100]))
+                    .collect(),
+            )
+            .unwrap();
+        let storage = CountingStorage {
+            inner,
+            reads: Arc::clone(&reads),
+        };
+        (CachedLogStorage::new(Box::new(storage), config), reads)
+    }
+
+    #[test]
+    fn test_repeated_range_reads_hit_cache() {
+        let (log, reads) = cached_log(LogCacheConfig::default());
+
+        let first = log.get_range(LogIndex(4), LogIndex(8)).unwrap();
+        assert_eq!(first.len(), 4);
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        for _ in 0..5 {
+            let again = log.get_range(LogIndex(4), LogIndex(8)).unwrap();
+            let indices: Vec<_> = again.iter().map(|e| e.index).collect();
+            assert_eq!(
+                indices,
+                [LogIndex(4), LogIndex(5), LogIndex(6), LogIndex(7)]
+            );
+            assert_eq!(
+                log.get(LogIndex(5)).unwrap().unwrap().command,
+                first[1].command
+            );
+        }
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        // Reaching past the cached part goes back to the backend once
+        assert_eq!(log.get_from(LogIndex(4)).unwrap().len(), 7);
+        assert_eq!(log.get_from(LogIndex(4)).unwrap().len(), 7);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_truncation_and_compaction_invalidate() {
+        let (mut log, reads) = cached_log(LogCacheConfig::default());
+        log.get_from(LogIndex(1)).unwrap();
+        assert_eq!(log.cached_entries(), 10);
+
+        log.delete_from(LogIndex(8)).unwrap();
+        log.append(vec![Entry::new(Term(2), LogIndex(8), b"new".to_vec())])
+            .unwrap();
+        let entry = log.get(LogIndex(8)).unwrap().unwrap();
+        assert_eq!(entry.term, Term(2));
+        assert!(log.get(LogIndex(9)).unwrap().is_none());
+
+        log.compact(LogIndex(3)).unwrap();
+        assert_eq!(log.cached_entries(), 5);
+        let before = reads.load(Ordering::SeqCst);
+        assert_eq!(log.get_range(LogIndex(4), LogIndex(9)).unwrap().len(), 5);
+        assert_eq!(reads.load(Ordering::SeqCst), before);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let entry_size = std::mem::size_of::<Entry>() + 100;
+        let (log, reads) = cached_log(LogCacheConfig {
+            max_entries: 100,
+            max_bytes: 3 * entry_size,
+        });
+
+        for i in [1, 2, 3] {
+            log.get(LogIndex(i)).unwrap();
+        }
+        // Touch 1 so 2 is the oldest when 4 comes in
+        log.get(LogIndex(1)).unwrap();
+        log.get(LogIndex(4)).unwrap();
+        assert_eq!(log.cached_entries(), 3);
+
+        let before = reads.load(Ordering::SeqCst);
+        log.get(LogIndex(1)).unwrap();
+        log.get(LogIndex(4)).unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), before);
+        log.get(LogIndex(2)).unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), before + 1);
+    }
+}
@@ -0,0 +1,135 @@
+//! How [`FileLogStorage`](super::FileLogStorage) encodes what it writes
+//!
+//! Log entries and snapshots are configured separately: entries sit on the
+//! write path of every proposal and want a cheap encoding, while snapshots are
+//! large, written rarely and usually compress well.
+
+use crate::types::{Entry, EntryKind, LogIndex, Term};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+/// Encoding of log records or the snapshot file
+///
+/// The codec isn't recorded on disk, so reopen a directory with the codecs it
+/// was written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// Plain bincode
+    #[default]
+    Bincode,
+
+    /// bincode, then zlib-compressed at `level` (0 = none, 9 = smallest)
+    Deflate { level: u32 },
+}
+
+impl Codec {
+    pub(crate) fn encode<T: Serialize>(&self, value: &T) -> bincode::Result<Vec<u8>> {
+        match *self {
+            Codec::Bincode => bincode::serialize(value),
+            Codec::Deflate { level } => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level.min(9)));
+                bincode::serialize_into(&mut encoder, value)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> bincode::Result<T> {
+        match self {
+            Codec::Bincode => bincode::deserialize(data),
+            Codec::Deflate { .. } => bincode::deserialize(&inflate(data)?),
+        }
+    }
+
+    /// Decode a log entry, including one written before entries carried
+    /// metadata
+    pub(crate) fn decode_entry(&self, data: &[u8]) -> bincode::Result<Entry> {
+        match self {
+            Codec::Bincode => decode_entry(data),
+            Codec::Deflate { .. } => decode_entry(&inflate(data)?),
+        }
+    }
+}
+
+fn inflate(data: &[u8]) -> bincode::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    ZlibDecoder::new(data).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// An [`Entry`] as encoded before it had a `metadata` field
+#[derive(Deserialize)]
+struct LegacyEntry {
+    term: Term,
+    index: LogIndex,
+    command: Vec<u8>,
+    kind: EntryKind,
+}
+
+/// Decode a bincode-encoded entry in either layout
+///
+/// bincode isn't self-describing, so an entry without `metadata` fails to
+/// decode as the current layout (it runs out of bytes) and is retried as the
+/// old one.
+fn decode_entry(data: &[u8]) -> bincode::Result<Entry> {
+    bincode::deserialize(data).or_else(|e| match bincode::deserialize::<LegacyEntry>(data) {
+        Ok(legacy) => Ok(Entry {
+            kind: legacy.kind,
+            ..Entry::new(legacy.term, legacy.index, legacy.command)
+        }),
+        Err(_) => Err(e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deflate_round_trip_is_smaller() {
+        let value = vec![7u8; 4096];
+        let plain = Codec::Bincode.encode(&value).unwrap();
+        let deflated = Codec::Deflate { level: 6 }.encode(&value).unwrap();
+        assert!(deflated.len() < plain.len() / 10);
+
+        let decoded: Vec<u8> = Codec::Deflate { level: 6 }.decode(&deflated).unwrap();
+        assert_eq!(decoded, value);
+        assert!(Codec::Deflate { level: 6 }
+            .decode::<Vec<u8>>(&plain)
+            .is_err());
+    }
+
+    #[test]
+    fn test_entries_without_metadata_still_decode() {
+        #[derive(Serialize)]
+        struct OldEntry {
+            term: Term,
+            index: LogIndex,
+            command: Vec<u8>,
+            kind: EntryKind,
+        }
+        let old = OldEntry {
+            term: Term(2),
+            index: LogIndex(7),
+            command: b"SET a 1".to_vec(),
+            kind: EntryKind::Normal,
+        };
+
+        for codec in [Codec::Bincode, Codec::Deflate { level: 6 }] {
+            let entry = codec.decode_entry(&codec.encode(&old).unwrap()).unwrap();
+            assert_eq!((entry.term, entry.index), (Term(2), LogIndex(7)));
+            assert_eq!(entry.command, b"SET a 1");
+            assert_eq!(entry.metadata, None);
+
+            let new = Entry::new(Term(3), LogIndex(8), b"SET b 2".to_vec())
+                .with_metadata(b"trace-1".to_vec());
+            let entry = codec.decode_entry(&codec.encode(&new).unwrap()).unwrap();
+            assert_eq!(entry.metadata.as_deref(), Some(&b"trace-1"[..]));
+        }
+    }
+}
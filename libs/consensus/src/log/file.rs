@@ -0,0 +1,1118 @@
+//! File-backed log storage
+//!
+//! Entries are written to segment files in a single directory. Each segment
+//! is named after the index of its first entry and holds a sequence of
+//! records, each a little-endian `u32` length and a little-endian CRC32 of
+//! the payload, followed by the payload: the [`Entry`] encoded with the
+//! configured entry [`Codec`]. A zero length marks the end of the written
+//! data, which is how pre-allocated (zero-filled) segments are read back.
+//!
+//! The top bit of the length says a checksum follows. Segments written
+//! before records were checksummed lack both, and are still read.
+//!
+//! Once the active segment reaches `segment_size` a new one is started, so
+//! compaction only has to delete the segments that lie wholly below the
+//! snapshot instead of rewriting anything.
+
+use crate::log::{Codec, LogStorage};
+use crate::types::{Entry, LogIndex, Snapshot, Term};
+use crate::{RaftError, Result};
+
+use parking_lot::Mutex;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Extension of segment files
+const SEGMENT_EXTENSION: &str = "seg";
+
+/// File holding the latest snapshot
+const SNAPSHOT_FILE: &str = "snapshot";
+
+/// Size of the length prefix in front of every record
+const RECORD_LEN_LEN: u64 = 4;
+
+/// Size of the length prefix and checksum in front of every record
+const RECORD_HEADER_LEN: u64 = RECORD_LEN_LEN + 4;
+
+/// Set in a record's length when a CRC32 of its payload follows it
+const CHECKSUM_FLAG: u32 = 1 << 31;
+
+/// Options for [`FileLogStorage`]
+#[derive(Debug, Clone)]
+pub struct FileLogConfig {
+    /// Size at which the active segment is closed and a new one started
+    ///
+    /// A single record larger than this still gets a segment of its own.
+    pub segment_size: u64,
+
+    /// Reserve `segment_size` bytes on disk up front for every new segment
+    ///
+    /// Uses `fallocate` where available; elsewhere segments simply grow as
+    /// records are appended.
+    pub preallocate: bool,
+
+    /// Encoding of log entries
+    pub entry_codec: Codec,
+
+    /// Encoding of the snapshot file
+    pub snapshot_codec: Codec,
+}
+
+impl Default for FileLogConfig {
+    fn default() -> Self {
+        Self {
+            // 64 MiB segments
+            segment_size: 64 * 1024 * 1024,
+
+            // Grow segments on demand
+            preallocate: false,
+
+            // Entries are on the proposal path; snapshots could be
+            // compressed, but plain bincode keeps existing directories readable
+            entry_codec: Codec::Bincode,
+            snapshot_codec: Codec::Bincode,
+        }
+    }
+}
+
+/// Position of one record within its segment
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    offset: u64,
+    len: u32,
+    term: Term,
+
+    /// CRC32 of the payload; `None` for records from before checksums
+    checksum: Option<u32>,
+}
+
+impl Record {
+    /// Bytes from the start of the record to its payload
+    fn header_len(&self) -> u64 {
+        match self.checksum {
+            Some(_) => RECORD_HEADER_LEN,
+            None => RECORD_LEN_LEN,
+        }
+    }
+
+    /// Bytes the whole record takes up in its segment
+    fn size(&self) -> u64 {
+        self.header_len() + self.len as u64
+    }
+}
+
+/// One segment file and the records it holds
+struct Segment {
+    first_index: LogIndex,
+    path: PathBuf,
+    file: Mutex<File>,
+    records: Vec<Record>,
+    codec: Codec,
+
+    /// Bytes of record data written; the file itself may be longer if it
+    /// was pre-allocated
+    len: u64,
+}
+
+impl Segment {
+    fn file_name(first_index: LogIndex) -> String {
+        format!("{:020}.{}", first_index.0, SEGMENT_EXTENSION)
+    }
+
+    /// Index one past the last record in this segment
+    fn next_index(&self) -> LogIndex {
+        self.first_index + self.records.len() as u64
+    }
+
+    fn record(&self, index: LogIndex) -> Option<Record> {
+        if index < self.first_index {
+            return None;
+        }
+        self.records
+            .get((index.0 - self.first_index.0) as usize)
+            .copied()
+    }
+
+    fn read(&self, record: Record) -> Result<Entry> {
+        let mut buf = vec![0; record.len as usize];
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(record.offset + record.header_len()))?;
+        file.read_exact(&mut buf)?;
+        drop(file);
+
+        if record
+            .checksum
+            .is_some_and(|sum| sum != crc32fast::hash(&buf))
+        {
+            return Err(RaftError::CorruptLog(format!(
+                "checksum mismatch at offset {} of {}",
+                record.offset,
+                self.path.display()
+            )));
+        }
+        self.codec.decode_entry(&buf).map_err(|e| {
+            RaftError::CorruptLog(format!(
+                "undecodable record at offset {} of {}: {}",
+                record.offset,
+                self.path.display(),
+                e
+            ))
+        })
+    }
+
+    /// Read back every complete record, stopping at the first zero length,
+    /// torn write or checksum mismatch
+    fn scan(&mut self) -> Result<()> {
+        let file = self.file.get_mut();
+        let file_len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(0))?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut offset = 0u64;
+        while offset + RECORD_LEN_LEN <= file_len {
+            let start = offset as usize;
+            let header: [u8; 4] = data[start..start + 4].try_into().expect("4-byte length");
+            let len = u32::from_le_bytes(header);
+            if len == 0 {
+                break;
+            }
+
+            let (len, checksum, header_len) = if len & CHECKSUM_FLAG != 0 {
+                let Some(sum) = data.get(start + 4..start + 8) else {
+                    warn!(
+                        "Torn record at offset {} of {}",
+                        offset,
+                        self.path.display()
+                    );
+                    break;
+                };
+                let sum = u32::from_le_bytes(sum.try_into().expect("4-byte checksum"));
+                (len & !CHECKSUM_FLAG, Some(sum), RECORD_HEADER_LEN)
+            } else {
+                (len, None, RECORD_LEN_LEN)
+            };
+
+            let body_start = start + header_len as usize;
+            let Some(body) = data.get(body_start..body_start + len as usize) else {
+                warn!(
+                    "Torn record at offset {} of {}",
+                    offset,
+                    self.path.display()
+                );
+                break;
+            };
+            if checksum.is_some_and(|sum| sum != crc32fast::hash(body)) {
+                warn!(
+                    "Checksum mismatch at offset {} of {}",
+                    offset,
+                    self.path.display()
+                );
+                break;
+            }
+            let entry = match self.codec.decode_entry(body) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!(
+                        "Undecodable record at offset {} of {}: {}",
+                        offset,
+                        self.path.display(),
+                        e
+                    );
+                    break;
+                }
+            };
+            if entry.index != self.next_index() {
+                return Err(RaftError::CorruptLog(format!(
+                    "{} holds entry {} where {} was expected",
+                    self.path.display(),
+                    entry.index,
+                    self.next_index()
+                )));
+            }
+
+            let record = Record {
+                offset,
+                len,
+                term: entry.term,
+                checksum,
+            };
+            offset += record.size();
+            self.records.push(record);
+        }
+
+        self.len = offset;
+        Ok(())
+    }
+}
+
+/// Durable log storage in segment files on local disk
+///
+/// Every `append` is fsynced before it returns. Entry terms and record
+/// positions are kept in memory, so only reading a command touches the disk.
+pub struct FileLogStorage {
+    dir: PathBuf,
+    config: FileLogConfig,
+    segments: Vec<Segment>,
+    snapshot: Option<Snapshot>,
+
+    /// First index still served; entries below it have been compacted
+    first_index: LogIndex,
+}
+
+impl FileLogStorage {
+    /// Open the log in `dir`, creating the directory if needed
+    ///
+    /// Existing segments are scanned to recover the log. A partially written
+    /// record at the end of the last segment (from a crash mid-append) is
+    /// discarded.
+    pub fn open(dir: impl AsRef<Path>, config: FileLogConfig) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut first_indexes = Vec::new();
+        for dir_entry in fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let first_index = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| {
+                    RaftError::CorruptLog(format!("unexpected segment name {}", path.display()))
+                })?;
+            first_indexes.push(LogIndex(first_index));
+        }
+        first_indexes.sort();
+
+        let mut segments: Vec<Segment> = Vec::with_capacity(first_indexes.len());
+        for first_index in first_indexes {
+            if let Some(prev) = segments.last() {
+                if prev.next_index() != first_index {
+                    return Err(RaftError::CorruptLog(format!(
+                        "segment {} does not follow on from entry {}",
+                        Segment::file_name(first_index),
+                        prev.next_index() - 1
+                    )));
+                }
+            }
+
+            let path = dir.join(Segment::file_name(first_index));
+            let file = OpenOptions::new().read(true).write(true).open(&path)?;
+            let mut segment = Segment {
+                first_index,
+                path,
+                file: Mutex::new(file),
+                records: Vec::new(),
+                codec: config.entry_codec,
+                len: 0,
+            };
+            segment.scan()?;
+            segments.push(segment);
+        }
+
+        let snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(data) => Some(
+                config
+                    .snapshot_codec
+                    .decode(&data)
+                    .map_err(|e| RaftError::CorruptLog(format!("undecodable snapshot: {}", e)))?,
+            ),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let first_index = segments
+            .first()
+            .map(|s| s.first_index)
+            .unwrap_or(LogIndex(1));
+
+        let mut storage = Self {
+            dir,
+            config,
+            segments,
+            snapshot,
+            first_index,
+        };
+
+        // Drop whatever a torn write left behind the last good record, so a
+        // shorter record written over it can't be followed by stale bytes
+        if let Some(segment) = storage.segments.last_mut() {
+            let len = segment.len;
+            storage.truncate_active(len)?;
+        }
+
+        debug!(
+            "Opened file log in {} with {} segments through {}",
+            storage.dir.display(),
+            storage.segments.len(),
+            storage.last_index()
+        );
+        Ok(storage)
+    }
+
+    /// The segment holding `index`, if it's still in the log
+    fn segment_for(&self, index: LogIndex) -> Option<&Segment> {
+        if index < self.first_index {
+            return None;
+        }
+        let pos = self
+            .segments
+            .partition_point(|s| s.first_index <= index)
+            .checked_sub(1)?;
+        Some(&self.segments[pos])
+    }
+
+    fn read_entry(&self, index: LogIndex) -> Result<Option<Entry>> {
+        let Some(segment) = self.segment_for(index) else {
+            return Ok(None);
+        };
+        match segment.record(index) {
+            Some(record) => segment.read(record).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Index of the last entry written to disk, ignoring compaction
+    fn last_written(&self) -> Option<LogIndex> {
+        self.segments
+            .iter()
+            .rev()
+            .find(|s| !s.records.is_empty())
+            .map(|s| s.next_index() - 1)
+    }
+
+    /// Start a new segment whose first entry will be `first_index`
+    fn roll_segment(&mut self, first_index: LogIndex) -> Result<()> {
+        if let Some(active) = self.segments.last_mut() {
+            active.file.get_mut().sync_all()?;
+        }
+
+        let path = self.dir.join(Segment::file_name(first_index));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        if self.config.preallocate && !preallocate(&file, self.config.segment_size) {
+            debug!(
+                "Pre-allocation unavailable for {}, appending instead",
+                path.display()
+            );
+        }
+        sync_dir(&self.dir)?;
+
+        self.segments.push(Segment {
+            first_index,
+            path,
+            file: Mutex::new(file),
+            records: Vec::new(),
+            codec: self.config.entry_codec,
+            len: 0,
+        });
+        Ok(())
+    }
+
+    /// Drop every segment and start the log over at `first_index`
+    ///
+    /// Only used once everything on disk has been compacted away.
+    fn restart_at(&mut self, first_index: LogIndex) -> Result<()> {
+        for segment in self.segments.drain(..) {
+            fs::remove_file(&segment.path)?;
+        }
+        self.roll_segment(first_index)?;
+        self.first_index = first_index;
+        Ok(())
+    }
+
+    /// Append `entries`, each turned into a record payload by `encode`
+    ///
+    /// The whole batch is encoded and checked before anything is written,
+    /// so an entry that can't be encoded leaves the files untouched. Records
+    /// are then written a segment at a time, and a failed write is cut back
+    /// off, so the segments only ever hold complete records.
+    fn append_encoded(
+        &mut self,
+        entries: Vec<Entry>,
+        encode: impl Fn(&Entry) -> bincode::Result<Vec<u8>>,
+    ) -> Result<()> {
+        let Some(first) = entries.first().map(|e| e.index) else {
+            return Ok(());
+        };
+
+        let mut payloads = Vec::with_capacity(entries.len());
+        for (entry, expected) in entries.iter().zip((first.0..).map(LogIndex)) {
+            if entry.index != expected {
+                return Err(RaftError::NonContiguousAppend {
+                    expected,
+                    got: entry.index,
+                });
+            }
+            let payload = encode(entry).map_err(|e| {
+                RaftError::InvalidEntry(format!("cannot encode entry {}: {}", entry.index, e))
+            })?;
+            if payload.len() >= CHECKSUM_FLAG as usize {
+                return Err(RaftError::InvalidEntry(format!(
+                    "entry {} encodes to {} bytes, more than a record can hold",
+                    entry.index,
+                    payload.len()
+                )));
+            }
+            payloads.push((entry.term, payload));
+        }
+
+        let next = self.last_written().map(|last| last + 1);
+        if self.last_index() < self.first_index {
+            // Nothing visible is left (empty or fully compacted), so the log
+            // may restart at any index
+            if next != Some(first) {
+                self.restart_at(first)?;
+            }
+        } else if next != Some(first) {
+            return Err(RaftError::NonContiguousAppend {
+                expected: next.unwrap_or(self.first_index),
+                got: first,
+            });
+        }
+
+        let mut buf = Vec::new();
+        let mut records = Vec::new();
+        for ((term, payload), index) in payloads.into_iter().zip((first.0..).map(LogIndex)) {
+            let record_len = RECORD_HEADER_LEN + payload.len() as u64;
+            let active = self.segments.last().expect("log has a segment");
+            let written = active.len + buf.len() as u64;
+            if (!active.records.is_empty() || !records.is_empty())
+                && written + record_len > self.config.segment_size
+            {
+                self.write_records(&buf, std::mem::take(&mut records))?;
+                buf.clear();
+                self.roll_segment(index)?;
+            }
+
+            let active = self.segments.last().expect("log has a segment");
+            let checksum = crc32fast::hash(&payload);
+            records.push(Record {
+                offset: active.len + buf.len() as u64,
+                len: payload.len() as u32,
+                term,
+                checksum: Some(checksum),
+            });
+            buf.extend_from_slice(&(payload.len() as u32 | CHECKSUM_FLAG).to_le_bytes());
+            buf.extend_from_slice(&checksum.to_le_bytes());
+            buf.extend_from_slice(&payload);
+        }
+        self.write_records(&buf, records)?;
+
+        if let Some(active) = self.segments.last_mut() {
+            active.file.get_mut().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Write `buf`, holding exactly `records`, to the end of the active
+    /// segment
+    ///
+    /// On failure whatever part of `buf` reached the file is cut off again.
+    fn write_records(&mut self, buf: &[u8], records: Vec<Record>) -> Result<()> {
+        let active = self.segments.last_mut().expect("log has a segment");
+        let len = active.len;
+        let file = active.file.get_mut();
+        let written = file
+            .seek(SeekFrom::Start(len))
+            .and_then(|_| file.write_all(buf));
+        if let Err(e) = written {
+            warn!(
+                "Failed to append to {}, truncating back to {} bytes: {}",
+                active.path.display(),
+                len,
+                e
+            );
+            self.truncate_active(len)?;
+            return Err(e.into());
+        }
+
+        active.records.extend(records);
+        active.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Cut the active segment's data back to `len` bytes
+    ///
+    /// Everything after `len` is zeroed (or removed, if the segment isn't
+    /// pre-allocated) so it can't be mistaken for records on recovery.
+    fn truncate_active(&mut self, len: u64) -> Result<()> {
+        let preallocate_len = self.config.preallocate.then_some(self.config.segment_size);
+        let Some(segment) = self.segments.last_mut() else {
+            return Ok(());
+        };
+
+        let file = segment.file.get_mut();
+        file.set_len(len)?;
+        if let Some(size) = preallocate_len {
+            if len < size {
+                preallocate(file, size);
+            }
+        }
+        file.sync_all()?;
+        segment.len = len;
+        Ok(())
+    }
+}
+
+impl LogStorage for FileLogStorage {
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let codec = self.config.entry_codec;
+        self.append_encoded(entries, |entry| codec.encode(entry))
+    }
+
+    fn get(&self, index: LogIndex) -> Result<Option<Entry>> {
+        self.read_entry(index)
+    }
+
+    fn get_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Entry>> {
+        if end <= start {
+            return Ok(Vec::new());
+        }
+        if start < self.first_index {
+            return Err(RaftError::LogIndexOutOfRange(start));
+        }
+        let end = end.min(self.last_index() + 1);
+
+        let mut entries = Vec::new();
+        let mut index = start;
+        while index < end {
+            match self.read_entry(index)? {
+                Some(entry) => entries.push(entry),
+                None => break,
+            }
+            index.increment();
+        }
+        Ok(entries)
+    }
+
+    fn get_from(&self, start: LogIndex) -> Result<Vec<Entry>> {
+        self.get_range(start, self.last_index() + 1)
+    }
+
+    fn delete_from(&mut self, index: LogIndex) -> Result<()> {
+        if index < self.first_index || self.last_written().is_none_or(|last| index > last) {
+            return Ok(());
+        }
+
+        // Whole segments past the cut go away
+        while self.segments.len() > 1
+            && self.segments.last().expect("log has a segment").first_index > index
+        {
+            let segment = self.segments.pop().expect("log has a segment");
+            fs::remove_file(&segment.path)?;
+        }
+
+        let segment = self.segments.last_mut().expect("log has a segment");
+        let keep = (index.0.saturating_sub(segment.first_index.0)) as usize;
+        let len = segment.records.get(keep).map_or(segment.len, |r| r.offset);
+        segment.records.truncate(keep);
+        self.truncate_active(len)
+    }
+
+    fn last_index(&self) -> LogIndex {
+        match self.last_written() {
+            Some(last) if last >= self.first_index => last,
+            _ => self
+                .snapshot
+                .as_ref()
+                .map(|s| s.metadata.last_included_index)
+                .unwrap_or(LogIndex::ZERO),
+        }
+    }
+
+    fn last_term(&self) -> Term {
+        match self.last_written() {
+            Some(last) if last >= self.first_index => self
+                .segment_for(last)
+                .and_then(|s| s.record(last))
+                .map(|r| r.term)
+                .unwrap_or(Term(0)),
+            _ => self
+                .snapshot
+                .as_ref()
+                .map(|s| s.metadata.last_included_term)
+                .unwrap_or(Term(0)),
+        }
+    }
+
+    fn get_term(&self, index: LogIndex) -> Result<Option<Term>> {
+        if let Some(snapshot) = &self.snapshot {
+            if index == snapshot.metadata.last_included_index {
+                return Ok(Some(snapshot.metadata.last_included_term));
+            }
+        }
+
+        // Answered from the record index; the segment file isn't touched
+        Ok(self
+            .segment_for(index)
+            .and_then(|s| s.record(index))
+            .map(|r| r.term))
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        let data = self
+            .config
+            .snapshot_codec
+            .encode(&snapshot)
+            .map_err(|e| RaftError::Internal(format!("cannot encode snapshot: {}", e)))?;
+
+        // Write aside and rename so a crash never leaves a half-written snapshot
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE))?;
+        sync_dir(&self.dir)?;
+
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+
+    fn get_snapshot(&self) -> Option<Snapshot> {
+        self.snapshot.clone()
+    }
+
+    fn compact(&mut self, through_index: LogIndex) -> Result<()> {
+        if through_index < self.first_index {
+            return Ok(());
+        }
+        self.first_index = through_index + 1;
+
+        // Delete segments wholly covered by the compaction; the active
+        // segment is kept so appends carry on where they left off
+        let closed = self.segments.len().saturating_sub(1);
+        let covered = self.segments[..closed]
+            .iter()
+            .take_while(|s| s.next_index() <= self.first_index)
+            .count();
+        for segment in self.segments.drain(..covered) {
+            debug!("Deleting compacted segment {}", segment.path.display());
+            fs::remove_file(&segment.path)?;
+        }
+        if covered > 0 {
+            sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reserve `len` bytes for `file` on disk, returning whether it worked
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the descriptor is owned by `file` and stays open for the call
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+    ret == 0
+}
+
+/// Reserve `len` bytes for `file` on disk, returning whether it worked
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &File, _len: u64) -> bool {
+    false
+}
+
+/// Make file creations and renames in `dir` durable
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(index: u64) -> Entry {
+        Entry::new(
+            Term(1 + index / 10),
+            LogIndex(index),
+            format!("command {}", index).into_bytes(),
+        )
+    }
+
+    fn segment_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().and_then(|e| e.to_str()) == Some(SEGMENT_EXTENSION))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_preallocated_segments_roll_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileLogConfig {
+            segment_size: 256,
+            preallocate: true,
+            ..Default::default()
+        };
+
+        let mut log = FileLogStorage::open(dir.path(), config.clone()).unwrap();
+        for index in 1..=100 {
+            log.append(vec![entry(index)]).unwrap();
+        }
+
+        let files = segment_files(dir.path());
+        assert!(files.len() > 1, "expected several segments");
+        if cfg!(target_os = "linux") {
+            // The active segment is reserved ahead of the data in it
+            let active = fs::metadata(files.last().unwrap()).unwrap().len();
+            assert_eq!(active, 256);
+        }
+
+        // Every segment boundary is crossed by this range
+        let all = log.get_range(LogIndex(1), LogIndex(101)).unwrap();
+        assert_eq!(all.len(), 100);
+        for (i, e) in all.iter().enumerate() {
+            assert_eq!(e.index, LogIndex(i as u64 + 1));
+            assert_eq!(e.command, entry(i as u64 + 1).command);
+        }
+        assert_eq!(log.last_index(), LogIndex(100));
+        assert_eq!(log.last_term(), Term(11));
+        drop(log);
+
+        // The zero-filled tail of each segment isn't mistaken for records
+        let log = FileLogStorage::open(dir.path(), config).unwrap();
+        assert_eq!(log.last_index(), LogIndex(100));
+        assert_eq!(log.get_from(LogIndex(40)).unwrap().len(), 61);
+        assert_eq!(log.get_term(LogIndex(55)).unwrap(), Some(Term(6)));
+    }
+
+    #[test]
+    fn test_get_term_reads_no_payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = FileLogStorage::open(dir.path(), FileLogConfig::default()).unwrap();
+        log.append((1..=20).map(entry).collect()).unwrap();
+
+        // With the segments emptied, only the in-memory index can answer
+        for path in segment_files(dir.path()) {
+            OpenOptions::new()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_len(0)
+                .unwrap();
+        }
+        for index in 1..=20 {
+            assert_eq!(
+                log.get_term(LogIndex(index)).unwrap(),
+                Some(entry(index).term)
+            );
+        }
+        assert!(log.get(LogIndex(1)).is_err());
+    }
+
+    #[test]
+    fn test_truncate_across_segments_then_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileLogConfig {
+            segment_size: 256,
+            preallocate: true,
+            ..Default::default()
+        };
+
+        let mut log = FileLogStorage::open(dir.path(), config.clone()).unwrap();
+        log.append((1..=50).map(entry).collect()).unwrap();
+        let before = segment_files(dir.path()).len();
+
+        log.delete_from(LogIndex(12)).unwrap();
+        assert_eq!(log.last_index(), LogIndex(11));
+        assert!(segment_files(dir.path()).len() < before);
+
+        let replacement = Entry::new(Term(7), LogIndex(12), b"replacement".to_vec());
+        log.append(vec![replacement]).unwrap();
+        drop(log);
+
+        let log = FileLogStorage::open(dir.path(), config).unwrap();
+        assert_eq!(log.last_index(), LogIndex(12));
+        assert_eq!(
+            log.get(LogIndex(12)).unwrap().unwrap().command,
+            b"replacement"
+        );
+        assert_eq!(log.get(LogIndex(13)).unwrap().map(|e| e.index), None);
+    }
+
+    #[test]
+    fn test_compaction_deletes_covered_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileLogConfig {
+            segment_size: 256,
+            preallocate: false,
+            ..Default::default()
+        };
+
+        let mut log = FileLogStorage::open(dir.path(), config.clone()).unwrap();
+        log.append((1..=60).map(entry).collect()).unwrap();
+        let before = segment_files(dir.path());
+
+        log.compact(LogIndex(30)).unwrap();
+        let after = segment_files(dir.path());
+        assert!(after.len() < before.len());
+
+        // Only segments holding nothing past the compaction point went away
+        let first_kept = log.segments[0].first_index;
+        assert!(first_kept <= LogIndex(31));
+        assert!(log.segments[0].next_index() > LogIndex(31));
+        assert_eq!(log.get(LogIndex(30)).unwrap().map(|e| e.index), None);
+        assert!(log.get_range(LogIndex(30), LogIndex(40)).is_err());
+
+        // Reads still cross the remaining segment boundaries
+        let tail = log.get_range(LogIndex(31), LogIndex(61)).unwrap();
+        assert_eq!(tail.len(), 30);
+        assert_eq!(tail[0].index, LogIndex(31));
+
+        // Appends continue in the active segment, and the deleted segments
+        // stay gone across a restart
+        log.append(vec![entry(61)]).unwrap();
+        drop(log);
+        let log = FileLogStorage::open(dir.path(), config).unwrap();
+        assert_eq!(log.segments[0].first_index, first_kept);
+        assert_eq!(log.last_index(), LogIndex(61));
+        assert_eq!(
+            log.get(LogIndex(45)).unwrap().unwrap().command,
+            entry(45).command
+        );
+    }
+
+    #[test]
+    fn test_compacting_everything_keeps_active_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileLogConfig {
+            segment_size: 256,
+            preallocate: false,
+            ..Default::default()
+        };
+
+        let mut log = FileLogStorage::open(dir.path(), config).unwrap();
+        log.append((1..=40).map(entry).collect()).unwrap();
+
+        log.compact(LogIndex(40)).unwrap();
+        assert_eq!(segment_files(dir.path()).len(), 1);
+        assert_eq!(log.get(LogIndex(40)).unwrap().map(|e| e.index), None);
+
+        log.append(vec![entry(41)]).unwrap();
+        assert_eq!(log.last_index(), LogIndex(41));
+        assert_eq!(log.get_from(LogIndex(41)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_plain_appends_without_preallocation() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileLogConfig {
+            segment_size: 256,
+            preallocate: false,
+            ..Default::default()
+        };
+
+        let mut log = FileLogStorage::open(dir.path(), config).unwrap();
+        log.append((1..=20).map(entry).collect()).unwrap();
+
+        // Without pre-allocation a segment is exactly as long as its records
+        let first = &log.segments[0];
+        let on_disk = fs::metadata(&first.path).unwrap().len();
+        assert_eq!(on_disk, first.len);
+        assert!(on_disk <= 256);
+        assert_eq!(log.get(LogIndex(20)).unwrap().unwrap().index, LogIndex(20));
+    }
+
+    #[test]
+    fn test_entries_and_snapshot_use_their_own_codecs() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileLogConfig {
+            entry_codec: Codec::Bincode,
+            snapshot_codec: Codec::Deflate { level: 6 },
+            ..Default::default()
+        };
+
+        let mut log = FileLogStorage::open(dir.path(), config.clone()).unwrap();
+        log.append((1..=20).map(entry).collect()).unwrap();
+        let data = b"state ".repeat(1000);
+        log.set_snapshot(Snapshot {
+            metadata: crate::types::SnapshotMetadata {
+                last_included_index: LogIndex(10),
+                last_included_term: Term(2),
+                configuration: crate::types::ClusterConfig::default(),
+            },
+            data: data.clone(),
+        })
+        .unwrap();
+        drop(log);
+
+        // Entries went to disk as plain bincode, the snapshot compressed
+        let segment = fs::read(&segment_files(dir.path())[0]).unwrap();
+        let first: Entry = bincode::deserialize(&segment[RECORD_HEADER_LEN as usize..]).unwrap();
+        assert_eq!(first.command, entry(1).command);
+        let snapshot_len = fs::metadata(dir.path().join(SNAPSHOT_FILE)).unwrap().len();
+        assert!(snapshot_len < data.len() as u64 / 10);
+
+        let log = FileLogStorage::open(dir.path(), config).unwrap();
+        assert_eq!(log.get_from(LogIndex(1)).unwrap().len(), 20);
+        assert_eq!(
+            log.get(LogIndex(15)).unwrap().unwrap().command,
+            entry(15).command
+        );
+        let snapshot = log.get_snapshot().unwrap();
+        assert_eq!(snapshot.metadata.last_included_index, LogIndex(10));
+        assert_eq!(snapshot.data, data);
+        drop(log);
+
+        // The wrong snapshot codec is caught rather than misread
+        assert!(matches!(
+            FileLogStorage::open(dir.path(), FileLogConfig::default()),
+            Err(RaftError::CorruptLog(_))
+        ));
+    }
+
+    #[test]
+    fn test_failed_encode_leaves_files_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileLogConfig {
+            segment_size: 256,
+            ..Default::default()
+        };
+        let files = |dir: &Path| -> Vec<(PathBuf, Vec<u8>)> {
+            segment_files(dir)
+                .into_iter()
+                .map(|path| {
+                    let data = fs::read(&path).unwrap();
+                    (path, data)
+                })
+                .collect()
+        };
+
+        let mut log = FileLogStorage::open(dir.path(), config.clone()).unwrap();
+        log.append((1..=5).map(entry).collect()).unwrap();
+        let before = files(dir.path());
+
+        // The batch would span several segments; its eighth entry fails
+        let result = log.append_encoded((6..=20).map(entry).collect(), |e| {
+            if e.index == LogIndex(12) {
+                Err(Box::new(bincode::ErrorKind::Custom("unencodable".into())))
+            } else {
+                Codec::Bincode.encode(e)
+            }
+        });
+        assert!(matches!(result, Err(RaftError::InvalidEntry(_))));
+        assert_eq!(files(dir.path()), before);
+        assert_eq!(log.last_index(), LogIndex(5));
+
+        // So does a batch with a gap in it
+        let gappy = vec![entry(6), entry(7), entry(9)];
+        assert!(matches!(
+            log.append(gappy),
+            Err(RaftError::NonContiguousAppend { .. })
+        ));
+        assert_eq!(files(dir.path()), before);
+
+        log.append((6..=20).map(entry).collect()).unwrap();
+        assert!(segment_files(dir.path()).len() > before.len());
+        drop(log);
+
+        let log = FileLogStorage::open(dir.path(), config).unwrap();
+        assert_eq!(log.last_index(), LogIndex(20));
+        assert_eq!(log.get_from(LogIndex(1)).unwrap().len(), 20);
+    }
+
+    #[test]
+    fn test_entries_survive_crash_mid_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = FileLogStorage::open(dir.path(), FileLogConfig::default()).unwrap();
+        log.append((1..=10).map(entry).collect()).unwrap();
+        drop(log);
+
+        // A crash partway through the next record leaves its header and a
+        // few bytes of payload behind
+        let segment = &segment_files(dir.path())[0];
+        let mut file = OpenOptions::new().append(true).open(segment).unwrap();
+        file.write_all(&(100 | CHECKSUM_FLAG).to_le_bytes())
+            .unwrap();
+        file.write_all(&[0xab; 10]).unwrap();
+        drop(file);
+
+        let mut log = FileLogStorage::open(dir.path(), FileLogConfig::default()).unwrap();
+        assert_eq!(log.last_index(), LogIndex(10));
+        assert_eq!(log.get_from(LogIndex(1)).unwrap().len(), 10);
+        log.append(vec![entry(11)]).unwrap();
+        drop(log);
+
+        let log = FileLogStorage::open(dir.path(), FileLogConfig::default()).unwrap();
+        assert_eq!(log.last_index(), LogIndex(11));
+        assert_eq!(
+            log.get(LogIndex(11)).unwrap().unwrap().command,
+            entry(11).command
+        );
+    }
+
+    #[test]
+    fn test_checksum_catches_damaged_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = FileLogStorage::open(dir.path(), FileLogConfig::default()).unwrap();
+        log.append((1..=5).map(entry).collect()).unwrap();
+
+        // A flipped bit in a payload that still decodes is caught on read
+        let segment = segment_files(dir.path())[0].clone();
+        let flip = |at: u64| {
+            let mut data = fs::read(&segment).unwrap();
+            let at = at as usize;
+            data[at] ^= 0x01;
+            fs::write(&segment, data).unwrap();
+        };
+        let record = log.segments[0].record(LogIndex(2)).unwrap();
+        flip(record.offset + record.size() - 1);
+        assert!(matches!(
+            log.get(LogIndex(2)),
+            Err(RaftError::CorruptLog(_))
+        ));
+        flip(record.offset + record.size() - 1);
+        drop(log);
+
+        // On recovery a damaged last record is treated as a torn write
+        let len = fs::metadata(&segment).unwrap().len();
+        flip(len - 1);
+        let log = FileLogStorage::open(dir.path(), FileLogConfig::default()).unwrap();
+        assert_eq!(log.last_index(), LogIndex(4));
+        assert_eq!(log.get_from(LogIndex(1)).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_records_without_checksums_still_read() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // A segment in the format from before checksums
+        let mut data = Vec::new();
+        for index in 1..=3 {
+            let payload = bincode::serialize(&entry(index)).unwrap();
+            data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            data.extend_from_slice(&payload);
+        }
+        fs::write(dir.path().join(Segment::file_name(LogIndex(1))), data).unwrap();
+
+        let mut log = FileLogStorage::open(dir.path(), FileLogConfig::default()).unwrap();
+        assert_eq!(log.last_index(), LogIndex(3));
+        log.append((4..=5).map(entry).collect()).unwrap();
+        drop(log);
+
+        let log = FileLogStorage::open(dir.path(), FileLogConfig::default()).unwrap();
+        let all = log.get_from(LogIndex(1)).unwrap();
+        assert_eq!(all.len(), 5);
+        for (i, e) in all.iter().enumerate() {
+            assert_eq!(e.command, entry(i as u64 + 1).command);
+        }
+    }
+}
@@ -0,0 +1,474 @@
+//! Log storage in an embedded sled database
+//!
+//! Entries live in their own tree keyed by big-endian index, so sled's
+//! ordered range scans serve `get_range` and `get_from` directly. The
+//! snapshot and the compaction point live in a second tree next to it.
+
+use crate::log::{Codec, LogStorage};
+use crate::types::{Entry, LogIndex, Snapshot, Term};
+use crate::{RaftError, Result};
+
+use std::path::Path;
+use tracing::debug;
+
+/// Tree holding the entries
+const ENTRIES_TREE: &str = "raft_log";
+
+/// Tree holding everything else
+const META_TREE: &str = "raft_log_meta";
+
+const SNAPSHOT_KEY: &[u8] = b"snapshot";
+
+/// First index still served; entries below it have been compacted
+const FIRST_INDEX_KEY: &[u8] = b"first_index";
+
+fn storage_error(e: sled::Error) -> RaftError {
+    RaftError::Storage(e.into())
+}
+
+fn key(index: LogIndex) -> [u8; 8] {
+    index.0.to_be_bytes()
+}
+
+fn decode_index(bytes: &[u8]) -> Result<LogIndex> {
+    let bytes: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| RaftError::CorruptLog(format!("bad index key {:?}", bytes)))?;
+    Ok(LogIndex(u64::from_be_bytes(bytes)))
+}
+
+fn decode_entry(index: LogIndex, bytes: &[u8]) -> Result<Entry> {
+    let entry = Codec::Bincode
+        .decode_entry(bytes)
+        .map_err(|e| RaftError::CorruptLog(format!("undecodable entry {}: {}", index, e)))?;
+    if entry.index != index {
+        return Err(RaftError::CorruptLog(format!(
+            "entry {} stored under key {}",
+            entry.index, index
+        )));
+    }
+    Ok(entry)
+}
+
+/// Durable log storage in a sled database
+///
+/// Every write is flushed before it returns. Truncation removes its entries
+/// in one atomic batch. The database can be shared with the application:
+/// [`from_db`](Self::from_db) only touches the `raft_log` and
+/// `raft_log_meta` trees.
+pub struct KvLogStorage {
+    db: sled::Db,
+    entries: sled::Tree,
+    meta: sled::Tree,
+    snapshot: Option<Snapshot>,
+    first_index: LogIndex,
+
+    /// Index and term of the last entry in `entries`, if there is one
+    last: Option<(LogIndex, Term)>,
+}
+
+impl KvLogStorage {
+    /// Open (or create) a database at `path` and the log in it
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_db(sled::open(path).map_err(storage_error)?)
+    }
+
+    /// Keep the log in an already open database
+    pub fn from_db(db: sled::Db) -> Result<Self> {
+        let entries = db.open_tree(ENTRIES_TREE).map_err(storage_error)?;
+        let meta = db.open_tree(META_TREE).map_err(storage_error)?;
+
+        let snapshot = match meta.get(SNAPSHOT_KEY).map_err(storage_error)? {
+            Some(data) => Some(
+                bincode::deserialize(&data)
+                    .map_err(|e| RaftError::CorruptLog(format!("undecodable snapshot: {}", e)))?,
+            ),
+            None => None,
+        };
+        let first_index = match meta.get(FIRST_INDEX_KEY).map_err(storage_error)? {
+            Some(bytes) => decode_index(&bytes)?,
+            None => LogIndex(1),
+        };
+
+        let mut storage = Self {
+            db,
+            entries,
+            meta,
+            snapshot,
+            first_index,
+            last: None,
+        };
+
+        // A crash during compaction can leave entries below the recorded
+        // compaction point behind
+        storage.remove_range(..key(first_index))?;
+        storage.last = storage.read_last()?;
+
+        debug!(
+            "Opened sled log with entries {} through {}",
+            storage.first_index,
+            storage.last_index()
+        );
+        Ok(storage)
+    }
+
+    fn read_last(&self) -> Result<Option<(LogIndex, Term)>> {
+        match self.entries.last().map_err(storage_error)? {
+            Some((k, v)) => {
+                let index = decode_index(&k)?;
+                Ok(Some((index, decode_entry(index, &v)?.term)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Atomically remove every entry whose key falls in `range`
+    fn remove_range<R: std::ops::RangeBounds<[u8; 8]>>(&mut self, range: R) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        let mut removed = 0;
+        for item in self.entries.range(range) {
+            let (k, _) = item.map_err(storage_error)?;
+            batch.remove(k);
+            removed += 1;
+        }
+        if removed > 0 {
+            self.entries.apply_batch(batch).map_err(storage_error)?;
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush().map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn set_first_index(&mut self, first_index: LogIndex) -> Result<()> {
+        self.meta
+            .insert(FIRST_INDEX_KEY, &key(first_index))
+            .map_err(storage_error)?;
+        self.flush()?;
+        self.first_index = first_index;
+        Ok(())
+    }
+
+    fn scan<R: std::ops::RangeBounds<[u8; 8]>>(&self, range: R) -> Result<Vec<Entry>> {
+        self.entries
+            .range(range)
+            .map(|item| {
+                let (k, v) = item.map_err(storage_error)?;
+                decode_entry(decode_index(&k)?, &v)
+            })
+            .collect()
+    }
+}
+
+impl LogStorage for KvLogStorage {
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let Some(first) = entries.first().map(|e| e.index) else {
+            return Ok(());
+        };
+
+        if self.last_index() < self.first_index {
+            // Nothing visible is left (empty or fully compacted), so the log
+            // may restart at any index
+            if first != self.first_index {
+                self.remove_range::<std::ops::RangeFull>(..)?;
+                self.last = None;
+                self.set_first_index(first)?;
+            }
+        }
+
+        let mut batch = sled::Batch::default();
+        let mut next = self.last.map_or(self.first_index, |(last, _)| last + 1);
+        for entry in &entries {
+            if entry.index != next {
+                return Err(RaftError::NonContiguousAppend {
+                    expected: next,
+                    got: entry.index,
+                });
+            }
+            let data = bincode::serialize(entry)
+                .map_err(|e| RaftError::InvalidEntry(format!("cannot encode entry: {}", e)))?;
+            batch.insert(&key(entry.index), data);
+            next.increment();
+        }
+        self.entries.apply_batch(batch).map_err(storage_error)?;
+        self.flush()?;
+
+        let last = entries.last().expect("entries aren't empty");
+        self.last = Some((last.index, last.term));
+        Ok(())
+    }
+
+    fn get(&self, index: LogIndex) -> Result<Option<Entry>> {
+        if index < self.first_index {
+            return Ok(None);
+        }
+        match self.entries.get(key(index)).map_err(storage_error)? {
+            Some(data) => decode_entry(index, &data).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn get_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Entry>> {
+        if end <= start {
+            return Ok(Vec::new());
+        }
+        if start < self.first_index {
+            return Err(RaftError::LogIndexOutOfRange(start));
+        }
+        self.scan(key(start)..key(end))
+    }
+
+    fn get_from(&self, start: LogIndex) -> Result<Vec<Entry>> {
+        if start < self.first_index {
+            return Err(RaftError::LogIndexOutOfRange(start));
+        }
+        self.scan(key(start)..)
+    }
+
+    fn delete_from(&mut self, index: LogIndex) -> Result<()> {
+        if index < self.first_index {
+            return Ok(());
+        }
+        self.remove_range(key(index)..)?;
+        self.last = self.read_last()?;
+        Ok(())
+    }
+
+    fn last_index(&self) -> LogIndex {
+        match self.last {
+            Some((last, _)) if last >= self.first_index => last,
+            _ => self
+                .snapshot
+                .as_ref()
+                .map(|s| s.metadata.last_included_index)
+                .unwrap_or(LogIndex::ZERO),
+        }
+    }
+
+    fn last_term(&self) -> Term {
+        match self.last {
+            Some((last, term)) if last >= self.first_index => term,
+            _ => self
+                .snapshot
+                .as_ref()
+                .map(|s| s.metadata.last_included_term)
+                .unwrap_or(Term(0)),
+        }
+    }
+
+    fn get_term(&self, index: LogIndex) -> Result<Option<Term>> {
+        if let Some(snapshot) = &self.snapshot {
+            if index == snapshot.metadata.last_included_index {
+                return Ok(Some(snapshot.metadata.last_included_term));
+            }
+        }
+        Ok(self.get(index)?.map(|e| e.term))
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        let data = bincode::serialize(&snapshot)
+            .map_err(|e| RaftError::Internal(format!("cannot encode snapshot: {}", e)))?;
+        self.meta
+            .insert(SNAPSHOT_KEY, data)
+            .map_err(storage_error)?;
+        self.flush()?;
+
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+
+    fn get_snapshot(&self) -> Option<Snapshot> {
+        self.snapshot.clone()
+    }
+
+    fn compact(&mut self, through_index: LogIndex) -> Result<()> {
+        if through_index < self.first_index {
+            return Ok(());
+        }
+
+        // Record the new start first; entries left below it by a crash are
+        // cleaned up on the next open
+        self.set_first_index(through_index + 1)?;
+        self.remove_range(..key(self.first_index))?;
+        if self.last.is_some_and(|(last, _)| last < self.first_index) {
+            self.last = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ClusterConfig, SnapshotMetadata};
+
+    fn entries(range: std::ops::RangeInclusive<u64>) -> Vec<Entry> {
+        range
+            .map(|i| {
+                Entry::new(
+                    Term(1 + i / 3),
+                    LogIndex(i),
+                    format!("cmd{}", i).into_bytes(),
+                )
+            })
+            .collect()
+    }
+
+    fn snapshot(index: u64, term: u64) -> Snapshot {
+        Snapshot {
+            metadata: SnapshotMetadata {
+                last_included_index: LogIndex(index),
+                last_included_term: Term(term),
+                configuration: ClusterConfig::default(),
+            },
+            data: b"snapshot_data".to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_append_and_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = KvLogStorage::open(dir.path()).unwrap();
+        log.append(entries(1..=3)).unwrap();
+
+        assert_eq!(log.last_index(), LogIndex(3));
+        assert_eq!(log.last_term(), Term(2));
+
+        let entry = log.get(LogIndex(2)).unwrap().unwrap();
+        assert_eq!(entry.command, b"cmd2");
+        assert_eq!(entry.term, Term(1));
+        assert!(log.get(LogIndex(4)).unwrap().is_none());
+
+        // Appends must carry on where the log ends
+        assert!(matches!(
+            log.append(entries(5..=5)),
+            Err(RaftError::NonContiguousAppend { .. })
+        ));
+    }
+
+    #[test]
+    fn test_delete_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = KvLogStorage::open(dir.path()).unwrap();
+        log.append(entries(1..=3)).unwrap();
+        log.delete_from(LogIndex(2)).unwrap();
+
+        assert_eq!(log.last_index(), LogIndex(1));
+        assert!(log.get(LogIndex(2)).unwrap().is_none());
+
+        log.append(entries(2..=4)).unwrap();
+        assert_eq!(log.last_index(), LogIndex(4));
+    }
+
+    #[test]
+    fn test_get_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = KvLogStorage::open(dir.path()).unwrap();
+        log.append(entries(1..=3)).unwrap();
+
+        let range = log.get_range(LogIndex(1), LogIndex(3)).unwrap();
+        assert_eq!(range.len(), 2);
+        assert_eq!(range[0].command, b"cmd1");
+        assert_eq!(range[1].command, b"cmd2");
+
+        assert_eq!(log.get_range(LogIndex(2), LogIndex(10)).unwrap().len(), 2);
+        assert_eq!(log.get_from(LogIndex(2)).unwrap().len(), 2);
+        assert!(log.get_range(LogIndex(3), LogIndex(3)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = KvLogStorage::open(dir.path()).unwrap();
+        log.append(entries(1..=3)).unwrap();
+
+        log.set_snapshot(snapshot(2, 1)).unwrap();
+        log.compact(LogIndex(2)).unwrap();
+
+        assert_eq!(log.last_index(), LogIndex(3));
+        assert!(log.get(LogIndex(1)).unwrap().is_none());
+        assert_eq!(log.get(LogIndex(3)).unwrap().unwrap().command, b"cmd3");
+        assert!(matches!(
+            log.get_range(LogIndex(1), LogIndex(4)),
+            Err(RaftError::LogIndexOutOfRange(LogIndex(1)))
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_covering_whole_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = KvLogStorage::open(dir.path()).unwrap();
+        log.append(entries(1..=3)).unwrap();
+        log.set_snapshot(snapshot(3, 2)).unwrap();
+        log.compact(LogIndex(3)).unwrap();
+
+        // The snapshot stands in for the last entry
+        assert_eq!(log.last_index(), LogIndex(3));
+        assert_eq!(log.last_term(), Term(2));
+        assert_eq!(log.get_term(LogIndex(3)).unwrap(), Some(Term(2)));
+        assert_eq!(log.get_term(LogIndex(2)).unwrap(), None);
+        for index in 1..=4 {
+            assert!(log.get(LogIndex(index)).unwrap().is_none());
+        }
+        assert!(log.get_range(LogIndex(4), LogIndex(10)).unwrap().is_empty());
+        assert!(log.get_from(LogIndex(4)).unwrap().is_empty());
+
+        // The log carries on after the snapshot
+        log.append(entries(4..=4)).unwrap();
+        assert_eq!(log.last_index(), LogIndex(4));
+    }
+
+    #[test]
+    fn test_reopen_recovers_log_and_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut log = KvLogStorage::open(dir.path()).unwrap();
+            log.append(entries(1..=6)).unwrap();
+            log.set_snapshot(snapshot(3, 2)).unwrap();
+            log.compact(LogIndex(3)).unwrap();
+            log.delete_from(LogIndex(6)).unwrap();
+        }
+
+        let log = KvLogStorage::open(dir.path()).unwrap();
+        assert_eq!(log.last_index(), LogIndex(5));
+        assert_eq!(log.last_term(), Term(2));
+        assert_eq!(
+            log.get_snapshot().unwrap().metadata.last_included_index,
+            LogIndex(3)
+        );
+        let rest: Vec<_> = log
+            .get_from(LogIndex(4))
+            .unwrap()
+            .into_iter()
+            .map(|e| e.index)
+            .collect();
+        assert_eq!(rest, [LogIndex(4), LogIndex(5)]);
+    }
+
+    #[test]
+    fn test_reopen_finishes_interrupted_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sled::open(dir.path()).unwrap();
+        {
+            let mut log = KvLogStorage::from_db(db.clone()).unwrap();
+            log.append(entries(1..=5)).unwrap();
+        }
+
+        // Crash after the compaction point was recorded, before the entries
+        // below it were removed
+        db.open_tree(META_TREE)
+            .unwrap()
+            .insert(FIRST_INDEX_KEY, &key(LogIndex(4)))
+            .unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        let log = KvLogStorage::open(dir.path()).unwrap();
+        assert!(log.get(LogIndex(3)).unwrap().is_none());
+        assert_eq!(log.get_from(LogIndex(4)).unwrap().len(), 2);
+        assert_eq!(log.entries.len(), 2);
+    }
+}
@@ -0,0 +1,47 @@
+//! Point-in-time metrics for monitoring a Raft node
+//!
+//! Snapshots are taken with [`RaftNode::metrics`](crate::RaftNode::metrics),
+//! or pushed at a fixed interval by
+//! [`RaftNode::metrics_stream`](crate::RaftNode::metrics_stream).
+
+use crate::state::RaftRole;
+use crate::types::{LogIndex, NodeId, Term};
+
+/// A snapshot of a node's progress
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftMetrics {
+    /// The node these metrics were taken from
+    pub id: NodeId,
+
+    /// Role at the time of the snapshot
+    pub role: RaftRole,
+
+    /// Current term
+    pub current_term: Term,
+
+    /// Leader this node last heard from (if any)
+    pub current_leader: Option<NodeId>,
+
+    /// Index of the last entry in the log
+    pub last_log_index: LogIndex,
+
+    /// Highest index known to be committed
+    pub commit_index: LogIndex,
+
+    /// Highest index applied to the state machine
+    pub last_applied: LogIndex,
+
+    /// Last index covered by the current snapshot (if any)
+    pub snapshot_index: Option<LogIndex>,
+
+    /// Proposals appended to the log while this node was leader, since it
+    /// started
+    pub proposals_accepted: u64,
+
+    /// Proposals failed with `NotLeader` because this node stepped down
+    /// before they committed, since it started
+    pub abandoned_proposals: u64,
+
+    /// Elections this node started in the last minute
+    pub elections_per_minute: u32,
+}
@@ -1,20 +1,49 @@
 //! Core Raft node implementation
 
 use crate::config::RaftConfig;
-use crate::log::RaftLog;
+use crate::election::{ElectionTimeoutStrategy, RandomizedTimeout};
+use crate::events::{PartitionReason, RaftEvent, SafetyViolation, EVENT_CHANNEL_CAPACITY};
+use crate::log::{LogStorage, RaftLog};
+use crate::metrics::RaftMetrics;
+use crate::observer::{self, ObservedTransport, RpcDirection, RpcObserver, RpcSummary};
 use crate::rpc::{
-    AppendEntriesRequest, AppendEntriesResponse, RequestVoteRequest, RequestVoteResponse,
+    AppendEntriesRequest, AppendEntriesResponse, ForwardRequest, ForwardResponse,
+    InstallSnapshotRequest, InstallSnapshotResponse, JoinRequest, JoinResponse, PingRequest,
+    PingResponse, RequestVoteRequest, RequestVoteResponse, VoteDenialReason,
+};
+use crate::state::{
+    joint_quorum_value, CandidateState, MemberInfo, NodeState, PeerProgress, PersistentState,
+    RaftRole,
+};
+use crate::state_storage::{MemoryStateStorage, StateStorage};
+use crate::transport::{NoopTransport, Transport};
+use crate::types::{
+    ClusterConfig, Entry, EntryKind, LogIndex, NodeId, Snapshot, SnapshotMetadata, StateBundle,
+    Term,
 };
-use crate::state::{NodeState, RaftRole};
-use crate::types::{Entry, LogIndex, NodeId, Snapshot, Term};
 use crate::{Result, RaftError};
 
+use futures::{Stream, StreamExt};
 use parking_lot::RwLock;
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::{mpsc, oneshot};
-use tokio::time::{interval, sleep};
-use tracing::{debug, info, warn};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{interval, Instant, MissedTickBehavior};
+use tracing::{debug, error, info, warn};
+
+/// Window over which a node's election rate is measured
+const ELECTION_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How many times in a row a joining node may fail to reach a leader before
+/// giving up
+const JOIN_ATTEMPTS: u32 = 20;
+
+/// How many heartbeat intervals a removed leader waits for the new voters to
+/// hear that their configuration committed before stepping down regardless
+const HANDOVER_HEARTBEATS: u32 = 10;
 
 /// Trait for state machines that can be replicated via Raft
 ///
@@ -30,16 +59,80 @@ pub trait StateMachine: Send + Sync + 'static {
 
     /// Restore state machine from a snapshot
     fn restore(&mut self, snapshot: &[u8]);
+
+    /// Observe a leader no-op entry at `index`
+    ///
+    /// Only called when `RaftConfig::deliver_noops_to_state_machine` is set,
+    /// for state machines that need to see every log index. Does nothing by
+    /// default.
+    fn apply_noop(&mut self, index: LogIndex) {
+        let _ = index;
+    }
+
+    /// Apply a committed command proposed with
+    /// [`RaftNode::propose_with_metadata`]
+    ///
+    /// Called in place of [`apply`](Self::apply) for entries that carry
+    /// metadata, which the default ignores.
+    fn apply_with_metadata(&mut self, command: &[u8], metadata: &[u8]) -> Vec<u8> {
+        let _ = metadata;
+        self.apply(command)
+    }
+}
+
+/// Apply a normal entry, handing over its metadata if it has any
+pub(crate) fn apply_entry<SM: StateMachine>(machine: &mut SM, entry: &Entry) -> Vec<u8> {
+    match &entry.metadata {
+        Some(metadata) => machine.apply_with_metadata(&entry.command, metadata),
+        None => machine.apply(&entry.command),
+    }
+}
+
+/// Linearizable read traffic, queued apart from [`RaftCommand`] so a backlog
+/// of writes can't starve it
+enum ReadCommand {
+    /// Find an index a linearizable read can be served at (only works on
+    /// leader)
+    Start {
+        response: oneshot::Sender<Result<LogIndex>>,
+    },
+
+    /// Find a read index on the strength of the leader's lease alone
+    Lease {
+        response: oneshot::Sender<Result<LogIndex>>,
+    },
+
+    /// A leadership confirmation round for `responses` finished;
+    /// `confirmed` says whether a quorum still followed us in `term`
+    RoundFinished {
+        term: Term,
+        read_index: LogIndex,
+        confirmed: bool,
+        responses: Vec<oneshot::Sender<Result<LogIndex>>>,
+    },
 }
 
+/// Committed commands handed to a manual-apply caller, with their indices
+type ReadyCommands = Vec<(LogIndex, Vec<u8>)>;
+
 /// Commands sent to the Raft node
 enum RaftCommand {
     /// Propose a new command (only works on leader)
     Propose {
         command: Vec<u8>,
+        metadata: Option<Vec<u8>>,
         response: oneshot::Sender<Result<Vec<u8>>>,
     },
 
+    /// A `propose` caller stopped waiting for its response
+    ProposalCancelled,
+
+    /// Append a command without waiting for commit (only works on leader)
+    ProposeNoWait {
+        command: Vec<u8>,
+        response: oneshot::Sender<Result<LogIndex>>,
+    },
+
     /// Handle RequestVote RPC
     RequestVote {
         request: RequestVoteRequest,
@@ -52,468 +145,10073 @@ enum RaftCommand {
         response: oneshot::Sender<AppendEntriesResponse>,
     },
 
+    /// Handle InstallSnapshot RPC
+    InstallSnapshot {
+        request: InstallSnapshotRequest,
+        response: oneshot::Sender<InstallSnapshotResponse>,
+    },
+
+    /// A background restore of an installed snapshot finished
+    RestoreFinished { last_included_index: LogIndex },
+
+    /// The apply thread applied everything up to and including `through`,
+    /// with the state machine's output for each normal entry
+    Applied {
+        through: LogIndex,
+        outputs: Vec<(LogIndex, Vec<u8>)>,
+    },
+
+    /// A peer answered one of our RequestVote RPCs
+    VoteResponse {
+        from: NodeId,
+        pre_vote: bool,
+        response: RequestVoteResponse,
+    },
+
+    /// A peer answered one of our AppendEntries RPCs, sent at `sent_at`,
+    /// which carried the log up to and including `sent_through`
+    AppendResponse {
+        from: NodeId,
+        sent_at: Instant,
+        sent_through: LogIndex,
+        response: AppendEntriesResponse,
+    },
+
+    /// A snapshot transfer to `peer` finished, successfully if the peer
+    /// answered the last chunk
+    SnapshotSent {
+        peer: NodeId,
+        last_included_index: LogIndex,
+        attempts: u32,
+        result: Result<InstallSnapshotResponse>,
+    },
+
+    /// Handle Join RPC (only works on leader)
+    Join {
+        request: JoinRequest,
+        response: oneshot::Sender<JoinResponse>,
+    },
+
+    /// Join a running cluster through any of `seeds`
+    JoinCluster {
+        seeds: Vec<NodeId>,
+        response: oneshot::Sender<Result<()>>,
+    },
+
+    /// The leader accepted us as a voter; `response` is answered once we
+    /// see that configuration ourselves
+    Joined {
+        response: oneshot::Sender<Result<()>>,
+    },
+
+    /// Answer `response` once this node has applied what the leader had
+    /// committed
+    WaitCaughtUp { response: oneshot::Sender<()> },
+
+    /// A background snapshot of the state machine finished (or was rejected
+    /// as inconsistent)
+    SnapshotReady(Result<Snapshot>),
+
+    /// Handle Ping RPC
+    Ping {
+        request: PingRequest,
+        response: oneshot::Sender<PingResponse>,
+    },
+
+    /// Ping a peer and report the round-trip time
+    PingPeer {
+        peer: NodeId,
+        response: oneshot::Sender<Result<Duration>>,
+    },
+
+    /// One of our pings finished (None if the peer didn't answer)
+    PingResult { peer: NodeId, rtt: Option<Duration> },
+
+    /// Move a member between the voter and learner sets (only works on leader)
+    ChangeMembership {
+        change: MembershipChange,
+        response: oneshot::Sender<Result<()>>,
+    },
+
+    /// Commit a no-op in the current term, answering with the configuration
+    /// in effect once it's applied (only works on leader)
+    ConfigNoop {
+        response: oneshot::Sender<Result<ClusterConfig>>,
+    },
+
+    /// Drive the membership to `target`, answering once it's applied (only
+    /// works on leader)
+    ChangeConfiguration {
+        target: ClusterConfig,
+        force: bool,
+        response: oneshot::Sender<Result<()>>,
+    },
+
+    /// Export the node's committed state for cloning a replica
+    ExportBundle {
+        response: oneshot::Sender<Result<StateBundle>>,
+    },
+
+    /// Get the snapshot the log currently holds
+    GetSnapshot {
+        response: oneshot::Sender<Option<Snapshot>>,
+    },
+
+    /// Snapshot everything applied so far, answering once it's stored
+    CreateSnapshot {
+        response: oneshot::Sender<Result<Snapshot>>,
+    },
+
+    /// Load an exported state into a fresh node
+    ImportBundle {
+        bundle: StateBundle,
+        response: oneshot::Sender<Result<()>>,
+    },
+
+    /// List the members of the applied configuration
+    Members {
+        response: oneshot::Sender<Vec<MemberInfo>>,
+    },
+
+    /// Take a snapshot of the node's metrics
+    Metrics {
+        response: oneshot::Sender<RaftMetrics>,
+    },
+
+    /// Report per-peer replication progress (only works on leader)
+    ReplicationProgress {
+        response: oneshot::Sender<Result<HashMap<NodeId, PeerProgress>>>,
+    },
+
+    /// Report the lowest applied index across the cluster (only works on
+    /// leader)
+    AppliedWatermark {
+        response: oneshot::Sender<Result<LogIndex>>,
+    },
+
+    /// List committed commands waiting for the caller to apply them
+    ApplyReady {
+        response: oneshot::Sender<Result<ReadyCommands>>,
+    },
+
+    /// The caller applied everything up to and including `through`
+    ConfirmApplied {
+        through: LogIndex,
+        response: oneshot::Sender<Result<()>>,
+    },
+
+    /// Read committed entries in `[start, end)`
+    ReadCommittedRange {
+        start: LogIndex,
+        end: LogIndex,
+        response: oneshot::Sender<Result<Vec<Entry>>>,
+    },
+
+    /// Register callbacks for gaining and losing leadership
+    OnLeadership {
+        callbacks: LeadershipCallbacks,
+        response: oneshot::Sender<()>,
+    },
+
+    /// A proposal a follower forwarded on behalf of its client
+    Forwarded {
+        command: Vec<u8>,
+        metadata: Option<Vec<u8>>,
+        response: oneshot::Sender<Result<Vec<u8>>>,
+    },
+
     /// Shutdown the node
     Shutdown,
+
+    /// Shutdown once everything committed has been applied, or at `deadline`
+    ShutdownDrained {
+        deadline: Instant,
+        response: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// A single-member change to the cluster configuration
+enum MembershipChange {
+    /// Stop counting a voter toward quorum but keep replicating to it
+    Demote(NodeId),
+
+    /// Turn a learner back into a voter
+    Promote(NodeId),
+
+    /// Start replicating to a node that isn't a member yet, without letting
+    /// it vote
+    AddLearner(NodeId),
+}
+
+/// A [`RaftNode::change_configuration`] call being worked through
+struct Reconfiguration {
+    target: ClusterConfig,
+    force: bool,
+    response: oneshot::Sender<Result<()>>,
+}
+
+/// Callbacks registered through `RaftNode::on_leadership`
+struct LeadershipCallbacks {
+    on_acquire: Box<dyn Fn(Term) + Send + Sync>,
+    on_lose: Box<dyn Fn(Term) + Send + Sync>,
+}
+
+/// Tells the node a proposal's caller has gone away if dropped while armed
+struct CancelOnDrop<'a> {
+    command_tx: &'a mpsc::UnboundedSender<RaftCommand>,
+    armed: bool,
+}
+
+impl CancelOnDrop<'_> {
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            let _ = self.command_tx.send(RaftCommand::ProposalCancelled);
+        }
+    }
 }
 
 /// Handle to a running Raft node
 pub struct RaftNode {
     id: NodeId,
     command_tx: mpsc::UnboundedSender<RaftCommand>,
+    read_tx: mpsc::UnboundedSender<ReadCommand>,
+    events: broadcast::Sender<RaftEvent>,
+
+    /// The `Arc<RwLock<AppliedStateMachine<SM>>>` shared with the main loop,
+    /// type-erased since the handle isn't generic over the state machine
+    state_machine: Arc<dyn Any + Send + Sync>,
+
+    /// Told about every RPC this handle answers
+    rpc_observer: Option<Arc<dyn RpcObserver>>,
 }
 
 impl RaftNode {
-    /// Create a new Raft node
+    /// Create a new Raft node that reaches its peers through `transport`
+    ///
+    /// A single-node cluster can pass a [`NoopTransport`](crate::NoopTransport).
+    /// Use [`RaftNodeBuilder`] for less common options.
     pub async fn new<SM: StateMachine>(
         id: NodeId,
         peers: Vec<NodeId>,
         config: RaftConfig,
         state_machine: SM,
+        transport: Arc<dyn Transport>,
     ) -> Result<Self> {
-        let (command_tx, command_rx) = mpsc::unbounded_channel();
+        RaftNodeBuilder::new(id, peers, state_machine)
+            .config(config)
+            .transport(transport)
+            .build()
+            .await
+    }
 
-        let node = RaftNode { id, command_tx };
+    /// This node's ID
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
 
-        // Spawn the node's main loop
-        tokio::spawn(run_node(id, peers, config, state_machine, command_rx));
+    /// Subscribe to events emitted by this node
+    ///
+    /// Only events emitted after subscribing are received.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<RaftEvent> {
+        self.events.subscribe()
+    }
 
-        Ok(node)
+    /// Run `on_acquire` whenever this node becomes leader and `on_lose`
+    /// whenever it stops, each with the term it led
+    ///
+    /// The two always come in pairs: `on_lose` also runs if the node shuts
+    /// down while leader, and if the node is already leader when this is
+    /// called, `on_acquire` runs straight away. Both are called on the node's
+    /// main loop, so they should hand slow work off rather than do it there.
+    pub async fn on_leadership<A, L>(&self, on_acquire: A, on_lose: L) -> Result<()>
+    where
+        A: Fn(Term) + Send + Sync + 'static,
+        L: Fn(Term) + Send + Sync + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::OnLeadership {
+                callbacks: LeadershipCallbacks {
+                    on_acquire: Box::new(on_acquire),
+                    on_lose: Box::new(on_lose),
+                },
+                response: tx,
+            })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)
     }
 
     /// Propose a command to the cluster
     ///
-    /// This will return an error if this node is not the leader.
+    /// This will return an error if this node is not the leader, unless
+    /// `forward_proposals` is set and the leader is known; the command is
+    /// then forwarded to the leader and its result relayed back.
     /// On success, returns the result of applying the command to the state machine.
+    ///
+    /// If the leader steps down before the command commits, this fails with
+    /// [`RaftError::NotLeader`] straight away. The entry stays in the old
+    /// leader's log: the next leader either commits it or overwrites it, so a
+    /// caller that retries may see the command applied twice.
+    ///
+    /// Dropping the returned future cancels the wait: the node forgets the
+    /// waiter, though a command already in the log may still commit.
     pub async fn propose(&self, command: Vec<u8>) -> Result<Vec<u8>> {
+        self.submit_proposal(command, None).await
+    }
+
+    /// Propose a command with opaque `metadata` attached, e.g. a trace id or
+    /// a client timestamp
+    ///
+    /// The metadata is replicated and stored with the entry but isn't part
+    /// of the command: every node hands it to
+    /// [`StateMachine::apply_with_metadata`] alongside the command. Otherwise
+    /// behaves like [`propose`](Self::propose).
+    pub async fn propose_with_metadata(
+        &self,
+        command: Vec<u8>,
+        metadata: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        self.submit_proposal(command, Some(metadata)).await
+    }
+
+    async fn submit_proposal(
+        &self,
+        command: Vec<u8>,
+        metadata: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
         let (tx, rx) = oneshot::channel();
         self.command_tx
             .send(RaftCommand::Propose {
                 command,
+                metadata,
                 response: tx,
             })
             .map_err(|_| RaftError::ShuttingDown)?;
 
-        rx.await.map_err(|_| RaftError::ShuttingDown)?
+        let guard = CancelOnDrop {
+            command_tx: &self.command_tx,
+            armed: true,
+        };
+        let result = rx.await;
+        guard.disarm();
+
+        result.map_err(|_| RaftError::ShuttingDown)?
     }
 
-    /// Handle RequestVote RPC
-    pub async fn request_vote(&self, request: RequestVoteRequest) -> RequestVoteResponse {
+    /// Append a command without waiting for it to commit
+    ///
+    /// Returns the index the command was appended at as soon as it's in the
+    /// leader's local log. The entry may never commit if leadership is lost
+    /// before it replicates; callers track commitment separately.
+    ///
+    /// This will return an error if this node is not the leader.
+    pub async fn propose_no_wait(&self, command: Vec<u8>) -> Result<LogIndex> {
         let (tx, rx) = oneshot::channel();
-        if self
-            .command_tx
-            .send(RaftCommand::RequestVote {
-                request,
+        self.command_tx
+            .send(RaftCommand::ProposeNoWait {
+                command,
                 response: tx,
             })
-            .is_err()
-        {
-            // Node is shutting down, reject vote
-            return RequestVoteResponse {
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Handle RequestVote RPC
+    pub async fn request_vote(&self, request: RequestVoteRequest) -> RequestVoteResponse {
+        let summary = self.rpc_observer.as_ref().map(|_| {
+            RpcSummary::request_vote(RpcDirection::Inbound, request.candidate_id, &request)
+        });
+        let response = async {
+            let (tx, rx) = oneshot::channel();
+            if self
+                .command_tx
+                .send(RaftCommand::RequestVote {
+                    request,
+                    response: tx,
+                })
+                .is_err()
+            {
+                // Node is shutting down, reject vote
+                return RequestVoteResponse {
+                    term: Term(0),
+                    vote_granted: false,
+                    denial_reason: None,
+                };
+            }
+
+            rx.await.unwrap_or(RequestVoteResponse {
                 term: Term(0),
                 vote_granted: false,
-            };
+                denial_reason: None,
+            })
         }
+        .await;
 
-        rx.await.unwrap_or(RequestVoteResponse {
-            term: Term(0),
-            vote_granted: false,
-        })
+        if let (Some(observer), Some(summary)) = (&self.rpc_observer, summary) {
+            observer::report(&**observer, summary, response.vote_granted);
+        }
+        response
     }
 
     /// Handle AppendEntries RPC
     pub async fn append_entries(&self, request: AppendEntriesRequest) -> AppendEntriesResponse {
-        let (tx, rx) = oneshot::channel();
-        if self
-            .command_tx
-            .send(RaftCommand::AppendEntries {
-                request,
-                response: tx,
-            })
-            .is_err()
-        {
-            return AppendEntriesResponse {
+        let summary = self.rpc_observer.as_ref().map(|_| {
+            RpcSummary::append_entries(RpcDirection::Inbound, request.leader_id, &request)
+        });
+        let response = async {
+            let (tx, rx) = oneshot::channel();
+            if self
+                .command_tx
+                .send(RaftCommand::AppendEntries {
+                    request,
+                    response: tx,
+                })
+                .is_err()
+            {
+                return AppendEntriesResponse {
+                    term: Term(0),
+                    success: false,
+                    match_index: None,
+                    conflict_term: None,
+                    conflict_index: None,
+                    commit_index: LogIndex::ZERO,
+                    last_applied: LogIndex::ZERO,
+                };
+            }
+
+            rx.await.unwrap_or(AppendEntriesResponse {
                 term: Term(0),
                 success: false,
                 match_index: None,
+                conflict_term: None,
+                conflict_index: None,
                 commit_index: LogIndex::ZERO,
-            };
+                last_applied: LogIndex::ZERO,
+            })
         }
+        .await;
 
-        rx.await.unwrap_or(AppendEntriesResponse {
-            term: Term(0),
-            success: false,
-            match_index: None,
-            commit_index: LogIndex::ZERO,
-        })
+        if let (Some(observer), Some(summary)) = (&self.rpc_observer, summary) {
+            observer::report(&**observer, summary, response.success);
+        }
+        response
     }
 
-    /// Shutdown the node gracefully
-    pub async fn shutdown(self) {
-        let _ = self.command_tx.send(RaftCommand::Shutdown);
-    }
-}
+    /// Handle InstallSnapshot RPC
+    pub async fn install_snapshot(
+        &self,
+        request: InstallSnapshotRequest,
+    ) -> InstallSnapshotResponse {
+        let summary = self.rpc_observer.as_ref().map(|_| {
+            RpcSummary::install_snapshot(RpcDirection::Inbound, request.leader_id, &request)
+        });
+        let response = async {
+            let (tx, rx) = oneshot::channel();
+            if self
+                .command_tx
+                .send(RaftCommand::InstallSnapshot {
+                    request,
+                    response: tx,
+                })
+                .is_err()
+            {
+                return InstallSnapshotResponse { term: Term(0) };
+            }
 
-/// Inner state of a Raft node
-struct RaftNodeInner<SM> {
-    state: Arc<RwLock<NodeState>>,
-    log: RaftLog,
-    config: RaftConfig,
-    state_machine: Arc<RwLock<SM>>,
-    last_heartbeat: Instant,
-}
+            rx.await
+                .unwrap_or(InstallSnapshotResponse { term: Term(0) })
+        }
+        .await;
 
-impl<SM: StateMachine> RaftNodeInner<SM> {
-    fn new(
-        id: NodeId,
-        peers: Vec<NodeId>,
-        config: RaftConfig,
-        state_machine: SM,
-    ) -> Self {
-        Self {
-            state: Arc::new(RwLock::new(NodeState::new(id, peers))),
-            log: RaftLog::new_memory(),
-            config,
-            state_machine: Arc::new(RwLock::new(state_machine)),
-            last_heartbeat: Instant::now(),
+        if let (Some(observer), Some(summary)) = (&self.rpc_observer, summary) {
+            observer::report(&**observer, summary, true);
         }
+        response
     }
 
-    /// Check if election timeout has elapsed
-    fn is_election_timeout(&self) -> bool {
-        let timeout = rand::random::<u64>()
-            % (self.config.election_timeout_max.as_millis() as u64
-                - self.config.election_timeout_min.as_millis() as u64)
-            + self.config.election_timeout_min.as_millis() as u64;
+    /// Handle Ping RPC
+    ///
+    /// Answered by the node loop without touching the log or state, so a
+    /// pong means the node is up and processing commands.
+    pub async fn ping(&self, request: PingRequest) -> PingResponse {
+        let summary = self
+            .rpc_observer
+            .as_ref()
+            .map(|_| RpcSummary::ping(RpcDirection::Inbound, request.from, &request));
+        let response = async {
+            let fallback = PingResponse {
+                term: Term(0),
+                from: self.id,
+            };
+            let (tx, rx) = oneshot::channel();
+            if self
+                .command_tx
+                .send(RaftCommand::Ping {
+                    request,
+                    response: tx,
+                })
+                .is_err()
+            {
+                return fallback;
+            }
+
+            rx.await.unwrap_or(fallback)
+        }
+        .await;
 
-        self.last_heartbeat.elapsed() > Duration::from_millis(timeout)
+        if let (Some(observer), Some(summary)) = (&self.rpc_observer, summary) {
+            observer::report(&**observer, summary, true);
+        }
+        response
     }
 
-    /// Reset election timeout (called when receiving valid RPC from leader)
-    fn reset_election_timeout(&mut self) {
-        self.last_heartbeat = Instant::now();
+    /// Get an index that reflects every write acknowledged before the call
+    ///
+    /// Implements Raft's ReadIndex: the leader notes its commit index, then
+    /// confirms with a quorum of voters that it is still leader. Once
+    /// `last_applied` reaches the returned index, reading the state machine
+    /// is linearizable. Fails with [`RaftError::NotLeader`] off the leader
+    /// and [`RaftError::Timeout`] if a quorum doesn't answer within the
+    /// maximum election timeout. Concurrent reads share confirmation rounds;
+    /// see [`RaftConfig::read_batch_size`].
+    pub async fn read_index(&self) -> Result<LogIndex> {
+        let (tx, rx) = oneshot::channel();
+        self.read_tx
+            .send(ReadCommand::Start { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
     }
 
-    /// Start an election
-    fn start_election(&mut self) -> Vec<RequestVoteRequest> {
-        let mut state = self.state.write();
-        state.become_candidate();
+    /// Like [`read_index`](Self::read_index), but trusting the leader's lease
+    /// instead of confirming leadership with a quorum
+    ///
+    /// The leader holds a lease for `election_timeout_min`, less
+    /// [`RaftConfig::max_clock_drift`], from the time it sent the
+    /// AppendEntries a quorum most recently answered; followers won't elect
+    /// anyone else before it runs out. Serving from the lease saves a round
+    /// trip but relies on clocks drifting no more than that margin. Fails
+    /// with [`RaftError::LeaseExpired`] if the lease has run out, in which
+    /// case `read_index` still works.
+    pub async fn lease_read_index(&self) -> Result<LogIndex> {
+        let (tx, rx) = oneshot::channel();
+        self.read_tx
+            .send(ReadCommand::Lease { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
 
-        info!(
-            "Node {} starting election for term {}",
-            state.id, state.persistent.current_term
-        );
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
 
-        self.reset_election_timeout();
+    /// Run `f` against the state machine once it reflects every write
+    /// acknowledged before the call
+    ///
+    /// Does the [`read_index`](Self::read_index) handshake, waits for the
+    /// state machine to be applied up to the read index, then calls `f`
+    /// under a read lock. `SM` must be the state machine type the node was
+    /// built with; it's usually inferred from the closure's argument.
+    pub async fn linearizable_read<SM, F, R>(&self, f: F) -> Result<R>
+    where
+        SM: StateMachine,
+        F: FnOnce(&SM) -> R,
+    {
+        let state_machine = Arc::clone(&self.state_machine)
+            .downcast::<RwLock<AppliedStateMachine<SM>>>()
+            .map_err(|_| {
+                RaftError::Internal(format!(
+                    "node {} was not built with a {}",
+                    self.id,
+                    std::any::type_name::<SM>()
+                ))
+            })?;
 
-        // Send RequestVote RPCs to all peers
-        let request = RequestVoteRequest {
-            term: state.persistent.current_term,
-            candidate_id: state.id,
-            last_log_index: self.log.last_index(),
-            last_log_term: self.log.last_term(),
-        };
+        // The node answers once `last_applied` has reached the read index
+        let read_index = self.read_index().await?;
 
-        state
-            .other_peers()
-            .iter()
-            .map(|_| request.clone())
-            .collect()
+        let sm = state_machine.read();
+        debug_assert!(sm.last_applied >= read_index);
+        Ok(f(&sm.machine))
     }
 
-    /// Handle RequestVote RPC
-    fn handle_request_vote(&mut self, req: RequestVoteRequest) -> RequestVoteResponse {
-        let mut state = self.state.write();
+    /// Handle a Join RPC from a node that wants to become a member
+    ///
+    /// The leader adds the node as a learner, catches it up and promotes it
+    /// to voter once it holds every committed entry. `accepted` is only set
+    /// once that promotion has taken effect; until then the node is expected
+    /// to ask again. Non-leaders answer with a hint of who the leader is.
+    pub async fn handle_join(&self, request: JoinRequest) -> JoinResponse {
+        let summary = self
+            .rpc_observer
+            .as_ref()
+            .map(|_| RpcSummary::join(RpcDirection::Inbound, request.node));
+        let response = async {
+            let (tx, rx) = oneshot::channel();
+            self.command_tx
+                .send(RaftCommand::Join {
+                    request,
+                    response: tx,
+                })
+                .ok();
 
-        // Update term if we see a higher one
-        if req.term > state.persistent.current_term {
-            state.become_follower(req.term, None);
+            rx.await.unwrap_or(JoinResponse {
+                accepted: false,
+                leader_hint: None,
+            })
         }
+        .await;
 
-        let mut vote_granted = false;
+        if let (Some(observer), Some(summary)) = (&self.rpc_observer, summary) {
+            observer::report(&**observer, summary, response.accepted);
+        }
+        response
+    }
 
-        // Grant vote if:
-        // 1. Candidate's term >= our term
-        // 2. We haven't voted for anyone else this term
-        // 3. Candidate's log is at least as up-to-date as ours
-        if req.term >= state.persistent.current_term {
-            let already_voted = state
-                .persistent
-                .voted_for
-                .map(|v| v != req.candidate_id)
-                .unwrap_or(false);
-
-            if !already_voted {
-                // Check if candidate's log is at least as up-to-date
-                let our_last_term = self.log.last_term();
-                let our_last_index = self.log.last_index();
-
-                let log_ok = req.last_log_term > our_last_term
-                    || (req.last_log_term == our_last_term
-                        && req.last_log_index >= our_last_index);
-
-                if log_ok {
-                    vote_granted = true;
+    /// Handle a proposal forwarded by a follower
+    ///
+    /// Proposes the command as if a client had called
+    /// [`propose`](Self::propose) here, except that a non-leader answers
+    /// `NotLeader` rather than forwarding it on.
+    pub async fn handle_forward(&self, request: ForwardRequest) -> ForwardResponse {
+        let summary = self
+            .rpc_observer
+            .as_ref()
+            .map(|_| RpcSummary::forward(RpcDirection::Inbound, request.from));
+        let response = async {
+            let (tx, rx) = oneshot::channel();
+            if self
+                .command_tx
+                .send(RaftCommand::Forwarded {
+                    command: request.command,
+                    metadata: request.metadata,
+                    response: tx,
+                })
+                .is_err()
+            {
+                return ForwardResponse::Failed(RaftError::ShuttingDown.to_string());
+            }
+
+            match rx.await {
+                Ok(Ok(output)) => ForwardResponse::Applied(output),
+                Ok(Err(RaftError::NotLeader(info))) => ForwardResponse::NotLeader(info.leader),
+                Ok(Err(e)) => ForwardResponse::Failed(e.to_string()),
+                Err(_) => ForwardResponse::Failed(RaftError::ShuttingDown.to_string()),
+            }
+        }
+        .await;
+
+        if let (Some(observer), Some(summary)) = (&self.rpc_observer, summary) {
+            observer::report(
+                &**observer,
+                summary,
+                matches!(response, ForwardResponse::Applied(_)),
+            );
+        }
+        response
+    }
+
+    /// Join a running cluster as a voter
+    ///
+    /// Build the node with no peers, then call this with the addresses of
+    /// any existing members. The seeds are asked in turn, following leader
+    /// hints, until the leader has caught this node up and promoted it.
+    /// Returns once this node sees itself as a voter, or
+    /// [`RaftError::Timeout`] if no leader could be reached.
+    pub async fn join(&self, seeds: Vec<NodeId>) -> Result<()> {
+        if seeds.is_empty() {
+            return Err(RaftError::InvalidConfiguration(
+                "no seed peers to join through".to_string(),
+            ));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::JoinCluster {
+                seeds,
+                response: tx,
+            })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Wait until this node has caught up with the leader
+    ///
+    /// A follower is caught up once it has applied everything the leader had
+    /// committed as of the last AppendEntries it accepted, so it lags by at
+    /// most one heartbeat. Until then, e.g. right after a restart, its state
+    /// machine is stale and it shouldn't serve reads. The leader is always
+    /// caught up. Fails with [`RaftError::Timeout`] if that doesn't happen
+    /// within `timeout`.
+    pub async fn wait_until_caught_up(&self, timeout: Duration) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::WaitCaughtUp { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(result) => result.map_err(|_| RaftError::ShuttingDown),
+            Err(_) => Err(RaftError::Timeout),
+        }
+    }
+
+    /// Ping `peer` and return the measured round-trip time
+    ///
+    /// Fails with [`RaftError::Timeout`] if the peer doesn't answer within
+    /// the minimum election timeout. The outcome is recorded in the peer's
+    /// health, as reported by [`RaftNode::replication_progress`].
+    pub async fn ping_peer(&self, peer: NodeId) -> Result<Duration> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::PingPeer { peer, response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Turn a voter into a learner without removing it from the cluster
+    ///
+    /// The node keeps receiving the log but stops counting toward quorum and
+    /// can't stand for election, e.g. so it can be taken down for maintenance.
+    /// Like any configuration change this goes through the log and takes
+    /// effect once applied. Reverse it with [`RaftNode::promote_learner`].
+    ///
+    /// This will return an error if this node is not the leader.
+    pub async fn demote_to_learner(&self, node: NodeId) -> Result<()> {
+        self.change_membership(MembershipChange::Demote(node)).await
+    }
+
+    /// Turn a learner into a voter
+    ///
+    /// This will return an error if this node is not the leader.
+    pub async fn promote_learner(&self, node: NodeId) -> Result<()> {
+        self.change_membership(MembershipChange::Promote(node))
+            .await
+    }
+
+    /// Make the cluster's members exactly `voters` and `learners`
+    ///
+    /// New nodes are first added as learners. Once they've caught up, the
+    /// voters change through a joint configuration of the old and new voters
+    /// together, in which every decision needs a majority of both, and from
+    /// there to the new voters alone. Each step is applied before the next
+    /// is made. Returns once the target configuration is applied on the
+    /// leader.
+    ///
+    /// The leader may be one of the voters removed. It keeps leading through
+    /// the joint configuration and steps down once the target is applied,
+    /// leaving the new voters to elect a leader among themselves.
+    ///
+    /// Refused with [`RaftError::InvalidConfiguration`] if a majority of the
+    /// old or the new voters isn't reachable, counting new nodes as
+    /// reachable; use [`RaftNode::force_change_configuration`] to go ahead
+    /// anyway.
+    ///
+    /// This will return an error if this node is not the leader.
+    pub async fn change_configuration(
+        &self,
+        voters: Vec<NodeId>,
+        learners: Vec<NodeId>,
+    ) -> Result<()> {
+        let target = ClusterConfig {
+            voters,
+            learners,
+            ..Default::default()
+        };
+        self.reconfigure(target, false).await
+    }
+
+    /// [`RaftNode::change_configuration`] without the reachability check
+    ///
+    /// The cluster can stop making progress if too few voters are left up.
+    pub async fn force_change_configuration(
+        &self,
+        voters: Vec<NodeId>,
+        learners: Vec<NodeId>,
+    ) -> Result<()> {
+        let target = ClusterConfig {
+            voters,
+            learners,
+            ..Default::default()
+        };
+        self.reconfigure(target, true).await
+    }
+
+    async fn reconfigure(&self, target: ClusterConfig, force: bool) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::ChangeConfiguration {
+                target,
+                force,
+                response: tx,
+            })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Confirm the current configuration by committing a no-op under it
+    ///
+    /// A configuration takes effect once its entry is applied, so the entry
+    /// itself was committed by the members before it. The no-op appended
+    /// here can only commit with a majority of the configuration in effect,
+    /// which confirms the new membership holds the log and can make progress.
+    /// Returns the configuration applied once the no-op is. The leader does
+    /// this itself after every configuration change, and `change_configuration`
+    /// waits for each step to be confirmed before making the next.
+    ///
+    /// This will return an error if this node is not the leader, or stops
+    /// being leader before the no-op commits.
+    pub async fn propose_config_noop(&self) -> Result<ClusterConfig> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::ConfigNoop { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    async fn change_membership(&self, change: MembershipChange) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::ChangeMembership {
+                change,
+                response: tx,
+            })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Get the snapshot this node currently holds, for backup
+    ///
+    /// Restoring `data` into a fresh state machine reproduces the state as of
+    /// `metadata.last_included_index`. Works on any node; `None` means no
+    /// snapshot has been taken or installed yet.
+    pub async fn get_snapshot(&self) -> Result<Option<Snapshot>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::GetSnapshot { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)
+    }
+
+    /// Snapshot everything this node has applied and return the snapshot
+    ///
+    /// Ignores `snapshot_threshold`, and compacts the log behind the new
+    /// snapshot like an automatic one. If nothing was applied since the
+    /// current snapshot, that one is returned as is.
+    pub async fn create_snapshot(&self) -> Result<Snapshot> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::CreateSnapshot { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Export this node's committed state as one transferable bundle
+    ///
+    /// The bundle holds the latest snapshot, the committed entries after it
+    /// and the committed configuration, all captured at the same point. Load
+    /// it into a new node with [`RaftNode::import_bundle`]; the new node then
+    /// only needs entries committed after the export.
+    pub async fn export_bundle(&self) -> Result<StateBundle> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::ExportBundle { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Load a bundle from [`RaftNode::export_bundle`] into this node
+    ///
+    /// Only works on a fresh node with an empty log; anything else is
+    /// rejected with [`RaftError::InvalidBundle`].
+    pub async fn import_bundle(&self, bundle: StateBundle) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::ImportBundle {
+                bundle,
+                response: tx,
+            })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// List the cluster's members and how each relates to this node
+    ///
+    /// Works on any node. Membership is the configuration this node has
+    /// applied, and the leader is the last one it heard from, so a lagging
+    /// follower may report a slightly stale view.
+    pub async fn members(&self) -> Result<Vec<MemberInfo>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::Members { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)
+    }
+
+    /// Take a snapshot of the node's metrics
+    pub async fn metrics(&self) -> Result<RaftMetrics> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::Metrics { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)
+    }
+
+    /// Emit a metrics snapshot every `period`, starting immediately
+    ///
+    /// The stream keeps its own timer, so nothing else needs to poll the
+    /// node. It ends once the node shuts down. A slow consumer skips ticks
+    /// rather than receiving a burst of stale snapshots.
+    pub fn metrics_stream(&self, period: Duration) -> impl Stream<Item = RaftMetrics> {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let command_tx = self.command_tx.clone();
+
+        futures::stream::unfold(
+            (ticker, command_tx),
+            |(mut ticker, command_tx)| async move {
+                ticker.tick().await;
+
+                let (tx, rx) = oneshot::channel();
+                command_tx
+                    .send(RaftCommand::Metrics { response: tx })
+                    .ok()?;
+                let metrics = rx.await.ok()?;
+                Some((metrics, (ticker, command_tx)))
+            },
+        )
+    }
+
+    /// Get the leader's view of each follower
+    ///
+    /// This will return an error if this node is not the leader.
+    pub async fn replication_progress(&self) -> Result<HashMap<NodeId, PeerProgress>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::ReplicationProgress { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Get the lowest index every member of the cluster has applied
+    ///
+    /// Followers report their applied index in AppendEntries responses, so
+    /// the watermark trails the cluster by up to a heartbeat and a member that
+    /// hasn't answered yet counts as having applied nothing. It is advisory:
+    /// anything up to the watermark is safe to treat as applied everywhere,
+    /// e.g. before pruning data other members might still need.
+    ///
+    /// This will return an error if this node is not the leader.
+    pub async fn global_applied_watermark(&self) -> Result<LogIndex> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::AppliedWatermark { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Get the committed commands that haven't been applied yet, in log
+    /// order, for the caller to apply
+    ///
+    /// Only for nodes built with [`RaftConfig::manual_apply`]; others fail
+    /// with [`RaftError::ManualApplyDisabled`]. Entries stay ready, and are
+    /// returned again, until [`RaftNode::confirm_applied`] covers them.
+    /// No-ops and configuration changes are left out.
+    pub async fn apply_ready(&self) -> Result<Vec<(LogIndex, Vec<u8>)>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::ApplyReady { response: tx })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Tell the node the caller has applied every committed entry up to and
+    /// including `through`
+    ///
+    /// Advances `last_applied` and answers the proposals it covers. Fails
+    /// with [`RaftError::LogIndexOutOfRange`] if `through` isn't committed
+    /// yet, and [`RaftError::ManualApplyDisabled`] unless the node was built
+    /// with [`RaftConfig::manual_apply`].
+    pub async fn confirm_applied(&self, through: LogIndex) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::ConfirmApplied {
+                through,
+                response: tx,
+            })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Read the committed entries in `[start, end)`
+    ///
+    /// For backfilling and replaying from the log itself, e.g. in tooling.
+    /// Fails with [`RaftError::LogIndexOutOfRange`] naming the first index
+    /// that can't be returned: the first uncommitted one if `end` reaches
+    /// past the commit index, or `start` if the range begins in entries
+    /// already compacted into a snapshot. An empty range returns nothing.
+    pub async fn read_committed_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Entry>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::ReadCommittedRange {
+                start,
+                end,
+                response: tx,
+            })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+
+    /// Shutdown the node gracefully
+    pub async fn shutdown(self) {
+        let _ = self.command_tx.send(RaftCommand::Shutdown);
+    }
+
+    /// Shutdown once the state machine has applied everything committed
+    ///
+    /// The node keeps running as usual until `last_applied` reaches the
+    /// commit index, so a state machine persisted on exit leaves nothing
+    /// committed for the next startup to replay. Shuts down either way, but
+    /// fails with [`RaftError::Timeout`] if applying hadn't caught up within
+    /// `timeout`. With `RaftConfig::manual_apply` set, applying is up to the
+    /// caller's `confirm_applied`.
+    pub async fn shutdown_drained(self, timeout: Duration) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(RaftCommand::ShutdownDrained {
+                deadline: Instant::now() + timeout,
+                response: tx,
+            })
+            .map_err(|_| RaftError::ShuttingDown)?;
+
+        rx.await.map_err(|_| RaftError::ShuttingDown)?
+    }
+}
+
+/// Builder for RaftNode
+pub struct RaftNodeBuilder<SM> {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    config: RaftConfig,
+    state_machine: SM,
+    persistent_state: PersistentState,
+    state_storage: Box<dyn StateStorage>,
+    transport: Arc<dyn Transport>,
+    log: RaftLog,
+    runtime: Option<Handle>,
+    rpc_observer: Option<Arc<dyn RpcObserver>>,
+    election_timeout: Option<Arc<dyn ElectionTimeoutStrategy>>,
+}
+
+impl<SM: StateMachine> RaftNodeBuilder<SM> {
+    pub fn new(id: NodeId, peers: Vec<NodeId>, state_machine: SM) -> Self {
+        Self {
+            id,
+            peers,
+            config: RaftConfig::default(),
+            state_machine,
+            persistent_state: PersistentState::default(),
+            state_storage: Box::new(MemoryStateStorage::new()),
+            transport: Arc::new(NoopTransport),
+            log: RaftLog::new_memory(),
+            runtime: None,
+            rpc_observer: None,
+            election_timeout: None,
+        }
+    }
+
+    /// Storage backend for the Raft log (in-memory by default)
+    pub fn log_storage(mut self, storage: Box<dyn LogStorage>) -> Self {
+        self.log = RaftLog::new(storage);
+        self
+    }
+
+    /// Storage backend for the current term and vote (in-memory by default)
+    ///
+    /// Hard state already saved there is loaded on startup and takes
+    /// precedence over [`RaftNodeBuilder::initial_state`].
+    pub fn state_storage(mut self, storage: Box<dyn StateStorage>) -> Self {
+        self.state_storage = storage;
+        self
+    }
+
+    /// Transport used to send RPCs to peers
+    ///
+    /// Without one the node can't reach anybody, which is only useful for
+    /// single-node clusters and tests.
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn config(mut self, config: RaftConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Start from the given term and vote instead of term 0 with no vote
+    ///
+    /// Mainly useful for tests that need a node which has already lived
+    /// through many terms.
+    pub fn initial_state(mut self, state: PersistentState) -> Self {
+        self.persistent_state = state;
+        self
+    }
+
+    /// Report every RPC the node sends or answers to `observer`
+    ///
+    /// Outbound RPCs are reported from the tasks that send them and inbound
+    /// ones from the [`RaftNode`] handle answering them, so a slow observer
+    /// holds up RPCs but never the node loop.
+    pub fn rpc_observer(mut self, observer: Arc<dyn RpcObserver>) -> Self {
+        self.rpc_observer = Some(observer);
+        self
+    }
+
+    /// Decide election timeouts with `strategy` instead of drawing them at
+    /// random from the configured range
+    pub fn election_timeout_strategy(mut self, strategy: Arc<dyn ElectionTimeoutStrategy>) -> Self {
+        self.election_timeout = Some(strategy);
+        self
+    }
+
+    /// Runtime to spawn the node's main loop on (the caller's by default)
+    ///
+    /// Everything the loop spawns in turn, like RPCs to peers, runs there
+    /// too, which keeps consensus off the application's own runtime. The
+    /// runtime must keep running for as long as the node is used; a
+    /// current-thread runtime needs a thread of its own blocked in
+    /// `block_on`.
+    pub fn runtime(mut self, handle: Handle) -> Self {
+        self.runtime = Some(handle);
+        self
+    }
+
+    /// Create the node and spawn its main loop
+    ///
+    /// Fails without starting anything if `verify_log_on_startup` is set and
+    /// the log is corrupt.
+    pub async fn build(self) -> Result<RaftNode> {
+        if self.config.verify_log_on_startup {
+            self.log.verify_integrity()?;
+        }
+
+        let saved = self.state_storage.load_hard_state()?;
+        let commit_hint = self.state_storage.load_commit_hint()?;
+        let persistent = match saved {
+            Some((current_term, voted_for)) => PersistentState {
+                current_term,
+                voted_for,
+            },
+            None => self.persistent_state,
+        };
+
+        let (command_tx, command_rx) = mpsc::unbounded_channel();
+        let (read_tx, read_rx) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+
+        let mut inner = RaftNodeInner::new(
+            self.id,
+            self.peers,
+            self.config,
+            self.state_machine,
+            events.clone(),
+        );
+
+        let node = RaftNode {
+            id: self.id,
+            command_tx,
+            read_tx,
+            events,
+            state_machine: Arc::clone(&inner.state_machine) as Arc<dyn Any + Send + Sync>,
+            rpc_observer: self.rpc_observer.clone(),
+        };
+
+        inner.state.write().persistent = persistent.clone();
+        inner.hard_state = HardState {
+            storage: self.state_storage,
+            saved: if saved.is_some() {
+                persistent
+            } else {
+                PersistentState::default()
+            },
+            commit_hint: commit_hint.unwrap_or(LogIndex::ZERO),
+        };
+        inner.transport = match self.rpc_observer {
+            Some(observer) => Arc::new(ObservedTransport {
+                inner: self.transport,
+                observer,
+            }),
+            None => self.transport,
+        };
+        if let Some(strategy) = self.election_timeout {
+            inner.election_timeout = strategy;
+            inner.reset_election_timeout();
+        }
+        inner.log = self.log;
+        inner.recover(commit_hint);
+        inner.command_tx = node.command_tx.clone();
+        inner.read_tx = node.read_tx.clone();
+        if inner.config.separate_apply_thread && !inner.config.manual_apply {
+            inner.start_apply_thread()?;
+        }
+        inner.apply_committed();
+
+        // Spawn the node's main loop
+        let main_loop = run_node(inner, command_rx, read_rx);
+        match self.runtime {
+            Some(handle) => handle.spawn(main_loop),
+            None => tokio::spawn(main_loop),
+        };
+
+        Ok(node)
+    }
+}
+
+/// A proposal waiting for the first leader to be elected
+struct LeaderWaiter {
+    command: Vec<u8>,
+    metadata: Option<Vec<u8>>,
+    response: oneshot::Sender<Result<Vec<u8>>>,
+    deadline: Instant,
+}
+
+/// A read whose leadership check passed, waiting to be applied up to
+/// `read_index`
+struct ReadWaiter {
+    read_index: LogIndex,
+    response: oneshot::Sender<Result<LogIndex>>,
+}
+
+/// A no-op confirming the configuration, waiting to be applied
+///
+/// The leader's own confirmations have no `response`.
+struct ConfigNoopWaiter {
+    term: Term,
+    index: LogIndex,
+    response: Option<oneshot::Sender<Result<ClusterConfig>>>,
+}
+
+/// A `create_snapshot` caller waiting for a snapshot through `through`
+struct SnapshotWaiter {
+    through: LogIndex,
+    response: oneshot::Sender<Result<Snapshot>>,
+}
+
+/// The node's term and vote as last made durable
+struct HardState {
+    storage: Box<dyn StateStorage>,
+
+    /// What's known to be on stable storage
+    saved: PersistentState,
+
+    /// Commit index last saved as a hint for the next startup
+    commit_hint: LogIndex,
+}
+
+impl HardState {
+    /// Make `state.persistent` durable if it changed since the last save
+    ///
+    /// Call before acting on a new term or vote: before replying, requesting
+    /// votes, or appending entries. On failure the in-memory term and vote
+    /// are rolled back to what was last saved, so the node never acts on
+    /// state a crash would forget.
+    fn persist(&mut self, state: &mut NodeState) -> Result<()> {
+        if state.persistent == self.saved {
+            return Ok(());
+        }
+
+        let persistent = &state.persistent;
+        match self
+            .storage
+            .save_hard_state(persistent.current_term, persistent.voted_for)
+        {
+            Ok(()) => {
+                self.saved = state.persistent.clone();
+                Ok(())
+            }
+            Err(e) => {
+                error!("Node {} failed to persist hard state: {}", state.id, e);
+                state.persistent = self.saved.clone();
+                Err(e)
+            }
+        }
+    }
+
+    /// Save the commit index as a hint for the next startup if it moved
+    ///
+    /// A failure is only logged; the node then restarts from an older hint.
+    fn save_commit_hint(&mut self, state: &NodeState) {
+        let commit_index = state.volatile.commit_index;
+        if commit_index <= self.commit_hint {
+            return;
+        }
+        match self.storage.save_commit_hint(commit_index) {
+            Ok(()) => self.commit_hint = commit_index,
+            Err(e) => warn!("Node {} failed to save commit hint: {}", state.id, e),
+        }
+    }
+}
+
+/// Work for the apply thread, carried out in the order it was sent
+enum ApplyJob {
+    /// Committed entries, contiguous and in log order
+    Entries(Vec<Entry>),
+
+    /// Replace the state machine's contents with an installed snapshot
+    Restore(Snapshot),
+}
+
+/// Body of the apply thread: run jobs until the node goes away
+fn run_apply_thread<SM: StateMachine>(
+    state_machine: Arc<RwLock<AppliedStateMachine<SM>>>,
+    mut jobs: mpsc::UnboundedReceiver<ApplyJob>,
+    command_tx: mpsc::UnboundedSender<RaftCommand>,
+    deliver_noops: bool,
+) {
+    while let Some(job) = jobs.blocking_recv() {
+        let done = match job {
+            ApplyJob::Entries(entries) => {
+                let Some(through) = entries.last().map(|e| e.index) else {
+                    continue;
+                };
+                let mut outputs = Vec::new();
+                for entry in entries {
+                    let mut sm = state_machine.write();
+                    // Already in the state machine through a restored snapshot
+                    if entry.index <= sm.last_applied {
+                        continue;
+                    }
+                    match entry.kind {
+                        EntryKind::Normal => {
+                            outputs.push((entry.index, apply_entry(&mut sm.machine, &entry)));
+                        }
+                        EntryKind::Noop if deliver_noops => sm.machine.apply_noop(entry.index),
+                        EntryKind::Noop | EntryKind::ConfigChange => {}
+                    }
+                    sm.last_applied = entry.index;
+                }
+                RaftCommand::Applied { through, outputs }
+            }
+            ApplyJob::Restore(snapshot) => {
+                let last_included_index = snapshot.metadata.last_included_index;
+                let mut sm = state_machine.write();
+                sm.machine.restore(&snapshot.data);
+                sm.last_applied = last_included_index;
+                RaftCommand::RestoreFinished {
+                    last_included_index,
+                }
+            }
+        };
+
+        if command_tx.send(done).is_err() {
+            break;
+        }
+    }
+}
+
+/// A snapshot being streamed to this node in chunks
+struct IncomingSnapshot {
+    last_included_index: LogIndex,
+    last_included_term: Term,
+    data: Vec<u8>,
+}
+
+/// What pings have told us about a peer
+#[derive(Debug, Clone, Copy, Default)]
+struct PeerHealth {
+    rtt: Option<Duration>,
+    last_contact: Option<Instant>,
+    reachable: bool,
+}
+
+/// The user's state machine together with the index it has applied up to
+///
+/// Both live under the same lock so a snapshot always sees the state and
+/// the index it reflects at the same instant.
+struct AppliedStateMachine<SM> {
+    machine: SM,
+    last_applied: LogIndex,
+}
+
+/// Inner state of a Raft node
+struct RaftNodeInner<SM> {
+    state: Arc<RwLock<NodeState>>,
+    log: RaftLog,
+    config: RaftConfig,
+    state_machine: Arc<RwLock<AppliedStateMachine<SM>>>,
+    last_heartbeat: Instant,
+
+    /// Decides how long each election timeout lasts
+    election_timeout: Arc<dyn ElectionTimeoutStrategy>,
+
+    /// How long after `last_heartbeat` the current election timeout fires
+    current_timeout: Duration,
+    events: broadcast::Sender<RaftEvent>,
+
+    /// Consecutive election timeouts since we last heard from a leader
+    timeouts_without_leader: u32,
+
+    /// When a leader last reached us
+    last_leader_contact: Option<Instant>,
+
+    /// Our term when we last reported a term gap
+    term_gap_reported: Option<Term>,
+
+    /// The pre-vote round we're running, for the term we'd stand in
+    pre_vote: Option<(Term, CandidateState)>,
+
+    /// When this node started each election in the last minute
+    recent_elections: VecDeque<Instant>,
+
+    /// Whether an election storm has been reported and not yet died down
+    in_election_storm: bool,
+
+    transport: Arc<dyn Transport>,
+
+    /// Proposals parked until the cluster elects its first leader
+    ///
+    /// Only used when `initial_leader_timeout` is configured.
+    leader_waiters: Vec<LeaderWaiter>,
+
+    /// Whether this node has ever known a leader
+    leader_known: bool,
+
+    /// Proposals appended to the log while leader
+    proposals_accepted: u64,
+
+    /// `propose` callers waiting for their entry to be applied, by index
+    pending_proposals: BTreeMap<LogIndex, oneshot::Sender<Result<Vec<u8>>>>,
+
+    /// Proposals failed because this node stopped being leader before they
+    /// committed
+    abandoned_proposals: u64,
+
+    /// Hands work to the apply thread, if applies run on one
+    applier: Option<mpsc::UnboundedSender<ApplyJob>>,
+
+    /// Last entry handed to the apply thread; `last_applied` catches up as
+    /// the thread reports back
+    apply_dispatched: LogIndex,
+
+    /// Snapshot chunks received so far from the leader
+    incoming_snapshot: Option<IncomingSnapshot>,
+
+    /// Whether an installed snapshot is being restored into the state
+    /// machine in the background; applies are paused until it finishes
+    restore_in_progress: bool,
+
+    /// Whether a snapshot is being built in the background; applies are
+    /// held back until it lands so the snapshot matches its metadata
+    snapshot_in_progress: bool,
+
+    /// Callers of `create_snapshot` waiting for their snapshot to be stored
+    snapshot_waiters: Vec<SnapshotWaiter>,
+
+    /// Durable copy of the term and vote
+    hard_state: HardState,
+
+    /// Liveness of each peer, as measured by pings
+    peer_health: HashMap<NodeId, PeerHealth>,
+
+    /// Followers being sent a snapshot; heartbeats leave them alone until
+    /// the transfer finishes (leader only)
+    snapshot_transfers: BTreeSet<NodeId>,
+
+    /// Nodes that asked to join and will be promoted once caught up (leader
+    /// only)
+    joining: BTreeSet<NodeId>,
+
+    /// `join` callers waiting to see this node become a voter
+    join_waiters: Vec<oneshot::Sender<Result<()>>>,
+
+    /// The `change_configuration` call in progress (leader only)
+    reconfiguration: Option<Reconfiguration>,
+
+    /// The last configuration a no-op was appended to confirm (leader only)
+    confirmed_configuration: Option<ClusterConfig>,
+
+    /// Configuration-confirming no-ops that haven't been applied yet
+    config_noops: Vec<ConfigNoopWaiter>,
+
+    /// When each peer last answered an AppendEntries in our term (leader
+    /// only)
+    append_acks: HashMap<NodeId, Instant>,
+
+    /// When this node last became leader
+    leader_since: Instant,
+
+    /// Whether a leader has reported losing its quorum and not yet heard
+    /// from one again
+    quorum_lost: bool,
+
+    /// When the latest AppendEntries each peer answered in our term was
+    /// sent (leader only)
+    lease_acks: HashMap<NodeId, Instant>,
+
+    /// When a leader found itself outside the applied configuration, and
+    /// the commit index then, while it waits to hand over
+    handover: Option<(Instant, LogIndex)>,
+
+    /// The leader's commit index as of the last AppendEntries we accepted
+    leader_commit: Option<LogIndex>,
+
+    /// `wait_until_caught_up` callers waiting to apply through
+    /// `leader_commit`
+    catch_up_waiters: Vec<oneshot::Sender<()>>,
+
+    /// First entry of the current leadership term, the no-op appended on
+    /// election; reads can't be served until it commits
+    term_start_index: LogIndex,
+
+    /// Confirmed reads waiting for `last_applied` to reach their read index
+    read_waiters: Vec<ReadWaiter>,
+
+    /// Reads waiting for the next leadership confirmation round
+    queued_reads: Vec<oneshot::Sender<Result<LogIndex>>>,
+
+    /// Confirmation rounds sent and not yet finished
+    read_rounds: usize,
+
+    /// Callbacks registered through `RaftNode::on_leadership`
+    leadership_callbacks: Vec<LeadershipCallbacks>,
+
+    /// The term we've told the leadership callbacks we lead, if any
+    leading_term: Option<Term>,
+
+    /// Sender for the node's own command channel, used by spawned RPC tasks
+    /// to feed responses back into the main loop
+    command_tx: mpsc::UnboundedSender<RaftCommand>,
+
+    /// Sender for the node's read queue, used to report finished
+    /// confirmation rounds
+    read_tx: mpsc::UnboundedSender<ReadCommand>,
+}
+
+impl<SM: StateMachine> RaftNodeInner<SM> {
+    fn new(
+        id: NodeId,
+        peers: Vec<NodeId>,
+        config: RaftConfig,
+        state_machine: SM,
+        events: broadcast::Sender<RaftEvent>,
+    ) -> Self {
+        let election_timeout = Arc::new(RandomizedTimeout::from_config(&config));
+        let current_timeout = election_timeout.next_timeout(0, config.election_priority);
+
+        Self {
+            state: Arc::new(RwLock::new(NodeState::new(id, peers))),
+            log: RaftLog::new_memory(),
+            config,
+            state_machine: Arc::new(RwLock::new(AppliedStateMachine {
+                machine: state_machine,
+                last_applied: LogIndex::ZERO,
+            })),
+            last_heartbeat: Instant::now(),
+            election_timeout,
+            current_timeout,
+            events,
+            timeouts_without_leader: 0,
+            last_leader_contact: None,
+            term_gap_reported: None,
+            pre_vote: None,
+            recent_elections: VecDeque::new(),
+            in_election_storm: false,
+            transport: Arc::new(NoopTransport),
+            leader_waiters: Vec::new(),
+            leader_known: false,
+            proposals_accepted: 0,
+            pending_proposals: BTreeMap::new(),
+            abandoned_proposals: 0,
+            applier: None,
+            apply_dispatched: LogIndex::ZERO,
+            incoming_snapshot: None,
+            restore_in_progress: false,
+            snapshot_in_progress: false,
+            snapshot_waiters: Vec::new(),
+            hard_state: HardState {
+                storage: Box::new(MemoryStateStorage::new()),
+                saved: PersistentState::default(),
+                commit_hint: LogIndex::ZERO,
+            },
+            peer_health: HashMap::new(),
+            snapshot_transfers: BTreeSet::new(),
+            joining: BTreeSet::new(),
+            reconfiguration: None,
+            confirmed_configuration: None,
+            config_noops: Vec::new(),
+            append_acks: HashMap::new(),
+            leader_since: Instant::now(),
+            quorum_lost: false,
+            lease_acks: HashMap::new(),
+            handover: None,
+            join_waiters: Vec::new(),
+            leader_commit: None,
+            catch_up_waiters: Vec::new(),
+            term_start_index: LogIndex::ZERO,
+            read_waiters: Vec::new(),
+            queued_reads: Vec::new(),
+            read_rounds: 0,
+            leadership_callbacks: Vec::new(),
+            leading_term: None,
+            command_tx: mpsc::unbounded_channel().0,
+            read_tx: mpsc::unbounded_channel().0,
+        }
+    }
+
+    /// Publish an event to subscribers (dropped if nobody is listening)
+    fn emit(&self, event: RaftEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Record an election timeout and report a suspected partition once the
+    /// configured number of silent election cycles has been reached
+    fn record_election_timeout(&mut self) {
+        self.timeouts_without_leader += 1;
+
+        let threshold = self.config.partition_detection_timeouts;
+        if threshold > 0 && self.timeouts_without_leader == threshold {
+            let state = self.state.read();
+            warn!(
+                "Node {} heard from no leader in {} election timeouts, suspecting partition",
+                state.id, self.timeouts_without_leader
+            );
+            self.emit(RaftEvent::SuspectedPartition {
+                term: state.persistent.current_term,
+                reason: PartitionReason::NoLeaderContact {
+                    election_timeouts: self.timeouts_without_leader,
+                },
+            });
+        }
+    }
+
+    /// Report a suspected partition once a leader has gone an election
+    /// timeout without acknowledgements from a quorum of voters
+    ///
+    /// Reported once each time the quorum is lost.
+    fn check_leader_quorum(&mut self) {
+        if self.config.partition_detection_timeouts == 0 {
+            return;
+        }
+        let window = self.config.election_timeout_max;
+        if self.leader_since.elapsed() < window {
+            return;
+        }
+
+        let state_lock = Arc::clone(&self.state);
+        let state = state_lock.read();
+        if state.role != RaftRole::Leader {
+            return;
+        }
+        let acked = |v: NodeId| {
+            v == state.id
+                || self
+                    .append_acks
+                    .get(&v)
+                    .is_some_and(|at| at.elapsed() < window)
+        };
+        if state.is_quorum(acked) {
+            self.quorum_lost = false;
+            return;
+        }
+        if self.quorum_lost {
+            return;
+        }
+
+        let voters = state.voters();
+        let acknowledged = voters.iter().filter(|&&v| acked(v)).count();
+        self.quorum_lost = true;
+        warn!(
+            "Node {} heard from {} of {} voters in the last election timeout, suspecting partition",
+            state.id,
+            acknowledged,
+            voters.len()
+        );
+        self.emit(RaftEvent::SuspectedPartition {
+            term: state.persistent.current_term,
+            reason: PartitionReason::LeaderLostQuorum {
+                acknowledged,
+                voters: voters.len(),
+            },
+        });
+    }
+
+    /// Forget elections that fell out of the one-minute window
+    fn prune_recent_elections(&mut self) {
+        let now = Instant::now();
+        while self
+            .recent_elections
+            .front()
+            .is_some_and(|started| now.duration_since(*started) >= ELECTION_RATE_WINDOW)
+        {
+            self.recent_elections.pop_front();
+        }
+    }
+
+    /// Whether enough time has passed since our last election to start
+    /// another
+    fn election_allowed(&self) -> bool {
+        match (
+            self.config.min_election_interval,
+            self.recent_elections.back(),
+        ) {
+            (Some(min), Some(last)) => last.elapsed() >= min,
+            _ => true,
+        }
+    }
+
+    /// Count an election we started, reporting a storm if there are too many
+    fn record_election(&mut self, id: NodeId, term: Term) {
+        self.prune_recent_elections();
+        self.recent_elections.push_back(Instant::now());
+
+        let threshold = self.config.election_storm_threshold;
+        if threshold == 0 {
+            return;
+        }
+
+        let elections_per_minute = self.recent_elections.len() as u32;
+        if elections_per_minute < threshold {
+            self.in_election_storm = false;
+        } else if !self.in_election_storm {
+            self.in_election_storm = true;
+            warn!(
+                "Node {} started {} elections in the last minute",
+                id, elections_per_minute
+            );
+            self.emit(RaftEvent::ElectionStorm {
+                term,
+                elections_per_minute,
+            });
+        }
+    }
+
+    /// Check if election timeout has elapsed
+    fn is_election_timeout(&self) -> bool {
+        self.last_heartbeat.elapsed() >= self.current_timeout
+    }
+
+    /// Reset election timeout (called when receiving valid RPC from leader)
+    ///
+    /// Draws the next timeout from the strategy.
+    fn reset_election_timeout(&mut self) {
+        self.last_heartbeat = Instant::now();
+        self.current_timeout = self
+            .election_timeout
+            .next_timeout(self.timeouts_without_leader, self.config.election_priority);
+    }
+
+    /// Whether `ahead` is further past `behind` than `max_term_gap` allows
+    fn exceeds_term_gap(&self, ahead: Term, behind: Term) -> bool {
+        self.config
+            .max_term_gap
+            .is_some_and(|max| ahead.0.saturating_sub(behind.0) > max)
+    }
+
+    /// Make the election timer fire on its next tick
+    fn expire_election_timeout(&mut self) {
+        let now = Instant::now();
+        self.last_heartbeat = now.checked_sub(self.current_timeout).unwrap_or(now);
+    }
+
+    /// Handle a client proposal
+    fn handle_propose(
+        &mut self,
+        command: Vec<u8>,
+        metadata: Option<Vec<u8>>,
+        response: oneshot::Sender<Result<Vec<u8>>>,
+    ) {
+        if let Err(e) = self.check_command(&command, metadata.as_deref()) {
+            let _ = response.send(Err(e));
+            return;
+        }
+
+        let state = self.state.read();
+        if state.role != RaftRole::Leader {
+            // Before any leader has been seen, optionally give the cluster
+            // time to come up instead of failing straight away
+            if let Some(timeout) = self.config.initial_leader_timeout {
+                if !self.leader_known && state.leader_id.is_none() {
+                    drop(state);
+                    self.leader_waiters.push(LeaderWaiter {
+                        command,
+                        metadata,
+                        response,
+                        deadline: Instant::now() + timeout,
+                    });
+                    return;
+                }
+            }
+
+            if let (true, Some(leader)) = (self.config.forward_proposals, state.leader_id) {
+                drop(state);
+                self.forward_proposal(leader, command, metadata, response);
+                return;
+            }
+
+            let _ = response.send(Err(RaftError::NotLeader(state.not_leader_info())));
+            return;
+        }
+
+        drop(state);
+        self.propose_locally(command, metadata, response);
+    }
+
+    /// Append a proposal to our own log, failing it if we aren't leader
+    fn propose_locally(
+        &mut self,
+        command: Vec<u8>,
+        metadata: Option<Vec<u8>>,
+        response: oneshot::Sender<Result<Vec<u8>>>,
+    ) {
+        let state = self.state.read();
+        if state.role != RaftRole::Leader {
+            let _ = response.send(Err(RaftError::NotLeader(state.not_leader_info())));
+            return;
+        }
+        drop(state);
+
+        match self.append_command(command, metadata) {
+            Ok(index) => {
+                self.pending_proposals.insert(index, response);
+
+                // A leader that is the only voter commits on its own
+                self.maybe_advance_commit();
+            }
+            Err(e) => {
+                let _ = response.send(Err(e));
+            }
+        }
+    }
+
+    /// Send a proposal to `leader` from a spawned task and relay the outcome
+    fn forward_proposal(
+        &self,
+        leader: NodeId,
+        command: Vec<u8>,
+        metadata: Option<Vec<u8>>,
+        response: oneshot::Sender<Result<Vec<u8>>>,
+    ) {
+        let from = self.state.read().id;
+        debug!("Node {} forwarding proposal to {}", from, leader);
+
+        let transport = Arc::clone(&self.transport);
+        tokio::spawn(async move {
+            let request = ForwardRequest {
+                from,
+                command,
+                metadata,
+            };
+            let result = match transport.send_forward(leader, request).await {
+                Ok(ForwardResponse::Applied(output)) => Ok(output),
+                Ok(ForwardResponse::NotLeader(hint)) => Err(RaftError::not_leader(hint)),
+                Ok(ForwardResponse::Failed(reason)) => Err(RaftError::Rpc(format!(
+                    "leader {} failed forwarded proposal: {}",
+                    leader, reason
+                ))),
+                Err(e) => Err(e),
+            };
+            let _ = response.send(result);
+        });
+    }
+
+    /// Hand a proposal's caller the state machine's output once its entry
+    /// has been applied
+    fn resolve_proposal(&mut self, index: LogIndex, output: Vec<u8>) {
+        if let Some(waiter) = self.pending_proposals.remove(&index) {
+            let _ = waiter.send(Ok(output));
+        }
+    }
+
+    /// Fail every proposal still waiting to commit once this node is no
+    /// longer leader
+    ///
+    /// Their entries are left in the log. Whether they survive is up to the
+    /// next leader: its AppendEntries either confirm them or overwrite them.
+    /// Either way the caller is told now rather than after an unbounded wait.
+    fn abandon_proposals(&mut self) {
+        if self.pending_proposals.is_empty() {
+            return;
+        }
+        let state = self.state.read();
+        if state.role == RaftRole::Leader {
+            return;
+        }
+        let leader_hint = state.not_leader_info();
+        let id = state.id;
+        drop(state);
+
+        let abandoned = std::mem::take(&mut self.pending_proposals);
+        warn!(
+            "Node {} stepped down with {} proposals uncommitted",
+            id,
+            abandoned.len()
+        );
+        self.abandoned_proposals += abandoned.len() as u64;
+        for (_, waiter) in abandoned {
+            let _ = waiter.send(Err(RaftError::NotLeader(leader_hint.clone())));
+        }
+    }
+
+    /// Append a client command to the leader's log, returning its index
+    ///
+    /// Leadership is checked and the entry appended under one hold of the
+    /// state lock, so a step-down on another task can't slip in between and
+    /// leave an entry from a term we no longer lead.
+    fn append_command(&mut self, command: Vec<u8>, metadata: Option<Vec<u8>>) -> Result<LogIndex> {
+        self.check_command(&command, metadata.as_deref())?;
+
+        let index = self.append_as_leader(|term, index| {
+            vec![Entry {
+                metadata,
+                ..Entry::new(term, index, command)
+            }]
+        })?;
+
+        self.proposals_accepted += 1;
+        Ok(index)
+    }
+
+    /// Append the entries `build` makes for our term to the leader's own
+    /// log, returning the index of the first
+    ///
+    /// `build` is given the term and the index of the first entry. The state
+    /// lock is held from the leadership check until the entries are in the
+    /// log. If storage fails and the config asks for it, we step down once
+    /// the lock has been released.
+    ///
+    /// Configuration changes go through `append_sync`, so they're durable
+    /// however the backend batches its syncs.
+    fn append_as_leader(
+        &mut self,
+        build: impl FnOnce(Term, LogIndex) -> Vec<Entry>,
+    ) -> Result<LogIndex> {
+        let state_lock = Arc::clone(&self.state);
+        let state = state_lock.read();
+        if state.role != RaftRole::Leader {
+            return Err(RaftError::NotLeader(state.not_leader_info()));
+        }
+        let term = state.persistent.current_term;
+        let index = self.log.last_index() + 1;
+        let entries = build(term, index);
+        let result = if entries.iter().any(|e| e.kind == EntryKind::ConfigChange) {
+            self.log.append_sync(entries)
+        } else {
+            self.log.append(entries)
+        };
+        drop(state);
+
+        let Err(e) = result else {
+            return Ok(index);
+        };
+        if !matches!(e, RaftError::Storage(_)) || !self.config.step_down_on_append_failure {
+            return Err(e);
+        }
+
+        let mut state = state_lock.write();
+        if state.role == RaftRole::Leader && state.persistent.current_term == term {
+            let term = state.persistent.current_term;
+            error!(
+                "Node {} stepping down after failing to append to its log: {}",
+                state.id, e
+            );
+            state.become_follower(term, None);
+            drop(state);
+            self.emit(RaftEvent::StorageFailure {
+                term,
+                error: e.to_string(),
+            });
+        }
+        Err(e)
+    }
+
+    /// Refuse commands the config doesn't allow to be proposed
+    fn check_command(&self, command: &[u8], metadata: Option<&[u8]>) -> Result<()> {
+        if command.is_empty() && !self.config.allow_empty_commands {
+            return Err(RaftError::EmptyCommand);
+        }
+        let len = command.len() + metadata.map_or(0, <[u8]>::len);
+        match self.transport.max_message_size() {
+            Some(max) if len > max => Err(RaftError::InvalidEntry(format!(
+                "proposal of {} bytes is over the transport's {} byte limit",
+                len, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn metrics(&self) -> RaftMetrics {
+        let state = self.state.read();
+        RaftMetrics {
+            id: state.id,
+            role: state.role,
+            current_term: state.persistent.current_term,
+            current_leader: state.leader_id,
+            last_log_index: self.log.last_index(),
+            commit_index: state.volatile.commit_index,
+            last_applied: state.volatile.last_applied,
+            snapshot_index: self
+                .log
+                .get_snapshot()
+                .map(|s| s.metadata.last_included_index),
+            proposals_accepted: self.proposals_accepted,
+            abandoned_proposals: self.abandoned_proposals,
+            elections_per_minute: self
+                .recent_elections
+                .iter()
+                .filter(|started| started.elapsed() < ELECTION_RATE_WINDOW)
+                .count() as u32,
+        }
+    }
+
+    /// Hand parked proposals on once a leader is known
+    fn release_leader_waiters(&mut self) {
+        if self.state.read().leader_id.is_none() {
+            return;
+        }
+        self.leader_known = true;
+
+        for waiter in std::mem::take(&mut self.leader_waiters) {
+            self.handle_propose(waiter.command, waiter.metadata, waiter.response);
+        }
+    }
+
+    /// Forget proposals whose callers have stopped waiting
+    fn drop_cancelled_proposals(&mut self) {
+        let before = self.leader_waiters.len() + self.pending_proposals.len();
+        self.leader_waiters.retain(|w| !w.response.is_closed());
+        self.pending_proposals.retain(|_, w| !w.is_closed());
+
+        let dropped = before - self.leader_waiters.len() - self.pending_proposals.len();
+        if dropped > 0 {
+            debug!("Dropped {} cancelled proposals", dropped);
+        }
+    }
+
+    /// Fail parked proposals whose wait for a first leader has run out
+    fn expire_leader_waiters(&mut self) {
+        let now = Instant::now();
+        let (expired, waiting) = std::mem::take(&mut self.leader_waiters)
+            .into_iter()
+            .partition(|w| w.deadline <= now);
+        self.leader_waiters = waiting;
+
+        for waiter in expired {
+            let _ = waiter.response.send(Err(RaftError::not_leader(None)));
+        }
+    }
+
+    /// Ask the other voters whether we could win an election
+    ///
+    /// The real election starts once a majority would vote for us; until
+    /// then our term and vote are left alone.
+    fn start_pre_vote(&mut self) {
+        if !self.election_allowed() {
+            debug!(
+                "Node {} holding off pre-vote, last election was too recent",
+                self.state.read().id
+            );
+            return;
+        }
+
+        let state = self.state.read();
+        let mut term = state.persistent.current_term;
+        term.increment();
+        let candidate = CandidateState::new();
+        if candidate.has_majority(&state) {
+            drop(state);
+            self.start_election();
+            return;
+        }
+
+        debug!("Node {} starting pre-vote for {}", state.id, term);
+        let last = self.log.last_position();
+        let request = RequestVoteRequest {
+            term,
+            candidate_id: state.id,
+            last_log_index: last.index,
+            last_log_term: last.term,
+            pre_vote: true,
+        };
+        let peers = state.other_peers();
+        drop(state);
+
+        self.reset_election_timeout();
+        self.pre_vote = Some((term, candidate));
+        self.request_votes(peers, request);
+    }
+
+    /// Count an answer to our pre-vote, starting the election once a
+    /// majority would vote for us
+    fn handle_pre_vote_response(&mut self, from: NodeId, resp: RequestVoteResponse) {
+        let state_lock = Arc::clone(&self.state);
+        let mut state = state_lock.write();
+
+        if resp.term > state.persistent.current_term && !resp.vote_granted {
+            self.pre_vote = None;
+            state.become_follower(resp.term, None);
+            let _ = self.hard_state.persist(&mut state);
+            return;
+        }
+
+        let mut next_term = state.persistent.current_term;
+        next_term.increment();
+        let Some((term, candidate)) = self.pre_vote.as_mut() else {
+            return;
+        };
+        if *term != next_term
+            || state.role == RaftRole::Leader
+            || !resp.vote_granted
+            || !state.is_voting(from)
+        {
+            return;
+        }
+
+        candidate.add_vote(from);
+        if candidate.has_majority(&state) {
+            debug!("Node {} won the pre-vote for {}", state.id, next_term);
+            self.pre_vote = None;
+            drop(state);
+            self.start_election();
+        }
+    }
+
+    /// Start an election
+    fn start_election(&mut self) {
+        if !self.election_allowed() {
+            debug!(
+                "Node {} holding off election, last one was too recent",
+                self.state.read().id
+            );
+            return;
+        }
+
+        self.pre_vote = None;
+        let state_lock = Arc::clone(&self.state);
+        let mut state = state_lock.write();
+        state.become_candidate();
+
+        // The new term and our vote for ourselves must be durable before
+        // anyone is asked for a vote
+        if self.hard_state.persist(&mut state).is_err() {
+            let term = state.persistent.current_term;
+            state.become_follower(term, None);
+            return;
+        }
+        self.record_election(state.id, state.persistent.current_term);
+
+        info!(
+            "Node {} starting election for term {}",
+            state.id, state.persistent.current_term
+        );
+
+        self.reset_election_timeout();
+
+        // A single-node cluster wins with its own vote
+        if state
+            .candidate_state
+            .as_ref()
+            .is_some_and(|c| c.has_majority(&state))
+        {
+            drop(state);
+            self.become_leader();
+            return;
+        }
+
+        // Send RequestVote RPCs to all peers
+        let last = self.log.last_position();
+        let request = RequestVoteRequest {
+            term: state.persistent.current_term,
+            candidate_id: state.id,
+            last_log_index: last.index,
+            last_log_term: last.term,
+            pre_vote: false,
+        };
+        let peers = state.other_peers();
+        drop(state);
+
+        self.request_votes(peers, request);
+    }
+
+    /// Fan RequestVote out to `peers` without blocking the main loop
+    ///
+    /// Responses are fed back as [`RaftCommand::VoteResponse`] as they arrive,
+    /// so the election can be won before slow peers answer.
+    fn request_votes(&self, peers: Vec<NodeId>, request: RequestVoteRequest) {
+        let transport = Arc::clone(&self.transport);
+        let command_tx = self.command_tx.clone();
+        let pre_vote = request.pre_vote;
+
+        tokio::spawn(async move {
+            let mut responses = transport.broadcast_request_vote(peers, request);
+            while let Some((from, result)) = responses.next().await {
+                match result {
+                    Ok(response) => {
+                        if command_tx
+                            .send(RaftCommand::VoteResponse {
+                                from,
+                                pre_vote,
+                                response,
+                            })
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(e) => debug!("RequestVote to {} failed: {}", from, e),
+                }
+            }
+        });
+    }
+
+    /// Count a vote from an election we started
+    fn handle_vote_response(&mut self, from: NodeId, resp: RequestVoteResponse) {
+        let state_lock = Arc::clone(&self.state);
+        let mut state = state_lock.write();
+
+        if resp.term > state.persistent.current_term {
+            state.become_follower(resp.term, None);
+            let _ = self.hard_state.persist(&mut state);
+            return;
+        }
+
+        // Only count votes for the election we're currently running. Votes
+        // can arrive after it's over: stragglers once we've won, or answers
+        // from an earlier term's election. Neither may change anything.
+        if state.role != RaftRole::Candidate || resp.term != state.persistent.current_term {
+            debug!(
+                "Node {} ignoring late vote from {} for {} (now {} in {})",
+                state.id, from, resp.term, state.role, state.persistent.current_term
+            );
+            return;
+        }
+
+        // Only members that actually vote count toward the majority
+        if !resp.vote_granted || !state.is_voting(from) {
+            return;
+        }
+
+        if let Some(candidate) = state.candidate_state.as_mut() {
+            candidate.add_vote(from);
+        }
+        let won = state
+            .candidate_state
+            .as_ref()
+            .is_some_and(|c| c.has_majority(&state));
+
+        if won {
+            drop(state);
+            self.become_leader();
+        }
+    }
+
+    /// Take over as leader for the current term
+    ///
+    /// Appends a no-op at the new term so entries left over from earlier
+    /// terms can be committed without waiting for a client proposal.
+    fn become_leader(&mut self) {
+        let mut state = self.state.write();
+        state.become_leader(self.log.last_index());
+        self.timeouts_without_leader = 0;
+        self.lease_acks.clear();
+        self.leader_since = Instant::now();
+        self.quorum_lost = false;
+
+        let term = state.persistent.current_term;
+        info!("Node {} became leader for {}", state.id, term);
+
+        let id = state.id;
+        drop(state);
+        match self.append_as_leader(|term, index| vec![Entry::noop(term, index)]) {
+            Ok(index) => self.term_start_index = index,
+            Err(e) => {
+                // Without its no-op the term has nothing to commit earlier
+                // entries or confirm reads with, so leave it to another leader
+                warn!(
+                    "Node {} stepping down, failed to append leader no-op: {}",
+                    id, e
+                );
+                let mut state = self.state.write();
+                if state.role == RaftRole::Leader && state.persistent.current_term == term {
+                    state.become_follower(term, None);
+                }
+                return;
+            }
+        }
+
+        // The no-op follows every configuration in the log, so it confirms
+        // whichever ends up in effect
+        self.config_noops.push(ConfigNoopWaiter {
+            term,
+            index: self.term_start_index,
+            response: None,
+        });
+
+        // A configuration appended under an earlier leader but not applied
+        // yet already decides who we replicate to
+        match self.latest_configuration() {
+            Ok(config) => {
+                self.track_members(&config);
+                self.confirmed_configuration = Some(config);
+            }
+            Err(e) => warn!("Failed to read latest configuration: {}", e),
+        }
+
+        self.emit(RaftEvent::BecameLeader { term });
+    }
+
+    /// Send every member the entries it's missing, or an empty heartbeat if
+    /// it has them all
+    ///
+    /// Responses come back as [`RaftCommand::AppendResponse`].
+    fn replicate(&mut self) {
+        let peers: Vec<NodeId> = match &self.state.read().leader_state {
+            Some(leader) => leader.next_index.iter().map(|&(peer, _)| peer).collect(),
+            None => return,
+        };
+
+        for peer in peers {
+            self.replicate_to(peer);
+        }
+    }
+
+    /// Build the AppendEntries for `peer` starting at its `next_index`,
+    /// carrying at most `max_entries` entries
+    ///
+    /// Returns `None` when not leader or when the entry before `next_index`
+    /// is no longer in the log.
+    fn append_request(
+        &self,
+        state: &NodeState,
+        peer: NodeId,
+        max_entries: usize,
+    ) -> Option<AppendEntriesRequest> {
+        let next_index = state.leader_state.as_ref()?.get_next_index(peer)?;
+
+        let prev_log_index = LogIndex(next_index.0.saturating_sub(1));
+        let prev_log_term = if prev_log_index == LogIndex::ZERO {
+            Term(0)
+        } else {
+            match self.log.get_term(prev_log_index) {
+                Ok(Some(term)) => term,
+                _ => {
+                    debug!(
+                        "Node {} can't replicate to {}: entry {} is no longer in the log",
+                        state.id, peer, prev_log_index
+                    );
+                    return None;
+                }
+            }
+        };
+
+        // Empty for a peer that's caught up, making this a heartbeat
+        let end = (self.log.last_index() + 1).min(next_index + max_entries as u64);
+        let mut entries = match self.log.get_range(next_index, end) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(
+                    "Node {} failed to read entries for {}: {}",
+                    state.id, peer, e
+                );
+                return None;
+            }
+        };
+
+        // Always send at least one entry, so one bigger than the limit
+        // can't stall replication
+        let limit = self.message_limit();
+        let mut bytes = 0;
+        let fits = entries
+            .iter()
+            .take_while(|entry| {
+                bytes += entry.payload_len();
+                bytes <= limit
+            })
+            .count();
+        entries.truncate(fits.max(1));
+
+        Some(AppendEntriesRequest {
+            term: state.persistent.current_term,
+            leader_id: state.id,
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit: state.volatile.commit_index,
+        })
+    }
+
+    /// Most entry or snapshot bytes to put in one request:
+    /// `max_append_bytes`, or the transport's limit if that's smaller
+    fn message_limit(&self) -> usize {
+        let limit = self.config.max_append_bytes;
+        self.transport
+            .max_message_size()
+            .map_or(limit, |max| limit.min(max))
+            .max(1)
+    }
+
+    /// Send `peer` one AppendEntries starting at its `next_index`, or the
+    /// snapshot if the entries it needs have been compacted away
+    fn replicate_to(&mut self, peer: NodeId) {
+        if self.snapshot_transfers.contains(&peer) {
+            return;
+        }
+
+        let state = self.state.read();
+        let next_index = state
+            .leader_state
+            .as_ref()
+            .and_then(|leader| leader.get_next_index(peer));
+        if let (Some(next_index), Some(snapshot)) = (next_index, self.log.get_snapshot()) {
+            if next_index <= snapshot.metadata.last_included_index {
+                let term = state.persistent.current_term;
+                let id = state.id;
+                drop(state);
+                self.send_snapshot(peer, term, id, snapshot);
+                return;
+            }
+        }
+
+        let Some(request) = self.append_request(&state, peer, self.config.max_append_entries)
+        else {
+            return;
+        };
+        drop(state);
+
+        let sent_through = request
+            .entries
+            .last()
+            .map(|e| e.index)
+            .unwrap_or(request.prev_log_index);
+        let transport = Arc::clone(&self.transport);
+        let command_tx = self.command_tx.clone();
+        let sent_at = Instant::now();
+        tokio::spawn(async move {
+            match transport.send_append_entries(peer, request).await {
+                Ok(response) => {
+                    let _ = command_tx.send(RaftCommand::AppendResponse {
+                        from: peer,
+                        sent_at,
+                        sent_through,
+                        response,
+                    });
+                }
+                Err(e) => debug!("AppendEntries to {} failed: {}", peer, e),
+            }
+        });
+    }
+
+    /// Send `snapshot` to `peer` in chunks of at most the message limit,
+    /// starting over after a backoff if a transfer fails
+    fn send_snapshot(&mut self, peer: NodeId, term: Term, leader_id: NodeId, snapshot: Snapshot) {
+        info!(
+            "Node {} sending snapshot through {} to {}",
+            leader_id, snapshot.metadata.last_included_index, peer
+        );
+        self.snapshot_transfers.insert(peer);
+
+        let transport = Arc::clone(&self.transport);
+        let command_tx = self.command_tx.clone();
+        let chunk_size = self.message_limit();
+        let retries = self.config.snapshot_transfer_retries;
+        let mut backoff = self.config.heartbeat_interval;
+        let last_included_index = snapshot.metadata.last_included_index;
+
+        tokio::spawn(async move {
+            let transfer = || async {
+                let mut offset = 0;
+                loop {
+                    let end = (offset + chunk_size).min(snapshot.data.len());
+                    let request = InstallSnapshotRequest {
+                        term,
+                        leader_id,
+                        last_included_index,
+                        last_included_term: snapshot.metadata.last_included_term,
+                        offset: offset as u64,
+                        data: snapshot.data[offset..end].to_vec(),
+                        done: end == snapshot.data.len(),
+                        configuration: (end == snapshot.data.len())
+                            .then(|| snapshot.metadata.configuration.clone()),
+                    };
+                    let response = transport.send_install_snapshot(peer, request).await?;
+                    if response.term != term || end == snapshot.data.len() {
+                        return Ok(response);
+                    }
+                    offset = end;
+                }
+            };
+
+            let mut attempts = 1;
+            let mut result = transfer().await;
+            while result.is_err() && attempts <= retries {
+                if let Err(e) = &result {
+                    debug!("Snapshot transfer to {} failed, retrying: {}", peer, e);
+                }
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempts += 1;
+                result = transfer().await;
+            }
+
+            let _ = command_tx.send(RaftCommand::SnapshotSent {
+                peer,
+                last_included_index,
+                attempts,
+                result,
+            });
+        });
+    }
+
+    /// Record the outcome of a snapshot transfer to `peer`
+    fn handle_snapshot_sent(
+        &mut self,
+        peer: NodeId,
+        last_included_index: LogIndex,
+        attempts: u32,
+        result: Result<InstallSnapshotResponse>,
+    ) {
+        self.snapshot_transfers.remove(&peer);
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                warn!(
+                    "Gave up sending snapshot to {} after {} attempts: {}",
+                    peer, attempts, e
+                );
+                self.emit(RaftEvent::SnapshotTransferFailed { peer, attempts });
+                return;
+            }
+        };
+
+        let state_lock = Arc::clone(&self.state);
+        let mut state = state_lock.write();
+        if response.term > state.persistent.current_term {
+            state.become_follower(response.term, None);
+            let _ = self.hard_state.persist(&mut state);
+            return;
+        }
+        if state.role != RaftRole::Leader || response.term != state.persistent.current_term {
+            return;
+        }
+
+        // An answer doesn't mean the snapshot was installed: a follower
+        // still restoring an earlier one drops it. The AppendEntries after
+        // it confirms how far the follower got.
+        if let Some(leader) = state.leader_state.as_mut() {
+            leader.set_next_index(peer, last_included_index + 1);
+        }
+        drop(state);
+
+        self.replicate_to(peer);
+    }
+
+    /// Record a follower's answer to AppendEntries
+    fn handle_append_response(
+        &mut self,
+        from: NodeId,
+        sent_at: Instant,
+        sent_through: LogIndex,
+        resp: AppendEntriesResponse,
+    ) {
+        let state_lock = Arc::clone(&self.state);
+        let mut state = state_lock.write();
+
+        if resp.term > state.persistent.current_term {
+            info!(
+                "Node {} saw term {} from {}, stepping down",
+                state.id, resp.term, from
+            );
+            state.become_follower(resp.term, None);
+            let _ = self.hard_state.persist(&mut state);
+
+            // Give the node that moved the term on the first shot at leading
+            // it, rather than racing it with an election of our own
+            self.reset_election_timeout();
+            return;
+        }
+
+        if state.role != RaftRole::Leader || resp.term != state.persistent.current_term {
+            return;
+        }
+        let Some(leader) = state.leader_state.as_mut() else {
+            return;
+        };
+        leader.set_applied_index(from, resp.last_applied);
+        self.append_acks.insert(from, Instant::now());
+        let acked = self.lease_acks.entry(from).or_insert(sent_at);
+        *acked = (*acked).max(sent_at);
+
+        if resp.success {
+            leader.set_match_index(from, sent_through);
+            leader.set_next_index(from, sent_through + 1);
+            drop(state);
+
+            self.maybe_advance_commit();
+            self.maybe_promote_joining();
+        } else {
+            // Try again straight away rather than waiting a heartbeat per
+            // step. A follower that reports where its conflicting term
+            // starts lets us skip that whole term, one that only reports
+            // its last index lets us skip past everything it doesn't have;
+            // otherwise back up one entry.
+            let Some(next_index) = leader.get_next_index(from) else {
+                return;
+            };
+            let mut retry = LogIndex(next_index.0.saturating_sub(1));
+            if let Some(conflict_index) = resp.conflict_index {
+                let resume = resp
+                    .conflict_term
+                    .and_then(|term| self.last_index_of_term(term, retry))
+                    .map_or(conflict_index, |last| last + 1);
+                retry = retry.min(resume);
+            } else if let Some(last_index) = resp.match_index {
+                retry = retry.min(last_index + 1);
+            }
+            leader.set_next_index(from, retry);
+            drop(state);
+
+            self.replicate_to(from);
+        }
+    }
+
+    /// First index of the run of `term` entries ending at `index` in our log
+    ///
+    /// Stops at the start of the log or the snapshot, whichever comes first.
+    fn first_index_of_term(&self, term: Term, index: LogIndex) -> LogIndex {
+        let mut first = index;
+        while first > LogIndex(1)
+            && matches!(self.log.get_term(first - 1), Ok(Some(t)) if t == term)
+        {
+            first = first - 1;
+        }
+        first
+    }
+
+    /// Last index at or below `index` holding an entry of `term`, if our log
+    /// has any
+    fn last_index_of_term(&self, term: Term, index: LogIndex) -> Option<LogIndex> {
+        let mut at = index.min(self.log.last_index());
+        while at > LogIndex::ZERO {
+            match self.log.get_term(at) {
+                Ok(Some(t)) if t == term => return Some(at),
+                Ok(Some(t)) if t > term => at = at - 1,
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    /// Commit everything a quorum of voters has replicated
+    ///
+    /// Only an entry from the current term is committed by counting
+    /// replicas; earlier entries are committed along with it.
+    fn maybe_advance_commit(&mut self) {
+        let last_index = self.log.last_index();
+        let mut state = self.state.write();
+        let Some(quorum_index) = state.quorum_match_index(last_index) else {
+            return;
+        };
+        if quorum_index <= state.volatile.commit_index {
+            return;
+        }
+
+        // Entries from earlier terms are never committed by counting
+        // replicas (Figure 8 in the Raft paper); they commit once an entry
+        // of our own term covers them. The no-op appended in
+        // `become_leader` is the first such entry, so nothing moves until
+        // it has reached a quorum.
+        if quorum_index < self.term_start_index {
+            return;
+        }
+        let current_term = state.persistent.current_term;
+        if !matches!(self.log.get_term(quorum_index), Ok(Some(term)) if term == current_term) {
+            return;
+        }
+
+        state.volatile.commit_index = quorum_index;
+        debug!("Node {} committed through {}", state.id, quorum_index);
+        drop(state);
+
+        self.apply_committed();
+    }
+
+    /// Start the ReadIndex handshake for a read
+    ///
+    /// The read joins the queue for the next confirmation round, which
+    /// starts now unless one is already in flight and the queue is still
+    /// shorter than `read_batch_size`. A read can't share a round that was
+    /// sent before it arrived: those acks may predate the read.
+    fn handle_read_index(&mut self, response: oneshot::Sender<Result<LogIndex>>) {
+        let state = self.state.read();
+        if state.role != RaftRole::Leader {
+            let _ = response.send(Err(RaftError::NotLeader(state.not_leader_info())));
+            return;
+        }
+        drop(state);
+
+        self.queued_reads.push(response);
+        if self.read_rounds == 0 || self.queued_reads.len() >= self.config.read_batch_size {
+            self.start_read_round();
+        }
+    }
+
+    /// Confirm leadership with a quorum on behalf of every queued read
+    ///
+    /// The read index is the commit index, or the leader's own no-op if that
+    /// hasn't committed yet, since until then the commit index may trail
+    /// entries earlier leaders committed.
+    fn start_read_round(&mut self) {
+        let responses = std::mem::take(&mut self.queued_reads);
+        let state = self.state.read();
+        if state.role != RaftRole::Leader {
+            for response in responses {
+                let _ = response.send(Err(RaftError::NotLeader(state.not_leader_info())));
+            }
+            return;
+        }
+        let term = state.persistent.current_term;
+        let read_index = state.volatile.commit_index.max(self.term_start_index);
+        let id = state.id;
+        let voters = state.peers.clone();
+        let outgoing_voters = state.outgoing_voters.clone();
+        let heartbeats: Vec<(NodeId, AppendEntriesRequest)> = state
+            .other_peers()
+            .into_iter()
+            .filter_map(|peer| Some((peer, self.append_request(&state, peer, 0)?)))
+            .collect();
+        drop(state);
+
+        if joint_quorum_value(&voters, &outgoing_voters, |node| node == id) == Some(true) {
+            self.read_waiters
+                .extend(responses.into_iter().map(|response| ReadWaiter {
+                    read_index,
+                    response,
+                }));
+            return;
+        }
+
+        self.read_rounds += 1;
+        let transport = Arc::clone(&self.transport);
+        let read_tx = self.read_tx.clone();
+        let timeout = self.config.election_timeout_max;
+        tokio::spawn(async move {
+            let confirm = async {
+                let mut acks = HashSet::from([id]);
+                let mut answers: futures::stream::FuturesUnordered<_> = heartbeats
+                    .into_iter()
+                    .map(|(peer, request)| {
+                        let answer = transport.send_append_entries(peer, request);
+                        async move { (peer, answer.await) }
+                    })
+                    .collect();
+
+                // A follower answering in our term accepts us as leader,
+                // whether or not its log matched the heartbeat
+                while let Some((peer, answer)) = answers.next().await {
+                    match answer {
+                        Ok(reply) if reply.term == term => {
+                            acks.insert(peer);
+                            let acked = |node| acks.contains(&node);
+                            if joint_quorum_value(&voters, &outgoing_voters, acked) == Some(true) {
+                                return true;
+                            }
+                        }
+                        Ok(reply) if reply.term > term => return false,
+                        _ => {}
+                    }
+                }
+                false
+            };
+
+            let confirmed = tokio::time::timeout(timeout, confirm).await == Ok(true);
+            let _ = read_tx.send(ReadCommand::RoundFinished {
+                term,
+                read_index,
+                confirmed,
+                responses,
+            });
+        });
+    }
+
+    /// When the leader's lease runs out, if it holds one
+    ///
+    /// Once a quorum of voters has answered AppendEntries sent at or after
+    /// some instant, none of them votes for another candidate until
+    /// `election_timeout_min` after it, so no other leader can be elected
+    /// before then. The lease ends that long after the quorum's oldest send
+    /// time, less `max_clock_drift` for followers whose clocks run fast.
+    fn lease_expiry(&self) -> Option<Instant> {
+        let state = self.state.read();
+        if state.role != RaftRole::Leader {
+            return None;
+        }
+        let now = Instant::now();
+        let acked = state.quorum_value(|node| {
+            if node == state.id {
+                Some(now)
+            } else {
+                self.lease_acks.get(&node).copied()
+            }
+        })?;
+
+        let lease = self
+            .config
+            .election_timeout_min
+            .checked_sub(self.config.max_clock_drift)?;
+        Some(acked? + lease)
+    }
+
+    /// Serve a read at the read index without a confirmation round, if the
+    /// lease still holds
+    fn handle_lease_read(&mut self, response: oneshot::Sender<Result<LogIndex>>) {
+        let state = self.state.read();
+        if state.role != RaftRole::Leader {
+            let _ = response.send(Err(RaftError::NotLeader(state.not_leader_info())));
+            return;
+        }
+        let read_index = state.volatile.commit_index.max(self.term_start_index);
+        drop(state);
+
+        if self
+            .lease_expiry()
+            .is_none_or(|expiry| Instant::now() >= expiry)
+        {
+            let _ = response.send(Err(RaftError::LeaseExpired));
+            return;
+        }
+        self.read_waiters.push(ReadWaiter {
+            read_index,
+            response,
+        });
+    }
+
+    fn handle_read_command(&mut self, command: ReadCommand) {
+        match command {
+            ReadCommand::Start { response } => self.handle_read_index(response),
+            ReadCommand::Lease { response } => self.handle_lease_read(response),
+            ReadCommand::RoundFinished {
+                term,
+                read_index,
+                confirmed,
+                responses,
+            } => self.finish_read_round(term, read_index, confirmed, responses),
+        }
+    }
+
+    /// Park the reads of a finished round until they can be served, and
+    /// start the next round for any that queued behind it
+    fn finish_read_round(
+        &mut self,
+        term: Term,
+        read_index: LogIndex,
+        confirmed: bool,
+        responses: Vec<oneshot::Sender<Result<LogIndex>>>,
+    ) {
+        self.read_rounds = self.read_rounds.saturating_sub(1);
+
+        let state = self.state.read();
+        if !confirmed {
+            for response in responses {
+                let _ = response.send(Err(RaftError::Timeout));
+            }
+        } else if state.role != RaftRole::Leader || state.persistent.current_term != term {
+            for response in responses {
+                let _ = response.send(Err(RaftError::NotLeader(state.not_leader_info())));
+            }
+        } else {
+            self.read_waiters
+                .extend(responses.into_iter().map(|response| ReadWaiter {
+                    read_index,
+                    response,
+                }));
+        }
+        drop(state);
+
+        if !self.queued_reads.is_empty() {
+            self.start_read_round();
+        }
+    }
+
+    fn add_leadership_callbacks(&mut self, callbacks: LeadershipCallbacks) {
+        if let Some(term) = self.leading_term {
+            (callbacks.on_acquire)(term);
+        }
+        self.leadership_callbacks.push(callbacks);
+    }
+
+    /// Tell the leadership callbacks if we've become or stopped being leader
+    fn notify_leadership(&mut self) {
+        let state = self.state.read();
+        let leading = (state.role == RaftRole::Leader).then_some(state.persistent.current_term);
+        drop(state);
+        if leading == self.leading_term {
+            return;
+        }
+
+        self.lose_leadership();
+        if let Some(term) = leading {
+            for callbacks in &self.leadership_callbacks {
+                (callbacks.on_acquire)(term);
+            }
+        }
+        self.leading_term = leading;
+    }
+
+    /// Run `on_lose` for the term we've been leading, if any
+    fn lose_leadership(&mut self) {
+        if let Some(term) = self.leading_term.take() {
+            for callbacks in &self.leadership_callbacks {
+                (callbacks.on_lose)(term);
+            }
+        }
+    }
+
+    /// Answer reads the state machine has caught up with, and fail them all
+    /// if we've stopped being leader
+    fn release_read_waiters(&mut self) {
+        if self.read_waiters.is_empty() && self.queued_reads.is_empty() {
+            return;
+        }
+
+        let state = self.state.read();
+        if state.role != RaftRole::Leader {
+            let queued = self.queued_reads.drain(..);
+            let waiting = self.read_waiters.drain(..).map(|waiter| waiter.response);
+            for response in queued.chain(waiting) {
+                let _ = response.send(Err(RaftError::NotLeader(state.not_leader_info())));
+            }
+            return;
+        }
+
+        let last_applied = state.volatile.last_applied;
+        drop(state);
+        let (ready, waiting) = std::mem::take(&mut self.read_waiters)
+            .into_iter()
+            .partition(|w| w.read_index <= last_applied);
+        self.read_waiters = waiting;
+
+        for waiter in ready {
+            let _ = waiter.response.send(Ok(waiter.read_index));
+        }
+    }
+
+    /// Answer a node asking to join
+    fn handle_join(&mut self, req: JoinRequest) -> JoinResponse {
+        let state = self.state.read();
+        if state.role != RaftRole::Leader {
+            return JoinResponse {
+                accepted: false,
+                leader_hint: state.leader_id,
+            };
+        }
+        let id = state.id;
+        let applied = state.configuration();
+        drop(state);
+
+        let node = req.node;
+        if applied.is_voter(node) {
+            self.joining.remove(&node);
+            return JoinResponse {
+                accepted: true,
+                leader_hint: Some(id),
+            };
+        }
+
+        if self.joining.insert(node) {
+            info!("Node {} adding {} to the cluster", id, node);
+        }
+        match self.latest_configuration() {
+            Ok(latest) if !latest.is_voter(node) && !latest.is_learner(node) => {
+                if let Err(e) = self.handle_change_membership(MembershipChange::AddLearner(node)) {
+                    debug!("Node {} can't add {} as a learner yet: {}", id, node, e);
+                }
+            }
+            Ok(_) => self.maybe_promote_joining(),
+            Err(e) => warn!("Failed to read latest configuration: {}", e),
+        }
+
+        JoinResponse {
+            accepted: false,
+            leader_hint: Some(id),
+        }
+    }
+
+    /// Promote joining learners that hold every committed entry
+    ///
+    /// One at a time, since only one configuration change may be pending.
+    fn maybe_promote_joining(&mut self) {
+        if self.joining.is_empty() {
+            return;
+        }
+        if !matches!(self.pending_configuration(), Ok(None)) {
+            return;
+        }
+
+        let state = self.state.read();
+        let Some(leader) = state.leader_state.as_ref() else {
+            return;
+        };
+        let applied = state.configuration();
+        let commit_index = state.volatile.commit_index;
+        let ready = self.joining.iter().copied().find(|&node| {
+            applied.is_learner(node)
+                && leader
+                    .get_match_index(node)
+                    .is_some_and(|matched| matched >= commit_index)
+        });
+        drop(state);
+
+        self.joining.retain(|&node| !applied.is_voter(node));
+        if let Some(node) = ready {
+            if let Err(e) = self.handle_change_membership(MembershipChange::Promote(node)) {
+                warn!("Failed to promote joining node {}: {}", node, e);
+            }
+        }
+    }
+
+    /// Ask `seeds` to let this node join until the leader accepts it
+    fn start_join(&self, seeds: Vec<NodeId>, response: oneshot::Sender<Result<()>>) {
+        let id = self.state.read().id;
+        let transport = Arc::clone(&self.transport);
+        let command_tx = self.command_tx.clone();
+        let retry_delay = self.config.heartbeat_interval;
+
+        tokio::spawn(async move {
+            let mut failures = 0;
+            let mut next_seed = 0;
+            let mut leader_hint = None;
+
+            while failures < JOIN_ATTEMPTS {
+                let target = leader_hint.take().unwrap_or_else(|| {
+                    next_seed += 1;
+                    seeds[(next_seed - 1) % seeds.len()]
+                });
+
+                match transport.send_join(target, JoinRequest { node: id }).await {
+                    Ok(reply) if reply.accepted => {
+                        let _ = command_tx.send(RaftCommand::Joined { response });
+                        return;
+                    }
+                    // The leader is still catching us up
+                    Ok(reply) if reply.leader_hint == Some(target) => {
+                        failures = 0;
+                        leader_hint = Some(target);
+                    }
+                    Ok(reply) => {
+                        debug!(
+                            "Node {} was not the leader, hint {:?}",
+                            target, reply.leader_hint
+                        );
+                        failures += 1;
+                        leader_hint = reply.leader_hint.filter(|&hint| hint != id);
+                    }
+                    Err(e) => {
+                        debug!("Join through {} failed: {}", target, e);
+                        failures += 1;
+                    }
+                }
+
+                tokio::time::sleep(retry_delay).await;
+            }
+
+            warn!("Node {} gave up joining after {} attempts", id, failures);
+            let _ = response.send(Err(RaftError::Timeout));
+        });
+    }
+
+    /// Answer `join` callers once this node sees itself as a voter
+    fn release_join_waiters(&mut self) {
+        if self.join_waiters.is_empty() || !self.state.read().is_voter() {
+            return;
+        }
+
+        for waiter in self.join_waiters.drain(..) {
+            let _ = waiter.send(Ok(()));
+        }
+    }
+
+    /// Answer `wait_until_caught_up` callers once this node has applied what
+    /// the leader last told it was committed
+    fn release_catch_up_waiters(&mut self) {
+        self.catch_up_waiters.retain(|waiter| !waiter.is_closed());
+        if self.catch_up_waiters.is_empty() {
+            return;
+        }
+
+        let state = self.state.read();
+        let caught_up = state.role == RaftRole::Leader
+            || self
+                .leader_commit
+                .is_some_and(|commit| state.volatile.last_applied >= commit);
+        drop(state);
+
+        if caught_up {
+            for waiter in self.catch_up_waiters.drain(..) {
+                let _ = waiter.send(());
+            }
+        }
+    }
+
+    /// Ping `peer` from a spawned task so a dead peer can't stall the loop
+    fn ping_peer(&self, peer: NodeId, response: oneshot::Sender<Result<Duration>>) {
+        let state = self.state.read();
+        let request = PingRequest {
+            term: state.persistent.current_term,
+            from: state.id,
+        };
+        drop(state);
+
+        let transport = Arc::clone(&self.transport);
+        let command_tx = self.command_tx.clone();
+        let timeout = self.config.election_timeout_min;
+
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let result =
+                match tokio::time::timeout(timeout, transport.send_ping(peer, request)).await {
+                    Ok(Ok(_)) => Ok(start.elapsed()),
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(RaftError::Timeout),
+                };
+
+            let rtt = result.as_ref().ok().copied();
+            let _ = command_tx.send(RaftCommand::PingResult { peer, rtt });
+            let _ = response.send(result);
+        });
+    }
+
+    /// Record the outcome of a ping to `peer`
+    fn record_ping(&mut self, peer: NodeId, rtt: Option<Duration>) {
+        let health = self.peer_health.entry(peer).or_default();
+        match rtt {
+            Some(rtt) => {
+                health.rtt = Some(rtt);
+                health.last_contact = Some(Instant::now());
+                health.reachable = true;
+            }
+            None => {
+                debug!("Ping to {} went unanswered", peer);
+                health.reachable = false;
+            }
+        }
+    }
+
+    /// Snapshot the leader's per-peer replication state
+    fn replication_progress(&self) -> Result<HashMap<NodeId, PeerProgress>> {
+        let state = self.state.read();
+        let leader = match (&state.role, &state.leader_state) {
+            (RaftRole::Leader, Some(leader)) => leader,
+            _ => return Err(RaftError::NotLeader(state.not_leader_info())),
+        };
+
+        Ok(leader
+            .next_index
+            .iter()
+            .map(|&(peer, _)| {
+                let health = self.peer_health.get(&peer).copied().unwrap_or_default();
+                let progress = PeerProgress {
+                    match_index: leader.get_match_index(peer).unwrap_or(LogIndex::ZERO),
+                    next_index: leader.get_next_index(peer).unwrap_or(LogIndex::ZERO),
+                    rtt: health.rtt,
+                    last_contact: health.last_contact.map(Instant::into_std),
+                    reachable: health.reachable,
+                };
+                (peer, progress)
+            })
+            .collect())
+    }
+
+    /// The lowest applied index across every voter and learner
+    fn global_applied_watermark(&self) -> Result<LogIndex> {
+        let state = self.state.read();
+        let leader = match (&state.role, &state.leader_state) {
+            (RaftRole::Leader, Some(leader)) => leader,
+            _ => return Err(RaftError::NotLeader(state.not_leader_info())),
+        };
+
+        let config = state.configuration();
+        Ok(config
+            .voters
+            .iter()
+            .chain(&config.learners)
+            .filter(|&&node| node != state.id)
+            .map(|&node| leader.get_applied_index(node).unwrap_or(LogIndex::ZERO))
+            .fold(state.volatile.last_applied, LogIndex::min))
+    }
+
+    /// Append a configuration change moving one member between the voter
+    /// and learner sets
+    fn handle_change_membership(&mut self, change: MembershipChange) -> Result<()> {
+        let state = self.state.read();
+        if state.role != RaftRole::Leader {
+            return Err(RaftError::NotLeader(state.not_leader_info()));
+        }
+        let id = state.id;
+        let mut config = state.configuration();
+        drop(state);
+
+        // One change at a time, so each builds on a configuration that's
+        // already in effect
+        if self.pending_configuration()?.is_some() {
+            return Err(RaftError::InvalidConfiguration(
+                "another configuration change is still pending".to_string(),
+            ));
+        }
+        if config.is_joint() {
+            return Err(RaftError::InvalidConfiguration(
+                "the cluster is still leaving a joint configuration".to_string(),
+            ));
+        }
+
+        match change {
+            MembershipChange::Demote(node) => {
+                if node == id {
+                    return Err(RaftError::InvalidConfiguration(
+                        "the leader can't demote itself".to_string(),
+                    ));
+                }
+                if !config.is_voter(node) {
+                    return Err(RaftError::InvalidConfiguration(format!(
+                        "{} is not a voter",
+                        node
+                    )));
+                }
+                config.voters.retain(|&v| v != node);
+                config.learners.push(node);
+            }
+            MembershipChange::Promote(node) => {
+                if !config.is_learner(node) {
+                    return Err(RaftError::InvalidConfiguration(format!(
+                        "{} is not a learner",
+                        node
+                    )));
+                }
+                config.learners.retain(|&l| l != node);
+                config.voters.push(node);
+            }
+            MembershipChange::AddLearner(node) => {
+                if config.is_voter(node) || config.is_learner(node) {
+                    return Err(RaftError::InvalidConfiguration(format!(
+                        "{} is already a member",
+                        node
+                    )));
+                }
+                config.learners.push(node);
+            }
+        }
+
+        self.append_configuration(config)
+    }
+
+    /// Append an entry switching the cluster to `config` and start
+    /// replicating to any members it adds
+    fn append_configuration(&mut self, config: ClusterConfig) -> Result<()> {
+        let index =
+            self.append_as_leader(|term, index| vec![Entry::config_change(term, index, &config)])?;
+        info!(
+            "Node {} changing configuration at {} to voters {:?}, learners {:?}, outgoing voters {:?}",
+            self.state.read().id,
+            index,
+            config.voters,
+            config.learners,
+            config.outgoing_voters
+        );
+        self.track_members(&config);
+        Ok(())
+    }
+
+    /// Start working towards `target`, answering `response` once it's
+    /// applied
+    fn handle_change_configuration(
+        &mut self,
+        target: ClusterConfig,
+        force: bool,
+        response: oneshot::Sender<Result<()>>,
+    ) {
+        let state = self.state.read();
+        if state.role != RaftRole::Leader {
+            let _ = response.send(Err(RaftError::NotLeader(state.not_leader_info())));
+            return;
+        }
+        let id = state.id;
+        let applied = state.configuration();
+        drop(state);
+
+        let invalid = |reason: String| Err(RaftError::InvalidConfiguration(reason));
+        let members: BTreeSet<_> = target.voters.iter().chain(&target.learners).collect();
+        let checked = if self.reconfiguration.is_some() {
+            invalid("another configuration change is in progress".to_string())
+        } else if members.len() != target.voters.len() + target.learners.len() {
+            invalid("members must be listed once".to_string())
+        } else if target.voters.is_empty() {
+            invalid("there must be at least one voter".to_string())
+        } else if force {
+            Ok(())
+        } else {
+            // The joint configuration needs a majority of the old voters and
+            // of the new ones; the new ones go on alone afterwards
+            self.check_reachable(&applied.voters, &applied)
+                .and_then(|()| self.check_reachable(&target.voters, &applied))
+        };
+        if let Err(e) = checked {
+            let _ = response.send(Err(e));
+            return;
+        }
+
+        info!(
+            "Node {} reconfiguring to voters {:?}, learners {:?}",
+            id, target.voters, target.learners
+        );
+        self.reconfiguration = Some(Reconfiguration {
+            target,
+            force,
+            response,
+        });
+        self.advance_reconfiguration();
+    }
+
+    /// Fail unless most of `voters` are reachable
+    ///
+    /// Voters that aren't members of `applied` yet are counted as reachable,
+    /// since they're only promoted once they've caught up.
+    fn check_reachable(&self, voters: &[NodeId], applied: &ClusterConfig) -> Result<()> {
+        let id = self.state.read().id;
+        let window = self.config.election_timeout_max;
+        let reachable = voters
+            .iter()
+            .filter(|&&v| {
+                v == id
+                    || !applied.is_voter(v) && !applied.is_learner(v)
+                    || self
+                        .append_acks
+                        .get(&v)
+                        .is_some_and(|at| at.elapsed() < window)
+            })
+            .count();
+        if reachable > voters.len() / 2 {
+            return Ok(());
+        }
+        Err(RaftError::InvalidConfiguration(format!(
+            "only {} of voters {:?} are reachable",
+            reachable, voters
+        )))
+    }
+
+    /// Make the next membership change towards the `change_configuration`
+    /// target, or answer the caller if there's nothing left to do
+    fn advance_reconfiguration(&mut self) {
+        let Some(reconfiguration) = self.reconfiguration.as_ref() else {
+            return;
+        };
+        if reconfiguration.response.is_closed() {
+            self.reconfiguration = None;
+            return;
+        }
+        let target = reconfiguration.target.clone();
+        let force = reconfiguration.force;
+
+        let state = self.state.read();
+        if state.role != RaftRole::Leader {
+            let leader_hint = state.not_leader_info();
+            drop(state);
+            self.finish_reconfiguration(Err(RaftError::NotLeader(leader_hint)));
+            return;
+        }
+        drop(state);
+
+        // Each step builds on the last one once it's applied and a no-op
+        // has committed under it
+        if !self.config_noops.is_empty() || !matches!(self.pending_configuration(), Ok(None)) {
+            return;
+        }
+
+        let state = self.state.read();
+        let applied = state.configuration();
+        if applied.is_joint() {
+            // `leave_joint_configuration` takes the next step
+            return;
+        }
+        let commit_index = state.volatile.commit_index;
+        let caught_up = |node: NodeId| {
+            state
+                .leader_state
+                .as_ref()
+                .and_then(|l| l.get_match_index(node))
+                .is_some_and(|matched| matched >= commit_index)
+        };
+        let same =
+            |a: &[NodeId], b: &[NodeId]| a.len() == b.len() && a.iter().all(|n| b.contains(n));
+
+        // New nodes join as learners, and only start voting through the
+        // joint configuration once they've caught up, so they can't hold up
+        // commits in the meantime
+        let newcomers: Vec<NodeId> = target
+            .voters
+            .iter()
+            .chain(&target.learners)
+            .copied()
+            .filter(|&n| !applied.is_voter(n) && !applied.is_learner(n))
+            .collect();
+        let next = if !newcomers.is_empty() {
+            let mut next = applied.clone();
+            next.learners.extend(newcomers);
+            next
+        } else if target
+            .voters
+            .iter()
+            .any(|&n| !applied.is_voter(n) && !caught_up(n))
+        {
+            return;
+        } else if !same(&applied.voters, &target.voters) {
+            ClusterConfig {
+                voters: target.voters.clone(),
+                learners: target.learners.clone(),
+                outgoing_voters: applied.voters.clone(),
+            }
+        } else if !same(&applied.learners, &target.learners) {
+            target.clone()
+        } else {
+            info!("Node {} finished reconfiguring", state.id);
+            drop(state);
+            self.finish_reconfiguration(Ok(()));
+            return;
+        };
+        drop(state);
+
+        // A voter may have dropped out since the call was checked
+        let checked = if next.is_joint() && !force {
+            self.check_reachable(&applied.voters, &applied)
+                .and_then(|()| self.check_reachable(&target.voters, &applied))
+        } else {
+            Ok(())
+        };
+        if let Err(e) = checked.and_then(|()| self.append_configuration(next)) {
+            self.finish_reconfiguration(Err(e));
+        }
+    }
+
+    /// Go on from a joint configuration to its incoming voters alone
+    ///
+    /// Any leader that finds a joint configuration in effect does this, so
+    /// a change interrupted by an election still completes.
+    fn leave_joint_configuration(&mut self) {
+        let state = self.state.read();
+        if state.role != RaftRole::Leader || state.outgoing_voters.is_empty() {
+            return;
+        }
+        let config = ClusterConfig {
+            outgoing_voters: vec![],
+            ..state.configuration()
+        };
+        drop(state);
+
+        // Like any change, once the joint configuration is confirmed
+        if !self.config_noops.is_empty() || !matches!(self.pending_configuration(), Ok(None)) {
+            return;
+        }
+        if let Err(e) = self.append_configuration(config) {
+            warn!("Failed to leave the joint configuration: {}", e);
+        }
+    }
+
+    /// Step down once a configuration we don't vote in is applied
+    ///
+    /// We keep leading until a quorum of the new voters holds the
+    /// configuration and has answered an AppendEntries sent since it was
+    /// applied, which told them it committed. They then put it into effect
+    /// and elect a leader from among themselves. If they don't answer
+    /// within `HANDOVER_HEARTBEATS` heartbeats we step down anyway.
+    fn step_down_if_removed(&mut self) {
+        let state = self.state.read();
+        if state.role != RaftRole::Leader || state.is_voter() {
+            self.handover = None;
+            return;
+        }
+        let Some((since, commit_index)) = self.handover else {
+            self.handover = Some((Instant::now(), state.volatile.commit_index));
+            drop(state);
+            self.replicate();
+            return;
+        };
+
+        let told = state.is_quorum(|node| {
+            let matched = state
+                .leader_state
+                .as_ref()
+                .and_then(|l| l.get_match_index(node))
+                .is_some_and(|matched| matched >= commit_index);
+            matched
+                && self
+                    .lease_acks
+                    .get(&node)
+                    .is_some_and(|&sent| sent >= since)
+        });
+        let waited = since.elapsed() >= self.config.heartbeat_interval * HANDOVER_HEARTBEATS;
+        if !told && !waited {
+            return;
+        }
+        drop(state);
+
+        self.handover = None;
+        let mut state = self.state.write();
+        if told {
+            info!("Node {} stepping down, no longer a voter", state.id);
+        } else {
+            warn!(
+                "Node {} stepping down, no longer a voter, before the new voters confirmed the commit",
+                state.id
+            );
+        }
+        let term = state.persistent.current_term;
+        state.become_follower(term, None);
+    }
+
+    /// Append a no-op in the current term to confirm the configuration,
+    /// returning its index
+    fn append_config_noop(&mut self) -> Result<LogIndex> {
+        let configuration = self.state.read().configuration();
+        let index = self.append_as_leader(|term, index| vec![Entry::noop(term, index)])?;
+        self.confirmed_configuration = Some(configuration);
+
+        debug!("Appended configuration no-op at {}", index);
+        Ok(index)
+    }
+
+    fn handle_config_noop(&mut self, response: oneshot::Sender<Result<ClusterConfig>>) {
+        match self.append_config_noop() {
+            Ok(index) => self.config_noops.push(ConfigNoopWaiter {
+                term: self.state.read().persistent.current_term,
+                index,
+                response: Some(response),
+            }),
+            Err(e) => {
+                let _ = response.send(Err(e));
+            }
+        }
+    }
+
+    /// Confirm a newly applied configuration with a no-op of our own
+    fn confirm_configuration(&mut self) {
+        let state = self.state.read();
+        if state.role != RaftRole::Leader
+            || !state.is_voter()
+            || self.confirmed_configuration.as_ref() == Some(&state.configuration())
+        {
+            return;
+        }
+        let term = state.persistent.current_term;
+        drop(state);
+
+        match self.append_config_noop() {
+            Ok(index) => self.config_noops.push(ConfigNoopWaiter {
+                term,
+                index,
+                response: None,
+            }),
+            Err(e) => warn!("Failed to append configuration no-op: {}", e),
+        }
+    }
+
+    /// Answer confirmations that have been applied, and fail them all if
+    /// we've stopped leading the term they were appended in
+    fn release_config_noops(&mut self) {
+        if self.config_noops.is_empty() {
+            return;
+        }
+
+        let state = self.state.read();
+        let term = state.persistent.current_term;
+        if state.role != RaftRole::Leader {
+            let leader_hint = state.not_leader_info();
+            drop(state);
+            for waiter in self.config_noops.drain(..) {
+                if let Some(response) = waiter.response {
+                    let _ = response.send(Err(RaftError::NotLeader(leader_hint.clone())));
+                }
+            }
+            return;
+        }
+
+        let last_applied = state.volatile.last_applied;
+        let config = state.configuration();
+        drop(state);
+        let (ready, waiting) = std::mem::take(&mut self.config_noops)
+            .into_iter()
+            .partition(|w| w.term != term || w.index <= last_applied);
+        self.config_noops = waiting;
+
+        for waiter in ready {
+            if let Some(response) = waiter.response {
+                let result = if waiter.term == term {
+                    Ok(config.clone())
+                } else {
+                    Err(RaftError::not_leader(None))
+                };
+                let _ = response.send(result);
+            }
+        }
+    }
+
+    /// Answer the `change_configuration` caller
+    fn finish_reconfiguration(&mut self, result: Result<()>) {
+        if let Some(reconfiguration) = self.reconfiguration.take() {
+            let _ = reconfiguration.response.send(result);
+        }
+    }
+
+    /// The newest configuration in the log that hasn't been applied yet
+    fn pending_configuration(&self) -> Result<Option<ClusterConfig>> {
+        let last_applied = self.state.read().volatile.last_applied;
+        let last_index = self.log.last_index();
+        if last_applied >= last_index {
+            return Ok(None);
+        }
+
+        let mut pending = None;
+        for entry in self.log.get_range(last_applied + 1, last_index + 1)? {
+            if let Some(config) = entry.config()? {
+                pending = Some(config);
+            }
+        }
+        Ok(pending)
+    }
+
+    /// The configuration a leader replicates under
+    ///
+    /// Per Raft, that's the latest one appended to the log, even though
+    /// `NodeState` only switches membership when the entry is applied.
+    fn latest_configuration(&self) -> Result<ClusterConfig> {
+        match self.pending_configuration()? {
+            Some(config) => Ok(config),
+            None => Ok(self.state.read().configuration()),
+        }
+    }
+
+    /// Make sure a leader replicates to every member of `config`
+    fn track_members(&self, config: &ClusterConfig) {
+        let last_index = self.log.last_index();
+        let mut state = self.state.write();
+        let id = state.id;
+        if let Some(leader) = state.leader_state.as_mut() {
+            let members = config.voters.iter().chain(&config.outgoing_voters);
+            for &node in members.chain(&config.learners) {
+                if node != id {
+                    leader.track(node, last_index);
+                }
+            }
+        }
+    }
+
+    /// Capture the snapshot, the committed entries after it and the
+    /// configuration as one consistent bundle
+    fn export_bundle(&self) -> Result<StateBundle> {
+        let state = self.state.read();
+        let commit_index = state.volatile.commit_index;
+        let configuration = state.configuration();
+        drop(state);
+
+        let snapshot = self.log.get_snapshot();
+        let start = snapshot
+            .as_ref()
+            .map(|s| s.metadata.last_included_index)
+            .unwrap_or(LogIndex::ZERO)
+            + 1;
+        let entries = if start <= commit_index {
+            self.log.get_range(start, commit_index + 1)?
+        } else {
+            vec![]
+        };
+
+        let bundle = StateBundle {
+            snapshot,
+            entries,
+            configuration,
+            commit_index,
+        };
+        bundle.validate()?;
+        Ok(bundle)
+    }
+
+    /// Seed a fresh node from an exported bundle
+    fn import_bundle(&mut self, bundle: StateBundle) -> Result<()> {
+        bundle.validate()?;
+        if self.log.last_index() > LogIndex::ZERO || self.log.get_snapshot().is_some() {
+            return Err(RaftError::InvalidBundle(
+                "bundles can only be imported into an empty node".to_string(),
+            ));
+        }
+
+        let StateBundle {
+            snapshot,
+            entries,
+            configuration,
+            commit_index,
+        } = bundle;
+        let last_term = entries
+            .last()
+            .map(|e| e.term)
+            .or(snapshot.as_ref().map(|s| s.metadata.last_included_term))
+            .unwrap_or(Term(0));
+
+        let mut last_applied = LogIndex::ZERO;
+        if let Some(snapshot) = snapshot {
+            last_applied = snapshot.metadata.last_included_index;
+            let mut sm = self.state_machine.write();
+            sm.machine.restore(&snapshot.data);
+            sm.last_applied = last_applied;
+            drop(sm);
+
+            self.log.set_snapshot(snapshot)?;
+            self.log.compact(last_applied)?;
+        }
+        self.log.append(entries)?;
+
+        let state_lock = Arc::clone(&self.state);
+        let mut state = state_lock.write();
+        if last_term > state.persistent.current_term {
+            state.become_follower(last_term, None);
+        }
+        state.set_configuration(configuration, self.log.last_index());
+        state.volatile.last_applied = last_applied;
+        state.volatile.commit_index = commit_index;
+        info!(
+            "Node {} imported state through {} (snapshot at {})",
+            state.id, commit_index, last_applied
+        );
+        drop(state);
+
+        self.apply_committed();
+        Ok(())
+    }
+
+    /// Handle RequestVote RPC
+    fn handle_request_vote(&mut self, req: RequestVoteRequest) -> RequestVoteResponse {
+        let state_lock = Arc::clone(&self.state);
+        let mut state = state_lock.write();
+
+        // Some candidates are turned away without even learning their term,
+        // so they can't unseat a working leader: nodes outside the
+        // configuration (e.g. removed ones that haven't noticed), anyone
+        // while we still hear from a leader, and candidates whose term ran
+        // away from the one we lead
+        let ignored = if !state.voters().is_empty() && !state.is_voting(req.candidate_id) {
+            Some(VoteDenialReason::NotAMember)
+        } else if state.role == RaftRole::Leader
+            && self.exceeds_term_gap(req.term, state.persistent.current_term)
+        {
+            Some(VoteDenialReason::TermGapExceeded)
+        } else if state.role == RaftRole::Follower
+            && state.leader_id.is_some()
+            && self
+                .last_leader_contact
+                .is_some_and(|at| at.elapsed() < self.config.election_timeout_min)
+        {
+            Some(VoteDenialReason::RecentLeaderContact)
+        } else {
+            None
+        };
+        if let Some(reason) = ignored {
+            debug!(
+                "Node {} ignored vote request from {} for {}: {:?}",
+                state.id, req.candidate_id, req.term, reason
+            );
+            return RequestVoteResponse {
+                term: state.persistent.current_term,
+                vote_granted: false,
+                denial_reason: Some(reason),
+            };
+        }
+
+        // A pre-vote is answered as the real vote would be, but changes
+        // nothing. A leader still has its followers and won't give way.
+        if req.pre_vote {
+            let current = state.persistent.current_term;
+            let denial_reason = if state.role == RaftRole::Leader {
+                Some(VoteDenialReason::RecentLeaderContact)
+            } else if req.term < current {
+                Some(VoteDenialReason::StaleTerm)
+            } else if req.term == current
+                && state
+                    .persistent
+                    .voted_for
+                    .is_some_and(|v| v != req.candidate_id)
+            {
+                Some(VoteDenialReason::AlreadyVoted)
+            } else if req.last_log_position() < self.log.last_position() {
+                Some(VoteDenialReason::LogNotUpToDate)
+            } else {
+                None
+            };
+            return RequestVoteResponse {
+                term: current,
+                vote_granted: denial_reason.is_none(),
+                denial_reason,
+            };
+        }
+
+        // Update term if we see a higher one
+        if req.term > state.persistent.current_term {
+            state.become_follower(req.term, None);
+        }
+
+        let mut vote_granted = false;
+        let mut denial_reason = None;
+
+        // Grant vote if:
+        // 1. Candidate's term >= our term
+        // 2. We haven't voted for anyone else this term
+        // 3. Candidate's log is at least as up-to-date as ours
+        if req.term < state.persistent.current_term {
+            denial_reason = Some(VoteDenialReason::StaleTerm);
+        } else {
+            // A candidate or leader has voted for itself in this term, and
+            // is held to that even if `voted_for` were to say otherwise
+            let already_voted = state.role != RaftRole::Follower && req.candidate_id != state.id
+                || state
+                    .persistent
+                    .voted_for
+                    .is_some_and(|v| v != req.candidate_id);
+
+            if already_voted {
+                denial_reason = Some(VoteDenialReason::AlreadyVoted);
+            } else if req.last_log_position() < self.log.last_position() {
+                denial_reason = Some(VoteDenialReason::LogNotUpToDate);
+            } else {
+                vote_granted = true;
+
+                // A repeated request from the candidate we already voted
+                // for gets the same answer, but mustn't keep pushing our
+                // election timer back
+                if state.persistent.voted_for == Some(req.candidate_id) {
+                    debug!(
+                        "Node {} repeated vote for {} in term {}",
+                        state.id, req.candidate_id, req.term
+                    );
+                } else {
                     state.persistent.voted_for = Some(req.candidate_id);
                     self.reset_election_timeout();
 
-                    debug!(
-                        "Node {} granted vote to {} for term {}",
-                        state.id, req.candidate_id, req.term
-                    );
+                    debug!(
+                        "Node {} granted vote to {} for term {}",
+                        state.id, req.candidate_id, req.term
+                    );
+                }
+            }
+        }
+
+        // Nothing is promised until the term and vote are durable
+        if self.hard_state.persist(&mut state).is_err() {
+            vote_granted = false;
+        }
+
+        RequestVoteResponse {
+            term: state.persistent.current_term,
+            vote_granted,
+            denial_reason,
+        }
+    }
+
+    /// Handle AppendEntries RPC
+    fn handle_append_entries(&mut self, req: AppendEntriesRequest) -> AppendEntriesResponse {
+        let state_lock = Arc::clone(&self.state);
+        let mut state = state_lock.write();
+
+        let term = state.persistent.current_term;
+        if req.term < term
+            && state.role != RaftRole::Leader
+            && state.leader_id.is_none()
+            && self.exceeds_term_gap(term, req.term)
+        {
+            let reset = self.config.reset_term_on_gap;
+            if self.term_gap_reported != Some(term) {
+                self.term_gap_reported = Some(term);
+                warn!(
+                    "Node {} at {} is more than {:?} terms ahead of leader {} at {}",
+                    state.id, term, self.config.max_term_gap, req.leader_id, req.term
+                );
+                self.emit(RaftEvent::TermGapDetected {
+                    term,
+                    leader_term: req.term,
+                    leader: req.leader_id,
+                    reset,
+                });
+            }
+
+            // Only a leader can have won its term, so counting our vote for
+            // it there keeps us from electing anyone else in that term
+            if reset {
+                state.become_follower(req.term, Some(req.leader_id));
+                state.persistent.voted_for = Some(req.leader_id);
+                if self.hard_state.persist(&mut state).is_err() {
+                    state.leader_id = None;
+                    return AppendEntriesResponse {
+                        term: state.persistent.current_term,
+                        success: false,
+                        match_index: None,
+                        conflict_term: None,
+                        conflict_index: None,
+                        commit_index: state.volatile.commit_index,
+                        last_applied: state.volatile.last_applied,
+                    };
+                }
+            }
+        }
+
+        // A leader from an older term is rejected outright; our term in the
+        // reply tells it to step down
+        if req.term < state.persistent.current_term {
+            // Nobody leads our newer term, and the old leader is about to
+            // give up its own. Stand for election now instead of leaving the
+            // cluster leaderless for another full timeout.
+            if state.role != RaftRole::Leader && state.leader_id.is_none() {
+                debug!(
+                    "Node {} at {} rejected stale leader {} from {}, campaigning",
+                    state.id, state.persistent.current_term, req.leader_id, req.term
+                );
+                self.expire_election_timeout();
+            }
+            return AppendEntriesResponse {
+                term: state.persistent.current_term,
+                success: false,
+                match_index: None,
+                conflict_term: None,
+                conflict_index: None,
+                commit_index: state.volatile.commit_index,
+                last_applied: state.volatile.last_applied,
+            };
+        }
+
+        if req.term > state.persistent.current_term {
+            state.become_follower(req.term, Some(req.leader_id));
+
+            // The new term must be durable before entries from it reach the
+            // log, or a crash could leave entries from a forgotten term
+            if self.hard_state.persist(&mut state).is_err() {
+                return AppendEntriesResponse {
+                    term: state.persistent.current_term,
+                    success: false,
+                    match_index: None,
+                    conflict_term: None,
+                    conflict_index: None,
+                    commit_index: state.volatile.commit_index,
+                    last_applied: state.volatile.last_applied,
+                };
+            }
+        } else if state.role == RaftRole::Leader && req.leader_id != state.id {
+            // Two leaders in one term means election safety is broken. Step
+            // down and don't trust either side's entries until a new term
+            // sorts it out.
+            let term = state.persistent.current_term;
+            error!(
+                "Node {} is leader for {} but received AppendEntries from {} at the same term",
+                state.id, term, req.leader_id
+            );
+            state.become_follower(term, None);
+            self.emit(RaftEvent::SafetyViolation {
+                term,
+                violation: SafetyViolation::ConflictingLeader {
+                    other_leader: req.leader_id,
+                },
+            });
+
+            return AppendEntriesResponse {
+                term,
+                success: false,
+                match_index: None,
+                conflict_term: None,
+                conflict_index: None,
+                commit_index: state.volatile.commit_index,
+                last_applied: state.volatile.last_applied,
+            };
+        } else if state.role == RaftRole::Candidate {
+            // Someone else won the election we're running; our vote for
+            // ourselves in this term stands
+            info!(
+                "Node {} lost the election for {} to {}",
+                state.id, req.term, req.leader_id
+            );
+            state.become_follower(req.term, Some(req.leader_id));
+        }
+
+        // Reset election timeout (valid leader heartbeat)
+        self.reset_election_timeout();
+        self.timeouts_without_leader = 0;
+        self.last_leader_contact = Some(Instant::now());
+        self.pre_vote = None;
+        state.leader_id = Some(req.leader_id);
+
+        // Check if our log contains an entry at prev_log_index with matching term
+        if req.prev_log_index > LogIndex::ZERO {
+            match self.log.get_term(req.prev_log_index) {
+                Ok(Some(term)) if term == req.prev_log_term => {
+                    // Log is consistent, proceed
+                }
+                mismatch => {
+                    // Log doesn't match, reject
+                    let (conflict_term, conflict_index) = match mismatch {
+                        Ok(Some(term)) => (
+                            Some(term),
+                            Some(self.first_index_of_term(term, req.prev_log_index)),
+                        ),
+                        _ if req.prev_log_index > self.log.last_index() => {
+                            (None, Some(self.log.last_index() + 1))
+                        }
+                        _ => (None, None),
+                    };
+                    return AppendEntriesResponse {
+                        term: state.persistent.current_term,
+                        success: false,
+                        match_index: Some(self.log.last_index()),
+                        conflict_term,
+                        conflict_index,
+                        commit_index: state.volatile.commit_index,
+                        last_applied: state.volatile.last_applied,
+                    };
+                }
+            }
+        }
+
+        // Skip entries we already hold and drop our log from the first one
+        // that conflicts with the leader's, so a resent request is harmless
+        let last_index = self.log.last_index();
+        let first_new = req
+            .entries
+            .iter()
+            .position(|entry| match self.log.get_term(entry.index) {
+                Ok(Some(term)) => term != entry.term,
+                // Compacted into our snapshot, so already committed here
+                Ok(None) => entry.index > last_index,
+                Err(_) => true,
+            });
+
+        if let Some(first_new) = first_new {
+            let index = req.entries[first_new].index;
+            if index <= last_index
+                && self
+                    .log
+                    .truncate_suffix_if_uncommitted(index, state.volatile.commit_index)
+                    .is_err()
+            {
+                return AppendEntriesResponse {
+                    term: state.persistent.current_term,
+                    success: false,
+                    match_index: None,
+                    conflict_term: None,
+                    conflict_index: None,
+                    commit_index: state.volatile.commit_index,
+                    last_applied: state.volatile.last_applied,
+                };
+            }
+
+            if let Err(e) = self.log.append(req.entries[first_new..].to_vec()) {
+                warn!("Failed to append entries: {}", e);
+                return AppendEntriesResponse {
+                    term: state.persistent.current_term,
+                    success: false,
+                    match_index: None,
+                    conflict_term: None,
+                    conflict_index: None,
+                    commit_index: state.volatile.commit_index,
+                    last_applied: state.volatile.last_applied,
+                };
+            }
+        }
+
+        self.leader_commit = Some(req.leader_commit);
+
+        // Update commit index, never past what this request showed to match
+        // the leader's log nor past what we actually hold, and never backward
+        if req.leader_commit > state.volatile.commit_index {
+            let last_new_index = req
+                .entries
+                .last()
+                .map(|e| e.index)
+                .unwrap_or(req.prev_log_index);
+
+            let commit_index = req
+                .leader_commit
+                .min(last_new_index)
+                .min(self.log.last_index());
+            if commit_index > state.volatile.commit_index {
+                state.volatile.commit_index = commit_index;
+            }
+        }
+
+        AppendEntriesResponse {
+            term: state.persistent.current_term,
+            success: true,
+            match_index: Some(self.log.last_index()),
+            conflict_term: None,
+            conflict_index: None,
+            commit_index: state.volatile.commit_index,
+            last_applied: state.volatile.last_applied,
+        }
+    }
+
+    /// Handle InstallSnapshot RPC
+    ///
+    /// Chunks are collected until the last one arrives. The log is then reset
+    /// to the snapshot straight away, so AppendEntries that follow it can be
+    /// accepted, while the state machine is restored in the background.
+    fn handle_install_snapshot(&mut self, req: InstallSnapshotRequest) -> InstallSnapshotResponse {
+        let state_lock = Arc::clone(&self.state);
+        let mut state = state_lock.write();
+
+        if req.term > state.persistent.current_term {
+            state.become_follower(req.term, Some(req.leader_id));
+            if self.hard_state.persist(&mut state).is_err() {
+                return InstallSnapshotResponse {
+                    term: state.persistent.current_term,
+                };
+            }
+        }
+
+        let term = state.persistent.current_term;
+        if req.term < term {
+            return InstallSnapshotResponse { term };
+        }
+
+        self.reset_election_timeout();
+        self.timeouts_without_leader = 0;
+        self.last_leader_contact = Some(Instant::now());
+        self.pre_vote = None;
+        state.leader_id = Some(req.leader_id);
+
+        // Nothing new in it, or we're still restoring the last one; the
+        // leader will retry if we're still behind
+        if req.last_included_index <= state.volatile.commit_index || self.restore_in_progress {
+            return InstallSnapshotResponse { term };
+        }
+
+        if req.offset == 0 {
+            self.incoming_snapshot = Some(IncomingSnapshot {
+                last_included_index: req.last_included_index,
+                last_included_term: req.last_included_term,
+                data: Vec::new(),
+            });
+        }
+        let Some(incoming) = self.incoming_snapshot.as_mut().filter(|s| {
+            s.last_included_index == req.last_included_index
+                && s.last_included_term == req.last_included_term
+                && s.data.len() as u64 == req.offset
+        }) else {
+            debug!(
+                "Node {} dropped out-of-order snapshot chunk at offset {}",
+                state.id, req.offset
+            );
+            return InstallSnapshotResponse { term };
+        };
+        incoming.data.extend_from_slice(&req.data);
+        if !req.done {
+            return InstallSnapshotResponse { term };
+        }
+
+        let Some(incoming) = self.incoming_snapshot.take() else {
+            return InstallSnapshotResponse { term };
+        };
+        let last_included_index = incoming.last_included_index;
+
+        // A leader that didn't send its membership leaves ours in place
+        let configuration = req.configuration.unwrap_or_else(|| state.configuration());
+        let snapshot = Snapshot {
+            metadata: SnapshotMetadata {
+                last_included_index,
+                last_included_term: incoming.last_included_term,
+                configuration: configuration.clone(),
+            },
+            data: incoming.data,
+        };
+
+        // Keep entries past the snapshot only if our log agrees with it
+        let matches = matches!(
+            self.log.get_term(last_included_index),
+            Ok(Some(t)) if t == incoming.last_included_term
+        );
+        let reset = self
+            .log
+            .set_snapshot(snapshot.clone())
+            .and_then(|_| self.log.compact(last_included_index))
+            .and_then(|_| {
+                if matches {
+                    Ok(())
+                } else {
+                    self.log.delete_from(last_included_index + 1)
+                }
+            });
+        if let Err(e) = reset {
+            warn!("Node {} failed to install snapshot: {}", state.id, e);
+            return InstallSnapshotResponse { term };
+        }
+
+        // Membership changes compacted into the snapshot reach us only here
+        state.set_configuration(configuration, self.log.last_index());
+
+        info!(
+            "Node {} restoring snapshot through {}",
+            state.id, last_included_index
+        );
+        drop(state);
+
+        // `last_applied` jumps to the snapshot once the restore lands; until
+        // then nothing may be applied on top of a half-restored state machine
+        self.restore_in_progress = true;
+        if let Some(applier) = &self.applier {
+            // Queued behind any entries still being applied
+            if applier.send(ApplyJob::Restore(snapshot)).is_err() {
+                error!("Node {} lost its apply thread", self.state.read().id);
+            }
+            return InstallSnapshotResponse { term };
+        }
+
+        let state_machine = Arc::clone(&self.state_machine);
+        let command_tx = self.command_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut sm = state_machine.write();
+            sm.machine.restore(&snapshot.data);
+            sm.last_applied = last_included_index;
+            drop(sm);
+
+            let _ = command_tx.send(RaftCommand::RestoreFinished {
+                last_included_index,
+            });
+        });
+
+        InstallSnapshotResponse { term }
+    }
+
+    /// Resume applying once an installed snapshot has been restored
+    fn finish_restore(&mut self, last_included_index: LogIndex) {
+        self.restore_in_progress = false;
+        self.apply_dispatched = self.apply_dispatched.max(last_included_index);
+
+        let mut state = self.state.write();
+        if state.volatile.last_applied < last_included_index {
+            state.volatile.last_applied = last_included_index;
+        }
+        if state.volatile.commit_index < last_included_index {
+            state.volatile.commit_index = last_included_index;
+        }
+        info!(
+            "Node {} restored snapshot through {}",
+            state.id, last_included_index
+        );
+        drop(state);
+
+        // Catch up on anything committed after the snapshot in the meantime
+        self.apply_committed();
+    }
+
+    /// Persist the commit index as a hint for the next startup
+    fn save_commit_hint(&mut self) {
+        let state = self.state.read();
+        self.hard_state.save_commit_hint(&state);
+    }
+
+    /// Pick up from the snapshot and log left by a previous run
+    ///
+    /// The snapshot goes into the state machine and its membership into
+    /// effect, in place of the peers the node was built with; configuration
+    /// entries after it are applied again as they commit. The commit index comes
+    /// from the persisted hint, never past the end of the log nor behind the
+    /// snapshot. Entries after the hint may never have committed: they stay
+    /// unapplied until a leader's commit index covers them, or are
+    /// overwritten by its log.
+    fn recover(&mut self, commit_hint: Option<LogIndex>) {
+        let snapshot = self.log.get_snapshot();
+        let snapshot_index = match &snapshot {
+            Some(snapshot) => {
+                let index = snapshot.metadata.last_included_index;
+                let mut sm = self.state_machine.write();
+                sm.machine.restore(&snapshot.data);
+                sm.last_applied = index;
+                index
+            }
+            None => LogIndex::ZERO,
+        };
+        let commit_index = commit_hint
+            .unwrap_or(LogIndex::ZERO)
+            .min(self.log.last_index())
+            .max(snapshot_index);
+        self.apply_dispatched = snapshot_index;
+
+        let mut state = self.state.write();
+        // Snapshots that recorded no voters predate membership tracking
+        if let Some(snapshot) = snapshot.filter(|s| !s.metadata.configuration.voters.is_empty()) {
+            state.set_configuration(snapshot.metadata.configuration, self.log.last_index());
+        }
+        state.volatile.last_applied = snapshot_index;
+        state.volatile.commit_index = commit_index;
+        if self.log.last_index() > snapshot_index {
+            info!(
+                "Node {} recovered a log through {}, committed through {} (snapshot at {})",
+                state.id,
+                self.log.last_index(),
+                commit_index,
+                snapshot_index
+            );
+        }
+    }
+
+    /// Pull `commit_index` back to the end of the log
+    ///
+    /// Nothing should ever commit past what the log holds; if something did,
+    /// applying would step over entries that don't exist.
+    fn clamp_commit_index(&self, state: &mut NodeState) {
+        let last_index = self.log.last_index();
+        if state.volatile.commit_index > last_index {
+            error!(
+                "Node {} commit index {} is past its last log index {}",
+                state.id, state.volatile.commit_index, last_index
+            );
+            state.volatile.commit_index = last_index;
+        }
+    }
+
+    /// Apply committed entries to state machine
+    fn apply_committed(&mut self) {
+        // The state machine is being snapshotted at a fixed `last_applied`,
+        // or restored to a new one; committed entries wait until it lands
+        if self.snapshot_in_progress || self.restore_in_progress {
+            return;
+        }
+
+        if self.applier.is_some() || self.config.manual_apply {
+            self.dispatch_committed();
+            return;
+        }
+
+        let state_lock = Arc::clone(&self.state);
+        let mut state = state_lock.write();
+        let state_machine = Arc::clone(&self.state_machine);
+        self.clamp_commit_index(&mut state);
+
+        while state.volatile.last_applied < state.volatile.commit_index {
+            let index = state.volatile.last_applied + 1;
+            let entry = match self.log.get(index) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => {
+                    debug_assert!(false, "committed entry {} missing from the log", index);
+                    error!("Node {} is missing committed entry {}", state.id, index);
+                    break;
+                }
+                Err(e) => {
+                    error!("Node {} failed to read entry {}: {}", state.id, index, e);
+                    break;
+                }
+            };
+            state.volatile.last_applied = index;
+
+            let mut sm = state_machine.write();
+            match entry.kind {
+                EntryKind::ConfigChange => self.apply_configuration(&mut state, &entry),
+                // Already in the state machine through a restored snapshot
+                _ if entry.index <= sm.last_applied => continue,
+                EntryKind::Normal => {
+                    let output = apply_entry(&mut sm.machine, &entry);
+                    self.resolve_proposal(entry.index, output);
+                }
+                EntryKind::Noop => {
+                    if self.config.deliver_noops_to_state_machine {
+                        sm.machine.apply_noop(entry.index);
+                    }
+                }
+            }
+            sm.last_applied = sm.last_applied.max(entry.index);
+
+            debug!(
+                "Node {} applied entry {} to state machine",
+                state.id, state.volatile.last_applied
+            );
+        }
+        drop(state);
+
+        self.maybe_start_snapshot();
+    }
+
+    /// Put a committed configuration entry into effect
+    fn apply_configuration(&self, state: &mut NodeState, entry: &Entry) {
+        match entry.config() {
+            Ok(Some(config)) => {
+                info!(
+                    "Node {} applied configuration with voters {:?}, learners {:?}",
+                    state.id, config.voters, config.learners
+                );
+                state.set_configuration(config, self.log.last_index());
+            }
+            Ok(None) => {}
+            Err(e) => error!("Node {} skipped configuration: {}", state.id, e),
+        }
+    }
+
+    /// Start the thread that applies committed entries off the main loop
+    fn start_apply_thread(&mut self) -> Result<()> {
+        let (applier, jobs) = mpsc::unbounded_channel();
+        let state_machine = Arc::clone(&self.state_machine);
+        let command_tx = self.command_tx.clone();
+        let deliver_noops = self.config.deliver_noops_to_state_machine;
+
+        std::thread::Builder::new()
+            .name(format!("raft-apply-{}", self.state.read().id))
+            .spawn(move || run_apply_thread(state_machine, jobs, command_tx, deliver_noops))?;
+
+        self.applier = Some(applier);
+        Ok(())
+    }
+
+    /// Hand newly committed entries to the apply thread, or mark them ready
+    /// for `apply_ready` with manual apply
+    ///
+    /// Configuration entries take effect here, on the consensus side, as soon
+    /// as they're committed; the state machine never sees them.
+    fn dispatch_committed(&mut self) {
+        if self.applier.is_none() && !self.config.manual_apply {
+            return;
+        }
+
+        let state_lock = Arc::clone(&self.state);
+        let mut state = state_lock.write();
+        self.clamp_commit_index(&mut state);
+        let start = self.apply_dispatched.max(state.volatile.last_applied) + 1;
+        let commit_index = state.volatile.commit_index;
+        if start > commit_index {
+            return;
+        }
+
+        let entries = match self.log.get_range(start, commit_index + 1) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Node {} failed to read committed entries: {}", state.id, e);
+                return;
+            }
+        };
+        for entry in &entries {
+            if entry.kind == EntryKind::ConfigChange {
+                self.apply_configuration(&mut state, entry);
+            }
+        }
+        let Some(through) = entries.last().map(|e| e.index) else {
+            return;
+        };
+
+        if let Some(applier) = &self.applier {
+            if applier.send(ApplyJob::Entries(entries)).is_err() {
+                error!("Node {} lost its apply thread", state.id);
+                return;
+            }
+        }
+        self.apply_dispatched = through;
+        debug!(
+            "Node {} handed entries through {} to apply",
+            state.id, through
+        );
+    }
+
+    /// The committed commands the caller hasn't confirmed applying yet
+    fn apply_ready(&self) -> Result<Vec<(LogIndex, Vec<u8>)>> {
+        if !self.config.manual_apply {
+            return Err(RaftError::ManualApplyDisabled);
+        }
+        let state = self.state.read();
+        let (last_applied, commit_index) =
+            (state.volatile.last_applied, state.volatile.commit_index);
+        drop(state);
+        if last_applied >= commit_index {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .log
+            .get_range(last_applied + 1, commit_index + 1)?
+            .into_iter()
+            .filter(|entry| entry.kind == EntryKind::Normal)
+            .map(|entry| (entry.index, entry.command))
+            .collect())
+    }
+
+    fn read_committed_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Entry>> {
+        if end <= start {
+            return Ok(Vec::new());
+        }
+        let commit_index = self.state.read().volatile.commit_index;
+        if end > commit_index + 1 {
+            return Err(RaftError::LogIndexOutOfRange(commit_index + 1));
+        }
+
+        // Backends refuse a start before their first entry; make sure one
+        // that skipped compacted entries instead can't slip through either
+        let entries = self.log.get_range(start, end)?;
+        if entries.first().map(|e| e.index) != Some(start)
+            || entries.len() as u64 != end.0 - start.0
+        {
+            return Err(RaftError::LogIndexOutOfRange(start));
+        }
+        Ok(entries)
+    }
+
+    /// Record that the caller applied everything through `through`
+    fn confirm_applied(&mut self, through: LogIndex) -> Result<()> {
+        if !self.config.manual_apply {
+            return Err(RaftError::ManualApplyDisabled);
+        }
+        let state = self.state.read();
+        let (last_applied, commit_index) =
+            (state.volatile.last_applied, state.volatile.commit_index);
+        drop(state);
+        if through > commit_index {
+            return Err(RaftError::LogIndexOutOfRange(through));
+        }
+        if through <= last_applied {
+            return Ok(());
+        }
+
+        let outputs = self
+            .pending_proposals
+            .range(..=through)
+            .map(|(&index, _)| (index, Vec::new()))
+            .collect();
+        self.finish_apply(through, outputs);
+        Ok(())
+    }
+
+    /// Record progress reported by the apply thread
+    fn finish_apply(&mut self, through: LogIndex, outputs: Vec<(LogIndex, Vec<u8>)>) {
+        for (index, output) in outputs {
+            self.resolve_proposal(index, output);
+        }
+
+        let mut state = self.state.write();
+        if through > state.volatile.last_applied {
+            state.volatile.last_applied = through;
+        }
+        drop(state);
+
+        self.maybe_start_snapshot();
+    }
+
+    /// Kick off a background snapshot once enough entries have been applied
+    /// since the last one
+    ///
+    /// `StateMachine::snapshot` can be slow for large states, so it runs on
+    /// the blocking pool while the main loop keeps answering RPCs. The
+    /// snapshot is taken at the current `last_applied`; entries committed in
+    /// the meantime are appended to the log as usual and applied afterwards.
+    fn maybe_start_snapshot(&mut self) {
+        let threshold = self.config.snapshot_threshold;
+        let requested = !self.snapshot_waiters.is_empty();
+        let automatic = threshold > 0 && !self.config.manual_apply;
+        if (!automatic && !requested) || self.snapshot_in_progress || self.restore_in_progress {
+            return;
+        }
+
+        let state = self.state.read();
+        let last_applied = state.volatile.last_applied;
+
+        // The apply thread is still working; the snapshot has to see the
+        // state machine at rest
+        if self.apply_dispatched > last_applied {
+            return;
+        }
+
+        let snapshot_index = self
+            .log
+            .get_snapshot()
+            .map(|s| s.metadata.last_included_index)
+            .unwrap_or(LogIndex::ZERO);
+        if last_applied <= snapshot_index
+            || (!requested && last_applied.0 - snapshot_index.0 < threshold)
+        {
+            return;
+        }
+
+        let Ok(Some(last_included_term)) = self.log.get_term(last_applied) else {
+            return;
+        };
+        let metadata = SnapshotMetadata {
+            last_included_index: last_applied,
+            last_included_term,
+            configuration: state.configuration(),
+        };
+        debug!("Node {} snapshotting through {}", state.id, last_applied);
+        drop(state);
+
+        self.snapshot_in_progress = true;
+        let state_machine = Arc::clone(&self.state_machine);
+        let command_tx = self.command_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            // Capture the data and the index it reflects under one lock, then
+            // make sure that index is the one the metadata claims
+            let sm = state_machine.read();
+            let captured_index = sm.last_applied;
+            let data = sm.machine.snapshot();
+            drop(sm);
+
+            let result = if captured_index == metadata.last_included_index {
+                Ok(Snapshot { metadata, data })
+            } else {
+                Err(RaftError::Internal(format!(
+                    "snapshot captured at applied index {} but metadata claims {}",
+                    captured_index, metadata.last_included_index
+                )))
+            };
+            let _ = command_tx.send(RaftCommand::SnapshotReady(result));
+        });
+    }
+
+    /// Snapshot everything applied so far for a `create_snapshot` caller
+    fn handle_create_snapshot(&mut self, response: oneshot::Sender<Result<Snapshot>>) {
+        let through = self.state.read().volatile.last_applied;
+        let current = self.log.get_snapshot();
+        let snapshot_index = current
+            .as_ref()
+            .map(|s| s.metadata.last_included_index)
+            .unwrap_or(LogIndex::ZERO);
+
+        if through <= snapshot_index {
+            let _ = response.send(current.ok_or_else(|| {
+                RaftError::Internal("nothing has been applied to snapshot".to_string())
+            }));
+            return;
+        }
+
+        self.snapshot_waiters
+            .push(SnapshotWaiter { through, response });
+        self.maybe_start_snapshot();
+    }
+
+    /// Answer `create_snapshot` callers the stored snapshot now covers
+    fn release_snapshot_waiters(&mut self) {
+        let Some(snapshot) = self.log.get_snapshot() else {
+            return;
+        };
+        let covered = snapshot.metadata.last_included_index;
+
+        let (ready, waiting) = std::mem::take(&mut self.snapshot_waiters)
+            .into_iter()
+            .partition(|waiter| waiter.through <= covered);
+        self.snapshot_waiters = waiting;
+        for waiter in ready {
+            let _ = waiter.response.send(Ok(snapshot.clone()));
+        }
+    }
+
+    /// Install a finished background snapshot and compact the log behind it
+    ///
+    /// An inconsistent snapshot is dropped; the next one is attempted once
+    /// more entries have been applied.
+    fn finish_snapshot(&mut self, snapshot: Result<Snapshot>) {
+        self.snapshot_in_progress = false;
+
+        let snapshot = match snapshot {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!("Node {} discarded snapshot: {}", self.state.read().id, e);
+                self.apply_committed();
+                return;
+            }
+        };
+
+        // A snapshot installed from the leader meanwhile may already cover it
+        let last_included_index = snapshot.metadata.last_included_index;
+        let installed = self
+            .log
+            .get_snapshot()
+            .map(|s| s.metadata.last_included_index);
+        if installed.is_some_and(|index| index >= last_included_index) {
+            debug!(
+                "Node {} discarded snapshot through {} superseded by an installed one",
+                self.state.read().id,
+                last_included_index
+            );
+            self.release_snapshot_waiters();
+            self.apply_committed();
+            return;
+        }
+
+        if let Err(e) = self.log.set_snapshot(snapshot) {
+            warn!("Failed to store snapshot: {}", e);
+            for waiter in self.snapshot_waiters.drain(..) {
+                let _ = waiter.response.send(Err(RaftError::Internal(format!(
+                    "failed to store snapshot: {}",
+                    e
+                ))));
+            }
+        } else {
+            let trailing = self.config.snapshot_trailing_logs;
+            if last_included_index.0 > trailing {
+                if let Err(e) = self.log.compact(last_included_index - trailing) {
+                    warn!("Failed to compact log: {}", e);
+                }
+            }
+
+            info!(
+                "Node {} created snapshot through {}",
+                self.state.read().id,
+                last_included_index
+            );
+            self.emit(RaftEvent::SnapshotCreated {
+                last_included_index,
+            });
+            self.release_snapshot_waiters();
+        }
+
+        // Catch up on anything committed while the snapshot was being built
+        self.apply_committed();
+    }
+}
+
+/// Main node event loop
+async fn run_node<SM: StateMachine>(
+    mut inner: RaftNodeInner<SM>,
+    mut command_rx: mpsc::UnboundedReceiver<RaftCommand>,
+    mut read_rx: mpsc::UnboundedReceiver<ReadCommand>,
+) {
+    let id = inner.state.read().id;
+
+    let mut election_timer = interval(Duration::from_millis(50));
+    let mut heartbeat_timer = interval(inner.config.heartbeat_interval);
+    let mut since_read = 0;
+    let mut role = inner.state.read().role;
+    let mut draining: Option<(Instant, oneshot::Sender<Result<()>>)> = None;
+
+    loop {
+        // A read that has waited through `read_priority` other commands
+        // goes next, however many writes are queued behind it
+        let read_priority = inner.config.read_priority;
+        if read_priority > 0 && since_read >= read_priority {
+            since_read = 0;
+            if let Ok(read) = read_rx.try_recv() {
+                inner.handle_read_command(read);
+            }
+        }
+
+        tokio::select! {
+            Some(read) = read_rx.recv() => {
+                since_read = 0;
+                inner.handle_read_command(read);
+            }
+
+            // Handle incoming commands
+            Some(cmd) = command_rx.recv() => {
+                since_read += 1;
+                match cmd {
+                    RaftCommand::Propose {
+                        command,
+                        metadata,
+                        response,
+                    } => {
+                        inner.handle_propose(command, metadata, response);
+                    }
+
+                    RaftCommand::Forwarded {
+                        command,
+                        metadata,
+                        response,
+                    } => {
+                        inner.propose_locally(command, metadata, response);
+                    }
+
+                    RaftCommand::ApplyReady { response } => {
+                        let _ = response.send(inner.apply_ready());
+                    }
+
+                    RaftCommand::ConfirmApplied { through, response } => {
+                        let _ = response.send(inner.confirm_applied(through));
+                    }
+
+                    RaftCommand::ReadCommittedRange {
+                        start,
+                        end,
+                        response,
+                    } => {
+                        let _ = response.send(inner.read_committed_range(start, end));
+                    }
+
+                    RaftCommand::OnLeadership { callbacks, response } => {
+                        inner.add_leadership_callbacks(callbacks);
+                        let _ = response.send(());
+                    }
+
+                    RaftCommand::ProposalCancelled => {
+                        inner.drop_cancelled_proposals();
+                    }
+
+                    RaftCommand::ProposeNoWait { command, response } => {
+                        let _ = response.send(inner.append_command(command, None));
+                    }
+
+                    RaftCommand::RequestVote { request, response } => {
+                        let reply = inner.handle_request_vote(request);
+                        let _ = response.send(reply);
+                    }
+
+                    RaftCommand::AppendEntries { request, response } => {
+                        let reply = inner.handle_append_entries(request);
+                        let _ = response.send(reply);
+
+                        // Apply committed entries
+                        inner.apply_committed();
+                    }
+
+                    RaftCommand::InstallSnapshot { request, response } => {
+                        let reply = inner.handle_install_snapshot(request);
+                        let _ = response.send(reply);
+                    }
+
+                    RaftCommand::RestoreFinished {
+                        last_included_index,
+                    } => {
+                        inner.finish_restore(last_included_index);
+                    }
+
+                    RaftCommand::Applied { through, outputs } => {
+                        inner.finish_apply(through, outputs);
+                    }
+
+                    RaftCommand::VoteResponse {
+                        from,
+                        pre_vote: false,
+                        response,
+                    } => {
+                        inner.handle_vote_response(from, response);
+                    }
+
+                    RaftCommand::VoteResponse {
+                        from,
+                        pre_vote: true,
+                        response,
+                    } => {
+                        inner.handle_pre_vote_response(from, response);
+                    }
+
+                    RaftCommand::AppendResponse {
+                        from,
+                        sent_at,
+                        sent_through,
+                        response,
+                    } => {
+                        inner.handle_append_response(from, sent_at, sent_through, response);
+                    }
+
+                    RaftCommand::SnapshotSent {
+                        peer,
+                        last_included_index,
+                        attempts,
+                        result,
+                    } => {
+                        inner.handle_snapshot_sent(peer, last_included_index, attempts, result);
+                    }
+
+                    RaftCommand::Join { request, response } => {
+                        let _ = response.send(inner.handle_join(request));
+                    }
+
+                    RaftCommand::JoinCluster { seeds, response } => {
+                        inner.start_join(seeds, response);
+                    }
+
+                    RaftCommand::Joined { response } => {
+                        inner.join_waiters.push(response);
+                    }
+
+                    RaftCommand::WaitCaughtUp { response } => {
+                        inner.catch_up_waiters.push(response);
+                    }
+
+                    RaftCommand::SnapshotReady(snapshot) => {
+                        inner.finish_snapshot(snapshot);
+                    }
+
+                    RaftCommand::Ping { request, response } => {
+                        debug!("Node {} pinged by {}", id, request.from);
+                        let _ = response.send(PingResponse {
+                            term: inner.state.read().persistent.current_term,
+                            from: id,
+                        });
+                    }
+
+                    RaftCommand::PingPeer { peer, response } => {
+                        inner.ping_peer(peer, response);
+                    }
+
+                    RaftCommand::PingResult { peer, rtt } => {
+                        inner.record_ping(peer, rtt);
+                    }
+
+                    RaftCommand::ChangeMembership { change, response } => {
+                        let _ = response.send(inner.handle_change_membership(change));
+                    }
+
+                    RaftCommand::ConfigNoop { response } => {
+                        inner.handle_config_noop(response);
+                    }
+
+                    RaftCommand::ChangeConfiguration {
+                        target,
+                        force,
+                        response,
+                    } => {
+                        inner.handle_change_configuration(target, force, response);
+                    }
+
+                    RaftCommand::GetSnapshot { response } => {
+                        let _ = response.send(inner.log.get_snapshot());
+                    }
+
+                    RaftCommand::CreateSnapshot { response } => {
+                        inner.handle_create_snapshot(response);
+                    }
+
+                    RaftCommand::ExportBundle { response } => {
+                        let _ = response.send(inner.export_bundle());
+                    }
+
+                    RaftCommand::ImportBundle { bundle, response } => {
+                        let _ = response.send(inner.import_bundle(bundle));
+                    }
+
+                    RaftCommand::Members { response } => {
+                        let _ = response.send(inner.state.read().members());
+                    }
+
+                    RaftCommand::Metrics { response } => {
+                        let _ = response.send(inner.metrics());
+                    }
+
+                    RaftCommand::ReplicationProgress { response } => {
+                        let _ = response.send(inner.replication_progress());
+                    }
+                    RaftCommand::AppliedWatermark { response } => {
+                        let _ = response.send(inner.global_applied_watermark());
+                    }
+
+                    RaftCommand::Shutdown => {
+                        info!("Node {} shutting down", id);
+                        break;
+                    }
+
+                    RaftCommand::ShutdownDrained { deadline, response } => {
+                        info!("Node {} shutting down once applied", id);
+                        draining = Some((deadline, response));
+                    }
+                }
+            }
+
+            // Check for election timeout
+            _ = election_timer.tick() => {
+                let is_leader = inner.state.read().role == RaftRole::Leader;
+                if !is_leader && inner.is_election_timeout() {
+
+                    inner.expire_leader_waiters();
+
+                    inner.record_election_timeout();
+
+                    // Learners wait for a leader rather than standing for
+                    // election, and voters until the silence is confirmed
+                    let confirmed = inner.timeouts_without_leader
+                        >= inner.config.election_confirmation_timeouts;
+                    if !inner.state.read().is_voter() || !confirmed {
+                        inner.reset_election_timeout();
+                    } else if inner.config.pre_vote {
+                        inner.start_pre_vote();
+                    } else {
+                        inner.start_election();
+                    }
+                }
+            }
+
+            // Send heartbeats if leader
+            _ = heartbeat_timer.tick() => {
+                let is_leader = inner.state.read().role == RaftRole::Leader;
+                if is_leader {
+                    debug!("Node {} sending heartbeats", id);
+                    inner.replicate();
+                    inner.check_leader_quorum();
+
+                    // Covers a leader that is the only voter
+                    inner.maybe_advance_commit();
+                }
+            }
+        }
+
+        // Both timers run whatever the role, so their phase is left over
+        // from before a transition. Restart the one the new role relies on:
+        // a new leader would heartbeat early, and a deposed one could
+        // campaign on its next tick.
+        let new_role = inner.state.read().role;
+        if new_role != role {
+            match new_role {
+                RaftRole::Leader => heartbeat_timer.reset(),
+                RaftRole::Follower => inner.reset_election_timeout(),
+                RaftRole::Candidate => {}
+            }
+            role = new_role;
+        }
+        inner.notify_leadership();
+
+        inner.release_leader_waiters();
+        inner.release_join_waiters();
+        inner.release_catch_up_waiters();
+        inner.release_config_noops();
+        inner.confirm_configuration();
+        inner.leave_joint_configuration();
+        inner.advance_reconfiguration();
+        inner.step_down_if_removed();
+        inner.abandon_proposals();
+        inner.release_read_waiters();
+        inner.save_commit_hint();
+
+        // The election timer's ticks bring us back here to check the deadline
+        if let Some((deadline, _)) = &draining {
+            let state = inner.state.read();
+            let drained = state.volatile.last_applied >= state.volatile.commit_index;
+            drop(state);
+            if drained || Instant::now() >= *deadline {
+                if let Some((_, response)) = draining.take() {
+                    let _ = response.send(if drained {
+                        Ok(())
+                    } else {
+                        Err(RaftError::Timeout)
+                    });
+                }
+                info!("Node {} shutting down", id);
+                break;
+            }
+        }
+    }
+
+    inner.lose_leadership();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::{FileLogStorage, MemoryLogStorage};
+    use crate::observer::RpcKind;
+    use crate::state::{MemberRelation, MemberRole};
+    use crate::NotLeaderInfo;
+    use parking_lot::Mutex;
+
+    /// Simple key-value state machine for testing
+    struct KvStore {
+        data: std::collections::HashMap<String, String>,
+        noops: Vec<LogIndex>,
+        metadata: Vec<Vec<u8>>,
+    }
+
+    impl KvStore {
+        fn new() -> Self {
+            Self {
+                data: std::collections::HashMap::new(),
+                noops: vec![],
+                metadata: vec![],
+            }
+        }
+    }
+
+    impl StateMachine for KvStore {
+        fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+            let cmd = String::from_utf8_lossy(command);
+            let parts: Vec<&str> = cmd.split_whitespace().collect();
+
+            match parts.as_slice() {
+                ["SET", key, value] => {
+                    self.data.insert(key.to_string(), value.to_string());
+                    b"OK".to_vec()
+                }
+                ["GET", key] => self
+                    .data
+                    .get(*key)
+                    .map(|v| v.as_bytes().to_vec())
+                    .unwrap_or_default(),
+                _ => b"ERROR".to_vec(),
+            }
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            serde_json::to_vec(&self.data).unwrap()
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) {
+            self.data = serde_json::from_slice(snapshot).unwrap();
+        }
+
+        fn apply_noop(&mut self, index: LogIndex) {
+            self.noops.push(index);
+        }
+
+        fn apply_with_metadata(&mut self, command: &[u8], metadata: &[u8]) -> Vec<u8> {
+            self.metadata.push(metadata.to_vec());
+            self.apply(command)
+        }
+    }
+
+    fn test_inner(
+        id: NodeId,
+        peers: Vec<NodeId>,
+    ) -> (RaftNodeInner<KvStore>, broadcast::Receiver<RaftEvent>) {
+        test_inner_with_config(id, peers, RaftConfig::default())
+    }
+
+    fn test_inner_with_config(
+        id: NodeId,
+        peers: Vec<NodeId>,
+        config: RaftConfig,
+    ) -> (RaftNodeInner<KvStore>, broadcast::Receiver<RaftEvent>) {
+        let (events, rx) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let inner = RaftNodeInner::new(id, peers, config, KvStore::new(), events);
+        (inner, rx)
+    }
+
+    /// Replicate `entries` to a follower inner and commit all of them
+    fn replicate_and_commit(inner: &mut RaftNodeInner<KvStore>, entries: Vec<Entry>) {
+        let leader_commit = entries.last().map(|e| e.index).unwrap_or(LogIndex::ZERO);
+        let response = inner.handle_append_entries(AppendEntriesRequest {
+            term: Term(1),
+            leader_id: NodeId(2),
+            prev_log_index: LogIndex::ZERO,
+            prev_log_term: Term(0),
+            entries,
+            leader_commit,
+        });
+        assert!(response.success);
+        inner.apply_committed();
+    }
+
+    fn mixed_noop_entries() -> Vec<Entry> {
+        vec![
+            Entry::noop(Term(1), LogIndex(1)),
+            Entry::new(Term(1), LogIndex(2), b"SET a 1".to_vec()),
+            Entry::noop(Term(1), LogIndex(3)),
+            Entry::new(Term(1), LogIndex(4), b"SET b 2".to_vec()),
+        ]
+    }
+
+    #[test]
+    fn test_noops_hidden_from_state_machine_by_default() {
+        let peers = vec![NodeId(1), NodeId(2)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+
+        replicate_and_commit(&mut inner, mixed_noop_entries());
+
+        let sm = &inner.state_machine.read().machine;
+        assert!(sm.noops.is_empty());
+        assert_eq!(sm.data.len(), 2);
+        assert_eq!(inner.state.read().volatile.last_applied, LogIndex(4));
+    }
+
+    #[test]
+    fn test_noops_delivered_when_configured() {
+        let peers = vec![NodeId(1), NodeId(2)];
+        let config = crate::RaftConfigBuilder::new()
+            .deliver_noops_to_state_machine(true)
+            .build();
+        let (mut inner, _events) = test_inner_with_config(NodeId(1), peers, config);
+
+        replicate_and_commit(&mut inner, mixed_noop_entries());
+
+        let sm = &inner.state_machine.read().machine;
+        assert_eq!(sm.noops, vec![LogIndex(1), LogIndex(3)]);
+        assert_eq!(sm.data.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_records_learners() {
+        let config = crate::RaftConfigBuilder::new()
+            .snapshot_threshold(5)
+            .snapshot_trailing_logs(0)
+            .build();
+        let (mut inner, _events) =
+            test_inner_with_config(NodeId(1), vec![NodeId(1), NodeId(2), NodeId(3)], config);
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        inner.command_tx = command_tx;
+
+        // Node 3 is demoted to a learner before the snapshot is taken
+        let membership = ClusterConfig {
+            voters: vec![NodeId(1), NodeId(2)],
+            learners: vec![NodeId(3)],
+            ..Default::default()
+        };
+        let mut entries = vec![Entry::config_change(Term(1), LogIndex(1), &membership)];
+        entries.extend(
+            (2..=5).map(|i| Entry::new(Term(1), LogIndex(i), format!("SET k{} v", i).into_bytes())),
+        );
+        replicate_and_commit(&mut inner, entries);
+
+        let Some(RaftCommand::SnapshotReady(snapshot)) = command_rx.recv().await else {
+            panic!("snapshot task went away");
+        };
+        let snapshot = snapshot.unwrap();
+        assert_eq!(snapshot.metadata.last_included_index, LogIndex(5));
+        assert_eq!(snapshot.metadata.configuration, membership);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_snapshot_matches_applied_index() {
+        let peers = vec![NodeId(1), NodeId(2)];
+        let config = crate::RaftConfigBuilder::new()
+            .snapshot_threshold(5)
+            .snapshot_trailing_logs(0)
+            .build();
+        let (mut inner, _events) = test_inner_with_config(NodeId(1), peers, config);
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        inner.command_tx = command_tx;
+
+        let check = |snapshot: &Result<Snapshot>| {
+            let snapshot = snapshot.as_ref().expect("inconsistent snapshot");
+            let mut restored = KvStore::new();
+            restored.restore(&snapshot.data);
+            // Every entry sets a distinct key, so the key count is the
+            // index the data reflects
+            assert_eq!(
+                restored.data.len() as u64,
+                snapshot.metadata.last_included_index.0
+            );
+        };
+
+        let mut snapshots = 0;
+        for i in 1..=60u64 {
+            let prev_log_term = if i == 1 { Term(0) } else { Term(1) };
+            let response = inner.handle_append_entries(AppendEntriesRequest {
+                term: Term(1),
+                leader_id: NodeId(2),
+                prev_log_index: LogIndex(i - 1),
+                prev_log_term,
+                entries: vec![Entry::new(
+                    Term(1),
+                    LogIndex(i),
+                    format!("SET k{} v", i).into_bytes(),
+                )],
+                leader_commit: LogIndex(i),
+            });
+            assert!(response.success);
+            inner.apply_committed();
+
+            while let Ok(RaftCommand::SnapshotReady(snapshot)) = command_rx.try_recv() {
+                check(&snapshot);
+                inner.finish_snapshot(snapshot);
+                snapshots += 1;
+            }
+        }
+
+        while inner.snapshot_in_progress {
+            let Some(RaftCommand::SnapshotReady(snapshot)) = command_rx.recv().await else {
+                panic!("snapshot task went away");
+            };
+            check(&snapshot);
+            inner.finish_snapshot(snapshot);
+            snapshots += 1;
+        }
+
+        assert!(snapshots > 0);
+        assert_eq!(inner.state.read().volatile.last_applied, LogIndex(60));
+    }
+
+    #[tokio::test]
+    async fn test_inconsistent_snapshot_discarded() {
+        let peers = vec![NodeId(1), NodeId(2)];
+        let (mut inner, mut events) = test_inner(NodeId(1), peers);
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        inner.command_tx = command_tx;
+
+        replicate_and_commit(
+            &mut inner,
+            vec![Entry::new(Term(1), LogIndex(1), b"SET a 1".to_vec())],
+        );
+
+        // Knock the state machine's applied index out of step with Raft's
+        inner.state_machine.write().last_applied = LogIndex(7);
+        inner.config.snapshot_threshold = 1;
+        inner.maybe_start_snapshot();
+
+        let Some(RaftCommand::SnapshotReady(snapshot)) = command_rx.recv().await else {
+            panic!("snapshot task went away");
+        };
+        assert!(matches!(snapshot, Err(RaftError::Internal(_))));
+
+        inner.finish_snapshot(snapshot);
+        assert!(inner.log.get_snapshot().is_none());
+        assert!(events.try_recv().is_err());
+    }
+
+    /// Make a test inner the leader of its cluster
+    fn elect(inner: &mut RaftNodeInner<KvStore>) {
+        inner.state.write().become_candidate();
+        inner.become_leader();
+    }
+
+    /// Pretend everything in the leader's log has been replicated, and apply it
+    fn commit_all(inner: &mut RaftNodeInner<KvStore>) {
+        inner.state.write().volatile.commit_index = inner.log.last_index();
+        inner.apply_committed();
+    }
+
+    /// A leader of nodes 1 and 2 with ten entries before its no-op
+    fn leader_with_log() -> RaftNodeInner<KvStore> {
+        let (mut inner, _events) = test_inner(NodeId(1), vec![NodeId(1), NodeId(2)]);
+        let entries = (1..=10)
+            .map(|i| Entry::new(Term(1), LogIndex(i), format!("SET k{} v", i).into_bytes()))
+            .collect();
+        inner.log.append(entries).unwrap();
+        elect(&mut inner);
+        inner
+    }
+
+    fn rejection(
+        inner: &RaftNodeInner<KvStore>,
+        match_index: Option<LogIndex>,
+    ) -> AppendEntriesResponse {
+        AppendEntriesResponse {
+            term: inner.state.read().persistent.current_term,
+            success: false,
+            match_index,
+            conflict_term: None,
+            conflict_index: None,
+            commit_index: LogIndex::ZERO,
+            last_applied: LogIndex::ZERO,
+        }
+    }
+
+    fn next_index(inner: &RaftNodeInner<KvStore>, peer: NodeId) -> LogIndex {
+        let state = inner.state.read();
+        state
+            .leader_state
+            .as_ref()
+            .unwrap()
+            .get_next_index(peer)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rejection_without_match_index_backs_up_one() {
+        let mut inner = leader_with_log();
+        assert_eq!(next_index(&inner, NodeId(2)), LogIndex(11));
+
+        for expected in [10, 9, 8] {
+            let response = rejection(&inner, None);
+            inner.handle_append_response(NodeId(2), Instant::now(), LogIndex(10), response);
+            assert_eq!(next_index(&inner, NodeId(2)), LogIndex(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejection_with_match_index_jumps_back() {
+        let mut inner = leader_with_log();
+
+        // The follower only has four entries
+        let response = rejection(&inner, Some(LogIndex(4)));
+        inner.handle_append_response(NodeId(2), Instant::now(), LogIndex(10), response);
+        assert_eq!(next_index(&inner, NodeId(2)), LogIndex(5));
+
+        // A longer but conflicting log still only backs up one entry
+        let response = rejection(&inner, Some(LogIndex(20)));
+        inner.handle_append_response(NodeId(2), Instant::now(), LogIndex(10), response);
+        assert_eq!(next_index(&inner, NodeId(2)), LogIndex(4));
+    }
+
+    /// In-memory log counting plain and synced appends
+    #[derive(Default)]
+    struct SyncRecordingLog {
+        inner: MemoryLogStorage,
+        appends: Arc<std::sync::atomic::AtomicUsize>,
+        syncs: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl LogStorage for SyncRecordingLog {
+        fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+            self.appends
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.append(entries)
+        }
+
+        fn append_sync(&mut self, entries: Vec<Entry>) -> Result<()> {
+            self.syncs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.append(entries)
+        }
+
+        fn get(&self, index: LogIndex) -> Result<Option<Entry>> {
+            self.inner.get(index)
+        }
+
+        fn get_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Entry>> {
+            self.inner.get_range(start, end)
+        }
+
+        fn get_from(&self, start: LogIndex) -> Result<Vec<Entry>> {
+            self.inner.get_from(start)
+        }
+
+        fn delete_from(&mut self, index: LogIndex) -> Result<()> {
+            self.inner.delete_from(index)
+        }
+
+        fn last_index(&self) -> LogIndex {
+            self.inner.last_index()
+        }
+
+        fn last_term(&self) -> Term {
+            self.inner.last_term()
+        }
+
+        fn get_term(&self, index: LogIndex) -> Result<Option<Term>> {
+            self.inner.get_term(index)
+        }
+
+        fn set_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+            self.inner.set_snapshot(snapshot)
+        }
+
+        fn get_snapshot(&self) -> Option<Snapshot> {
+            self.inner.get_snapshot()
+        }
+
+        fn compact(&mut self, through_index: LogIndex) -> Result<()> {
+            self.inner.compact(through_index)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_config_change_appended_with_one_sync() {
+        use crate::log::{CachedLogStorage, LogCacheConfig};
+        use std::sync::atomic::Ordering;
+
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        let log = SyncRecordingLog::default();
+        let appends = Arc::clone(&log.appends);
+        let syncs = Arc::clone(&log.syncs);
+        let counts = || (appends.load(Ordering::SeqCst), syncs.load(Ordering::SeqCst));
+
+        // Behind a cache, which has to pass the sync on
+        let cached = CachedLogStorage::new(Box::new(log), LogCacheConfig::default());
+        inner.log = RaftLog::new(Box::new(cached));
+        elect(&mut inner);
+        commit_all(&mut inner);
+        assert_eq!(counts(), (1, 0));
+
+        inner
+            .handle_change_membership(MembershipChange::AddLearner(NodeId(4)))
+            .unwrap();
+        assert_eq!(counts(), (1, 1));
+        assert_eq!(
+            inner.log.get(inner.log.last_index()).unwrap().unwrap().kind,
+            EntryKind::ConfigChange
+        );
+
+        // Ordinary commands are left to the backend's plain appends
+        let (tx, _rx) = oneshot::channel();
+        inner.handle_propose(b"SET a 1".to_vec(), None, tx);
+        assert_eq!(counts(), (2, 1));
+    }
+
+    #[test]
+    fn test_demote_voter_to_learner_and_back() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        elect(&mut inner);
+        commit_all(&mut inner);
+
+        // Self plus one vote isn't a majority of four voters
+        let mut votes = crate::state::CandidateState::new();
+        votes.add_vote(NodeId(2));
+        assert_eq!(inner.state.read().effective_cluster_size(), 4);
+        assert!(!votes.has_majority(&inner.state.read()));
+
+        inner
+            .handle_change_membership(MembershipChange::Demote(NodeId(4)))
+            .unwrap();
+
+        // Nothing changes until the configuration entry is applied
+        assert_eq!(inner.state.read().peers.len(), 4);
+        assert!(matches!(
+            inner.handle_change_membership(MembershipChange::Demote(NodeId(3))),
+            Err(RaftError::InvalidConfiguration(_))
+        ));
+
+        commit_all(&mut inner);
+        {
+            let state = inner.state.read();
+            assert_eq!(state.peers, vec![NodeId(1), NodeId(2), NodeId(3)]);
+            assert_eq!(state.learners, vec![NodeId(4)]);
+            assert_eq!(state.effective_cluster_size(), 3);
+            assert!(votes.has_majority(&state));
+
+            // Still replicated to
+            let leader = state.leader_state.as_ref().unwrap();
+            assert!(leader.get_next_index(NodeId(4)).is_some());
+        }
+
+        inner
+            .handle_change_membership(MembershipChange::Promote(NodeId(4)))
+            .unwrap();
+        commit_all(&mut inner);
+
+        let state = inner.state.read();
+        assert_eq!(state.peers.len(), 4);
+        assert!(state.learners.is_empty());
+        assert_eq!(state.effective_cluster_size(), 4);
+        assert!(!votes.has_majority(&state));
+    }
+
+    /// What the main loop does after every event to move a reconfiguration on
+    fn reconfigure_step(inner: &mut RaftNodeInner<KvStore>) {
+        commit_all(inner);
+        inner.release_config_noops();
+        inner.confirm_configuration();
+        inner.leave_joint_configuration();
+        inner.advance_reconfiguration();
+        inner.step_down_if_removed();
+    }
+
+    #[tokio::test]
+    async fn test_change_configuration_goes_through_joint_configuration() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        elect(&mut inner);
+        reconfigure_step(&mut inner);
+
+        let target = vec![NodeId(2), NodeId(3), NodeId(4)];
+        let (tx, mut rx) = oneshot::channel();
+        inner.handle_change_configuration(
+            ClusterConfig {
+                voters: target.clone(),
+                ..Default::default()
+            },
+            true,
+            tx,
+        );
+
+        // The newcomer is added as a learner and nothing more happens until
+        // it has caught up
+        let latest = inner.latest_configuration().unwrap();
+        assert_eq!(latest.learners, vec![NodeId(4)]);
+        assert!(!latest.is_joint());
+        reconfigure_step(&mut inner);
+        reconfigure_step(&mut inner);
+        assert!(!inner.latest_configuration().unwrap().is_joint());
+
+        let last_index = inner.log.last_index();
+        let mut state = inner.state.write();
+        let leader = state.leader_state.as_mut().unwrap();
+        leader.set_match_index(NodeId(4), last_index);
+        drop(state);
+        inner.advance_reconfiguration();
+        let joint = inner.latest_configuration().unwrap();
+        assert_eq!(joint.voters, target);
+        assert_eq!(joint.outgoing_voters, vec![NodeId(1), NodeId(2), NodeId(3)]);
+
+        // Once applied, both the old and the new voters count, and the
+        // leader keeps leading on the strength of the old ones
+        reconfigure_step(&mut inner);
+        {
+            let state = inner.state.read();
+            assert_eq!(state.configuration(), joint);
+            assert_eq!(state.role, RaftRole::Leader);
+            let mut votes = crate::state::CandidateState::new();
+            votes.add_vote(NodeId(2));
+            assert!(!votes.has_majority(&state));
+            votes.add_vote(NodeId(3));
+            assert!(votes.has_majority(&state));
+        }
+        assert!(rx.try_recv().is_err());
+
+        // The leader moves on to the new voters alone, and the call returns
+        // once that's applied
+        reconfigure_step(&mut inner);
+        assert_eq!(
+            inner.latest_configuration().unwrap(),
+            ClusterConfig {
+                voters: target.clone(),
+                ..Default::default()
+            }
+        );
+        reconfigure_step(&mut inner);
+        assert!(matches!(rx.try_recv(), Ok(Ok(()))));
+        {
+            let state = inner.state.read();
+            assert_eq!(state.peers, target);
+            assert!(state.outgoing_voters.is_empty());
+            assert!(!state.is_voter());
+        }
+
+        // No longer a voter, it only steps down once a quorum of the new
+        // voters has heard the configuration committed
+        inner.step_down_if_removed();
+        assert_eq!(inner.state.read().role, RaftRole::Leader);
+        let commit_index = inner.state.read().volatile.commit_index;
+        for node in [NodeId(2), NodeId(3)] {
+            let mut state = inner.state.write();
+            let leader = state.leader_state.as_mut().unwrap();
+            leader.set_match_index(node, commit_index);
+            drop(state);
+
+            assert_eq!(inner.state.read().role, RaftRole::Leader);
+            inner.lease_acks.insert(node, Instant::now());
+            inner.step_down_if_removed();
+        }
+        assert_eq!(inner.state.read().role, RaftRole::Follower);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_removed_leader_steps_down_unacknowledged() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        elect(&mut inner);
+        reconfigure_step(&mut inner);
+        inner.state.write().set_configuration(
+            ClusterConfig {
+                voters: vec![NodeId(2), NodeId(3)],
+                ..Default::default()
+            },
+            inner.log.last_index(),
+        );
+
+        // The new voters never answer, so it gives up waiting on them
+        inner.step_down_if_removed();
+        tokio::time::advance(inner.config.heartbeat_interval * (HANDOVER_HEARTBEATS - 1)).await;
+        inner.step_down_if_removed();
+        assert_eq!(inner.state.read().role, RaftRole::Leader);
+
+        tokio::time::advance(inner.config.heartbeat_interval).await;
+        inner.step_down_if_removed();
+        assert_eq!(inner.state.read().role, RaftRole::Follower);
+    }
+
+    /// Apply committed entries one index at a time, recording the voters in
+    /// effect after each
+    fn step_apply(
+        inner: &mut RaftNodeInner<KvStore>,
+        through: LogIndex,
+        timeline: &mut Vec<(LogIndex, Vec<NodeId>)>,
+    ) {
+        loop {
+            let mut state = inner.state.write();
+            if state.volatile.last_applied >= through {
+                return;
+            }
+            let next = state.volatile.last_applied + 1;
+            state.volatile.commit_index = next;
+            drop(state);
+
+            inner.apply_committed();
+            timeline.push((next, inner.state.read().peers.clone()));
+        }
+    }
+
+    fn propose(inner: &mut RaftNodeInner<KvStore>, command: &str) {
+        let (tx, _rx) = oneshot::channel();
+        inner.handle_propose(command.as_bytes().to_vec(), None, tx);
+    }
+
+    #[test]
+    fn test_config_changes_apply_at_their_log_position() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4)];
+        let (mut leader, _events) = test_inner(NodeId(1), peers.clone());
+        elect(&mut leader);
+
+        let mut leader_timeline = Vec::new();
+        propose(&mut leader, "SET a 1");
+        leader
+            .handle_change_membership(MembershipChange::Demote(NodeId(4)))
+            .unwrap();
+        propose(&mut leader, "SET b 2");
+
+        // The leader replicates under the appended configuration while its
+        // applied membership hasn't moved yet
+        let latest = leader.latest_configuration().unwrap();
+        assert_eq!(latest.learners, vec![NodeId(4)]);
+        assert_eq!(leader.state.read().peers.len(), 4);
+
+        step_apply(&mut leader, LogIndex(4), &mut leader_timeline);
+        leader
+            .handle_change_membership(MembershipChange::Promote(NodeId(4)))
+            .unwrap();
+        propose(&mut leader, "SET c 3");
+        step_apply(&mut leader, LogIndex(6), &mut leader_timeline);
+
+        let four = peers.clone();
+        let three = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let promoted = vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4)];
+        assert_eq!(
+            leader_timeline,
+            vec![
+                (LogIndex(1), four.clone()),
+                (LogIndex(2), four.clone()),
+                (LogIndex(3), three.clone()),
+                (LogIndex(4), three.clone()),
+                (LogIndex(5), promoted.clone()),
+                (LogIndex(6), promoted),
+            ]
+        );
+
+        // Followers receive the whole log at once but switch membership at
+        // exactly the same indices
+        let entries = leader.log.get_from(LogIndex(1)).unwrap();
+        for id in [NodeId(2), NodeId(3)] {
+            let (mut follower, _events) = test_inner(id, peers.clone());
+            let response = follower.handle_append_entries(AppendEntriesRequest {
+                term: Term(1),
+                leader_id: NodeId(1),
+                prev_log_index: LogIndex::ZERO,
+                prev_log_term: Term(0),
+                entries: entries.clone(),
+                leader_commit: LogIndex::ZERO,
+            });
+            assert!(response.success);
+
+            let mut timeline = Vec::new();
+            step_apply(&mut follower, LogIndex(6), &mut timeline);
+            assert_eq!(timeline, leader_timeline);
+            assert_eq!(
+                follower.state_machine.read().machine.data,
+                leader.state_machine.read().machine.data
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_membership_changes_rejected() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+
+        assert!(matches!(
+            inner.handle_change_membership(MembershipChange::Demote(NodeId(2))),
+            Err(RaftError::NotLeader(NotLeaderInfo { leader: None, .. }))
+        ));
+
+        elect(&mut inner);
+        for change in [
+            MembershipChange::Demote(NodeId(1)),
+            MembershipChange::Demote(NodeId(9)),
+            MembershipChange::Promote(NodeId(2)),
+        ] {
+            assert!(matches!(
+                inner.handle_change_membership(change),
+                Err(RaftError::InvalidConfiguration(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_heartbeat_carries_commit_to_follower() {
+        let mut leader = leader_with_log();
+        let (mut follower, _events) = test_inner(NodeId(2), vec![NodeId(1), NodeId(2)]);
+        let request_for = |leader: &RaftNodeInner<KvStore>, max_entries| {
+            let state = leader.state.read();
+            leader
+                .append_request(&state, NodeId(2), max_entries)
+                .unwrap()
+        };
+
+        // The follower receives the whole log before any of it commits
+        if let Some(progress) = leader.state.write().leader_state.as_mut() {
+            progress.set_next_index(NodeId(2), LogIndex(1));
+        }
+        let replicate = request_for(&leader, 100);
+        assert_eq!(replicate.entries.len(), 11);
+        assert_eq!(replicate.leader_commit, LogIndex::ZERO);
+        assert!(follower.handle_append_entries(replicate).success);
+        follower.apply_committed();
+        assert_eq!(follower.state.read().volatile.last_applied, LogIndex::ZERO);
+
+        // Once the leader commits, an empty heartbeat is enough to apply it
+        commit_all(&mut leader);
+        if let Some(progress) = leader.state.write().leader_state.as_mut() {
+            progress.set_next_index(NodeId(2), LogIndex(12));
+        }
+        let heartbeat = request_for(&leader, 0);
+        assert!(heartbeat.entries.is_empty());
+        assert_eq!(heartbeat.leader_commit, LogIndex(11));
+        assert!(follower.handle_append_entries(heartbeat).success);
+        follower.apply_committed();
+        assert_eq!(follower.state.read().volatile.last_applied, LogIndex(11));
+        assert_eq!(
+            follower.state_machine.read().machine.data,
+            leader.state_machine.read().machine.data
+        );
+    }
+
+    #[test]
+    fn test_last_applied_never_outruns_log() {
+        let peers = vec![NodeId(1), NodeId(2)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        let entries = (1..=3)
+            .map(|i| Entry::new(Term(1), LogIndex(i), format!("SET k{} v", i).into_bytes()))
+            .collect();
+        let response = inner.handle_append_entries(AppendEntriesRequest {
+            term: Term(1),
+            leader_id: NodeId(2),
+            prev_log_index: LogIndex::ZERO,
+            prev_log_term: Term(0),
+            entries,
+            leader_commit: LogIndex(u64::MAX),
+        });
+        assert!(response.success);
+        inner.apply_committed();
+        assert_eq!(inner.state.read().volatile.last_applied, LogIndex(3));
+
+        // Even a commit index that got past the log some other way
+        inner.state.write().volatile.commit_index = LogIndex(10);
+        inner.apply_committed();
+        let state = inner.state.read();
+        assert_eq!(state.volatile.commit_index, LogIndex(3));
+        assert_eq!(state.volatile.last_applied, LogIndex(3));
+        assert_eq!(inner.state_machine.read().machine.data.len(), 3);
+    }
+
+    #[test]
+    fn test_rejection_reports_conflicting_term() {
+        let (mut inner, _events) = test_inner(NodeId(2), vec![NodeId(1), NodeId(2)]);
+        let entries = [1, 2, 2, 2]
+            .into_iter()
+            .zip(1..)
+            .map(|(term, index)| Entry::new(Term(term), LogIndex(index), b"SET a 1".to_vec()))
+            .collect();
+        inner.log.append(entries).unwrap();
+        let append_after = |inner: &mut RaftNodeInner<KvStore>, index: u64, term: u64| {
+            inner.handle_append_entries(AppendEntriesRequest::heartbeat(
+                Term(3),
+                NodeId(1),
+                LogIndex(index),
+                Term(term),
+                LogIndex::ZERO,
+            ))
+        };
+
+        // Our entry 4 is from term 2, which started at entry 2
+        let response = append_after(&mut inner, 4, 3);
+        assert!(!response.success);
+        assert_eq!(response.conflict_term, Some(Term(2)));
+        assert_eq!(response.conflict_index, Some(LogIndex(2)));
+        assert_eq!(response.match_index, Some(LogIndex(4)));
+
+        // Past the end of our log there's no term to report
+        let response = append_after(&mut inner, 6, 3);
+        assert_eq!(response.conflict_term, None);
+        assert_eq!(response.conflict_index, Some(LogIndex(5)));
+
+        assert!(append_after(&mut inner, 4, 2).success);
+    }
+
+    #[tokio::test]
+    async fn test_conflicting_term_skipped_in_one_step() {
+        // Three entries from term 1 and three from term 3, then leader for
+        // term 4, first offering node 2 everything from 7
+        let leader = || {
+            let (mut inner, _events) = test_inner(NodeId(1), vec![NodeId(1), NodeId(2)]);
+            let entries = [1, 1, 1, 3, 3, 3]
+                .into_iter()
+                .zip(1..)
+                .map(|(term, index)| Entry::new(Term(term), LogIndex(index), b"SET a 1".to_vec()))
+                .collect();
+            inner.log.append(entries).unwrap();
+            inner.state.write().persistent.current_term = Term(3);
+            elect(&mut inner);
+            assert_eq!(next_index(&inner, NodeId(2)), LogIndex(7));
+            inner
+        };
+        let conflict =
+            |inner: &RaftNodeInner<KvStore>, term: Option<u64>, index: u64| AppendEntriesResponse {
+                conflict_term: term.map(Term),
+                conflict_index: Some(LogIndex(index)),
+                ..rejection(inner, Some(LogIndex(9)))
+            };
+
+        // We have nothing from the follower's term 2, so all of it goes
+        let mut inner = leader();
+        let response = conflict(&inner, Some(2), 4);
+        inner.handle_append_response(NodeId(2), Instant::now(), LogIndex(7), response);
+        assert_eq!(next_index(&inner, NodeId(2)), LogIndex(4));
+
+        // We share its term 1, so resume after our last entry of it
+        let mut inner = leader();
+        let response = conflict(&inner, Some(1), 1);
+        inner.handle_append_response(NodeId(2), Instant::now(), LogIndex(7), response);
+        assert_eq!(next_index(&inner, NodeId(2)), LogIndex(4));
+
+        // A log too short to conflict resumes just past its end
+        let mut inner = leader();
+        let response = conflict(&inner, None, 3);
+        inner.handle_append_response(NodeId(2), Instant::now(), LogIndex(7), response);
+        assert_eq!(next_index(&inner, NodeId(2)), LogIndex(3));
+    }
+
+    #[tokio::test]
+    async fn test_leader_only_counts_replicas_of_its_own_term() {
+        // Ten entries from term 1, then leader for term 2 with its no-op
+        let (mut inner, _events) = test_inner(NodeId(1), vec![NodeId(1), NodeId(2)]);
+        let entries = (1..=10)
+            .map(|i| Entry::new(Term(1), LogIndex(i), format!("SET k{} v", i).into_bytes()))
+            .collect();
+        inner.log.append(entries).unwrap();
+        inner.state.write().persistent.current_term = Term(1);
+        elect(&mut inner);
+        let term = inner.state.read().persistent.current_term;
+        assert_eq!(term, Term(2));
+        let ack = |through: u64| AppendEntriesResponse {
+            term,
+            success: true,
+            match_index: Some(LogIndex(through)),
+            conflict_term: None,
+            conflict_index: None,
+            commit_index: LogIndex::ZERO,
+            last_applied: LogIndex::ZERO,
+        };
+
+        // Node 2 holds the earlier term's entries, a majority of two, but
+        // they can't be committed by counting
+        inner.handle_append_response(NodeId(2), Instant::now(), LogIndex(10), ack(10));
+        assert_eq!(inner.state.read().volatile.commit_index, LogIndex::ZERO);
+
+        // The leader's no-op commits them along with itself
+        inner.handle_append_response(NodeId(2), Instant::now(), LogIndex(11), ack(11));
+        let state = inner.state.read();
+        assert_eq!(state.volatile.commit_index, LogIndex(11));
+        assert_eq!(state.volatile.last_applied, LogIndex(11));
+    }
+
+    #[test]
+    fn test_new_leader_commits_earlier_terms_through_its_noop() {
+        // The log a Figure 8 leader inherits: entries from two earlier
+        // terms, neither known to be committed
+        let voters: Vec<NodeId> = (1..=5).map(NodeId).collect();
+        let (mut inner, _events) = test_inner(NodeId(1), voters);
+        inner
+            .log
+            .append(vec![
+                Entry::new(Term(1), LogIndex(1), b"SET a 1".to_vec()),
+                Entry::new(Term(2), LogIndex(2), b"SET b 2".to_vec()),
+            ])
+            .unwrap();
+        inner.state.write().persistent.current_term = Term(2);
+        elect(&mut inner);
+        let term = inner.state.read().persistent.current_term;
+        assert_eq!(term, Term(3));
+
+        // Taking over appended a no-op of the new term behind them
+        let noop = inner.log.get(LogIndex(3)).unwrap().unwrap();
+        assert!(noop.is_noop());
+        assert_eq!(noop.term, term);
+
+        let ack = |through: u64| AppendEntriesResponse {
+            term,
+            success: true,
+            match_index: Some(LogIndex(through)),
+            conflict_term: None,
+            conflict_index: None,
+            commit_index: LogIndex::ZERO,
+            last_applied: LogIndex::ZERO,
+        };
+        let commit_index =
+            |inner: &RaftNodeInner<KvStore>| inner.state.read().volatile.commit_index;
+
+        // A majority holding the earlier entries isn't enough
+        inner.handle_append_response(NodeId(2), Instant::now(), LogIndex(2), ack(2));
+        inner.handle_append_response(NodeId(3), Instant::now(), LogIndex(2), ack(2));
+        assert_eq!(commit_index(&inner), LogIndex::ZERO);
+
+        // Nor is the no-op on a minority
+        inner.handle_append_response(NodeId(4), Instant::now(), LogIndex(3), ack(3));
+        assert_eq!(commit_index(&inner), LogIndex::ZERO);
+
+        // Once the no-op reaches a majority, everything before it commits
+        inner.handle_append_response(NodeId(2), Instant::now(), LogIndex(3), ack(3));
+        assert_eq!(commit_index(&inner), LogIndex(3));
+        assert_eq!(inner.state.read().volatile.last_applied, LogIndex(3));
+    }
+
+    #[test]
+    fn test_leader_commit_clamped_to_local_log() {
+        let peers = vec![NodeId(1), NodeId(2)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        let append = |inner: &mut RaftNodeInner<KvStore>, prev: u64, entries: Vec<Entry>| {
+            inner.handle_append_entries(AppendEntriesRequest {
+                term: Term(1),
+                leader_id: NodeId(2),
+                prev_log_index: LogIndex(prev),
+                prev_log_term: if prev == 0 { Term(0) } else { Term(1) },
+                entries,
+                leader_commit: LogIndex(1000),
+            })
+        };
+
+        let entries = (1..=3)
+            .map(|i| Entry::new(Term(1), LogIndex(i), format!("SET k{} v", i).into_bytes()))
+            .collect();
+        let response = append(&mut inner, 0, entries);
+        assert!(response.success);
+        assert_eq!(response.commit_index, LogIndex(3));
+
+        // A heartbeat from a leader far ahead still only commits what we hold
+        let response = append(&mut inner, 3, vec![]);
+        assert!(response.success);
+        assert_eq!(response.commit_index, LogIndex(3));
+
+        // A delayed request covering less of the log doesn't pull it back
+        let response = append(&mut inner, 0, vec![]);
+        assert!(response.success);
+        assert_eq!(response.commit_index, LogIndex(3));
+        assert_eq!(inner.state.read().volatile.commit_index, LogIndex(3));
+    }
+
+    #[tokio::test]
+    async fn test_clone_replica_from_bundle() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let config = crate::RaftConfigBuilder::new()
+            .snapshot_threshold(5)
+            .snapshot_trailing_logs(0)
+            .build();
+        let (mut source, _events) = test_inner_with_config(NodeId(2), peers.clone(), config);
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        source.command_tx = command_tx;
+
+        let entry = |i: u64| {
+            Entry::new(
+                Term(1),
+                LogIndex(i),
+                format!("SET k{} v{}", i, i).into_bytes(),
+            )
+        };
+        let append = |inner: &mut RaftNodeInner<KvStore>, prev: u64, last: u64| {
+            let response = inner.handle_append_entries(AppendEntriesRequest {
+                term: Term(1),
+                leader_id: NodeId(1),
+                prev_log_index: LogIndex(prev),
+                prev_log_term: if prev == 0 { Term(0) } else { Term(1) },
+                entries: (prev + 1..=last).map(entry).collect(),
+                leader_commit: LogIndex(last),
+            });
+            assert!(response.success);
+            inner.apply_committed();
+        };
+
+        // Snapshot at 8, then two more committed entries on top
+        append(&mut source, 0, 8);
+        let Some(RaftCommand::SnapshotReady(snapshot)) = command_rx.recv().await else {
+            panic!("snapshot task went away");
+        };
+        source.finish_snapshot(snapshot);
+        append(&mut source, 8, 10);
+
+        let bundle = source.export_bundle().unwrap();
+        assert_eq!(
+            bundle
+                .snapshot
+                .as_ref()
+                .unwrap()
+                .metadata
+                .last_included_index,
+            LogIndex(8)
+        );
+        assert_eq!(bundle.entries.len(), 2);
+
+        // The bundle survives being shipped over the wire
+        let bytes = bincode::serialize(&bundle).unwrap();
+        let bundle: StateBundle = bincode::deserialize(&bytes).unwrap();
+
+        let (mut replica, _events) = test_inner(NodeId(3), peers);
+        replica.import_bundle(bundle).unwrap();
+        assert_eq!(replica.state.read().volatile.last_applied, LogIndex(10));
+        assert_eq!(replica.log.get(LogIndex(8)).unwrap().map(|e| e.index), None);
+
+        // Catching up only needs what was committed after the export
+        append(&mut source, 10, 12);
+        append(&mut replica, 10, 12);
+        assert_eq!(
+            replica.state_machine.read().machine.data,
+            source.state_machine.read().machine.data
+        );
+        assert_eq!(replica.state_machine.read().machine.data.len(), 12);
+
+        // A node that already has a log can't be overwritten
+        let again = source.export_bundle().unwrap();
+        assert!(matches!(
+            replica.import_bundle(again),
+            Err(RaftError::InvalidBundle(_))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_vote_request_does_not_reset_timer() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        let request = RequestVoteRequest {
+            term: Term(1),
+            candidate_id: NodeId(2),
+            last_log_index: LogIndex::ZERO,
+            last_log_term: Term(0),
+            pre_vote: false,
+        };
+
+        let stale_heartbeat = Instant::now() - Duration::from_secs(1);
+        inner.last_heartbeat = stale_heartbeat;
+        assert!(inner.handle_request_vote(request.clone()).vote_granted);
+        assert!(inner.last_heartbeat > stale_heartbeat);
+
+        // Same candidate asks again: granted again, timer left alone
+        inner.last_heartbeat = stale_heartbeat;
+        let response = inner.handle_request_vote(request);
+        assert!(response.vote_granted);
+        assert_eq!(inner.last_heartbeat, stale_heartbeat);
+        assert_eq!(inner.state.read().persistent.voted_for, Some(NodeId(2)));
+    }
+
+    fn vote_request(term: u64, candidate: u64, last_log_term: u64) -> RequestVoteRequest {
+        RequestVoteRequest {
+            term: Term(term),
+            candidate_id: NodeId(candidate),
+            last_log_index: LogIndex(last_log_term),
+            last_log_term: Term(last_log_term),
+            pre_vote: false,
+        }
+    }
+
+    #[test]
+    fn test_candidate_denies_rival_in_same_term() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        inner.state.write().become_candidate();
+        let term = inner.state.read().persistent.current_term;
+
+        let response = inner.handle_request_vote(vote_request(term.0, 2, 0));
+        assert!(!response.vote_granted);
+        assert_eq!(response.denial_reason, Some(VoteDenialReason::AlreadyVoted));
+        let state = inner.state.read();
+        assert_eq!(state.role, RaftRole::Candidate);
+        assert_eq!(state.persistent.voted_for, Some(NodeId(1)));
+        drop(state);
+
+        // The role alone is enough to refuse, without the self-vote on record
+        inner.state.write().persistent.voted_for = None;
+        let response = inner.handle_request_vote(vote_request(term.0, 3, 0));
+        assert!(!response.vote_granted);
+        assert_eq!(response.denial_reason, Some(VoteDenialReason::AlreadyVoted));
+        assert_eq!(inner.state.read().persistent.voted_for, None);
+
+        // A later term makes it a follower free to vote again
+        let response = inner.handle_request_vote(vote_request(term.0 + 1, 3, 0));
+        assert!(response.vote_granted);
+    }
+
+    #[test]
+    fn test_vote_denial_reasons() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        inner.state.write().become_follower(Term(3), None);
+
+        let response = inner.handle_request_vote(vote_request(2, 2, 0));
+        assert!(!response.vote_granted);
+        assert_eq!(response.denial_reason, Some(VoteDenialReason::StaleTerm));
+
+        let response = inner.handle_request_vote(vote_request(3, 2, 0));
+        assert!(response.vote_granted);
+        assert_eq!(response.denial_reason, None);
+        let response = inner.handle_request_vote(vote_request(3, 3, 0));
+        assert_eq!(response.denial_reason, Some(VoteDenialReason::AlreadyVoted));
+
+        inner
+            .log
+            .append(vec![Entry::new(Term(3), LogIndex(1), b"SET a 1".to_vec())])
+            .unwrap();
+        let response = inner.handle_request_vote(vote_request(4, 3, 0));
+        assert_eq!(
+            response.denial_reason,
+            Some(VoteDenialReason::LogNotUpToDate)
+        );
+        assert_eq!(response.term, Term(4));
+
+        // Outsiders don't even get to move our term on
+        let response = inner.handle_request_vote(vote_request(9, 7, 5));
+        assert_eq!(response.denial_reason, Some(VoteDenialReason::NotAMember));
+        assert_eq!(inner.state.read().persistent.current_term, Term(4));
+    }
+
+    #[test]
+    fn test_vote_denied_while_leader_is_alive() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        let heartbeat = inner.handle_append_entries(AppendEntriesRequest {
+            term: Term(3),
+            leader_id: NodeId(2),
+            prev_log_index: LogIndex::ZERO,
+            prev_log_term: Term(0),
+            entries: vec![],
+            leader_commit: LogIndex::ZERO,
+        });
+        assert!(heartbeat.success);
+
+        let response = inner.handle_request_vote(vote_request(4, 3, 0));
+        assert!(!response.vote_granted);
+        assert_eq!(
+            response.denial_reason,
+            Some(VoteDenialReason::RecentLeaderContact)
+        );
+        assert_eq!(response.term, Term(3));
+
+        // Once the leader has been silent for an election timeout, the
+        // candidate is heard
+        inner.last_leader_contact = Some(Instant::now() - Duration::from_secs(1));
+        let response = inner.handle_request_vote(vote_request(4, 3, 0));
+        assert!(response.vote_granted);
+        assert_eq!(response.denial_reason, None);
+    }
+
+    #[test]
+    fn test_propose_racing_step_down() {
+        for _ in 0..20 {
+            let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+            let (mut inner, _events) = test_inner(NodeId(1), peers);
+            {
+                let mut state = inner.state.write();
+                state.become_follower(Term(2), None);
+                state.become_leader(LogIndex::ZERO);
+            }
+
+            // Another task learns of a newer term while proposals stream in,
+            // noting how far the log reached when it did
+            let state = Arc::clone(&inner.state);
+            let log = inner.log.clone();
+            let step_down = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_micros(100));
+                let mut state = state.write();
+                state.become_follower(Term(3), Some(NodeId(2)));
+                log.last_index()
+            });
+
+            // Keep proposing until the step-down lands, then a few more times
+            let mut rejected = 0;
+            let mut accepted = Vec::new();
+            for i in 0..1_000_000u32 {
+                match inner.append_command(i.to_le_bytes().to_vec(), None) {
+                    Ok(index) => {
+                        assert_eq!(rejected, 0, "appended after a NotLeader");
+                        accepted.push(index);
+                    }
+                    Err(RaftError::NotLeader(info)) => {
+                        assert_eq!(info.leader, Some(NodeId(2)));
+                        rejected += 1;
+                        if rejected == 10 {
+                            break;
+                        }
+                    }
+                    Err(e) => panic!("unexpected error: {}", e),
+                }
+            }
+            let stepped_down_at = step_down.join().unwrap();
+            assert_eq!(rejected, 10);
+
+            // Everything that got in was appended while we still led
+            assert_eq!(inner.log.last_index(), LogIndex(accepted.len() as u64));
+            assert_eq!(inner.log.last_index(), stepped_down_at);
+            for index in accepted {
+                assert_eq!(inner.log.get_term(index).unwrap(), Some(Term(2)));
+            }
+        }
+    }
+
+    /// A follower at term 3 that last heard from leader 2
+    fn follower_at_term_3() -> RaftNodeInner<KvStore> {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (inner, _events) = test_inner(NodeId(1), peers);
+        inner
+            .state
+            .write()
+            .become_follower(Term(3), Some(NodeId(2)));
+        inner
+    }
+
+    #[test]
+    fn test_stale_term_append_rejected() {
+        let mut inner = follower_at_term_3();
+        let stale_heartbeat = Instant::now() - Duration::from_secs(1);
+        inner.last_heartbeat = stale_heartbeat;
+
+        let response = inner.handle_append_entries(AppendEntriesRequest {
+            term: Term(2),
+            leader_id: NodeId(3),
+            prev_log_index: LogIndex::ZERO,
+            prev_log_term: Term(0),
+            entries: vec![Entry::new(Term(2), LogIndex(1), b"SET a 1".to_vec())],
+            leader_commit: LogIndex::ZERO,
+        });
+
+        assert!(!response.success);
+        assert_eq!(response.term, Term(3));
+        assert_eq!(inner.log.last_index(), LogIndex::ZERO);
+        assert_eq!(inner.last_heartbeat, stale_heartbeat);
+        let state = inner.state.read();
+        assert_eq!(state.persistent.current_term, Term(3));
+        assert_eq!(state.leader_id, Some(NodeId(2)));
+    }
+
+    #[test]
+    fn test_stale_append_without_leader_triggers_election() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        inner.state.write().become_follower(Term(3), None);
+        inner.reset_election_timeout();
+        assert!(!inner.is_election_timeout());
+
+        let response = inner.handle_append_entries(AppendEntriesRequest {
+            term: Term(2),
+            leader_id: NodeId(2),
+            prev_log_index: LogIndex::ZERO,
+            prev_log_term: Term(0),
+            entries: vec![],
+            leader_commit: LogIndex::ZERO,
+        });
+
+        assert!(!response.success);
+        assert_eq!(response.term, Term(3));
+        assert!(inner.is_election_timeout());
+    }
+
+    #[test]
+    fn test_equal_term_append_steps_candidate_down() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        inner.state.write().become_candidate();
+        let term = inner.state.read().persistent.current_term;
+
+        let response = inner.handle_append_entries(AppendEntriesRequest::heartbeat(
+            term,
+            NodeId(2),
+            LogIndex::ZERO,
+            Term(0),
+            LogIndex::ZERO,
+        ));
+
+        assert!(response.success);
+        assert_eq!(response.term, term);
+        let state = inner.state.read();
+        assert_eq!(state.role, RaftRole::Follower);
+        assert!(state.candidate_state.is_none());
+        assert_eq!(state.leader_id, Some(NodeId(2)));
+        assert_eq!(state.persistent.current_term, term);
+        assert_eq!(state.persistent.voted_for, Some(NodeId(1)));
+    }
+
+    #[test]
+    fn test_higher_term_append_adopts_term() {
+        let mut inner = follower_at_term_3();
+        inner.state.write().persistent.voted_for = Some(NodeId(2));
+
+        let response = inner.handle_append_entries(AppendEntriesRequest::heartbeat(
+            Term(5),
+            NodeId(3),
+            LogIndex::ZERO,
+            Term(0),
+            LogIndex::ZERO,
+        ));
+
+        assert!(response.success);
+        assert_eq!(response.term, Term(5));
+        assert_eq!(inner.hard_state.saved.current_term, Term(5));
+        let state = inner.state.read();
+        assert_eq!(state.role, RaftRole::Follower);
+        assert_eq!(state.leader_id, Some(NodeId(3)));
+        assert_eq!(state.persistent.current_term, Term(5));
+        assert_eq!(state.persistent.voted_for, None);
+    }
+
+    #[test]
+    fn test_same_term_leader_is_safety_violation() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, mut events) = test_inner(NodeId(1), peers);
+        {
+            let mut state = inner.state.write();
+            state.become_candidate();
+            state.become_leader(LogIndex::ZERO);
+        }
+        let term = inner.state.read().persistent.current_term;
+
+        let response = inner.handle_append_entries(AppendEntriesRequest::heartbeat(
+            term,
+            NodeId(2),
+            LogIndex::ZERO,
+            Term(0),
+            LogIndex::ZERO,
+        ));
+
+        assert!(!response.success);
+        assert_eq!(inner.state.read().role, RaftRole::Follower);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            RaftEvent::SafetyViolation {
+                term,
+                violation: SafetyViolation::ConflictingLeader {
+                    other_leader: NodeId(2)
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_late_vote_responses_ignored_after_winning() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4), NodeId(5)];
+        let (mut inner, mut events) = test_inner(NodeId(1), peers);
+        inner.state.write().become_candidate();
+        let term = inner.state.read().persistent.current_term;
+        let granted = |term| RequestVoteResponse {
+            term,
+            vote_granted: true,
+            denial_reason: None,
+        };
+
+        inner.handle_vote_response(NodeId(2), granted(term));
+        inner.handle_vote_response(NodeId(3), granted(term));
+        assert_eq!(inner.state.read().role, RaftRole::Leader);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(RaftEvent::BecameLeader { .. })
+        ));
+        let last_index = inner.log.last_index();
+
+        // Stragglers from the won election, a repeat, and one from an
+        // election we ran in an earlier term
+        inner.handle_vote_response(NodeId(4), granted(term));
+        inner.handle_vote_response(NodeId(5), granted(term));
+        inner.handle_vote_response(NodeId(2), granted(term));
+        inner.handle_vote_response(NodeId(4), granted(Term(term.0 - 1)));
+        inner.handle_vote_response(
+            NodeId(5),
+            RequestVoteResponse {
+                term,
+                vote_granted: false,
+                denial_reason: None,
+            },
+        );
+
+        let state = inner.state.read();
+        assert_eq!(state.role, RaftRole::Leader);
+        assert_eq!(state.persistent.current_term, term);
+        assert_eq!(state.persistent.voted_for, Some(NodeId(1)));
+        assert!(state.candidate_state.is_none());
+        assert_eq!(inner.log.last_index(), last_index);
+        assert!(events.try_recv().is_err());
+    }
+
+    /// Grants every vote, but only answers quickly for the `fast` peers
+    struct SplitLatencyTransport {
+        fast: Vec<NodeId>,
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for SplitLatencyTransport {
+        async fn send_request_vote(
+            &self,
+            target: NodeId,
+            request: RequestVoteRequest,
+        ) -> Result<RequestVoteResponse> {
+            if !self.fast.contains(&target) {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            Ok(RequestVoteResponse {
+                term: request.term,
+                vote_granted: true,
+                denial_reason: None,
+            })
+        }
+
+        async fn send_append_entries(
+            &self,
+            target: NodeId,
+            _request: AppendEntriesRequest,
+        ) -> Result<AppendEntriesResponse> {
+            Err(RaftError::Rpc(format!("unreachable {}", target)))
+        }
+
+        async fn send_install_snapshot(
+            &self,
+            target: NodeId,
+            _request: InstallSnapshotRequest,
+        ) -> Result<InstallSnapshotResponse> {
+            Err(RaftError::Rpc(format!("unreachable {}", target)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_candidate_wins_without_waiting_for_stragglers() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4), NodeId(5)];
+        let config = crate::RaftConfigBuilder::new()
+            .election_timeout(Duration::from_millis(20), Duration::from_millis(40))
+            .heartbeat_interval(Duration::from_millis(10))
+            .build();
+        let transport = Arc::new(SplitLatencyTransport {
+            fast: vec![NodeId(2), NodeId(3)],
+        });
+
+        let node = RaftNodeBuilder::new(NodeId(1), peers, KvStore::new())
+            .config(config)
+            .transport(transport)
+            .build()
+            .await
+            .unwrap();
+        let mut events = node.subscribe_events();
+
+        // Self + nodes 2 and 3 form a majority of 5; 4 and 5 take a minute
+        let elected = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let RaftEvent::BecameLeader { term } = events.recv().await.unwrap() {
+                    return term;
+                }
+            }
+        })
+        .await
+        .expect("candidate waited for slow voters");
+        assert_eq!(elected, Term(1));
+
+        node.shutdown().await;
+    }
+
+    /// Grants every vote; answers pings only from the `alive` peers
+    struct PingTransport {
+        alive: Vec<NodeId>,
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for PingTransport {
+        async fn send_request_vote(
+            &self,
+            _target: NodeId,
+            request: RequestVoteRequest,
+        ) -> Result<RequestVoteResponse> {
+            Ok(RequestVoteResponse {
+                term: request.term,
+                vote_granted: true,
+                denial_reason: None,
+            })
+        }
+
+        async fn send_append_entries(
+            &self,
+            target: NodeId,
+            _request: AppendEntriesRequest,
+        ) -> Result<AppendEntriesResponse> {
+            Err(RaftError::Rpc(format!("unreachable {}", target)))
+        }
+
+        async fn send_install_snapshot(
+            &self,
+            target: NodeId,
+            _request: InstallSnapshotRequest,
+        ) -> Result<InstallSnapshotResponse> {
+            Err(RaftError::Rpc(format!("unreachable {}", target)))
+        }
+
+        async fn send_ping(&self, target: NodeId, request: PingRequest) -> Result<PingResponse> {
+            if !self.alive.contains(&target) {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            Ok(PingResponse {
+                term: request.term,
+                from: target,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_tracks_peer_health() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let config = crate::RaftConfigBuilder::new()
+            .election_timeout(Duration::from_millis(20), Duration::from_millis(40))
+            .heartbeat_interval(Duration::from_millis(10))
+            .build();
+        let transport = Arc::new(PingTransport {
+            alive: vec![NodeId(2)],
+        });
+
+        let node = RaftNodeBuilder::new(NodeId(1), peers, KvStore::new())
+            .config(config)
+            .transport(transport)
+            .build()
+            .await
+            .unwrap();
+        let mut events = node.subscribe_events();
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !matches!(events.recv().await.unwrap(), RaftEvent::BecameLeader { .. }) {}
+        })
+        .await
+        .expect("node never became leader");
+
+        assert!(node.ping_peer(NodeId(2)).await.is_ok());
+        assert!(matches!(
+            node.ping_peer(NodeId(3)).await,
+            Err(RaftError::Timeout)
+        ));
+
+        let progress = node.replication_progress().await.unwrap();
+        let live = &progress[&NodeId(2)];
+        assert!(live.reachable);
+        assert!(live.rtt.is_some());
+        assert!(live.last_contact.is_some());
+        let down = &progress[&NodeId(3)];
+        assert!(!down.reachable);
+        assert_eq!(down.rtt, None);
+
+        // Answering a ping leaves the node's own state alone
+        let pong = node
+            .ping(PingRequest {
+                term: Term(99),
+                from: NodeId(2),
+            })
+            .await;
+        assert_eq!(pong.term, Term(1));
+        assert_eq!(pong.from, NodeId(1));
+
+        node.shutdown().await;
+    }
+
+    /// KV store whose snapshots take a long time to produce
+    struct SlowSnapshotStore {
+        inner: KvStore,
+        delay: Duration,
+    }
+
+    impl StateMachine for SlowSnapshotStore {
+        fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+            self.inner.apply(command)
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            std::thread::sleep(self.delay);
+            self.inner.snapshot()
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) {
+            self.inner.restore(snapshot)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_answered_during_slow_snapshot() {
+        let config = crate::RaftConfigBuilder::new()
+            .election_timeout(Duration::from_secs(5), Duration::from_secs(10))
+            .snapshot_threshold(5)
+            .snapshot_trailing_logs(0)
+            .build();
+        let sm = SlowSnapshotStore {
+            inner: KvStore::new(),
+            delay: Duration::from_millis(500),
+        };
+        let node = RaftNodeBuilder::new(NodeId(1), vec![NodeId(1), NodeId(2)], sm)
+            .config(config)
+            .build()
+            .await
+            .unwrap();
+        let mut events = node.subscribe_events();
+
+        let entries = (1..=10)
+            .map(|i| Entry::new(Term(1), LogIndex(i), format!("SET k{} v", i).into_bytes()))
+            .collect();
+        let response = node
+            .append_entries(AppendEntriesRequest {
+                term: Term(1),
+                leader_id: NodeId(2),
+                prev_log_index: LogIndex::ZERO,
+                prev_log_term: Term(0),
+                entries,
+                leader_commit: LogIndex(10),
+            })
+            .await;
+        assert!(response.success);
+
+        // Applying crossed the threshold, so a snapshot is now being built.
+        // The node must keep answering heartbeats promptly meanwhile.
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_millis(300) {
+            let sent = Instant::now();
+            let response = node
+                .append_entries(AppendEntriesRequest::heartbeat(
+                    Term(1),
+                    NodeId(2),
+                    LogIndex(10),
+                    Term(1),
+                    LogIndex(10),
+                ))
+                .await;
+            assert!(response.success);
+            assert!(sent.elapsed() < Duration::from_millis(100));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(events.try_recv().is_err(), "snapshot finished too early");
+
+        let created = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let RaftEvent::SnapshotCreated {
+                    last_included_index,
+                } = events.recv().await.unwrap()
+                {
+                    return last_included_index;
+                }
+            }
+        })
+        .await
+        .expect("snapshot never completed");
+        assert_eq!(created, LogIndex(10));
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_propose_waits_for_initial_leader() {
+        let config = crate::RaftConfigBuilder::new()
+            .initial_leader_timeout(Duration::from_secs(2))
+            .build();
+        let node = RaftNodeBuilder::new(NodeId(1), vec![NodeId(1)], KvStore::new())
+            .config(config)
+            .build()
+            .await
+            .unwrap();
+
+        // Proposed before the first election has completed
+        let result = node.propose(b"SET a 1".to_vec()).await;
+        assert!(result.is_ok(), "propose failed: {:?}", result);
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_empty_command_policy() {
+        let single_node = |allow| async move {
+            let config = crate::RaftConfigBuilder::new()
+                .initial_leader_timeout(Duration::from_secs(2))
+                .allow_empty_commands(allow)
+                .build();
+            RaftNodeBuilder::new(NodeId(1), vec![NodeId(1)], KvStore::new())
+                .config(config)
+                .build()
+                .await
+                .unwrap()
+        };
+
+        let node = single_node(false).await;
+        assert!(matches!(
+            node.propose(vec![]).await,
+            Err(RaftError::EmptyCommand)
+        ));
+        assert!(matches!(
+            node.propose_no_wait(vec![]).await,
+            Err(RaftError::EmptyCommand)
+        ));
+        node.propose(b"SET a 1".to_vec()).await.unwrap();
+        let last_log_index = node.metrics().await.unwrap().last_log_index;
+        assert!(node.propose(vec![]).await.is_err());
+        assert_eq!(node.metrics().await.unwrap().last_log_index, last_log_index);
+        node.shutdown().await;
+
+        // Allowed, the empty command reaches the state machine like any other
+        let node = single_node(true).await;
+        let before = node.metrics().await.unwrap().last_applied;
+        node.propose(vec![]).await.unwrap();
+        assert!(node.metrics().await.unwrap().last_applied > before);
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_node_runs_on_provided_runtime() {
+        /// Answers every command with the name of the thread applying it
+        struct ThreadName;
+
+        impl StateMachine for ThreadName {
+            fn apply(&mut self, _command: &[u8]) -> Vec<u8> {
+                std::thread::current()
+                    .name()
+                    .unwrap_or("")
+                    .as_bytes()
+                    .to_vec()
+            }
+            fn snapshot(&self) -> Vec<u8> {
+                vec![]
+            }
+            fn restore(&mut self, _snapshot: &[u8]) {}
+        }
+
+        // A single-threaded runtime driven by a thread of its own
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let handle = runtime.handle().clone();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let raft_thread = std::thread::Builder::new()
+            .name("raft".into())
+            .spawn(move || {
+                runtime.block_on(async {
+                    let _ = stop_rx.await;
+                })
+            })
+            .unwrap();
+
+        let config = crate::RaftConfigBuilder::new()
+            .initial_leader_timeout(Duration::from_secs(2))
+            .build();
+        let node = RaftNodeBuilder::new(NodeId(1), vec![NodeId(1)], ThreadName)
+            .config(config)
+            .runtime(handle)
+            .build()
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(node.propose(b"where".to_vec()).await.unwrap(), b"raft");
+        }
+        assert_eq!(node.metrics().await.unwrap().role, RaftRole::Leader);
+
+        node.shutdown().await;
+        stop_tx.send(()).unwrap();
+        raft_thread.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dropping_propose_signals_cancellation() {
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        let node = RaftNode {
+            id: NodeId(1),
+            command_tx,
+            read_tx: mpsc::unbounded_channel().0,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            state_machine: Arc::new(()),
+            rpc_observer: None,
+        };
+
+        // Nobody answers, so the caller gives up
+        let result =
+            tokio::time::timeout(Duration::from_millis(20), node.propose(b"SET a 1".to_vec()))
+                .await;
+        assert!(result.is_err());
+
+        assert!(matches!(
+            command_rx.recv().await,
+            Some(RaftCommand::Propose { .. })
+        ));
+        assert!(matches!(
+            command_rx.recv().await,
+            Some(RaftCommand::ProposalCancelled)
+        ));
+    }
+
+    #[test]
+    fn test_cancelled_proposal_waiter_removed() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let config = crate::RaftConfigBuilder::new()
+            .initial_leader_timeout(Duration::from_secs(60))
+            .build();
+        let (mut inner, _events) = test_inner_with_config(NodeId(1), peers, config);
+
+        let (kept_tx, mut kept_rx) = oneshot::channel();
+        let (cancelled_tx, cancelled_rx) = oneshot::channel();
+        inner.handle_propose(b"SET a 1".to_vec(), None, kept_tx);
+        inner.handle_propose(b"SET b 2".to_vec(), None, cancelled_tx);
+        assert_eq!(inner.leader_waiters.len(), 2);
+
+        drop(cancelled_rx);
+        inner.drop_cancelled_proposals();
+        assert_eq!(inner.leader_waiters.len(), 1);
+        assert_eq!(inner.leader_waiters[0].command, b"SET a 1");
+
+        // The remaining proposal is still answered once a leader shows up
+        inner.state.write().leader_id = Some(NodeId(2));
+        inner.release_leader_waiters();
+        assert!(matches!(
+            kept_rx.try_recv(),
+            Ok(Err(RaftError::NotLeader(NotLeaderInfo {
+                leader: Some(NodeId(2)),
+                ..
+            })))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_propose_answers_once_a_majority_has_the_entry() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        elect(&mut inner);
+        commit_all(&mut inner);
+
+        let (tx, mut rx) = oneshot::channel();
+        inner.handle_propose(b"SET a 1".to_vec(), None, tx);
+        let index = inner.log.last_index();
+
+        // Only in the leader's own log: not committed, so not answered
+        assert!(rx.try_recv().is_err());
+        assert!(inner.pending_proposals.contains_key(&index));
+
+        // Node 2's copy makes a majority of three
+        let term = inner.state.read().persistent.current_term;
+        inner.handle_append_response(
+            NodeId(2),
+            Instant::now(),
+            index,
+            AppendEntriesResponse {
+                term,
+                success: true,
+                match_index: Some(index),
+                conflict_term: None,
+                conflict_index: None,
+                commit_index: LogIndex::ZERO,
+                last_applied: LogIndex::ZERO,
+            },
+        );
+        assert_eq!(inner.state.read().volatile.commit_index, index);
+        assert_eq!(rx.try_recv().unwrap().unwrap(), b"OK");
+        assert!(inner.pending_proposals.is_empty());
+    }
+
+    #[test]
+    fn test_step_down_fails_uncommitted_proposals() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner(NodeId(1), peers);
+        elect(&mut inner);
+
+        let mut waiters = Vec::new();
+        for command in ["SET a 1", "SET b 2"] {
+            let (tx, rx) = oneshot::channel();
+            inner.handle_propose(command.as_bytes().to_vec(), None, tx);
+            waiters.push(rx);
+        }
+        assert_eq!(inner.pending_proposals.len(), 2);
+        let last_index = inner.log.last_index();
+
+        // Node 2 wins a later term before either entry reaches a quorum
+        let response = inner.handle_append_entries(AppendEntriesRequest::heartbeat(
+            Term(2),
+            NodeId(2),
+            LogIndex::ZERO,
+            Term(0),
+            LogIndex::ZERO,
+        ));
+        assert!(response.success);
+        inner.abandon_proposals();
+
+        for mut rx in waiters {
+            assert!(matches!(
+                rx.try_recv(),
+                Ok(Err(RaftError::NotLeader(NotLeaderInfo {
+                    leader: Some(NodeId(2)),
+                    ..
+                })))
+            ));
+        }
+        assert!(inner.pending_proposals.is_empty());
+        assert_eq!(inner.metrics().abandoned_proposals, 2);
+
+        // The entries wait for the new leader to confirm or overwrite them
+        assert_eq!(inner.log.last_index(), last_index);
+        assert_eq!(
+            inner.log.get(last_index).unwrap().unwrap().command,
+            b"SET b 2"
+        );
+        assert_eq!(inner.state.read().volatile.commit_index, LogIndex::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_propose_fails_fast_without_initial_leader_timeout() {
+        let node = RaftNode::new(
+            NodeId(1),
+            vec![NodeId(1)],
+            RaftConfig::default(),
+            KvStore::new(),
+            Arc::new(NoopTransport),
+        )
+        .await
+        .unwrap();
+
+        let result = node.propose(b"SET a 1".to_vec()).await;
+        assert!(matches!(
+            result,
+            Err(RaftError::NotLeader(NotLeaderInfo { leader: None, .. }))
+        ));
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_members_from_follower() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4)];
+        let node = RaftNode::new(
+            NodeId(2),
+            peers,
+            RaftConfig::default(),
+            KvStore::new(),
+            Arc::new(NoopTransport),
+        )
+        .await
+        .unwrap();
+
+        let config = ClusterConfig {
+            voters: vec![NodeId(1), NodeId(2), NodeId(3)],
+            learners: vec![NodeId(4)],
+            ..Default::default()
+        };
+        let response = node
+            .append_entries(AppendEntriesRequest {
+                term: Term(1),
+                leader_id: NodeId(1),
+                prev_log_index: LogIndex::ZERO,
+                prev_log_term: Term(0),
+                entries: vec![Entry::config_change(Term(1), LogIndex(1), &config)],
+                leader_commit: LogIndex(1),
+            })
+            .await;
+        assert!(response.success);
+
+        let members = node.members().await.unwrap();
+        let expected = [
+            (NodeId(1), MemberRole::Voter, MemberRelation::Leader),
+            (NodeId(2), MemberRole::Voter, MemberRelation::Local),
+            (NodeId(3), MemberRole::Voter, MemberRelation::Peer),
+            (NodeId(4), MemberRole::Learner, MemberRelation::Peer),
+        ];
+        assert_eq!(members.len(), expected.len());
+        for (member, (id, role, relation)) in members.iter().zip(expected) {
+            assert_eq!(member.id, id);
+            assert_eq!(member.role, role);
+            assert_eq!(member.relation, relation);
+        }
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_propose_no_wait_returns_sequential_indices() {
+        let config = crate::RaftConfigBuilder::new()
+            .election_timeout(Duration::from_millis(20), Duration::from_millis(40))
+            .heartbeat_interval(Duration::from_millis(10))
+            .build();
+        let node = RaftNode::new(
+            NodeId(1),
+            vec![NodeId(1)],
+            config,
+            KvStore::new(),
+            Arc::new(NoopTransport),
+        )
+        .await
+        .unwrap();
+        let mut events = node.subscribe_events();
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !matches!(events.recv().await.unwrap(), RaftEvent::BecameLeader { .. }) {}
+        })
+        .await
+        .expect("node never became leader");
+
+        // Index 1 holds the leader's no-op
+        for expected in 2..=4 {
+            let index = node.propose_no_wait(b"SET a 1".to_vec()).await.unwrap();
+            assert_eq!(index, LogIndex(expected));
+        }
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_propose_no_wait_on_follower() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let node = RaftNode::new(
+            NodeId(1),
+            peers,
+            RaftConfig::default(),
+            KvStore::new(),
+            Arc::new(NoopTransport),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            node.propose_no_wait(b"SET a 1".to_vec()).await,
+            Err(RaftError::NotLeader(NotLeaderInfo { leader: None, .. }))
+        ));
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_node_creation() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let config = RaftConfig::default();
+        let sm = KvStore::new();
+
+        let node = RaftNode::new(NodeId(1), peers, config, sm, Arc::new(NoopTransport))
+            .await
+            .unwrap();
+
+        // Node should be created and running
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_initial_state_rejects_stale_vote() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let node = RaftNodeBuilder::new(NodeId(1), peers, KvStore::new())
+            .initial_state(PersistentState {
+                current_term: Term(100),
+                voted_for: None,
+            })
+            .build()
+            .await
+            .unwrap();
+
+        let response = node
+            .request_vote(RequestVoteRequest {
+                term: Term(50),
+                candidate_id: NodeId(2),
+                last_log_index: LogIndex(10),
+                last_log_term: Term(50),
+                pre_vote: false,
+            })
+            .await;
+
+        assert!(!response.vote_granted);
+        assert_eq!(response.term, Term(100));
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_log_rejected_on_startup() {
+        // Terms go backwards, which append lets through but no real log
+        // can contain
+        let mut storage = crate::MemoryLogStorage::new();
+        storage
+            .append(vec![
+                Entry::new(Term(2), LogIndex(1), b"SET a 1".to_vec()),
+                Entry::new(Term(1), LogIndex(2), b"SET b 2".to_vec()),
+            ])
+            .unwrap();
+
+        let config = crate::RaftConfigBuilder::new()
+            .verify_log_on_startup(true)
+            .build();
+        let result = RaftNodeBuilder::new(NodeId(1), vec![NodeId(1)], KvStore::new())
+            .config(config)
+            .log_storage(Box::new(storage))
+            .build()
+            .await;
+
+        assert!(matches!(result, Err(RaftError::CorruptLog(_))));
+    }
+
+    #[tokio::test]
+    async fn test_isolated_node_suspects_partition() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let config = crate::RaftConfigBuilder::new()
+            .election_timeout(Duration::from_millis(20), Duration::from_millis(40))
+            .heartbeat_interval(Duration::from_millis(10))
+            .partition_detection_timeouts(2)
+            .build();
+
+        let node = RaftNode::new(
+            NodeId(1),
+            peers,
+            config,
+            KvStore::new(),
+            Arc::new(NoopTransport),
+        )
+        .await
+        .unwrap();
+        let mut events = node.subscribe_events();
+
+        // No peer ever answers, so the node keeps timing out
+        let event = tokio::time::timeout(Duration::from_secs(2), events.recv())
+            .await
+            .expect("no partition event")
+            .unwrap();
+
+        assert!(matches!(
+            event,
+            RaftEvent::SuspectedPartition {
+                reason: PartitionReason::NoLeaderContact {
+                    election_timeouts: 2
+                },
+                ..
+            }
+        ));
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_isolated_leader_suspects_partition() {
+        // The followers never campaign, so nothing deposes the leader once
+        // it's cut off
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let patient = local_config()
+            .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+            .build();
+        let network = LocalNetwork::new(patient);
+        let leader = network
+            .add_custom_node(
+                RaftNodeBuilder::new(NodeId(1), voters.clone(), KvStore::new())
+                    .config(local_config().build()),
+            )
+            .await;
+        network.add_node(NodeId(2), voters.clone()).await;
+        network.add_node(NodeId(3), voters).await;
+        assert_eq!(network.wait_for_leader().await.id(), leader.id());
+        let mut events = leader.subscribe_events();
+
+        // Cut the leader's heartbeats off from both followers
+        network.isolated.write().extend([NodeId(2), NodeId(3)]);
+        let reason = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let RaftEvent::SuspectedPartition { reason, .. } = events.recv().await.unwrap() {
+                    return reason;
+                }
+            }
+        })
+        .await
+        .expect("no partition event");
+        assert_eq!(
+            reason,
+            PartitionReason::LeaderLostQuorum {
+                acknowledged: 1,
+                voters: 3
+            }
+        );
+
+        drop(leader);
+        network.shutdown().await;
+    }
+
+    /// Writes left before storage starts failing, shared across backends so
+    /// a crash can land between any two of them
+    #[derive(Clone)]
+    struct WriteBudget(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl WriteBudget {
+        fn new(writes: usize) -> Self {
+            Self(Arc::new(std::sync::atomic::AtomicUsize::new(writes)))
+        }
+
+        fn spend(&self) -> Result<()> {
+            use std::sync::atomic::Ordering;
+            self.0
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .map(|_| ())
+                .map_err(|_| RaftError::Storage(std::io::Error::other("injected crash")))
+        }
+    }
+
+    /// Log storage whose contents outlive the node, failing writes once the
+    /// budget runs out
+    struct CrashingLog {
+        durable: Arc<parking_lot::Mutex<MemoryLogStorage>>,
+        budget: WriteBudget,
+    }
+
+    impl LogStorage for CrashingLog {
+        fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+            self.budget.spend()?;
+            self.durable.lock().append(entries)
+        }
+
+        fn get(&self, index: LogIndex) -> Result<Option<Entry>> {
+            self.durable.lock().get(index)
+        }
+
+        fn get_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Entry>> {
+            self.durable.lock().get_range(start, end)
+        }
+
+        fn get_from(&self, start: LogIndex) -> Result<Vec<Entry>> {
+            self.durable.lock().get_from(start)
+        }
+
+        fn delete_from(&mut self, index: LogIndex) -> Result<()> {
+            self.budget.spend()?;
+            self.durable.lock().delete_from(index)
+        }
+
+        fn last_index(&self) -> LogIndex {
+            self.durable.lock().last_index()
+        }
+
+        fn last_term(&self) -> Term {
+            self.durable.lock().last_term()
+        }
+
+        fn get_term(&self, index: LogIndex) -> Result<Option<Term>> {
+            self.durable.lock().get_term(index)
+        }
+
+        fn set_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+            self.budget.spend()?;
+            self.durable.lock().set_snapshot(snapshot)
+        }
+
+        fn get_snapshot(&self) -> Option<Snapshot> {
+            self.durable.lock().get_snapshot()
+        }
+
+        fn compact(&mut self, through_index: LogIndex) -> Result<()> {
+            self.budget.spend()?;
+            self.durable.lock().compact(through_index)
+        }
+    }
+
+    /// Hard state storage that fails writes once the budget runs out
+    struct CrashingStateStorage {
+        durable: Arc<MemoryStateStorage>,
+        budget: WriteBudget,
+    }
+
+    impl StateStorage for CrashingStateStorage {
+        fn save_hard_state(&self, term: Term, voted_for: Option<NodeId>) -> Result<()> {
+            self.budget.spend()?;
+            self.durable.save_hard_state(term, voted_for)
+        }
+
+        fn load_hard_state(&self) -> Result<Option<(Term, Option<NodeId>)>> {
+            self.durable.load_hard_state()
+        }
+    }
+
+    /// What a crash leaves behind: the log and the hard state on disk
+    struct DurableState {
+        log: Arc<parking_lot::Mutex<MemoryLogStorage>>,
+        hard_state: Arc<MemoryStateStorage>,
+    }
+
+    impl DurableState {
+        fn term(&self) -> Term {
+            self.hard_state
+                .load_hard_state()
+                .unwrap()
+                .map(|(term, _)| term)
+                .unwrap_or(Term(0))
+        }
+    }
+
+    /// A follower whose storage fails after `writes` successful writes
+    fn crashing_inner(writes: usize) -> (RaftNodeInner<KvStore>, DurableState) {
+        let budget = WriteBudget::new(writes);
+        let durable = DurableState {
+            log: Arc::new(parking_lot::Mutex::new(MemoryLogStorage::new())),
+            hard_state: Arc::new(MemoryStateStorage::new()),
+        };
+
+        let (mut inner, _events) = test_inner(NodeId(1), vec![NodeId(1), NodeId(2), NodeId(3)]);
+        inner.log = RaftLog::new(Box::new(CrashingLog {
+            durable: Arc::clone(&durable.log),
+            budget: budget.clone(),
+        }));
+        inner.hard_state.storage = Box::new(CrashingStateStorage {
+            durable: Arc::clone(&durable.hard_state),
+            budget,
+        });
+        (inner, durable)
+    }
+
+    #[test]
+    fn test_crash_never_leaves_entries_from_unpersisted_term() {
+        for writes in 0..=2 {
+            let (mut inner, durable) = crashing_inner(writes);
+
+            let response = inner.handle_append_entries(AppendEntriesRequest {
+                term: Term(2),
+                leader_id: NodeId(2),
+                prev_log_index: LogIndex::ZERO,
+                prev_log_term: Term(0),
+                entries: vec![
+                    Entry::new(Term(2), LogIndex(1), b"SET a 1".to_vec()),
+                    Entry::new(Term(2), LogIndex(2), b"SET b 2".to_vec()),
+                ],
+                leader_commit: LogIndex::ZERO,
+            });
+
+            // Whatever survived, no entry is from a term the node would
+            // forget on restart
+            let log = durable.log.lock();
+            let durable_term = durable.term();
+            for entry in log.get_from(LogIndex(1)).unwrap() {
+                assert!(
+                    entry.term <= durable_term,
+                    "entry from term {} survived with hard state at term {} ({} writes)",
+                    entry.term,
+                    durable_term,
+                    writes
+                );
+            }
+
+            // Acknowledged entries are all durable
+            if response.success {
+                assert_eq!(log.last_index(), LogIndex(2));
+                assert_eq!(durable_term, Term(2));
+            }
+
+            // In memory, the node never claims a term it couldn't save
+            assert_eq!(inner.state.read().persistent.current_term, durable_term);
+        }
+    }
+
+    #[test]
+    fn test_crash_never_grants_unpersisted_vote() {
+        for writes in 0..=1 {
+            let (mut inner, durable) = crashing_inner(writes);
+
+            let response = inner.handle_request_vote(RequestVoteRequest {
+                term: Term(3),
+                candidate_id: NodeId(3),
+                last_log_index: LogIndex::ZERO,
+                last_log_term: Term(0),
+                pre_vote: false,
+            });
+
+            let saved = durable.hard_state.load_hard_state().unwrap();
+            if response.vote_granted {
+                assert_eq!(saved, Some((Term(3), Some(NodeId(3)))));
+            } else {
+                assert_eq!(saved, None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_leader_steps_down_when_its_log_fails() {
+        for step_down in [true, false] {
+            let config = crate::RaftConfigBuilder::new()
+                .step_down_on_append_failure(step_down)
+                .build();
+            let (mut inner, mut events) =
+                test_inner_with_config(NodeId(1), vec![NodeId(1), NodeId(2)], config);
+            // Room for the leader's no-op and nothing more
+            inner.log = RaftLog::new(Box::new(CrashingLog {
+                durable: Arc::new(parking_lot::Mutex::new(MemoryLogStorage::new())),
+                budget: WriteBudget::new(1),
+            }));
+            elect(&mut inner);
+            assert_eq!(
+                events.try_recv().unwrap(),
+                RaftEvent::BecameLeader { term: Term(1) }
+            );
+
+            assert!(matches!(
+                inner.append_command(b"SET a 1".to_vec(), None),
+                Err(RaftError::Storage(_))
+            ));
+            let state = inner.state.read();
+            if step_down {
+                assert_eq!(state.role, RaftRole::Follower);
+                assert_eq!(state.persistent.current_term, Term(1));
+                assert!(matches!(
+                    events.try_recv(),
+                    Ok(RaftEvent::StorageFailure { term: Term(1), .. })
+                ));
+            } else {
+                assert_eq!(state.role, RaftRole::Leader);
+                assert!(events.try_recv().is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_leader_steps_down_without_its_noop() {
+        for step_down in [true, false] {
+            let config = crate::RaftConfigBuilder::new()
+                .step_down_on_append_failure(step_down)
+                .build();
+            let (mut inner, mut events) =
+                test_inner_with_config(NodeId(1), vec![NodeId(1), NodeId(2)], config);
+            // Not even room for the no-op
+            inner.log = RaftLog::new(Box::new(CrashingLog {
+                durable: Arc::new(parking_lot::Mutex::new(MemoryLogStorage::new())),
+                budget: WriteBudget::new(0),
+            }));
+            elect(&mut inner);
+
+            // Nothing is left waiting on an index the no-op never took
+            let state = inner.state.read();
+            assert_eq!(state.role, RaftRole::Follower);
+            assert_eq!(state.persistent.current_term, Term(1));
+            drop(state);
+            assert!(inner.config_noops.is_empty());
+            assert_eq!(inner.log.last_index(), LogIndex::ZERO);
+            assert!(!matches!(
+                events.try_recv(),
+                Ok(RaftEvent::BecameLeader { .. })
+            ));
+            assert!(matches!(
+                inner.append_command(b"SET a 1".to_vec(), None),
+                Err(RaftError::NotLeader(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_election_not_started_without_durable_term() {
+        let (mut inner, durable) = crashing_inner(0);
+
+        inner.start_election();
+
+        let state = inner.state.read();
+        assert_eq!(state.role, RaftRole::Follower);
+        assert_eq!(state.persistent.current_term, Term(0));
+        assert_eq!(durable.hard_state.load_hard_state().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_saved_hard_state_loaded_on_build() {
+        let storage = MemoryStateStorage::new();
+        storage.save_hard_state(Term(5), Some(NodeId(2))).unwrap();
+
+        let node = RaftNodeBuilder::new(NodeId(1), vec![NodeId(1), NodeId(2)], KvStore::new())
+            .state_storage(Box::new(storage))
+            .build()
+            .await
+            .unwrap();
+
+        let response = node
+            .request_vote(RequestVoteRequest {
+                term: Term(5),
+                candidate_id: NodeId(3),
+                last_log_index: LogIndex::ZERO,
+                last_log_term: Term(0),
+                pre_vote: false,
+            })
+            .await;
+        assert_eq!(response.term, Term(5));
+        assert!(!response.vote_granted);
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_vote_survives_restart_in_file_state() {
+        use crate::FileStateStorage;
+
+        let dir = tempfile::tempdir().unwrap();
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let vote_from = |candidate| RequestVoteRequest {
+            term: Term(4),
+            candidate_id: candidate,
+            last_log_index: LogIndex::ZERO,
+            last_log_term: Term(0),
+            pre_vote: false,
+        };
+
+        let node = RaftNodeBuilder::new(NodeId(1), voters.clone(), KvStore::new())
+            .state_storage(Box::new(FileStateStorage::open(dir.path()).unwrap()))
+            .build()
+            .await
+            .unwrap();
+        assert!(node.request_vote(vote_from(NodeId(2))).await.vote_granted);
+        node.shutdown().await;
+
+        // The restarted node remembers it already voted in term 4
+        let node = RaftNodeBuilder::new(NodeId(1), voters, KvStore::new())
+            .state_storage(Box::new(FileStateStorage::open(dir.path()).unwrap()))
+            .build()
+            .await
+            .unwrap();
+        assert!(!node.request_vote(vote_from(NodeId(3))).await.vote_granted);
+        assert!(node.request_vote(vote_from(NodeId(2))).await.vote_granted);
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_metrics_stream_tracks_proposals() {
+        let config = crate::RaftConfigBuilder::new()
+            .election_timeout(Duration::from_millis(20), Duration::from_millis(40))
+            .heartbeat_interval(Duration::from_millis(10))
+            .build();
+        let node = RaftNode::new(
+            NodeId(1),
+            vec![NodeId(1)],
+            config,
+            KvStore::new(),
+            Arc::new(NoopTransport),
+        )
+        .await
+        .unwrap();
+
+        let stream = node.metrics_stream(Duration::from_millis(20));
+        futures::pin_mut!(stream);
+
+        // Wait for the single node to elect itself
+        loop {
+            let metrics = stream.next().await.unwrap();
+            if metrics.role == RaftRole::Leader {
+                break;
+            }
+        }
+
+        let mut previous = stream.next().await.unwrap();
+        for i in 0..3 {
+            node.propose_no_wait(format!("SET k{} v", i).into_bytes())
+                .await
+                .unwrap();
+
+            let metrics = stream.next().await.unwrap();
+            assert_eq!(metrics.id, NodeId(1));
+            assert!(metrics.proposals_accepted > previous.proposals_accepted);
+            assert!(metrics.last_log_index > previous.last_log_index);
+            previous = metrics;
+        }
+        assert_eq!(previous.proposals_accepted, 3);
+
+        node.shutdown().await;
+        let end = tokio::time::timeout(Duration::from_secs(1), async {
+            while stream.next().await.is_some() {}
+        })
+        .await;
+        assert!(end.is_ok(), "metrics stream outlived the node");
+    }
+
+    /// State machine that records the order of applies and restores
+    struct TracingStore {
+        trace: Arc<parking_lot::Mutex<Vec<String>>>,
+    }
+
+    impl StateMachine for TracingStore {
+        fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+            let command = String::from_utf8_lossy(command).into_owned();
+            self.trace.lock().push(format!("apply {}", command));
+            vec![]
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) {
+            self.trace.lock().push("restore begin".to_string());
+            std::thread::sleep(Duration::from_millis(50));
+            let snapshot = String::from_utf8_lossy(snapshot).into_owned();
+            self.trace.lock().push(format!("restore end {}", snapshot));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_paused_during_snapshot_restore() {
+        let trace = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let mut inner = RaftNodeInner::new(
+            NodeId(1),
+            vec![NodeId(1), NodeId(2)],
+            RaftConfig::default(),
+            TracingStore {
+                trace: Arc::clone(&trace),
+            },
+            events,
+        );
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        inner.command_tx = command_tx;
+
+        // The leader ships a snapshot through index 10 in two chunks
+        for (offset, chunk, done) in [(0, &b"snap"[..], false), (4, &b"shot"[..], true)] {
+            let response = inner.handle_install_snapshot(InstallSnapshotRequest {
+                term: Term(1),
+                leader_id: NodeId(2),
+                last_included_index: LogIndex(10),
+                last_included_term: Term(1),
+                offset,
+                data: chunk.to_vec(),
+                done,
+                configuration: None,
+            });
+            assert_eq!(response.term, Term(1));
+        }
+        assert!(inner.restore_in_progress);
+
+        // Entries after the snapshot are committed while the restore runs
+        let response = inner.handle_append_entries(AppendEntriesRequest {
+            term: Term(1),
+            leader_id: NodeId(2),
+            prev_log_index: LogIndex(10),
+            prev_log_term: Term(1),
+            entries: (11..=13)
+                .map(|i| Entry::new(Term(1), LogIndex(i), format!("{}", i).into_bytes()))
+                .collect(),
+            leader_commit: LogIndex(13),
+        });
+        assert!(response.success);
+        inner.apply_committed();
+        assert_eq!(inner.state.read().volatile.last_applied, LogIndex::ZERO);
+
+        let Some(RaftCommand::RestoreFinished {
+            last_included_index,
+        }) = command_rx.recv().await
+        else {
+            panic!("restore task went away");
+        };
+        inner.finish_restore(last_included_index);
+
+        assert_eq!(
+            *trace.lock(),
+            vec![
+                "restore begin",
+                "restore end snapshot",
+                "apply 11",
+                "apply 12",
+                "apply 13"
+            ]
+        );
+        assert_eq!(inner.state.read().volatile.last_applied, LogIndex(13));
+        assert_eq!(inner.state_machine.read().last_applied, LogIndex(13));
+    }
+
+    #[tokio::test]
+    async fn test_installed_snapshot_brings_membership() {
+        let (mut inner, _events) = test_inner(NodeId(3), vec![NodeId(1), NodeId(2), NodeId(3)]);
+        let (command_tx, _command_rx) = mpsc::unbounded_channel();
+        inner.command_tx = command_tx;
+
+        // Node 4 joined and node 2 was demoted in entries the leader has
+        // since compacted away
+        let membership = ClusterConfig {
+            voters: vec![NodeId(1), NodeId(3), NodeId(4)],
+            learners: vec![NodeId(2)],
+            ..Default::default()
+        };
+        for (offset, chunk, done) in [(0, &b"{}"[..], false), (2, &b""[..], true)] {
+            inner.handle_install_snapshot(InstallSnapshotRequest {
+                term: Term(1),
+                leader_id: NodeId(1),
+                last_included_index: LogIndex(10),
+                last_included_term: Term(1),
+                offset,
+                data: chunk.to_vec(),
+                done,
+                configuration: done.then(|| membership.clone()),
+            });
+        }
+
+        assert_eq!(inner.state.read().configuration(), membership);
+        assert_eq!(inner.state.read().effective_cluster_size(), 3);
+        let snapshot = inner.log.get_snapshot().unwrap();
+        assert_eq!(snapshot.metadata.configuration, membership);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_over_log_not_applied_twice() {
+        let trace = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let mut inner = RaftNodeInner::new(
+            NodeId(1),
+            vec![NodeId(1), NodeId(2)],
+            RaftConfig::default(),
+            TracingStore {
+                trace: Arc::clone(&trace),
+            },
+            events,
+        );
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        inner.command_tx = command_tx;
+        let append = |prev: u64, entries: Vec<Entry>, leader_commit: u64| AppendEntriesRequest {
+            term: Term(1),
+            leader_id: NodeId(2),
+            prev_log_index: LogIndex(prev),
+            prev_log_term: Term(u64::from(prev > 0)),
+            entries,
+            leader_commit: LogIndex(leader_commit),
+        };
+
+        // Entries 1-5 arrive, only the first two committed
+        let entries = (1..=5)
+            .map(|i| Entry::new(Term(1), LogIndex(i), format!("{}", i).into_bytes()))
+            .collect();
+        assert!(inner.handle_append_entries(append(0, entries, 2)).success);
+        inner.apply_committed();
+
+        // A snapshot through 3 covers entries the log still holds
+        inner.handle_install_snapshot(InstallSnapshotRequest {
+            term: Term(1),
+            leader_id: NodeId(2),
+            last_included_index: LogIndex(3),
+            last_included_term: Term(1),
+            offset: 0,
+            data: b"3".to_vec(),
+            done: true,
+            configuration: None,
+        });
+        let Some(RaftCommand::RestoreFinished {
+            last_included_index,
+        }) = command_rx.recv().await
+        else {
+            panic!("restore task went away");
+        };
+        inner.finish_restore(last_included_index);
+        assert_eq!(inner.state.read().volatile.last_applied, LogIndex(3));
+        assert!(inner.log.get(LogIndex(3)).unwrap().is_none());
+        assert!(inner.log.get(LogIndex(4)).unwrap().is_some());
+
+        assert!(inner.handle_append_entries(append(5, vec![], 5)).success);
+        inner.apply_committed();
+        assert_eq!(
+            *trace.lock(),
+            vec![
+                "apply 1",
+                "apply 2",
+                "restore begin",
+                "restore end 3",
+                "apply 4",
+                "apply 5"
+            ]
+        );
+        assert_eq!(inner.state.read().volatile.last_applied, LogIndex(5));
+        assert_eq!(inner.state_machine.read().last_applied, LogIndex(5));
+    }
+
+    #[tokio::test]
+    async fn test_election_storm_throttled_and_reported() {
+        let config = crate::RaftConfigBuilder::new()
+            .election_timeout(Duration::from_millis(10), Duration::from_millis(20))
+            .heartbeat_interval(Duration::from_millis(5))
+            .partition_detection_timeouts(0)
+            .min_election_interval(Duration::from_millis(150))
+            .election_storm_threshold(3)
+            .build();
+
+        // Nobody ever answers, so every election times out
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let node = RaftNode::new(
+            NodeId(1),
+            peers,
+            config,
+            KvStore::new(),
+            Arc::new(NoopTransport),
+        )
+        .await
+        .unwrap();
+        let mut events = node.subscribe_events();
+
+        let event = tokio::time::timeout(Duration::from_secs(2), events.recv())
+            .await
+            .expect("no election storm reported")
+            .unwrap();
+        assert!(matches!(
+            event,
+            RaftEvent::ElectionStorm {
+                elections_per_minute: 3,
+                ..
+            }
+        ));
+
+        // The timeout fires every election tick, but the guard holds the
+        // node to one election per interval
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let metrics = node.metrics().await.unwrap();
+        assert!(
+            metrics.elections_per_minute <= 6,
+            "{} elections despite the guard",
+            metrics.elections_per_minute
+        );
+        assert_eq!(metrics.current_term.0, metrics.elections_per_minute as u64);
+
+        // Still in the same storm, so it isn't reported twice
+        assert!(events.try_recv().is_err());
+
+        node.shutdown().await;
+    }
+
+    /// State machine that burns CPU on every apply
+    struct SlowApplyStore {
+        applied: Arc<parking_lot::Mutex<Vec<String>>>,
+    }
+
+    impl StateMachine for SlowApplyStore {
+        fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+            let started = std::time::Instant::now();
+            while started.elapsed() < Duration::from_millis(60) {
+                std::hint::spin_loop();
+            }
+            self.applied
+                .lock()
+                .push(String::from_utf8_lossy(command).into_owned());
+            vec![]
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn restore(&mut self, _snapshot: &[u8]) {}
+    }
+
+    #[tokio::test]
+    async fn test_drained_shutdown_applies_everything_committed() {
+        let start = |applied: &Arc<parking_lot::Mutex<Vec<String>>>| {
+            let config = crate::RaftConfigBuilder::new()
+                .separate_apply_thread(true)
+                .build();
+            RaftNodeBuilder::new(
+                NodeId(1),
+                vec![NodeId(1), NodeId(2)],
+                SlowApplyStore {
+                    applied: Arc::clone(applied),
+                },
+            )
+            .config(config)
+            .build()
+        };
+        let replicate = AppendEntriesRequest {
+            term: Term(1),
+            leader_id: NodeId(2),
+            prev_log_index: LogIndex::ZERO,
+            prev_log_term: Term(0),
+            entries: (1..=6)
+                .map(|i| Entry::new(Term(1), LogIndex(i), format!("cmd-{}", i).into_bytes()))
+                .collect(),
+            leader_commit: LogIndex(6),
+        };
+
+        // Applying takes 360ms, far longer than this shutdown will wait
+        let applied = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let node = start(&applied).await.unwrap();
+        assert!(node.append_entries(replicate.clone()).await.success);
+        assert!(matches!(
+            node.shutdown_drained(Duration::from_millis(50)).await,
+            Err(RaftError::Timeout)
+        ));
+        assert!(applied.lock().len() < 6);
+
+        // Given the time, the state machine holds everything committed by the
+        // time the node stops, so nothing is left to replay on restart
+        let applied = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let node = start(&applied).await.unwrap();
+        assert!(node.append_entries(replicate).await.success);
+        node.shutdown_drained(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(applied.lock().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_on_schedule_while_apply_lags() {
+        let applied = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let config = crate::RaftConfigBuilder::new()
+            .separate_apply_thread(true)
+            .build();
+        let node = RaftNodeBuilder::new(
+            NodeId(1),
+            vec![NodeId(1), NodeId(2)],
+            SlowApplyStore {
+                applied: Arc::clone(&applied),
+            },
+        )
+        .config(config)
+        .build()
+        .await
+        .unwrap();
+
+        let response = node
+            .append_entries(AppendEntriesRequest {
+                term: Term(1),
+                leader_id: NodeId(2),
+                prev_log_index: LogIndex::ZERO,
+                prev_log_term: Term(0),
+                entries: (1..=6)
+                    .map(|i| Entry::new(Term(1), LogIndex(i), format!("cmd-{}", i).into_bytes()))
+                    .collect(),
+                leader_commit: LogIndex(6),
+            })
+            .await;
+        assert!(response.success);
+
+        // Applying takes 360ms of CPU; heartbeats keep being answered at once
+        // even on a single-threaded runtime
+        let mut lagged = false;
+        for _ in 0..10 {
+            let sent = std::time::Instant::now();
+            let response = node
+                .append_entries(AppendEntriesRequest::heartbeat(
+                    Term(1),
+                    NodeId(2),
+                    LogIndex(6),
+                    Term(1),
+                    LogIndex(6),
+                ))
+                .await;
+            assert!(response.success);
+            assert!(
+                sent.elapsed() < Duration::from_millis(30),
+                "heartbeat took {:?}",
+                sent.elapsed()
+            );
+
+            let metrics = node.metrics().await.unwrap();
+            lagged |= metrics.last_applied < metrics.commit_index;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(lagged, "applies never fell behind");
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while node.metrics().await.unwrap().last_applied < LogIndex(6) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("applies never caught up");
+
+        let expected: Vec<String> = (1..=6).map(|i| format!("cmd-{}", i)).collect();
+        assert_eq!(*applied.lock(), expected);
+
+        node.shutdown().await;
+    }
+
+    /// Hands every RPC straight to an in-process node
+    struct LocalNetwork {
+        nodes: RwLock<HashMap<NodeId, Arc<RaftNode>>>,
+
+        /// Config every node on the network is built with
+        config: RaftConfig,
+
+        /// How many InstallSnapshot chunks to drop before delivering any
+        snapshot_failures: std::sync::atomic::AtomicU32,
+
+        /// Nodes no RPC can reach
+        isolated: RwLock<BTreeSet<NodeId>>,
+    }
+
+    /// Fast elections and heartbeats, no snapshots
+    fn local_config() -> crate::RaftConfigBuilder {
+        crate::RaftConfigBuilder::new()
+            .election_timeout(Duration::from_millis(100), Duration::from_millis(500))
+            .heartbeat_interval(Duration::from_millis(10))
+            .snapshot_threshold(0)
+    }
+
+    impl LocalNetwork {
+        fn node(&self, target: NodeId) -> Result<Arc<RaftNode>> {
+            if self.isolated.read().contains(&target) {
+                return Err(RaftError::Rpc(format!("{} unreachable", target)));
+            }
+            self.nodes
+                .read()
+                .get(&target)
+                .cloned()
+                .ok_or_else(|| RaftError::Rpc(format!("unknown node {}", target)))
+        }
+
+        fn new(config: RaftConfig) -> Arc<Self> {
+            Arc::new(Self {
+                nodes: RwLock::new(HashMap::new()),
+                config,
+                snapshot_failures: Default::default(),
+                isolated: Default::default(),
+            })
+        }
+
+        /// Start a node on the network
+        async fn add_node(self: &Arc<Self>, id: NodeId, peers: Vec<NodeId>) -> Arc<RaftNode> {
+            let builder =
+                RaftNodeBuilder::new(id, peers, KvStore::new()).config(self.config.clone());
+            self.add_custom_node(builder).await
+        }
+
+        /// Start a node built by `builder` on the network
+        async fn add_custom_node(
+            self: &Arc<Self>,
+            builder: RaftNodeBuilder<KvStore>,
+        ) -> Arc<RaftNode> {
+            let node = builder
+                .transport(Arc::clone(self) as Arc<dyn Transport>)
+                .build()
+                .await
+                .unwrap();
+            let node = Arc::new(node);
+            self.nodes.write().insert(node.id(), Arc::clone(&node));
+            node
+        }
+
+        /// Start a cluster of `voters` and wait for it to elect a leader
+        async fn start(voters: &[NodeId]) -> (Arc<Self>, Arc<RaftNode>) {
+            Self::start_with_config(voters, local_config().build()).await
+        }
+
+        async fn start_with_config(
+            voters: &[NodeId],
+            config: RaftConfig,
+        ) -> (Arc<Self>, Arc<RaftNode>) {
+            let network = Self::new(config);
+            for &id in voters {
+                network.add_node(id, voters.to_vec()).await;
+            }
+
+            let leader = network.wait_for_leader().await;
+            (network, leader)
+        }
+
+        async fn wait_for_leader(&self) -> Arc<RaftNode> {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let nodes: Vec<_> = self.nodes.read().values().cloned().collect();
+                    for node in nodes {
+                        if node.metrics().await.unwrap().role == RaftRole::Leader {
+                            return node;
+                        }
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("no leader elected")
+        }
+
+        async fn shutdown(&self) {
+            let nodes = std::mem::take(&mut *self.nodes.write());
+            for (_, node) in nodes {
+                if let Ok(node) = Arc::try_unwrap(node) {
+                    node.shutdown().await;
+                }
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for LocalNetwork {
+        async fn send_request_vote(
+            &self,
+            target: NodeId,
+            request: RequestVoteRequest,
+        ) -> Result<RequestVoteResponse> {
+            Ok(self.node(target)?.request_vote(request).await)
+        }
+
+        async fn send_append_entries(
+            &self,
+            target: NodeId,
+            request: AppendEntriesRequest,
+        ) -> Result<AppendEntriesResponse> {
+            Ok(self.node(target)?.append_entries(request).await)
+        }
+
+        async fn send_install_snapshot(
+            &self,
+            target: NodeId,
+            request: InstallSnapshotRequest,
+        ) -> Result<InstallSnapshotResponse> {
+            use std::sync::atomic::Ordering;
+            let dropped = self
+                .snapshot_failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if dropped {
+                return Err(RaftError::Rpc(format!("{} restarted", target)));
+            }
+            Ok(self.node(target)?.install_snapshot(request).await)
+        }
+
+        async fn send_join(&self, target: NodeId, request: JoinRequest) -> Result<JoinResponse> {
+            Ok(self.node(target)?.handle_join(request).await)
+        }
+
+        async fn send_forward(
+            &self,
+            target: NodeId,
+            request: ForwardRequest,
+        ) -> Result<ForwardResponse> {
+            Ok(self.node(target)?.handle_forward(request).await)
+        }
+    }
+
+    /// Transport declaring a payload limit, recording what it's asked to
+    /// carry
+    struct LimitedTransport {
+        network: Arc<LocalNetwork>,
+        limit: usize,
+
+        /// Entries and payload bytes of every AppendEntries with entries
+        appends: Mutex<Vec<(usize, usize)>>,
+
+        /// Payload bytes of every InstallSnapshot chunk
+        chunks: Mutex<Vec<usize>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for LimitedTransport {
+        async fn send_request_vote(
+            &self,
+            target: NodeId,
+            request: RequestVoteRequest,
+        ) -> Result<RequestVoteResponse> {
+            self.network.send_request_vote(target, request).await
+        }
+
+        async fn send_append_entries(
+            &self,
+            target: NodeId,
+            request: AppendEntriesRequest,
+        ) -> Result<AppendEntriesResponse> {
+            if !request.entries.is_empty() {
+                let bytes = request.entries.iter().map(|e| e.command.len()).sum();
+                self.appends.lock().push((request.entries.len(), bytes));
+            }
+            self.network.send_append_entries(target, request).await
+        }
+
+        async fn send_install_snapshot(
+            &self,
+            target: NodeId,
+            request: InstallSnapshotRequest,
+        ) -> Result<InstallSnapshotResponse> {
+            self.chunks.lock().push(request.data.len());
+            self.network.send_install_snapshot(target, request).await
+        }
+
+        fn max_message_size(&self) -> Option<usize> {
+            Some(self.limit)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_fit_transport_limit() {
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let network = LocalNetwork::new(local_config().build());
+        let transport = Arc::new(LimitedTransport {
+            network: Arc::clone(&network),
+            limit: 256,
+            appends: Mutex::new(Vec::new()),
+            chunks: Mutex::new(Vec::new()),
+        });
+
+        // Only node 1 campaigns, so node 3 can be cut off without standing
+        // for election itself
+        let patient = local_config()
+            .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+            .snapshot_trailing_logs(0)
+            .build();
+        for &id in &voters {
+            let config = if id == NodeId(1) {
+                local_config().snapshot_trailing_logs(0).build()
+            } else {
+                patient.clone()
+            };
+            let node = RaftNodeBuilder::new(id, voters.clone(), KvStore::new())
+                .config(config)
+                .transport(Arc::clone(&transport) as Arc<dyn Transport>)
+                .build()
+                .await
+                .unwrap();
+            network.nodes.write().insert(id, Arc::new(node));
+        }
+        let leader = network.wait_for_leader().await;
+        assert_eq!(leader.id(), NodeId(1));
+
+        // Node 3 misses a snapshot's worth of entries and the ones after it
+        network.isolated.write().insert(NodeId(3));
+        let value = "x".repeat(100);
+        for i in 0..40 {
+            if i == 20 {
+                leader.create_snapshot().await.unwrap();
+            }
+            leader
+                .propose(format!("SET k{:02} {}", i, value).into_bytes())
+                .await
+                .unwrap();
+        }
+        network.isolated.write().clear();
+        let committed = leader.metrics().await.unwrap().commit_index;
+        let straggler = network.node(NodeId(3)).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while straggler.metrics().await.unwrap().last_applied < committed {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("node 3 never caught up");
+
+        // Node 3's backlog of 108-byte commands went two at a time (plus an
+        // empty no-op at most), though up to 100 entries would be allowed
+        let appends = transport.appends.lock().clone();
+        assert!(appends.iter().all(|&(_, bytes)| bytes <= 256));
+        assert!(appends.iter().all(|&(entries, _)| entries <= 3));
+        let chunks = transport.chunks.lock().clone();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|&bytes| bytes <= 256));
+
+        // A command that could never be sent is refused up front
+        assert!(matches!(
+            leader.propose(vec![b'x'; 257]).await,
+            Err(RaftError::InvalidEntry(_))
+        ));
+
+        drop((leader, straggler));
+        network.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_diverged_follower_converges_on_leader_log() {
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let network = LocalNetwork::new(local_config().build());
+        let entry = |term: u64, index: u64, command: &str| {
+            Entry::new(Term(term), LogIndex(index), command.as_bytes().to_vec())
+        };
+
+        // Node 2 kept three entries from a term-1 leader that never
+        // committed them; nodes 1 and 3 moved on in term 2
+        let agreed = vec![
+            entry(1, 1, "SET a 1"),
+            entry(2, 2, "SET b 2"),
+            entry(2, 3, "SET c 3"),
+        ];
+        let diverged = vec![
+            entry(1, 1, "SET a 1"),
+            entry(1, 2, "SET x 1"),
+            entry(1, 3, "SET y 1"),
+            entry(1, 4, "SET z 1"),
+        ];
+        for &id in &voters {
+            let mut log = MemoryLogStorage::new();
+            log.append(if id == NodeId(2) {
+                diverged.clone()
+            } else {
+                agreed.clone()
+            })
+            .unwrap();
+
+            // Only node 1 campaigns, so it's the one that has to repair node 2
+            let config = if id == NodeId(1) {
+                local_config().build()
+            } else {
+                local_config()
+                    .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+                    .build()
+            };
+            let builder = RaftNodeBuilder::new(id, voters.clone(), KvStore::new())
+                .config(config)
+                .log_storage(Box::new(log))
+                .initial_state(PersistentState {
+                    current_term: Term(2),
+                    voted_for: None,
+                });
+            network.add_custom_node(builder).await;
+        }
+        let leader = network.wait_for_leader().await;
+        assert_eq!(leader.id(), NodeId(1));
+        leader.propose(b"SET d 4".to_vec()).await.unwrap();
+
+        let committed = leader.metrics().await.unwrap().commit_index;
+        let follower = network.node(NodeId(2)).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while follower.metrics().await.unwrap().last_applied < committed {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("node 2 never caught up");
+
+        // Node 2's own entries past the first were overwritten by the leader's
+        let end = committed + 1;
+        let expected = leader.read_committed_range(LogIndex(1), end).await.unwrap();
+        let repaired = follower
+            .read_committed_range(LogIndex(1), end)
+            .await
+            .unwrap();
+        let positions = |entries: &[Entry]| -> Vec<_> {
+            entries
+                .iter()
+                .map(|e| (e.index, e.term, e.command.clone()))
+                .collect()
+        };
+        assert_eq!(positions(&repaired), positions(&expected));
+        assert_eq!(repaired[1].command, b"SET b 2");
+        assert!(repaired.iter().all(|e| !e.command.starts_with(b"SET z")));
+
+        drop((leader, follower));
+        network.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_new_node_joins_through_follower() {
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (network, leader) = LocalNetwork::start(&voters).await;
+        for i in 1..=5 {
+            leader
+                .propose(format!("SET k{} v{}", i, i).into_bytes())
+                .await
+                .unwrap();
+        }
+        let follower = voters
+            .iter()
+            .copied()
+            .find(|&id| id != leader.id())
+            .unwrap();
+
+        // The new node knows nobody but the follower it was pointed at
+        let newcomer = network.add_node(NodeId(4), vec![]).await;
+        tokio::time::timeout(Duration::from_secs(5), newcomer.join(vec![follower]))
+            .await
+            .expect("join timed out")
+            .unwrap();
+
+        let local = newcomer.members().await.unwrap();
+        assert!(local.contains(&MemberInfo {
+            id: NodeId(4),
+            role: MemberRole::Voter,
+            relation: MemberRelation::Local,
+        }));
+        let members = leader.members().await.unwrap();
+        assert!(members
+            .iter()
+            .any(|m| m.id == NodeId(4) && m.role == MemberRole::Voter));
+        assert_eq!(members.len(), 4);
+
+        // Promotion waited for the newcomer to hold every committed entry
+        let progress = leader.replication_progress().await.unwrap();
+        let metrics = newcomer.metrics().await.unwrap();
+        assert!(progress[&NodeId(4)].match_index >= LogIndex(6));
+        assert!(metrics.last_log_index >= LogIndex(6));
+
+        drop((leader, newcomer));
+        network.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_config_noop_confirms_new_membership() {
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (network, leader) = LocalNetwork::start(&voters).await;
+        let newcomer = network.add_node(NodeId(4), vec![]).await;
+        tokio::time::timeout(Duration::from_secs(5), newcomer.join(vec![leader.id()]))
+            .await
+            .expect("join timed out")
+            .unwrap();
+
+        let config = tokio::time::timeout(Duration::from_secs(5), leader.propose_config_noop())
+            .await
+            .expect("no-op never committed")
+            .unwrap();
+        let mut confirmed = config.voters.clone();
+        confirmed.sort();
+        assert_eq!(confirmed, vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4)]);
+        let committed = leader.metrics().await.unwrap().commit_index;
+
+        let follower = voters.iter().find(|&&id| id != leader.id()).unwrap();
+        let result = network.node(*follower).unwrap().propose_config_noop().await;
+        assert!(matches!(result, Err(RaftError::NotLeader(_))));
+
+        // A majority of the new membership applies it, and it stays put
+        let nodes: Vec<_> = voters
+            .iter()
+            .chain([&NodeId(4)])
+            .map(|&id| network.node(id).unwrap())
+            .collect();
+        let agreeing = || async {
+            let mut agreeing = 0;
+            for node in &nodes {
+                let applied = node.metrics().await.unwrap().last_applied;
+                let members = node.members().await.unwrap();
+                let voters = members.iter().filter(|m| m.role == MemberRole::Voter);
+                if applied >= committed && voters.count() == 4 {
+                    agreeing += 1;
+                }
+            }
+            agreeing
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while agreeing().await < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the new membership never reached a majority");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(agreeing().await >= 3);
+        assert_eq!(leader.propose_config_noop().await.unwrap(), config);
+
+        drop((leader, newcomer, nodes));
+        network.shutdown().await;
+    }
+
+    async fn wait_for_watermark(leader: &RaftNode, target: LogIndex) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while leader.global_applied_watermark().await.unwrap() != target {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("watermark never caught up");
+    }
+
+    #[tokio::test]
+    async fn test_applied_watermark_tracks_slowest_member() {
+        let (network, leader) = LocalNetwork::start(&[NodeId(1), NodeId(2)]).await;
+
+        // The laggard never campaigns, so cutting it off can't disturb the
+        // leader
+        let patient = local_config()
+            .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+            .build();
+        let laggard = network
+            .add_custom_node(
+                RaftNodeBuilder::new(NodeId(3), vec![], KvStore::new()).config(patient),
+            )
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), laggard.join(vec![leader.id()]))
+            .await
+            .expect("join timed out")
+            .unwrap();
+
+        leader.propose(b"SET a 1".to_vec()).await.unwrap();
+        let applied = leader.metrics().await.unwrap().last_applied;
+        wait_for_watermark(&leader, applied).await;
+
+        network.isolated.write().insert(NodeId(3));
+        leader.propose(b"SET b 2".to_vec()).await.unwrap();
+        leader.propose(b"SET c 3".to_vec()).await.unwrap();
+
+        // Give the other follower time to report its progress
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let slowest = laggard.metrics().await.unwrap().last_applied;
+        assert_eq!(slowest, applied);
+        assert!(leader.metrics().await.unwrap().last_applied > slowest);
+        assert_eq!(leader.global_applied_watermark().await.unwrap(), slowest);
+
+        let follower = network
+            .node(if leader.id() == NodeId(1) {
+                NodeId(2)
+            } else {
+                NodeId(1)
+            })
+            .unwrap();
+        assert!(matches!(
+            follower.global_applied_watermark().await,
+            Err(RaftError::NotLeader(_))
+        ));
+
+        network.isolated.write().clear();
+        let applied = leader.metrics().await.unwrap().last_applied;
+        wait_for_watermark(&leader, applied).await;
+
+        drop((laggard, follower));
+        network.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_restart_leaves_uncommitted_tail_unapplied() {
+        // The previous run appended five entries but only saw three commit
+        let mut log = MemoryLogStorage::new();
+        let entries = (1..=5)
+            .map(|i| Entry::new(Term(1), LogIndex(i), format!("k{}", i).into_bytes()))
+            .collect();
+        log.append(entries).unwrap();
+        let storage = MemoryStateStorage::new();
+        storage.save_hard_state(Term(1), None).unwrap();
+        storage.save_commit_hint(LogIndex(3)).unwrap();
+
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let patient = local_config()
+            .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+            .build();
+        let node = RaftNodeBuilder::new(
+            NodeId(1),
+            vec![NodeId(1), NodeId(2), NodeId(3)],
+            TracingStore {
+                trace: Arc::clone(&trace),
+            },
+        )
+        .config(patient)
+        .log_storage(Box::new(log))
+        .state_storage(Box::new(storage))
+        .build()
+        .await
+        .unwrap();
+
+        let metrics = node.metrics().await.unwrap();
+        assert_eq!(metrics.last_log_index, LogIndex(5));
+        assert_eq!(metrics.commit_index, LogIndex(3));
+        assert_eq!(*trace.lock(), ["apply k1", "apply k2", "apply k3"]);
+
+        // A new leader commits entry 4 and replaces entry 5 with its own
+        let heartbeat =
+            AppendEntriesRequest::heartbeat(Term(2), NodeId(2), LogIndex(4), Term(1), LogIndex(4));
+        assert!(node.append_entries(heartbeat).await.success);
+        assert_eq!(trace.lock().last().unwrap(), "apply k4");
+
+        let replace = AppendEntriesRequest {
+            term: Term(2),
+            leader_id: NodeId(2),
+            prev_log_index: LogIndex(4),
+            prev_log_term: Term(1),
+            entries: vec![Entry::new(Term(2), LogIndex(5), b"k5 again".to_vec())],
+            leader_commit: LogIndex(5),
+        };
+        assert!(node.append_entries(replace).await.success);
+        assert_eq!(
+            *trace.lock(),
+            [
+                "apply k1",
+                "apply k2",
+                "apply k3",
+                "apply k4",
+                "apply k5 again"
+            ]
+        );
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_restart_restores_membership_from_snapshot() {
+        // The previous run demoted node 3 and then compacted the change into
+        // a snapshot; node 4 joined after it
+        let shrunk = ClusterConfig {
+            voters: vec![NodeId(1), NodeId(2)],
+            learners: vec![NodeId(3)],
+            ..Default::default()
+        };
+        let grown = ClusterConfig {
+            voters: vec![NodeId(1), NodeId(2)],
+            learners: vec![NodeId(3), NodeId(4)],
+            ..Default::default()
+        };
+        let mut log = MemoryLogStorage::new();
+        let mut entries = vec![Entry::config_change(Term(1), LogIndex(1), &shrunk)];
+        entries.extend((2..=5).map(|i| Entry::new(Term(1), LogIndex(i), vec![])));
+        entries.push(Entry::config_change(Term(1), LogIndex(6), &grown));
+        log.append(entries).unwrap();
+        log.set_snapshot(Snapshot {
+            metadata: SnapshotMetadata {
+                last_included_index: LogIndex(5),
+                last_included_term: Term(1),
+                configuration: shrunk.clone(),
+            },
+            data: b"{}".to_vec(),
+        })
+        .unwrap();
+        log.compact(LogIndex(5)).unwrap();
+        let storage = MemoryStateStorage::new();
+        storage.save_hard_state(Term(1), None).unwrap();
+        storage.save_commit_hint(LogIndex(5)).unwrap();
+
+        // Restarted with the peers it was first built with
+        let patient = local_config()
+            .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+            .build();
+        let node = RaftNodeBuilder::new(
+            NodeId(1),
+            vec![NodeId(1), NodeId(2), NodeId(3)],
+            KvStore::new(),
+        )
+        .config(patient)
+        .log_storage(Box::new(log))
+        .state_storage(Box::new(storage))
+        .build()
+        .await
+        .unwrap();
+        let membership = |members: Vec<MemberInfo>| {
+            let mut config = ClusterConfig::default();
+            for member in members {
+                match member.role {
+                    MemberRole::Voter => config.voters.push(member.id),
+                    MemberRole::Learner => config.learners.push(member.id),
+                }
+            }
+            config
+        };
+        assert_eq!(membership(node.members().await.unwrap()), shrunk);
+
+        // The change after the snapshot takes effect again once it commits
+        let heartbeat =
+            AppendEntriesRequest::heartbeat(Term(1), NodeId(2), LogIndex(6), Term(1), LogIndex(6));
+        assert!(node.append_entries(heartbeat).await.success);
+        assert_eq!(membership(node.members().await.unwrap()), grown);
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_restarted_follower_waits_to_catch_up() {
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let network = LocalNetwork::new(local_config().build());
+        network.add_node(NodeId(1), voters.clone()).await;
+        network.add_node(NodeId(2), voters.clone()).await;
+
+        // The follower keeps its log on disk across the restart, and never
+        // campaigns so it stays a follower
+        let dir = tempfile::tempdir().unwrap();
+        let patient = local_config()
+            .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+            .build();
+        let follower = || {
+            let log = FileLogStorage::open(dir.path(), Default::default()).unwrap();
+            RaftNodeBuilder::new(NodeId(3), voters.clone(), KvStore::new())
+                .config(patient.clone())
+                .log_storage(Box::new(log))
+        };
+        let node = network.add_custom_node(follower()).await;
+
+        let leader = network.wait_for_leader().await;
+        leader
+            .wait_until_caught_up(Duration::ZERO)
+            .await
+            .expect("the leader is always caught up");
+        leader.propose(b"SET a 1".to_vec()).await.unwrap();
+        node.wait_until_caught_up(Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        drop(node);
+        let stopped = network.nodes.write().remove(&NodeId(3)).unwrap();
+        Arc::try_unwrap(stopped).ok().unwrap().shutdown().await;
+        for i in 0..20 {
+            leader
+                .propose(format!("SET k{} v", i).into_bytes())
+                .await
+                .unwrap();
+        }
+        let committed = leader.metrics().await.unwrap().commit_index;
+
+        let restarted = network.add_custom_node(follower()).await;
+        assert!(restarted.metrics().await.unwrap().last_log_index < committed);
+        assert!(matches!(
+            restarted.wait_until_caught_up(Duration::ZERO).await,
+            Err(RaftError::Timeout)
+        ));
+
+        restarted
+            .wait_until_caught_up(Duration::from_secs(5))
+            .await
+            .unwrap();
+        let metrics = restarted.metrics().await.unwrap();
+        assert!(metrics.last_applied >= committed);
+
+        drop((leader, restarted));
+        network.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_follower_forwards_proposals() {
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let config = local_config().forward_proposals(true).build();
+        let (network, leader) = LocalNetwork::start_with_config(&voters, config).await;
+        let follower = voters
+            .iter()
+            .copied()
+            .find(|&id| id != leader.id())
+            .unwrap();
+        let follower = network.node(follower).unwrap();
+
+        // Wait for the follower to hear from the leader
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while follower.metrics().await.unwrap().current_leader != Some(leader.id()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("follower never learned the leader");
+
+        follower.propose(b"SET color green".to_vec()).await.unwrap();
+        let color = leader
+            .linearizable_read(|kv: &KvStore| kv.data.get("color").cloned())
+            .await
+            .unwrap();
+        assert_eq!(color.as_deref(), Some("green"));
+        assert_eq!(leader.metrics().await.unwrap().proposals_accepted, 1);
+
+        // A forwarded proposal that reaches a follower isn't passed on again
+        let response = follower
+            .handle_forward(ForwardRequest {
+                from: NodeId(9),
+                command: b"SET color red".to_vec(),
+                metadata: None,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            ForwardResponse::NotLeader(Some(id)) if id == leader.id()
+        ));
+
+        drop((leader, follower));
+        network.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_metadata_reaches_apply_on_every_node() {
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let config = local_config().forward_proposals(true).build();
+        let (network, leader) = LocalNetwork::start_with_config(&voters, config).await;
+        let follower = voters
+            .iter()
+            .copied()
+            .find(|&id| id != leader.id())
+            .unwrap();
+        let follower = network.node(follower).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while follower.metrics().await.unwrap().current_leader != Some(leader.id()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("follower never learned the leader");
+
+        let output = leader
+            .propose_with_metadata(b"SET a 1".to_vec(), b"trace-1".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(output, b"OK");
+        leader.propose(b"SET b 2".to_vec()).await.unwrap();
+        // Forwarding carries the metadata to the leader too
+        follower
+            .propose_with_metadata(b"SET c 3".to_vec(), b"trace-3".to_vec())
+            .await
+            .unwrap();
+
+        let seen = leader
+            .linearizable_read(|kv: &KvStore| kv.metadata.clone())
+            .await
+            .unwrap();
+        assert_eq!(seen, vec![b"trace-1".to_vec(), b"trace-3".to_vec()]);
+
+        // Followers apply the replicated entries with their metadata
+        let commit_index = leader.metrics().await.unwrap().commit_index;
+        for &id in &voters {
+            let node = network.node(id).unwrap();
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while node.metrics().await.unwrap().last_applied < commit_index {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("node never applied the proposals");
+            let entries = node
+                .read_committed_range(LogIndex(1), commit_index + 1)
+                .await
+                .unwrap();
+            let metadata: Vec<_> = entries
+                .iter()
+                .filter(|e| e.kind == EntryKind::Normal)
+                .map(|e| e.metadata.as_deref())
+                .collect();
+            assert_eq!(
+                metadata,
+                vec![Some(&b"trace-1"[..]), None, Some(&b"trace-3"[..])]
+            );
+        }
+
+        drop((leader, follower));
+        network.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_forwarding_without_known_leader_fails() {
+        let config = local_config()
+            .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+            .forward_proposals(true)
+            .build();
+        let node = RaftNodeBuilder::new(NodeId(1), vec![NodeId(1), NodeId(2)], KvStore::new())
+            .config(config)
+            .build()
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            node.propose(b"SET a 1".to_vec()).await,
+            Err(RaftError::NotLeader(NotLeaderInfo { leader: None, .. }))
+        ));
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_snapshot_transfer_retried() {
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let config = local_config()
+            .snapshot_threshold(5)
+            .snapshot_trailing_logs(0)
+            .max_append_bytes(16)
+            .build();
+        let (network, leader) = LocalNetwork::start_with_config(&voters, config).await;
+        let mut events = leader.subscribe_events();
+
+        for i in 1..=12 {
+            leader
+                .propose(format!("SET k{} v{}", i, i).into_bytes())
+                .await
+                .unwrap();
+        }
+        let snapshot_index = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(index) = leader.metrics().await.unwrap().snapshot_index {
+                    return index;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("leader never compacted its log");
+
+        // The newcomer can only be caught up from the snapshot, and the
+        // first transfer is cut off partway through
+        network
+            .snapshot_failures
+            .store(1, std::sync::atomic::Ordering::SeqCst);
+        let follower = voters
+            .iter()
+            .copied()
+            .find(|&id| id != leader.id())
+            .unwrap();
+        let newcomer = network.add_node(NodeId(4), vec![]).await;
+        tokio::time::timeout(Duration::from_secs(5), newcomer.join(vec![follower]))
+            .await
+            .expect("join timed out")
+            .unwrap();
+
+        assert_eq!(
+            network
+                .snapshot_failures
+                .load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+        let metrics = newcomer.metrics().await.unwrap();
+        assert!(metrics.snapshot_index >= Some(snapshot_index));
+        assert!(metrics.last_applied >= snapshot_index);
+        while let Ok(event) = events.try_recv() {
+            assert!(!matches!(event, RaftEvent::SnapshotTransferFailed { .. }));
+        }
+
+        drop((leader, newcomer));
+        network.shutdown().await;
+    }
+
+    /// Accepts every snapshot chunk and AppendEntries, recording them
+    #[derive(Default)]
+    struct SnapshotSink {
+        chunks: Mutex<Vec<InstallSnapshotRequest>>,
+        appends: Mutex<Vec<AppendEntriesRequest>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for SnapshotSink {
+        async fn send_request_vote(
+            &self,
+            target: NodeId,
+            _request: RequestVoteRequest,
+        ) -> Result<RequestVoteResponse> {
+            Err(RaftError::Rpc(format!("unreachable {}", target)))
+        }
+
+        async fn send_append_entries(
+            &self,
+            _target: NodeId,
+            request: AppendEntriesRequest,
+        ) -> Result<AppendEntriesResponse> {
+            let term = request.term;
+            self.appends.lock().push(request);
+            Ok(AppendEntriesResponse {
+                term,
+                success: false,
+                match_index: None,
+                conflict_term: None,
+                conflict_index: None,
+                commit_index: LogIndex::ZERO,
+                last_applied: LogIndex::ZERO,
+            })
+        }
+
+        async fn send_install_snapshot(
+            &self,
+            _target: NodeId,
+            request: InstallSnapshotRequest,
+        ) -> Result<InstallSnapshotResponse> {
+            let term = request.term;
+            self.chunks.lock().push(request);
+            Ok(InstallSnapshotResponse { term })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follower_behind_log_start_sent_snapshot() {
+        let config = crate::RaftConfigBuilder::new().max_append_bytes(16).build();
+        let (mut leader, _events) =
+            test_inner_with_config(NodeId(1), vec![NodeId(1), NodeId(2)], config);
+        let sink = Arc::new(SnapshotSink::default());
+        leader.transport = Arc::clone(&sink) as Arc<dyn Transport>;
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        leader.command_tx = command_tx;
+
+        // Everything through 10 lives only in the snapshot
+        let entries = (1..=10)
+            .map(|i| Entry::new(Term(1), LogIndex(i), format!("SET k{} v", i).into_bytes()))
+            .collect();
+        leader.log.append(entries).unwrap();
+        let snapshot = Snapshot {
+            metadata: SnapshotMetadata {
+                last_included_index: LogIndex(10),
+                last_included_term: Term(1),
+                configuration: ClusterConfig {
+                    voters: vec![NodeId(1), NodeId(2)],
+                    ..Default::default()
+                },
+            },
+            data: (0..50).collect(),
+        };
+        leader.log.set_snapshot(snapshot.clone()).unwrap();
+        leader.log.compact(LogIndex(10)).unwrap();
+        elect(&mut leader);
+
+        // A fresh follower needs entries from the very start
+        leader
+            .state
+            .write()
+            .leader_state
+            .as_mut()
+            .unwrap()
+            .set_next_index(NodeId(2), LogIndex(1));
+        leader.replicate_to(NodeId(2));
+        let Some(RaftCommand::SnapshotSent {
+            peer,
+            last_included_index,
+            attempts,
+            result,
+        }) = command_rx.recv().await
+        else {
+            panic!("snapshot transfer went away");
+        };
+        assert!(sink.appends.lock().is_empty());
+
+        // In order, within the byte limit, and together the whole snapshot
+        let chunks = std::mem::take(&mut *sink.chunks.lock());
+        assert_eq!(chunks.len(), 4);
+        let mut data = Vec::new();
+        for chunk in &chunks {
+            assert!(chunk.data.len() <= 16);
+            assert_eq!(chunk.configuration.is_some(), chunk.done);
+            assert_eq!(chunk.offset, data.len() as u64);
+            assert_eq!(chunk.last_included_index, LogIndex(10));
+            data.extend_from_slice(&chunk.data);
+        }
+        assert!(chunks.last().unwrap().done);
+        assert_eq!(
+            chunks.last().unwrap().configuration,
+            Some(snapshot.metadata.configuration.clone())
+        );
+        assert_eq!(data, snapshot.data);
+
+        // Once it's answered, replication picks up right after the snapshot
+        leader.handle_snapshot_sent(peer, last_included_index, attempts, result);
+        let next_index = leader
+            .state
+            .read()
+            .leader_state
+            .as_ref()
+            .unwrap()
+            .get_next_index(NodeId(2));
+        assert_eq!(next_index, Some(LogIndex(11)));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while sink.appends.lock().is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("no AppendEntries after the snapshot");
+        let append = sink.appends.lock()[0].clone();
+        assert_eq!(append.prev_log_index, LogIndex(10));
+        assert_eq!(append.prev_log_term, Term(1));
+        assert_eq!(append.entries.first().map(|e| e.index), Some(LogIndex(11)));
+    }
+
+    #[tokio::test]
+    async fn test_linearizable_read_sees_preceding_write() {
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (network, leader) = LocalNetwork::start(&voters).await;
+
+        leader.propose(b"SET color blue".to_vec()).await.unwrap();
+        let color = leader
+            .linearizable_read(|kv: &KvStore| kv.data.get("color").cloned())
+            .await
+            .unwrap();
+        assert_eq!(color.as_deref(), Some("blue"));
+
+        let follower = voters
+            .iter()
+            .copied()
+            .find(|&id| id != leader.id())
+            .unwrap();
+        let follower = network.node(follower).unwrap();
+        assert!(matches!(
+            follower
+                .linearizable_read(|kv: &KvStore| kv.data.len())
+                .await,
+            Err(RaftError::NotLeader(NotLeaderInfo { leader: Some(id), .. })) if id == leader.id()
+        ));
+
+        drop((leader, follower));
+        network.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lease_read_refused_before_raw_lease_runs_out() {
+        let config = local_config()
+            .election_timeout(Duration::from_millis(300), Duration::from_millis(600))
+            .max_clock_drift(Duration::from_millis(100))
+            .build();
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (mut inner, _events) = test_inner_with_config(NodeId(1), peers, config);
+        elect(&mut inner);
+        commit_all(&mut inner);
+        let lease_read = |inner: &mut RaftNodeInner<KvStore>| {
+            let (tx, mut rx) = oneshot::channel();
+            inner.handle_lease_read(tx);
+            inner.release_read_waiters();
+            rx.try_recv().unwrap()
+        };
+
+        // No follower has answered yet, so there's no quorum behind a lease
+        assert!(matches!(
+            lease_read(&mut inner),
+            Err(RaftError::LeaseExpired)
+        ));
+
+        let sent_at = Instant::now();
+        let term = inner.state.read().persistent.current_term;
+        let last_index = inner.log.last_index();
+        inner.handle_append_response(
+            NodeId(2),
+            sent_at,
+            last_index,
+            AppendEntriesResponse {
+                term,
+                success: true,
+                match_index: Some(last_index),
+                conflict_term: None,
+                conflict_index: None,
+                commit_index: last_index,
+                last_applied: last_index,
+            },
+        );
+        assert_eq!(
+            inner.lease_expiry(),
+            Some(sent_at + Duration::from_millis(200))
+        );
+        tokio::time::advance(Duration::from_millis(150)).await;
+        assert_eq!(lease_read(&mut inner).unwrap(), last_index);
+
+        // The raw lease would last until 300ms, but the drift margin ends it
+        // at 200ms
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(matches!(
+            lease_read(&mut inner),
+            Err(RaftError::LeaseExpired)
+        ));
+
+        // A drift margin as long as the election timeout leaves no lease
+        inner.config.max_clock_drift = Duration::from_millis(300);
+        inner.handle_append_response(
+            NodeId(3),
+            Instant::now(),
+            last_index,
+            AppendEntriesResponse {
+                term,
+                success: true,
+                match_index: Some(last_index),
+                conflict_term: None,
+                conflict_index: None,
+                commit_index: last_index,
+                last_applied: last_index,
+            },
+        );
+        assert!(matches!(
+            lease_read(&mut inner),
+            Err(RaftError::LeaseExpired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_reads_share_confirmation_rounds() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let config = local_config()
+            .election_timeout(Duration::from_secs(1), Duration::from_secs(2))
+            .heartbeat_interval(Duration::from_millis(200))
+            .build();
+        let appends = Arc::new(AtomicUsize::new(0));
+        let network = LocalNetwork::new(config.clone());
+        for &id in &voters {
+            let sent = Arc::clone(&appends);
+            let observer = move |rpc: &RpcSummary| {
+                if rpc.direction == RpcDirection::Outbound && rpc.kind == RpcKind::AppendEntries {
+                    sent.fetch_add(1, Ordering::SeqCst);
                 }
-            }
+            };
+            network
+                .add_custom_node(
+                    RaftNodeBuilder::new(id, voters.clone(), KvStore::new())
+                        .config(config.clone())
+                        .rpc_observer(Arc::new(observer)),
+                )
+                .await;
         }
+        let leader = network.wait_for_leader().await;
+        leader.propose(b"SET color blue".to_vec()).await.unwrap();
+        let committed = leader.metrics().await.unwrap().commit_index;
 
-        RequestVoteResponse {
-            term: state.persistent.current_term,
-            vote_granted,
+        const READS: usize = 200;
+        let before = appends.load(Ordering::SeqCst);
+        let reads = (0..READS).map(|_| {
+            let leader = Arc::clone(&leader);
+            tokio::spawn(async move {
+                let index = leader.read_index().await.unwrap();
+                let color = leader
+                    .linearizable_read(|kv: &KvStore| kv.data.get("color").cloned())
+                    .await
+                    .unwrap();
+                (index, color)
+            })
+        });
+        let results = futures::future::join_all(reads).await;
+        let sent = appends.load(Ordering::SeqCst) - before;
+
+        for result in results {
+            let (index, color) = result.unwrap();
+            assert!(index >= committed);
+            assert_eq!(color.as_deref(), Some("blue"));
         }
+        // Unbatched, each of the 2 * READS handshakes sends its own
+        // heartbeats
+        assert!(
+            sent < READS / 4,
+            "{} AppendEntries for {} reads",
+            sent,
+            READS
+        );
+
+        drop(leader);
+        network.shutdown().await;
     }
 
-    /// Handle AppendEntries RPC
-    fn handle_append_entries(&mut self, req: AppendEntriesRequest) -> AppendEntriesResponse {
-        let mut state = self.state.write();
+    #[tokio::test]
+    async fn test_not_leader_carries_term_and_membership() {
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (network, leader) = LocalNetwork::start(&voters).await;
+        leader.propose(b"SET a 1".to_vec()).await.unwrap();
 
-        // Update term if we see a higher one
-        if req.term > state.persistent.current_term {
-            state.become_follower(req.term, Some(req.leader_id));
+        let follower = voters
+            .iter()
+            .copied()
+            .find(|&id| id != leader.id())
+            .unwrap();
+        let follower = network.node(follower).unwrap();
+        let term = follower.metrics().await.unwrap().current_term;
+        match follower.propose(b"SET b 2".to_vec()).await {
+            Err(RaftError::NotLeader(info)) => {
+                assert_eq!(info.term, Some(term));
+                assert_eq!(info.voters, voters);
+                assert_eq!(info.leader, Some(leader.id()));
+            }
+            other => panic!("expected NotLeader, got {:?}", other),
         }
 
-        // Reject if term is old
-        if req.term < state.persistent.current_term {
-            return AppendEntriesResponse {
-                term: state.persistent.current_term,
-                success: false,
-                match_index: None,
-                commit_index: state.volatile.commit_index,
-            };
+        drop((leader, follower));
+        network.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_read_committed_range() {
+        let config = local_config().snapshot_trailing_logs(2).build();
+        let (network, leader) = LocalNetwork::start_with_config(&[NodeId(1)], config).await;
+        for i in 1..=5 {
+            leader
+                .propose(format!("SET k{} v", i).into_bytes())
+                .await
+                .unwrap();
         }
+        let commit_index = leader.metrics().await.unwrap().commit_index;
 
-        // Reset election timeout (valid leader heartbeat)
-        self.reset_election_timeout();
-        state.leader_id = Some(req.leader_id);
+        // The term's no-op comes first, then the five commands
+        let entries = leader
+            .read_committed_range(LogIndex(2), commit_index + 1)
+            .await
+            .unwrap();
+        let commands: Vec<_> = entries.iter().map(|e| e.command.clone()).collect();
+        let expected: Vec<_> = (1..=5)
+            .map(|i| format!("SET k{} v", i).into_bytes())
+            .collect();
+        assert_eq!(commands, expected);
+        assert_eq!(entries.last().unwrap().index, commit_index);
 
-        // Check if our log contains an entry at prev_log_index with matching term
-        if req.prev_log_index > LogIndex::ZERO {
-            match self.log.get_term(req.prev_log_index) {
-                Ok(Some(term)) if term == req.prev_log_term => {
-                    // Log is consistent, proceed
-                }
-                _ => {
-                    // Log doesn't match, reject
-                    return AppendEntriesResponse {
-                        term: state.persistent.current_term,
-                        success: false,
-                        match_index: Some(self.log.last_index()),
-                        commit_index: state.volatile.commit_index,
-                    };
-                }
-            }
+        assert!(matches!(
+            leader.read_committed_range(LogIndex(2), commit_index + 2).await,
+            Err(RaftError::LogIndexOutOfRange(index)) if index == commit_index + 1
+        ));
+        assert!(leader
+            .read_committed_range(LogIndex(3), LogIndex(3))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Only the trailing entries outlive the snapshot
+        leader.create_snapshot().await.unwrap();
+        assert!(matches!(
+            leader
+                .read_committed_range(LogIndex(1), commit_index + 1)
+                .await,
+            Err(RaftError::LogIndexOutOfRange(LogIndex(1)))
+        ));
+        let trailing = leader
+            .read_committed_range(commit_index - 1, commit_index + 1)
+            .await
+            .unwrap();
+        assert_eq!(trailing.len(), 2);
+
+        drop(leader);
+        network.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_backup_restores_state() {
+        let (network, leader) = LocalNetwork::start(&[NodeId(1)]).await;
+        assert!(leader.get_snapshot().await.unwrap().is_none());
+
+        for (key, value) in [("a", "1"), ("b", "2"), ("a", "3")] {
+            leader
+                .propose(format!("SET {} {}", key, value).into_bytes())
+                .await
+                .unwrap();
         }
+        let applied = leader.metrics().await.unwrap().last_applied;
+
+        let created = leader.create_snapshot().await.unwrap();
+        assert_eq!(created.metadata.last_included_index, applied);
+        let backup = leader.get_snapshot().await.unwrap().unwrap();
+        assert_eq!(backup.metadata.last_included_index, applied);
+        assert_eq!(
+            backup.metadata.last_included_term,
+            created.metadata.last_included_term
+        );
+        assert_eq!(backup.data, created.data);
 
-        // Append new entries
-        if !req.entries.is_empty() {
-            // Delete conflicting entries and append new ones
-            if let Some(first_new) = req.entries.first() {
-                if let Ok(Some(existing_term)) = self.log.get_term(first_new.index) {
-                    if existing_term != first_new.term {
-                        // Conflict detected, delete from this point
-                        let _ = self.log.delete_from(first_new.index);
+        // Nothing new to capture, so the stored snapshot comes back
+        let again = leader.create_snapshot().await.unwrap();
+        assert_eq!(again.metadata.last_included_index, applied);
+
+        let mut restored = KvStore::new();
+        restored.restore(&backup.data);
+        let expected = leader
+            .linearizable_read(|kv: &KvStore| kv.data.clone())
+            .await
+            .unwrap();
+        assert_eq!(restored.data, expected);
+        assert_eq!(restored.data.get("a").map(String::as_str), Some("3"));
+
+        drop(leader);
+        network.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_reads_not_starved_by_write_burst() {
+        let config = local_config().read_priority(4).build();
+        let (network, leader) = LocalNetwork::start_with_config(&[NodeId(1)], config).await;
+
+        // Writers keep thousands of proposals queued ahead of every read
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writers: Vec<_> = (0..4)
+            .map(|w| {
+                let leader = Arc::clone(&leader);
+                let done = Arc::clone(&done);
+                tokio::spawn(async move {
+                    let mut n = 0;
+                    while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                        let burst = (0..1000).map(|_| {
+                            n += 1;
+                            leader.propose_no_wait(format!("SET w{} {}", w, n).into_bytes())
+                        });
+                        futures::future::join_all(burst).await;
                     }
-                }
-            }
+                })
+            })
+            .collect();
 
-            // Append new entries
-            if let Err(e) = self.log.append(req.entries.clone()) {
-                warn!("Failed to append entries: {}", e);
-                return AppendEntriesResponse {
-                    term: state.persistent.current_term,
-                    success: false,
-                    match_index: None,
-                    commit_index: state.volatile.commit_index,
-                };
-            }
+        let mut latencies = Vec::new();
+        for _ in 0..20 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let started = std::time::Instant::now();
+            leader.read_index().await.unwrap();
+            latencies.push(started.elapsed());
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        for writer in writers {
+            writer.await.unwrap();
         }
 
-        // Update commit index
-        if req.leader_commit > state.volatile.commit_index {
-            let last_new_index = req
-                .entries
-                .last()
-                .map(|e| e.index)
-                .unwrap_or(req.prev_log_index);
+        let worst = latencies.iter().max().unwrap();
+        assert!(
+            *worst < Duration::from_millis(50),
+            "read latencies {:?}",
+            latencies
+        );
+        assert!(leader.metrics().await.unwrap().commit_index > LogIndex(4_000));
 
-            state.volatile.commit_index = req.leader_commit.min(last_new_index);
-        }
+        drop(leader);
+        network.shutdown().await;
+    }
 
-        AppendEntriesResponse {
-            term: state.persistent.current_term,
-            success: true,
-            match_index: Some(self.log.last_index()),
-            commit_index: state.volatile.commit_index,
+    #[tokio::test]
+    async fn test_read_index_times_out_without_quorum() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let config = crate::RaftConfigBuilder::new()
+            .election_timeout(Duration::from_millis(20), Duration::from_millis(40))
+            .heartbeat_interval(Duration::from_millis(10))
+            .build();
+        // Votes get through, heartbeats never do
+        let transport = Arc::new(PingTransport { alive: vec![] });
+        let node = RaftNodeBuilder::new(NodeId(1), peers, KvStore::new())
+            .config(config)
+            .transport(transport)
+            .build()
+            .await
+            .unwrap();
+
+        let mut events = node.subscribe_events();
+        while !matches!(events.recv().await, Ok(RaftEvent::BecameLeader { .. })) {}
+
+        assert!(matches!(node.read_index().await, Err(RaftError::Timeout)));
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_follower_with_newer_term_takes_over() {
+        let cluster = crate::testing::TestCluster::builder(3)
+            .build(|_| KvStore::new())
+            .await
+            .unwrap();
+        let old = cluster.wait_for_leader().await.unwrap();
+        cluster
+            .propose_on_leader(b"SET a 1".to_vec())
+            .await
+            .unwrap();
+
+        // Cut a follower off until its failed elections carry it past the
+        // leader's term. Nothing is proposed meanwhile, so its log stays as
+        // up to date as anyone's.
+        let follower = cluster
+            .nodes()
+            .into_iter()
+            .find(|n| n.id() != old.id())
+            .unwrap();
+        let commit = old.metrics().await.unwrap().commit_index;
+        while follower.metrics().await.unwrap().commit_index < commit {
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        cluster.partition(follower.id());
+        let old_term = old.metrics().await.unwrap().current_term;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while follower.metrics().await.unwrap().current_term <= old_term {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("follower never moved past the leader's term");
+
+        // The old leader's next heartbeat is rejected; it steps down and the
+        // follower wins the election that follows
+        cluster.heal(follower.id());
+        let new = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match cluster.leader().await {
+                    Some(leader) if leader.id() != old.id() => return leader,
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("old leader never stepped down");
+        assert_eq!(new.id(), follower.id());
+        assert!(new.metrics().await.unwrap().current_term > old_term);
+        assert_eq!(
+            cluster
+                .propose_on_leader(b"SET b 2".to_vec())
+                .await
+                .unwrap(),
+            b"OK"
+        );
+
+        drop((old, follower, new));
+        cluster.shutdown().await;
     }
 
-    /// Apply committed entries to state machine
-    fn apply_committed(&mut self) {
-        let mut state = self.state.write();
+    #[tokio::test]
+    async fn test_rpc_observer_sees_election_and_replication() {
+        fn recorder() -> (Arc<Mutex<Vec<RpcSummary>>>, Arc<dyn RpcObserver>) {
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let sink = Arc::clone(&log);
+            let observer = move |rpc: &RpcSummary| sink.lock().push(rpc.clone());
+            (log, Arc::new(observer))
+        }
 
-        while state.volatile.last_applied < state.volatile.commit_index {
-            state.volatile.last_applied.increment();
+        // Only node 1 campaigns, so it wins the first election
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let patient = local_config()
+            .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+            .build();
+        let network = LocalNetwork::new(patient.clone());
+        let (leader_log, observer) = recorder();
+        let leader = network
+            .add_custom_node(
+                RaftNodeBuilder::new(NodeId(1), voters.clone(), KvStore::new())
+                    .config(local_config().build())
+                    .rpc_observer(observer),
+            )
+            .await;
+        let (follower_log, observer) = recorder();
+        network
+            .add_custom_node(
+                RaftNodeBuilder::new(NodeId(2), voters.clone(), KvStore::new())
+                    .config(patient)
+                    .rpc_observer(observer),
+            )
+            .await;
+        network.add_node(NodeId(3), voters).await;
+        assert_eq!(network.wait_for_leader().await.id(), leader.id());
 
-            if let Ok(Some(entry)) = self.log.get(state.volatile.last_applied) {
-                let mut sm = self.state_machine.write();
-                sm.apply(&entry.command);
+        leader.propose(b"SET a 1".to_vec()).await.unwrap();
+        let index = leader.metrics().await.unwrap().commit_index;
+        let term = leader.metrics().await.unwrap().current_term;
 
-                debug!(
-                    "Node {} applied entry {} to state machine",
-                    state.id, state.volatile.last_applied
-                );
+        let sent = leader_log.lock().clone();
+        let first = &sent[0];
+        assert_eq!(first.direction, RpcDirection::Outbound);
+        assert_eq!(first.kind, RpcKind::RequestVote);
+        assert_eq!(first.term, term);
+        let granted = sent
+            .iter()
+            .position(|rpc| rpc.kind == RpcKind::RequestVote && rpc.success)
+            .expect("no vote was granted");
+        let first_append = sent
+            .iter()
+            .position(|rpc| rpc.kind == RpcKind::AppendEntries)
+            .expect("nothing was replicated");
+        assert!(granted < first_append);
+        assert!(sent
+            .iter()
+            .all(|rpc| rpc.direction == RpcDirection::Outbound));
+        assert!(sent.iter().any(|rpc| rpc.kind == RpcKind::AppendEntries
+            && rpc.success
+            && matches!(rpc.entries, Some((first, last)) if first <= index && index <= last)));
+
+        let received = follower_log.lock().clone();
+        assert_eq!(
+            received[0],
+            RpcSummary {
+                direction: RpcDirection::Inbound,
+                kind: RpcKind::RequestVote,
+                peer: NodeId(1),
+                term,
+                entries: None,
+                success: true,
             }
-        }
+        );
+        assert!(received.iter().any(|rpc| rpc.kind == RpcKind::AppendEntries
+            && rpc.peer == NodeId(1)
+            && rpc.success
+            && rpc.entries.is_some()));
     }
-}
 
-/// Main node event loop
-async fn run_node<SM: StateMachine>(
-    id: NodeId,
-    peers: Vec<NodeId>,
-    config: RaftConfig,
-    state_machine: SM,
-    mut command_rx: mpsc::UnboundedReceiver<RaftCommand>,
-) {
-    let mut inner = RaftNodeInner::new(id, peers, config.clone(), state_machine);
+    #[tokio::test]
+    async fn test_rejoining_node_falls_back_to_cluster_term() {
+        let config = local_config()
+            .max_term_gap(3)
+            .reset_term_on_gap(true)
+            .build();
+        let cluster = crate::testing::TestCluster::builder(3)
+            .config(config)
+            .build(|_| KvStore::new())
+            .await
+            .unwrap();
+        let leader = cluster.wait_for_leader().await.unwrap();
+        let term = leader.metrics().await.unwrap().current_term;
 
-    let mut election_timer = interval(Duration::from_millis(50));
-    let mut heartbeat_timer = interval(config.heartbeat_interval);
+        // Cut a follower off until its failed elections leave it well past
+        // the allowed gap
+        let follower = cluster
+            .nodes()
+            .into_iter()
+            .find(|n| n.id() != leader.id())
+            .unwrap();
+        cluster.partition(follower.id());
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while follower.metrics().await.unwrap().current_term.0 <= term.0 + 5 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("follower never ran its term up");
 
-    loop {
-        tokio::select! {
-            // Handle incoming commands
-            Some(cmd) = command_rx.recv() => {
-                match cmd {
-                    RaftCommand::Propose { command, response } => {
-                        let state = inner.state.read();
-                        if state.role != RaftRole::Leader {
-                            let _ = response.send(Err(RaftError::NotLeader(state.leader_id)));
-                            continue;
-                        }
-                        drop(state);
-
-                        // Append to local log
-                        let term = inner.state.read().persistent.current_term;
-                        let index = inner.log.last_index() + 1;
-                        let entry = Entry::new(term, index, command);
-
-                        if let Err(e) = inner.log.append(vec![entry]) {
-                            let _ = response.send(Err(e));
-                        } else {
-                            // For now, just acknowledge immediately
-                            // In a real implementation, we'd wait for replication
-                            let _ = response.send(Ok(vec![]));
-                        }
-                    }
+        let mut events = follower.subscribe_events();
+        cluster.heal(follower.id());
+        let event = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(event @ RaftEvent::TermGapDetected { .. }) = events.recv().await {
+                    return event;
+                }
+            }
+        })
+        .await
+        .expect("term gap never reported");
+        assert!(matches!(
+            event,
+            RaftEvent::TermGapDetected { leader_term, leader: from, reset: true, .. }
+                if leader_term == term && from == leader.id()
+        ));
 
-                    RaftCommand::RequestVote { request, response } => {
-                        let reply = inner.handle_request_vote(request);
-                        let _ = response.send(reply);
-                    }
+        // The leader kept its term and the follower is back behind it
+        cluster
+            .propose_on_leader(b"SET a 1".to_vec())
+            .await
+            .unwrap();
+        let metrics = leader.metrics().await.unwrap();
+        assert_eq!(metrics.role, RaftRole::Leader);
+        assert_eq!(metrics.current_term, term);
+        let metrics = follower.metrics().await.unwrap();
+        assert_eq!(metrics.current_term, term);
+        assert_eq!(metrics.current_leader, Some(leader.id()));
 
-                    RaftCommand::AppendEntries { request, response } => {
-                        let reply = inner.handle_append_entries(request);
-                        let _ = response.send(reply);
+        cluster.shutdown().await;
+    }
 
-                        // Apply committed entries
-                        inner.apply_committed();
-                    }
+    #[tokio::test(start_paused = true)]
+    async fn test_election_timeout_strategy_decides_when_elections_fire() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&calls);
+        let strategy = move |attempt: u32, priority: u32| {
+            seen.lock().push((attempt, priority));
+            Duration::from_millis(400) * (attempt + 1)
+        };
 
-                    RaftCommand::Shutdown => {
-                        info!("Node {} shutting down", id);
-                        break;
-                    }
-                }
+        // Node 2 never answers, so every election times out in turn
+        let started = Instant::now();
+        let node = RaftNodeBuilder::new(NodeId(1), vec![NodeId(1), NodeId(2)], KvStore::new())
+            .config(local_config().election_priority(3).build())
+            .election_timeout_strategy(Arc::new(strategy))
+            .build()
+            .await
+            .unwrap();
+        let mut fired = Vec::new();
+        while fired.len() < 2 {
+            let term = node.metrics().await.unwrap().current_term;
+            if term.0 > fired.len() as u64 {
+                fired.push(started.elapsed());
             }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
 
-            // Check for election timeout
-            _ = election_timer.tick() => {
-                let state = inner.state.read();
-                if state.role != RaftRole::Leader && inner.is_election_timeout() {
-                    drop(state);
+        // The first election waits 400ms and the second backs off to 800ms
+        // more, never a timer tick longer
+        let tick = Duration::from_millis(50);
+        let first = Duration::from_millis(400);
+        let second = Duration::from_millis(1200);
+        assert!(fired[0] >= first && fired[0] < first + tick, "{:?}", fired);
+        assert!(
+            fired[1] >= second && fired[1] < second + tick,
+            "{:?}",
+            fired
+        );
+        assert_eq!(calls.lock()[..3], [(0, 3), (1, 3), (2, 3)]);
 
-                    // Start election
-                    let _requests = inner.start_election();
+        node.shutdown().await;
+    }
 
-                    // In a real implementation, we'd send these requests to peers
-                    // For now, we'll just log that an election started
-                }
+    #[tokio::test]
+    async fn test_pre_vote_waits_for_confirmed_silence() {
+        let transport = crate::transport::ChannelTransport::new();
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let config = local_config()
+            .election_timeout(Duration::from_millis(100), Duration::from_millis(200))
+            .pre_vote(true)
+            .election_confirmation_timeouts(3)
+            .build();
+        let mut logs = HashMap::new();
+        for &id in &voters {
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let sink = Arc::clone(&log);
+            let node = RaftNodeBuilder::new(id, voters.clone(), KvStore::new())
+                .config(config.clone())
+                .transport(Arc::clone(&transport) as Arc<dyn Transport>)
+                .rpc_observer(Arc::new(move |rpc: &RpcSummary| {
+                    if rpc.direction == RpcDirection::Outbound
+                        && matches!(rpc.kind, RpcKind::PreVote | RpcKind::RequestVote)
+                    {
+                        sink.lock().push(rpc.clone());
+                    }
+                }))
+                .build()
+                .await
+                .unwrap();
+            transport.register(Arc::new(node));
+            logs.insert(id, log);
+        }
+        let leader_among = |ids: Vec<NodeId>| {
+            let transport = Arc::clone(&transport);
+            async move {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    loop {
+                        for &id in &ids {
+                            let node = transport.node(id).unwrap();
+                            if node.metrics().await.unwrap().role == RaftRole::Leader {
+                                return node;
+                            }
+                        }
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                })
+                .await
+                .expect("no leader elected")
             }
+        };
+        let leader = leader_among(voters.clone()).await;
+        let term = leader.metrics().await.unwrap().current_term;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        logs.values().for_each(|log| log.lock().clear());
 
-            // Send heartbeats if leader
-            _ = heartbeat_timer.tick() => {
-                let state = inner.state.read();
-                if state.role == RaftRole::Leader {
-                    debug!("Node {} sending heartbeats", id);
-                    // In a real implementation, send AppendEntries to all peers
+        // A gap of about one election timeout goes unanswered
+        transport.partition(leader.id());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        transport.heal(leader.id());
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(logs.values().all(|log| log.lock().is_empty()));
+        let metrics = leader.metrics().await.unwrap();
+        assert_eq!(metrics.role, RaftRole::Leader);
+        assert_eq!(metrics.current_term, term);
+
+        // Lasting silence brings a pre-vote, then the election it cleared
+        transport.partition(leader.id());
+        let others: Vec<_> = voters
+            .iter()
+            .copied()
+            .filter(|&id| id != leader.id())
+            .collect();
+        let new = leader_among(others).await;
+        assert!(new.metrics().await.unwrap().current_term > term);
+        let sent = logs[&new.id()].lock().clone();
+        let pre_vote = sent
+            .iter()
+            .position(|rpc| rpc.kind == RpcKind::PreVote && rpc.success)
+            .expect("no pre-vote granted");
+        let vote = sent
+            .iter()
+            .position(|rpc| rpc.kind == RpcKind::RequestVote)
+            .expect("no election");
+        assert!(pre_vote < vote);
+        assert_eq!(sent[pre_vote].term, sent[vote].term);
+
+        transport.heal(leader.id());
+        drop((leader, new));
+        for id in voters {
+            if let Some(node) = transport.unregister(id) {
+                if let Ok(node) = Arc::try_unwrap(node) {
+                    node.shutdown().await;
                 }
             }
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_change_configuration_removes_the_leader() {
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let (network, leader) = LocalNetwork::start(&voters).await;
+        leader.propose(b"SET a 1".to_vec()).await.unwrap();
+        network.add_node(NodeId(4), vec![]).await;
+        network.add_node(NodeId(5), vec![]).await;
 
-    /// Simple key-value state machine for testing
-    struct KvStore {
-        data: std::collections::HashMap<String, String>,
-    }
+        // The leader is among the voters replaced
+        let mut target: Vec<_> = voters.into_iter().filter(|&v| v != leader.id()).collect();
+        target.extend([NodeId(4), NodeId(5)]);
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            leader.change_configuration(target.clone(), vec![]),
+        )
+        .await
+        .expect("reconfiguration timed out")
+        .unwrap();
 
-    impl KvStore {
-        fn new() -> Self {
-            Self {
-                data: std::collections::HashMap::new(),
-            }
+        let members = leader.members().await.unwrap();
+        let mut voters: Vec<_> = members
+            .iter()
+            .filter(|m| m.role == MemberRole::Voter)
+            .map(|m| m.id)
+            .collect();
+        voters.sort();
+        assert_eq!(voters, target);
+        assert_eq!(members.len(), 4);
+
+        // The new voters elect a leader among themselves and carry on
+        network.nodes.write().remove(&leader.id());
+        network.isolated.write().insert(leader.id());
+        let successor = network.wait_for_leader().await;
+        assert!(target.contains(&successor.id()));
+        successor.propose(b"SET b 2".to_vec()).await.unwrap();
+        assert_ne!(leader.metrics().await.unwrap().role, RaftRole::Leader);
+
+        drop(successor);
+        if let Ok(leader) = Arc::try_unwrap(leader) {
+            leader.shutdown().await;
         }
+        network.shutdown().await;
     }
 
-    impl StateMachine for KvStore {
-        fn apply(&mut self, command: &[u8]) -> Vec<u8> {
-            let cmd = String::from_utf8_lossy(command);
-            let parts: Vec<&str> = cmd.split_whitespace().collect();
+    #[tokio::test]
+    async fn test_change_configuration_keeps_quorum_reachable() {
+        // The followers never campaign, so the one cut off can't depose the
+        // leader meanwhile
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let patient = local_config()
+            .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+            .build();
+        let network = LocalNetwork::new(patient);
+        let leader = network
+            .add_custom_node(
+                RaftNodeBuilder::new(NodeId(1), voters.clone(), KvStore::new())
+                    .config(local_config().build()),
+            )
+            .await;
+        network.add_node(NodeId(2), voters.clone()).await;
+        network.add_node(NodeId(3), voters).await;
+        assert_eq!(network.wait_for_leader().await.id(), leader.id());
+        let others = [NodeId(2), NodeId(3)];
 
-            match parts.as_slice() {
-                ["SET", key, value] => {
-                    self.data.insert(key.to_string(), value.to_string());
-                    b"OK".to_vec()
+        // With one follower gone, dropping the other leaves the leader alone
+        // with an unreachable voter
+        network.isolated.write().insert(others[0]);
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let result = leader
+            .change_configuration(vec![leader.id(), others[0]], vec![])
+            .await;
+        assert!(matches!(result, Err(RaftError::InvalidConfiguration(_))));
+        assert!(matches!(
+            leader
+                .change_configuration(vec![others[0], others[1]], vec![])
+                .await,
+            Err(RaftError::InvalidConfiguration(_))
+        ));
+        assert_eq!(leader.members().await.unwrap().len(), 3);
+
+        drop(leader);
+        network.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_manual_apply_waits_for_confirmation() {
+        let config = local_config().manual_apply(true).build();
+        let node = Arc::new(
+            RaftNode::new(
+                NodeId(1),
+                vec![NodeId(1)],
+                config,
+                KvStore::new(),
+                Arc::new(NoopTransport),
+            )
+            .await
+            .unwrap(),
+        );
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while node.metrics().await.unwrap().role != RaftRole::Leader {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no leader elected");
+        let proposer = Arc::clone(&node);
+        let proposal = tokio::spawn(async move { proposer.propose(b"SET a 1".to_vec()).await });
+
+        // Committed, but nothing is applied until the caller says so
+        let ready = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let ready = node.apply_ready().await.unwrap();
+                if !ready.is_empty() {
+                    return ready;
                 }
-                ["GET", key] => self
-                    .data
-                    .get(*key)
-                    .map(|v| v.as_bytes().to_vec())
-                    .unwrap_or_default(),
-                _ => b"ERROR".to_vec(),
+                tokio::time::sleep(Duration::from_millis(10)).await;
             }
-        }
+        })
+        .await
+        .expect("proposal never committed");
+        let (index, command) = ready[0].clone();
+        assert_eq!(ready, vec![(index, b"SET a 1".to_vec())]);
+        let metrics = node.metrics().await.unwrap();
+        assert!(metrics.commit_index >= index);
+        assert!(metrics.last_applied < index);
+        assert_eq!(node.apply_ready().await.unwrap(), vec![(index, command)]);
+        assert!(!proposal.is_finished());
 
-        fn snapshot(&self) -> Vec<u8> {
-            serde_json::to_vec(&self.data).unwrap()
-        }
+        assert!(matches!(
+            node.confirm_applied(metrics.commit_index + 1).await,
+            Err(RaftError::LogIndexOutOfRange(_))
+        ));
+        node.confirm_applied(index).await.unwrap();
+        assert_eq!(node.metrics().await.unwrap().last_applied, index);
+        assert!(node.apply_ready().await.unwrap().is_empty());
+        assert_eq!(proposal.await.unwrap().unwrap(), Vec::<u8>::new());
 
-        fn restore(&mut self, snapshot: &[u8]) {
-            self.data = serde_json::from_slice(snapshot).unwrap();
-        }
+        let automatic = RaftNode::new(
+            NodeId(2),
+            vec![NodeId(2)],
+            local_config().build(),
+            KvStore::new(),
+            Arc::new(NoopTransport),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            automatic.apply_ready().await,
+            Err(RaftError::ManualApplyDisabled)
+        ));
+
+        automatic.shutdown().await;
+        Arc::try_unwrap(node).ok().unwrap().shutdown().await;
     }
 
     #[tokio::test]
-    async fn test_node_creation() {
-        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
-        let config = RaftConfig::default();
-        let sm = KvStore::new();
+    async fn test_leadership_callbacks_paired() {
+        // Only node 1 campaigns, so it's the one to lead each term
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let patient = local_config()
+            .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+            .build();
+        let network = LocalNetwork::new(patient);
+        let node = network
+            .add_custom_node(
+                RaftNodeBuilder::new(NodeId(1), voters.clone(), KvStore::new())
+                    .config(local_config().build()),
+            )
+            .await;
+        network.add_node(NodeId(2), voters.clone()).await;
+        network.add_node(NodeId(3), voters).await;
+        assert_eq!(network.wait_for_leader().await.id(), node.id());
 
-        let node = RaftNode::new(NodeId(1), peers, config, sm).await.unwrap();
+        // Registered on a leader, so `on_acquire` runs straight away
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (acquired, lost) = (Arc::clone(&calls), Arc::clone(&calls));
+        node.on_leadership(
+            move |term| acquired.lock().push(("acquire", term)),
+            move |term| lost.lock().push(("lose", term)),
+        )
+        .await
+        .unwrap();
+        let wait_for = |count: usize| {
+            let calls = Arc::clone(&calls);
+            async move {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    while calls.lock().len() < count {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                })
+                .await
+                .expect("callback never ran");
+            }
+        };
+        wait_for(1).await;
+        let first = node.metrics().await.unwrap().current_term;
 
-        // Node should be created and running
-        node.shutdown().await;
+        // Deposed by a higher term, then elected again in a later one
+        let response = node.request_vote(vote_request(first.0 + 5, 2, 0)).await;
+        assert!(!response.vote_granted);
+        wait_for(3).await;
+        let second = node.metrics().await.unwrap().current_term;
+        assert!(second > first);
+
+        network.nodes.write().remove(&node.id());
+        Arc::try_unwrap(node).ok().unwrap().shutdown().await;
+        wait_for(4).await;
+        assert_eq!(
+            *calls.lock(),
+            vec![
+                ("acquire", first),
+                ("lose", first),
+                ("acquire", second),
+                ("lose", second)
+            ]
+        );
+
+        network.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_role_changes_restart_timers() {
+        // Only node 1 campaigns; its sends are timed as they complete, so a
+        // recorded time is never earlier than the send itself
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&sent);
+        let observer = move |rpc: &RpcSummary| {
+            if rpc.direction == RpcDirection::Outbound {
+                sink.lock().push((Instant::now(), rpc.kind, rpc.success));
+            }
+        };
+        let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let patient = local_config()
+            .election_timeout(Duration::from_secs(60), Duration::from_secs(120))
+            .build();
+        let network = LocalNetwork::new(patient.clone());
+        let heartbeat = Duration::from_millis(200);
+        let election_min = Duration::from_millis(300);
+        let leader = network
+            .add_custom_node(
+                RaftNodeBuilder::new(NodeId(1), voters.clone(), KvStore::new())
+                    .config(
+                        local_config()
+                            .election_timeout(election_min, Duration::from_millis(600))
+                            .heartbeat_interval(heartbeat)
+                            .build(),
+                    )
+                    .rpc_observer(Arc::new(observer)),
+            )
+            .await;
+        network.add_node(NodeId(2), voters.clone()).await;
+        network.add_node(NodeId(3), voters).await;
+        assert_eq!(network.wait_for_leader().await.id(), leader.id());
+        tokio::time::sleep(heartbeat * 2).await;
+
+        // The first heartbeat waits a full interval after the winning vote
+        let log = sent.lock().clone();
+        let won = log
+            .iter()
+            .filter(|(_, kind, granted)| *kind == RpcKind::RequestVote && *granted)
+            .map(|(at, _, _)| *at)
+            .min()
+            .unwrap();
+        let first_heartbeat = log
+            .iter()
+            .find(|(_, kind, _)| *kind == RpcKind::AppendEntries)
+            .map(|(at, _, _)| *at)
+            .unwrap();
+        assert!(first_heartbeat.duration_since(won) >= heartbeat);
+
+        // Deposed by a candidate it won't vote for, it waits a whole
+        // election timeout before standing again
+        let term = leader.metrics().await.unwrap().current_term;
+        sent.lock().clear();
+        let deposed = Instant::now();
+        let response = leader.request_vote(vote_request(term.0 + 5, 2, 0)).await;
+        assert!(!response.vote_granted);
+        assert_eq!(leader.metrics().await.unwrap().role, RaftRole::Follower);
+        let campaigned = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let first_vote = sent
+                    .lock()
+                    .iter()
+                    .find(|(_, kind, _)| *kind == RpcKind::RequestVote)
+                    .map(|(at, _, _)| *at);
+                if let Some(at) = first_vote {
+                    return at;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("deposed leader never campaigned");
+        assert!(campaigned.duration_since(deposed) >= election_min);
+
+        drop(leader);
+        network.shutdown().await;
     }
 }
@@ -0,0 +1,222 @@
+//! Observing the RPCs a node sends and answers
+//!
+//! An [`RpcObserver`] set with
+//! [`RaftNodeBuilder::rpc_observer`](crate::RaftNodeBuilder::rpc_observer)
+//! is told about every RPC once its outcome is known, without turning on
+//! trace logging. Nodes without one don't pay for it.
+
+use crate::rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, ForwardRequest, ForwardResponse,
+    InstallSnapshotRequest, InstallSnapshotResponse, JoinRequest, JoinResponse, PingRequest,
+    PingResponse, RequestVoteRequest, RequestVoteResponse,
+};
+use crate::transport::Transport;
+use crate::types::{Entry, LogIndex, NodeId, Term};
+use crate::Result;
+
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Whether an RPC was sent or answered by the observed node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcDirection {
+    /// Received from `peer` and answered
+    Inbound,
+
+    /// Sent to `peer`
+    Outbound,
+}
+
+/// Which RPC was exchanged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcKind {
+    RequestVote,
+    PreVote,
+    AppendEntries,
+    InstallSnapshot,
+    Ping,
+    Join,
+    Forward,
+}
+
+/// One RPC as seen by the observed node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcSummary {
+    pub direction: RpcDirection,
+    pub kind: RpcKind,
+
+    /// The node at the other end
+    pub peer: NodeId,
+
+    /// Term the request was sent at, or 0 for RPCs that carry none
+    pub term: Term,
+
+    /// First and last index of the entries an AppendEntries carried; `None`
+    /// for heartbeats and other RPCs
+    pub entries: Option<(LogIndex, LogIndex)>,
+
+    /// Whether the RPC achieved what it asked for: a vote granted, entries
+    /// accepted, a join or forwarded proposal accepted, or any answer at all
+    /// for InstallSnapshot and Ping. Transport failures count as `false`.
+    pub success: bool,
+}
+
+impl RpcSummary {
+    fn new(direction: RpcDirection, kind: RpcKind, peer: NodeId, term: Term) -> Self {
+        Self {
+            direction,
+            kind,
+            peer,
+            term,
+            entries: None,
+            success: false,
+        }
+    }
+
+    fn with_entries(mut self, entries: &[Entry]) -> Self {
+        if let (Some(first), Some(last)) = (entries.first(), entries.last()) {
+            self.entries = Some((first.index, last.index));
+        }
+        self
+    }
+
+    pub(crate) fn request_vote(
+        direction: RpcDirection,
+        peer: NodeId,
+        req: &RequestVoteRequest,
+    ) -> Self {
+        let kind = if req.pre_vote {
+            RpcKind::PreVote
+        } else {
+            RpcKind::RequestVote
+        };
+        Self::new(direction, kind, peer, req.term)
+    }
+
+    pub(crate) fn append_entries(
+        direction: RpcDirection,
+        peer: NodeId,
+        req: &AppendEntriesRequest,
+    ) -> Self {
+        Self::new(direction, RpcKind::AppendEntries, peer, req.term).with_entries(&req.entries)
+    }
+
+    pub(crate) fn install_snapshot(
+        direction: RpcDirection,
+        peer: NodeId,
+        req: &InstallSnapshotRequest,
+    ) -> Self {
+        Self::new(direction, RpcKind::InstallSnapshot, peer, req.term)
+    }
+
+    pub(crate) fn ping(direction: RpcDirection, peer: NodeId, req: &PingRequest) -> Self {
+        Self::new(direction, RpcKind::Ping, peer, req.term)
+    }
+
+    pub(crate) fn join(direction: RpcDirection, peer: NodeId) -> Self {
+        Self::new(direction, RpcKind::Join, peer, Term(0))
+    }
+
+    pub(crate) fn forward(direction: RpcDirection, peer: NodeId) -> Self {
+        Self::new(direction, RpcKind::Forward, peer, Term(0))
+    }
+}
+
+/// Receives a summary of every RPC a node sends or answers
+///
+/// Called on the task that made or answered the RPC, never on the node's
+/// main loop, but still on the RPC's path: hand the summary off somewhere
+/// cheap (a ring buffer, a channel) rather than doing I/O here.
+pub trait RpcObserver: Send + Sync + 'static {
+    fn observe(&self, rpc: &RpcSummary);
+}
+
+impl<F> RpcObserver for F
+where
+    F: Fn(&RpcSummary) + Send + Sync + 'static,
+{
+    fn observe(&self, rpc: &RpcSummary) {
+        self(rpc)
+    }
+}
+
+/// Report `summary` to `observer` with the outcome filled in
+pub(crate) fn report(observer: &dyn RpcObserver, mut summary: RpcSummary, success: bool) {
+    summary.success = success;
+    observer.observe(&summary);
+}
+
+/// Transport that reports every outbound RPC to an observer
+pub(crate) struct ObservedTransport {
+    pub(crate) inner: Arc<dyn Transport>,
+    pub(crate) observer: Arc<dyn RpcObserver>,
+}
+
+#[async_trait]
+impl Transport for ObservedTransport {
+    async fn send_request_vote(
+        &self,
+        target: NodeId,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        let summary = RpcSummary::request_vote(RpcDirection::Outbound, target, &request);
+        let result = self.inner.send_request_vote(target, request).await;
+        let success = matches!(&result, Ok(r) if r.vote_granted);
+        report(&*self.observer, summary, success);
+        result
+    }
+
+    async fn send_append_entries(
+        &self,
+        target: NodeId,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        let summary = RpcSummary::append_entries(RpcDirection::Outbound, target, &request);
+        let result = self.inner.send_append_entries(target, request).await;
+        let success = matches!(&result, Ok(r) if r.success);
+        report(&*self.observer, summary, success);
+        result
+    }
+
+    async fn send_install_snapshot(
+        &self,
+        target: NodeId,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        let summary = RpcSummary::install_snapshot(RpcDirection::Outbound, target, &request);
+        let result = self.inner.send_install_snapshot(target, request).await;
+        report(&*self.observer, summary, result.is_ok());
+        result
+    }
+
+    async fn send_ping(&self, target: NodeId, request: PingRequest) -> Result<PingResponse> {
+        let summary = RpcSummary::ping(RpcDirection::Outbound, target, &request);
+        let result = self.inner.send_ping(target, request).await;
+        report(&*self.observer, summary, result.is_ok());
+        result
+    }
+
+    async fn send_join(&self, target: NodeId, request: JoinRequest) -> Result<JoinResponse> {
+        let summary = RpcSummary::join(RpcDirection::Outbound, target);
+        let result = self.inner.send_join(target, request).await;
+        let success = matches!(&result, Ok(r) if r.accepted);
+        report(&*self.observer, summary, success);
+        result
+    }
+
+    async fn send_forward(
+        &self,
+        target: NodeId,
+        request: ForwardRequest,
+    ) -> Result<ForwardResponse> {
+        let summary = RpcSummary::forward(RpcDirection::Outbound, target);
+        let result = self.inner.send_forward(target, request).await;
+        let success = matches!(&result, Ok(ForwardResponse::Applied(_)));
+        report(&*self.observer, summary, success);
+        result
+    }
+
+    fn max_message_size(&self) -> Option<usize> {
+        self.inner.max_message_size()
+    }
+}
@@ -1,7 +1,8 @@
 //! Raft RPC messages
 
-use crate::types::{Entry, LogIndex, NodeId, Term};
+use crate::types::{ClusterConfig, Entry, LogIndex, LogPosition, NodeId, Payload, Term};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 /// RequestVote RPC - sent by candidates to gather votes
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,6 +18,20 @@ pub struct RequestVoteRequest {
 
     /// Term of candidate's last log entry
     pub last_log_term: Term,
+
+    /// Only ask whether the vote would be granted
+    ///
+    /// `term` is the term the candidate would stand in. The voter changes
+    /// neither its term nor its vote; see `RaftConfig::pre_vote`.
+    #[serde(default)]
+    pub pre_vote: bool,
+}
+
+impl RequestVoteRequest {
+    /// Position of the candidate's last log entry
+    pub fn last_log_position(&self) -> LogPosition {
+        LogPosition::new(self.last_log_term, self.last_log_index)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +41,36 @@ pub struct RequestVoteResponse {
 
     /// True if candidate received vote
     pub vote_granted: bool,
+
+    /// Why the vote was refused
+    ///
+    /// Only informational; `None` when the vote was granted, or when the
+    /// responder couldn't make its vote durable or doesn't report reasons.
+    #[serde(default)]
+    pub denial_reason: Option<VoteDenialReason>,
+}
+
+/// Why a node refused its vote
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VoteDenialReason {
+    /// The candidate's term is older than the voter's
+    StaleTerm,
+
+    /// The voter already voted for another candidate in this term
+    AlreadyVoted,
+
+    /// The voter's log is more up to date than the candidate's
+    LogNotUpToDate,
+
+    /// The candidate isn't a voter in the voter's configuration
+    NotAMember,
+
+    /// The voter heard from a live leader less than an election timeout ago
+    RecentLeaderContact,
+
+    /// The voter leads a term further behind the candidate's than
+    /// `RaftConfig::max_term_gap` allows
+    TermGapExceeded,
 }
 
 /// AppendEntries RPC - sent by leader to replicate log and provide heartbeat
@@ -84,14 +129,40 @@ pub struct AppendEntriesResponse {
 
     /// For optimization: the index of the last log entry that matched
     /// Used to quickly find the right prev_log_index on retry
+    ///
+    /// On a rejection this is the follower's last log index, so the leader
+    /// can skip entries the follower doesn't have. `None` leaves the leader
+    /// to back up one entry at a time.
+    ///
+    /// Deprecated as a rejection hint: `conflict_term` and `conflict_index`
+    /// skip further, and leaders prefer them when present. It is still sent
+    /// for leaders that don't know them yet.
     pub match_index: Option<LogIndex>,
 
+    /// On a rejection for a mismatched `prev_log_index`, the term of the
+    /// follower's entry there, or `None` if its log doesn't reach that far
+    #[serde(default)]
+    pub conflict_term: Option<Term>,
+
+    /// On a rejection for a mismatched `prev_log_index`, the first index of
+    /// `conflict_term` in the follower's log, or just past its last entry if
+    /// the log is too short
+    ///
+    /// The leader resumes after its own last entry of `conflict_term`, or
+    /// here if it has none, skipping a whole term per retry.
+    #[serde(default)]
+    pub conflict_index: Option<LogIndex>,
+
     /// The follower's current commit index (for monitoring)
     pub commit_index: LogIndex,
+
+    /// The follower's last applied index (for the leader's applied watermark)
+    #[serde(default)]
+    pub last_applied: LogIndex,
 }
 
 /// InstallSnapshot RPC - sent by leader when it needs to send a snapshot
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct InstallSnapshotRequest {
     /// Leader's term
     pub term: Term,
@@ -113,6 +184,29 @@ pub struct InstallSnapshotRequest {
 
     /// True if this is the last chunk
     pub done: bool,
+
+    /// Cluster membership as of `last_included_index`, sent with the last
+    /// chunk
+    ///
+    /// A follower installing the snapshot adopts it, since the entries that
+    /// changed it are gone from the leader's log.
+    #[serde(default)]
+    pub configuration: Option<ClusterConfig>,
+}
+
+impl fmt::Debug for InstallSnapshotRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InstallSnapshotRequest")
+            .field("term", &self.term)
+            .field("leader_id", &self.leader_id)
+            .field("last_included_index", &self.last_included_index)
+            .field("last_included_term", &self.last_included_term)
+            .field("offset", &self.offset)
+            .field("data", &Payload(&self.data))
+            .field("done", &self.done)
+            .field("configuration", &self.configuration)
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -121,6 +215,79 @@ pub struct InstallSnapshotResponse {
     pub term: Term,
 }
 
+/// Ping RPC - lightweight liveness probe that never touches the log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PingRequest {
+    /// Sender's current term
+    pub term: Term,
+
+    /// Node sending the ping
+    pub from: NodeId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PingResponse {
+    /// Responder's current term (informational only)
+    pub term: Term,
+
+    /// Node answering the ping
+    pub from: NodeId,
+}
+
+/// Join RPC - sent by a new node asking to be added to the cluster
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinRequest {
+    /// Node that wants to join
+    pub node: NodeId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinResponse {
+    /// True if the node is now a member (as a learner until it catches up)
+    pub accepted: bool,
+
+    /// Where to ask instead when not accepted, if the responder knows the
+    /// leader
+    pub leader_hint: Option<NodeId>,
+}
+
+/// Forwarded proposal - sent by a follower on behalf of a client
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ForwardRequest {
+    /// Follower the client proposed to
+    pub from: NodeId,
+
+    /// The client's command
+    pub command: Vec<u8>,
+
+    /// Metadata the client attached to the command, if any
+    #[serde(default)]
+    pub metadata: Option<Vec<u8>>,
+}
+
+impl fmt::Debug for ForwardRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForwardRequest")
+            .field("from", &self.from)
+            .field("command", &Payload(&self.command))
+            .field("metadata", &self.metadata.as_deref().map(Payload))
+            .finish()
+    }
+}
+
+/// What became of a forwarded proposal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ForwardResponse {
+    /// Committed and applied, with the state machine's output
+    Applied(Vec<u8>),
+
+    /// The receiver wasn't leader; it doesn't forward again
+    NotLeader(Option<NodeId>),
+
+    /// The leader failed the proposal
+    Failed(String),
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,8 +1,10 @@
 //! Raft node state and role management
 
-use crate::types::{LogIndex, NodeId, Term};
+use crate::types::{ClusterConfig, LogIndex, NodeId, Term};
+use crate::NotLeaderInfo;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::time::{Duration, Instant};
 
 /// The role a Raft node can be in
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -28,7 +30,7 @@ impl std::fmt::Display for RaftRole {
 /// Persistent state that must survive crashes
 ///
 /// This state is written to stable storage before responding to RPCs
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PersistentState {
     /// Latest term this server has seen (initialized to 0, increases monotonically)
     pub current_term: Term,
@@ -73,6 +75,9 @@ pub struct LeaderState {
 
     /// For each server, index of highest log entry known to be replicated
     pub match_index: Vec<(NodeId, LogIndex)>,
+
+    /// For each server, the last applied index it reported
+    pub applied_index: Vec<(NodeId, LogIndex)>,
 }
 
 impl LeaderState {
@@ -83,9 +88,26 @@ impl LeaderState {
                 .map(|&id| (id, last_log_index + 1))
                 .collect(),
             match_index: peers.iter().map(|&id| (id, LogIndex::ZERO)).collect(),
+            applied_index: peers.iter().map(|&id| (id, LogIndex::ZERO)).collect(),
+        }
+    }
+
+    /// Start replicating to `node` if it isn't tracked yet
+    pub fn track(&mut self, node: NodeId, last_log_index: LogIndex) {
+        if self.get_next_index(node).is_none() {
+            self.next_index.push((node, last_log_index + 1));
+            self.match_index.push((node, LogIndex::ZERO));
+            self.applied_index.push((node, LogIndex::ZERO));
         }
     }
 
+    /// Stop replicating to `node`
+    pub fn untrack(&mut self, node: NodeId) {
+        self.next_index.retain(|&(id, _)| id != node);
+        self.match_index.retain(|&(id, _)| id != node);
+        self.applied_index.retain(|&(id, _)| id != node);
+    }
+
     pub fn get_next_index(&self, node: NodeId) -> Option<LogIndex> {
         self.next_index
             .iter()
@@ -93,9 +115,15 @@ impl LeaderState {
             .map(|(_, idx)| *idx)
     }
 
+    /// Set the next index to send to `node`
+    ///
+    /// `next_index` may move backward when a follower rejects AppendEntries,
+    /// but never to or below an index the follower is already known to hold,
+    /// and never below 1.
     pub fn set_next_index(&mut self, node: NodeId, index: LogIndex) {
+        let floor = self.get_match_index(node).unwrap_or(LogIndex::ZERO) + 1;
         if let Some(entry) = self.next_index.iter_mut().find(|(id, _)| *id == node) {
-            entry.1 = index;
+            entry.1 = index.max(floor);
         }
     }
 
@@ -106,11 +134,79 @@ impl LeaderState {
             .map(|(_, idx)| *idx)
     }
 
+    /// Record that `node` has replicated the log up to `index`
+    ///
+    /// `match_index` only ever moves forward: a stale or reordered response
+    /// reporting a lower index is ignored so commit calculations can't regress.
     pub fn set_match_index(&mut self, node: NodeId, index: LogIndex) {
         if let Some(entry) = self.match_index.iter_mut().find(|(id, _)| *id == node) {
-            entry.1 = index;
+            entry.1 = entry.1.max(index);
         }
     }
+
+    pub fn get_applied_index(&self, node: NodeId) -> Option<LogIndex> {
+        self.applied_index
+            .iter()
+            .find(|(id, _)| *id == node)
+            .map(|(_, idx)| *idx)
+    }
+
+    /// Record that `node` has applied the log up to `index`
+    ///
+    /// Like `match_index`, this only moves forward.
+    pub fn set_applied_index(&mut self, node: NodeId, index: LogIndex) {
+        if let Some(entry) = self.applied_index.iter_mut().find(|(id, _)| *id == node) {
+            entry.1 = entry.1.max(index);
+        }
+    }
+}
+
+/// A leader's view of one follower, as reported by
+/// `RaftNode::replication_progress`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerProgress {
+    /// Index of highest log entry known to be replicated on the peer
+    pub match_index: LogIndex,
+
+    /// Index of the next log entry to send to the peer
+    pub next_index: LogIndex,
+
+    /// Round-trip time of the last successful ping (None if never measured)
+    pub rtt: Option<Duration>,
+
+    /// When the peer last answered a ping (None if it never has)
+    pub last_contact: Option<Instant>,
+
+    /// Whether the most recent ping to the peer was answered
+    pub reachable: bool,
+}
+
+/// Whether a member votes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberRole {
+    /// Counts toward quorum and may stand for election
+    Voter,
+    /// Only receives the replicated log
+    Learner,
+}
+
+/// How a member relates to the node that was asked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberRelation {
+    /// The node answering
+    Local,
+    /// The current leader, as far as the answering node knows
+    Leader,
+    /// Any other member
+    Peer,
+}
+
+/// One cluster member, as reported by `RaftNode::members`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemberInfo {
+    pub id: NodeId,
+    pub role: MemberRole,
+    pub relation: MemberRelation,
 }
 
 /// Candidate-specific state
@@ -131,10 +227,44 @@ impl CandidateState {
         self.votes_received.insert(node);
     }
 
-    pub fn has_majority(&self, cluster_size: usize) -> bool {
-        // +1 for self
-        (self.votes_received.len() + 1) > cluster_size / 2
+    /// Whether our own vote and those received make a quorum of `state`'s
+    /// configuration
+    pub fn has_majority(&self, state: &NodeState) -> bool {
+        state.is_quorum(|node| node == state.id || self.votes_received.contains(&node))
+    }
+}
+
+/// The highest `value` that `quorum` of `voters` have reached
+fn quorum_of<T: Ord>(voters: &[NodeId], quorum: usize, value: impl Fn(NodeId) -> T) -> Option<T> {
+    let mut values: Vec<T> = voters.iter().map(|&node| value(node)).collect();
+    if values.is_empty() {
+        return None;
+    }
+
+    // Sorted descending, the value at position quorum-1 is reached by at
+    // least a quorum
+    values.sort_unstable_by(|a, b| b.cmp(a));
+    values.into_iter().nth(quorum - 1)
+}
+
+/// The highest `value` that a majority of `voters` has reached
+fn majority_value<T: Ord>(voters: &[NodeId], value: impl Fn(NodeId) -> T) -> Option<T> {
+    quorum_of(voters, voters.len() / 2 + 1, value)
+}
+
+/// The highest `value` a quorum has reached: a majority of `voters` and,
+/// while a change is joint, a majority of `outgoing_voters` as well
+pub(crate) fn joint_quorum_value<T: Ord>(
+    voters: &[NodeId],
+    outgoing_voters: &[NodeId],
+    value: impl Fn(NodeId) -> T,
+) -> Option<T> {
+    let incoming = majority_value(voters, &value)?;
+    if outgoing_voters.is_empty() {
+        return Some(incoming);
     }
+    let outgoing = majority_value(outgoing_voters, &value)?;
+    Some(incoming.min(outgoing))
 }
 
 /// Complete Raft node state
@@ -161,8 +291,15 @@ pub struct NodeState {
     /// Candidate-specific state (only valid when role == Candidate)
     pub candidate_state: Option<CandidateState>,
 
-    /// All nodes in the cluster (including self)
+    /// Voting members of the cluster (including self, unless demoted)
     pub peers: Vec<NodeId>,
+
+    /// Non-voting members that only receive the replicated log
+    pub learners: Vec<NodeId>,
+
+    /// Voters of the configuration being left while a change is joint,
+    /// empty otherwise
+    pub outgoing_voters: Vec<NodeId>,
 }
 
 impl NodeState {
@@ -176,12 +313,19 @@ impl NodeState {
             leader_state: None,
             candidate_state: None,
             peers,
+            learners: Vec::new(),
+            outgoing_voters: Vec::new(),
         }
     }
 
     /// Transition to follower state
+    ///
+    /// Moving to a newer term clears `voted_for`, since votes are per term.
     pub fn become_follower(&mut self, term: Term, leader: Option<NodeId>) {
         self.role = RaftRole::Follower;
+        if term > self.persistent.current_term {
+            self.persistent.voted_for = None;
+        }
         self.persistent.current_term = term;
         self.leader_id = leader;
         self.leader_state = None;
@@ -203,26 +347,170 @@ impl NodeState {
         self.role = RaftRole::Leader;
         self.leader_id = Some(self.id);
 
-        // Initialize leader state
-        let other_peers: Vec<NodeId> = self
-            .peers
+        // Initialize leader state; learners are replicated to like voters
+        self.leader_state = Some(LeaderState::new(&self.replicas(), last_log_index));
+        self.candidate_state = None;
+    }
+
+    /// What to tell a client that asked this node to lead
+    pub fn not_leader_info(&self) -> NotLeaderInfo {
+        NotLeaderInfo {
+            leader: self.leader_id,
+            term: Some(self.persistent.current_term),
+            voters: self.voters(),
+        }
+    }
+
+    /// The current membership
+    pub fn configuration(&self) -> ClusterConfig {
+        ClusterConfig {
+            voters: self.peers.clone(),
+            learners: self.learners.clone(),
+            outgoing_voters: self.outgoing_voters.clone(),
+        }
+    }
+
+    /// Switch to a new membership
+    ///
+    /// A leader starts tracking replication for members it didn't know about
+    /// and stops for those that left; progress for members that stay is kept.
+    pub fn set_configuration(&mut self, config: ClusterConfig, last_log_index: LogIndex) {
+        self.peers = config.voters;
+        self.learners = config.learners;
+        self.outgoing_voters = config.outgoing_voters;
+
+        let replicas = self.replicas();
+        if let Some(leader) = self.leader_state.as_mut() {
+            let departed: Vec<NodeId> = leader
+                .next_index
+                .iter()
+                .map(|&(id, _)| id)
+                .filter(|id| !replicas.contains(id))
+                .collect();
+            for node in departed {
+                leader.untrack(node);
+            }
+            for &node in &replicas {
+                leader.track(node, last_log_index);
+            }
+        }
+    }
+
+    /// Every member of the current configuration, voters first
+    ///
+    /// During a joint change the outgoing voters are still voters.
+    pub fn members(&self) -> Vec<MemberInfo> {
+        let voters = self.voters();
+        let learners: Vec<NodeId> = self
+            .learners
             .iter()
-            .filter(|&&p| p != self.id)
+            .filter(|id| !voters.contains(id))
             .copied()
             .collect();
+        let voters = voters.into_iter().map(|id| (id, MemberRole::Voter));
+        let learners = learners.into_iter().map(|id| (id, MemberRole::Learner));
+
+        voters
+            .chain(learners)
+            .map(|(id, role)| {
+                let relation = if id == self.id {
+                    MemberRelation::Local
+                } else if Some(id) == self.leader_id {
+                    MemberRelation::Leader
+                } else {
+                    MemberRelation::Peer
+                };
+                MemberInfo { id, role, relation }
+            })
+            .collect()
+    }
 
-        self.leader_state = Some(LeaderState::new(&other_peers, last_log_index));
-        self.candidate_state = None;
+    /// Every voting member, including the outgoing voters of a joint change
+    pub fn voters(&self) -> Vec<NodeId> {
+        let mut voters = self.peers.clone();
+        for &node in &self.outgoing_voters {
+            if !voters.contains(&node) {
+                voters.push(node);
+            }
+        }
+        voters
     }
 
-    /// Get other peers (excluding self)
+    /// Number of voting members, the population every quorum is drawn from
+    ///
+    /// Learners are never counted, so adding them can't change how many
+    /// votes or acknowledgements a decision needs. During a joint change
+    /// this is the number of incoming voters; the outgoing ones form a
+    /// second population, which [`NodeState::is_quorum`] and
+    /// [`NodeState::quorum_value`] also require a majority of.
+    pub fn effective_cluster_size(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// The highest `value` a quorum of voters has reached
+    ///
+    /// That's a majority of the [`NodeState::effective_cluster_size`]
+    /// voters and, during a joint change, of the outgoing voters as well.
+    /// Returns `None` when there are no voters.
+    pub fn quorum_value<T: Ord>(&self, value: impl Fn(NodeId) -> T) -> Option<T> {
+        let quorum = self.effective_cluster_size() / 2 + 1;
+        let incoming = quorum_of(&self.peers, quorum, &value)?;
+        if self.outgoing_voters.is_empty() {
+            return Some(incoming);
+        }
+        let outgoing = majority_value(&self.outgoing_voters, &value)?;
+        Some(incoming.min(outgoing))
+    }
+
+    /// Whether the voters for which `acked` holds make a quorum
+    pub fn is_quorum(&self, acked: impl Fn(NodeId) -> bool) -> bool {
+        self.quorum_value(acked).unwrap_or(false)
+    }
+
+    /// Highest log index known to be stored on a quorum of voters
+    ///
+    /// `last_log_index` is the leader's own log, which counts toward the
+    /// quorum if the leader is a voter. Returns `None` when not leader.
+    pub fn quorum_match_index(&self, last_log_index: LogIndex) -> Option<LogIndex> {
+        let leader = self.leader_state.as_ref()?;
+
+        self.quorum_value(|node| {
+            if node == self.id {
+                last_log_index
+            } else {
+                leader.get_match_index(node).unwrap_or(LogIndex::ZERO)
+            }
+        })
+    }
+
+    /// Whether `node` counts toward quorum
+    pub fn is_voting(&self, node: NodeId) -> bool {
+        self.peers.contains(&node) || self.outgoing_voters.contains(&node)
+    }
+
+    /// Whether this node currently counts toward quorum
+    pub fn is_voter(&self) -> bool {
+        self.is_voting(self.id)
+    }
+
+    /// Get other voting peers (excluding self)
     pub fn other_peers(&self) -> Vec<NodeId> {
-        self.peers
-            .iter()
-            .filter(|&&p| p != self.id)
-            .copied()
+        self.voters()
+            .into_iter()
+            .filter(|&p| p != self.id)
             .collect()
     }
+
+    /// Every member but ourselves, each once: the nodes a leader replicates to
+    fn replicas(&self) -> Vec<NodeId> {
+        let mut replicas = self.other_peers();
+        for &node in &self.learners {
+            if node != self.id && !replicas.contains(&node) {
+                replicas.push(node);
+            }
+        }
+        replicas
+    }
 }
 
 #[cfg(test)]
@@ -264,10 +552,151 @@ mod tests {
         candidate.add_vote(NodeId(3));
 
         // 3-node cluster: self + 2 votes = majority
-        assert!(candidate.has_majority(3));
+        let three = NodeState::new(NodeId(1), (1..=3).map(NodeId).collect());
+        assert!(candidate.has_majority(&three));
 
-        // 5-node cluster: self + 2 votes = not majority (need 3 total)
-        assert!(!candidate.has_majority(5));
+        // 7-node cluster: self + 2 votes = not majority (need 4 total)
+        let seven = NodeState::new(NodeId(1), (1..=7).map(NodeId).collect());
+        assert!(!candidate.has_majority(&seven));
+    }
+
+    #[test]
+    fn test_quorum_ignores_learners() {
+        let peers = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let mut state = NodeState::new(NodeId(1), peers);
+        state.set_configuration(
+            ClusterConfig {
+                voters: vec![NodeId(1), NodeId(2), NodeId(3)],
+                learners: vec![NodeId(4), NodeId(5)],
+                ..Default::default()
+            },
+            LogIndex::ZERO,
+        );
+        assert_eq!(state.effective_cluster_size(), 3);
+
+        state.become_candidate();
+        state.become_leader(LogIndex(10));
+
+        // Both learners are fully caught up, but neither voter is
+        {
+            let leader = state.leader_state.as_mut().unwrap();
+            leader.set_match_index(NodeId(4), LogIndex(10));
+            leader.set_match_index(NodeId(5), LogIndex(10));
+            leader.set_match_index(NodeId(2), LogIndex(4));
+        }
+        assert_eq!(state.quorum_match_index(LogIndex(10)), Some(LogIndex(4)));
+
+        // One voter besides the leader is enough for three voters
+        state
+            .leader_state
+            .as_mut()
+            .unwrap()
+            .set_match_index(NodeId(3), LogIndex(7));
+        assert_eq!(state.quorum_match_index(LogIndex(10)), Some(LogIndex(7)));
+
+        // Votes follow the same population: two of three voters win, however
+        // many learners there are
+        let mut votes = CandidateState::new();
+        votes.add_vote(NodeId(2));
+        assert!(votes.has_majority(&state));
+    }
+
+    #[test]
+    fn test_joint_quorum_needs_both_majorities() {
+        // Moving from {1, 2, 3} to {3, 4, 5}
+        let mut state = NodeState::new(NodeId(1), vec![]);
+        state.set_configuration(
+            ClusterConfig {
+                voters: vec![NodeId(3), NodeId(4), NodeId(5)],
+                learners: vec![],
+                outgoing_voters: vec![NodeId(1), NodeId(2), NodeId(3)],
+            },
+            LogIndex::ZERO,
+        );
+        assert!(state.is_voter());
+        assert_eq!(state.effective_cluster_size(), 3);
+        assert_eq!(state.voters().len(), 5);
+        assert_eq!(state.other_peers().len(), 4);
+
+        // Three of five, but only one of the incoming voters
+        let mut votes = CandidateState::new();
+        votes.add_vote(NodeId(2));
+        votes.add_vote(NodeId(3));
+        assert!(!votes.has_majority(&state));
+        votes.add_vote(NodeId(4));
+        assert!(votes.has_majority(&state));
+
+        // The incoming voters alone aren't enough either
+        let mut votes = CandidateState::new();
+        votes.add_vote(NodeId(4));
+        votes.add_vote(NodeId(5));
+        assert!(!votes.has_majority(&state));
+
+        state.become_candidate();
+        state.become_leader(LogIndex(10));
+        {
+            let leader = state.leader_state.as_mut().unwrap();
+            for (peer, index) in [(2, 9), (3, 6), (4, 8), (5, 2)] {
+                leader.set_match_index(NodeId(peer), LogIndex(index));
+            }
+        }
+
+        // The outgoing voters hold 9, the incoming ones only 6
+        assert_eq!(state.quorum_match_index(LogIndex(10)), Some(LogIndex(6)));
+
+        // Leaving the joint configuration, the old voters stop counting
+        state.set_configuration(
+            ClusterConfig {
+                voters: vec![NodeId(3), NodeId(4), NodeId(5)],
+                ..Default::default()
+            },
+            LogIndex(10),
+        );
+        assert!(!state.is_voter());
+        assert_eq!(state.quorum_match_index(LogIndex(10)), Some(LogIndex(6)));
+        assert_eq!(
+            state
+                .leader_state
+                .as_ref()
+                .unwrap()
+                .get_match_index(NodeId(2)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_quorum_match_index_is_the_median_of_five() {
+        let peers: Vec<NodeId> = (1..=5).map(NodeId).collect();
+        let mut state = NodeState::new(NodeId(1), peers);
+        state.become_candidate();
+        state.become_leader(LogIndex(20));
+
+        // With nobody caught up, only the leader holds anything
+        assert_eq!(state.quorum_match_index(LogIndex(20)), Some(LogIndex::ZERO));
+
+        // Leader at 20 and followers at 3, 9, 12 and 15: three of five hold
+        // 12, only two hold 15
+        {
+            let leader = state.leader_state.as_mut().unwrap();
+            for (peer, index) in [(2, 3), (3, 9), (4, 12), (5, 15)] {
+                leader.set_match_index(NodeId(peer), LogIndex(index));
+            }
+        }
+        assert_eq!(state.quorum_match_index(LogIndex(20)), Some(LogIndex(12)));
+
+        // The laggard catching up past the others moves the median
+        state
+            .leader_state
+            .as_mut()
+            .unwrap()
+            .set_match_index(NodeId(2), LogIndex(18));
+        assert_eq!(state.quorum_match_index(LogIndex(20)), Some(LogIndex(15)));
+
+        // A follower can't count for more than the leader itself has
+        assert_eq!(state.quorum_match_index(LogIndex(14)), Some(LogIndex(14)));
+
+        state.become_follower(Term(2), None);
+        assert_eq!(state.quorum_match_index(LogIndex(20)), None);
     }
 
     #[test]
@@ -286,4 +715,33 @@ mod tests {
         assert_eq!(leader.get_next_index(NodeId(2)), Some(LogIndex(15)));
         assert_eq!(leader.get_match_index(NodeId(2)), Some(LogIndex(14)));
     }
+
+    #[test]
+    fn test_match_index_is_monotonic() {
+        let peers = vec![NodeId(2), NodeId(3)];
+        let mut leader = LeaderState::new(&peers, LogIndex(10));
+
+        leader.set_match_index(NodeId(2), LogIndex(14));
+
+        // An older response arriving late must not move match_index backward
+        leader.set_match_index(NodeId(2), LogIndex(12));
+        assert_eq!(leader.get_match_index(NodeId(2)), Some(LogIndex(14)));
+
+        leader.set_match_index(NodeId(2), LogIndex(16));
+        assert_eq!(leader.get_match_index(NodeId(2)), Some(LogIndex(16)));
+    }
+
+    #[test]
+    fn test_next_index_stays_above_match_index() {
+        let peers = vec![NodeId(2), NodeId(3)];
+        let mut leader = LeaderState::new(&peers, LogIndex(10));
+
+        leader.set_match_index(NodeId(2), LogIndex(8));
+        leader.set_next_index(NodeId(2), LogIndex(3));
+        assert_eq!(leader.get_next_index(NodeId(2)), Some(LogIndex(9)));
+
+        // Never below 1, even for a peer with nothing replicated
+        leader.set_next_index(NodeId(3), LogIndex::ZERO);
+        assert_eq!(leader.get_next_index(NodeId(3)), Some(LogIndex(1)));
+    }
 }
@@ -0,0 +1,261 @@
+//! Asynchronous state machine interface
+//!
+//! [`StateMachine`] is synchronous and runs wherever the caller applies
+//! entries. State machines that do I/O while applying can implement
+//! [`AsyncStateMachine`] instead; existing synchronous ones can be wrapped in
+//! [`BlockingStateMachine`] without being rewritten.
+//!
+//! These are standalone adapters for the application's own use. The node
+//! has no async apply path: [`RaftNode`](crate::RaftNode) only ever applies
+//! entries through a synchronous [`StateMachine`].
+
+use crate::node::StateMachine;
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+/// A call waiting its turn on the state machine thread
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Async counterpart of [`StateMachine`]
+///
+/// Commands are applied one at a time in log order: `apply` takes `&mut self`,
+/// so the next command can't start until the previous call has resolved.
+#[async_trait]
+pub trait AsyncStateMachine: Send + Sync + 'static {
+    /// Apply a committed command to the state machine
+    async fn apply(&mut self, command: Vec<u8>) -> Vec<u8>;
+
+    /// Create a snapshot of the current state machine state
+    async fn snapshot(&self) -> Vec<u8>;
+
+    /// Restore state machine from a snapshot
+    async fn restore(&mut self, snapshot: Vec<u8>);
+}
+
+/// Adapts a synchronous [`StateMachine`] to [`AsyncStateMachine`]
+///
+/// Every call runs on a thread of the adapter's own, so a slow `apply`
+/// never stalls the async runtime. Calls are queued to that thread in the
+/// order they're issued, so applies execute strictly in order, even when
+/// the caller drops a future and moves on before the call has run.
+pub struct BlockingStateMachine<SM> {
+    inner: Arc<Mutex<SM>>,
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl<SM: StateMachine> BlockingStateMachine<SM> {
+    /// Wrap `state_machine`, starting the thread its calls run on
+    ///
+    /// The thread exits once the adapter is dropped or unwrapped and the
+    /// calls already issued have run.
+    pub fn new(state_machine: SM) -> Self {
+        let (jobs, mut queue) = mpsc::unbounded_channel::<Job>();
+        std::thread::Builder::new()
+            .name("blocking-state-machine".to_string())
+            .spawn(move || {
+                while let Some(job) = queue.blocking_recv() {
+                    job();
+                }
+            })
+            .expect("failed to spawn the state machine thread");
+
+        Self {
+            inner: Arc::new(Mutex::new(state_machine)),
+            jobs,
+        }
+    }
+
+    /// Run `f` against the wrapped state machine on the calling thread
+    pub fn with_inner<R>(&self, f: impl FnOnce(&SM) -> R) -> R {
+        f(&self.inner.lock())
+    }
+
+    /// Unwrap the adapter, returning the synchronous state machine
+    ///
+    /// A call whose future was dropped before it finished still runs on
+    /// the adapter's thread. Until it has, the adapter is handed back
+    /// unchanged; try again once it has finished.
+    pub fn into_inner(self) -> Result<SM, Self> {
+        let Self { inner, jobs } = self;
+        Arc::try_unwrap(inner)
+            .map(Mutex::into_inner)
+            .map_err(|inner| Self { inner, jobs })
+    }
+
+    async fn run_blocking<R, F>(&self, f: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut SM) -> R + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| f(&mut inner.lock())));
+            // Let go of the state machine before answering, so `into_inner`
+            // succeeds as soon as the caller has its result
+            drop(inner);
+            let _ = tx.send(result);
+        });
+
+        // The thread only stops once every sender is gone, and it catches
+        // panics, so it always runs the job and answers
+        self.jobs
+            .send(job)
+            .expect("the state machine thread outlives the adapter");
+        match rx
+            .await
+            .expect("the state machine thread answers every call")
+        {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+#[async_trait]
+impl<SM: StateMachine> AsyncStateMachine for BlockingStateMachine<SM> {
+    async fn apply(&mut self, command: Vec<u8>) -> Vec<u8> {
+        self.run_blocking(move |sm| sm.apply(&command)).await
+    }
+
+    async fn snapshot(&self) -> Vec<u8> {
+        self.run_blocking(|sm| sm.snapshot()).await
+    }
+
+    async fn restore(&mut self, snapshot: Vec<u8>) {
+        self.run_blocking(move |sm| sm.restore(&snapshot)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Key-value store that also records the order commands were applied in
+    #[derive(Default)]
+    struct KvStore {
+        data: HashMap<String, String>,
+        applied: Vec<String>,
+    }
+
+    impl StateMachine for KvStore {
+        fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+            let cmd = String::from_utf8_lossy(command).to_string();
+            let parts: Vec<&str> = cmd.split_whitespace().collect();
+
+            let result = match parts.as_slice() {
+                ["SET", key, value] => {
+                    self.data.insert(key.to_string(), value.to_string());
+                    b"OK".to_vec()
+                }
+                _ => b"ERROR".to_vec(),
+            };
+            self.applied.push(cmd);
+            result
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            serde_json::to_vec(&self.data).unwrap()
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) {
+            self.data = serde_json::from_slice(snapshot).unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_blocking_adapter_applies_in_order() {
+        let mut sm = BlockingStateMachine::new(KvStore::default());
+
+        let commands: Vec<String> = (0..100).map(|i| format!("SET key {}", i)).collect();
+        for cmd in &commands {
+            assert_eq!(sm.apply(cmd.clone().into_bytes()).await, b"OK");
+        }
+
+        let Ok(kv) = sm.into_inner() else {
+            panic!("no call was left running");
+        };
+        assert_eq!(kv.applied, commands);
+        assert_eq!(kv.data.get("key").map(String::as_str), Some("99"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_dropped_apply_still_lands_in_order() {
+        let mut sm = BlockingStateMachine::new(KvStore::default());
+
+        let mut expected = Vec::new();
+        for i in 0..100 {
+            // Issued on the first poll, then given up on straight away
+            let dropped = format!("SET dropped {}", i);
+            let _ = tokio::time::timeout(
+                std::time::Duration::ZERO,
+                sm.apply(dropped.clone().into_bytes()),
+            )
+            .await;
+
+            let kept = format!("SET kept {}", i);
+            assert_eq!(sm.apply(kept.clone().into_bytes()).await, b"OK");
+            expected.extend([dropped, kept]);
+        }
+
+        sm.with_inner(|kv| assert_eq!(kv.applied, expected));
+    }
+
+    #[tokio::test]
+    async fn test_blocking_adapter_snapshot_restore() {
+        let mut sm = BlockingStateMachine::new(KvStore::default());
+        sm.apply(b"SET a 1".to_vec()).await;
+        let snapshot = sm.snapshot().await;
+
+        let mut restored = BlockingStateMachine::new(KvStore::default());
+        restored.restore(snapshot).await;
+        restored.with_inner(|kv| assert_eq!(kv.data.get("a").map(String::as_str), Some("1")));
+    }
+
+    #[tokio::test]
+    async fn test_into_inner_waits_out_abandoned_calls() {
+        /// Takes its time over every command
+        struct Slow(Vec<Vec<u8>>);
+
+        impl StateMachine for Slow {
+            fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                self.0.push(command.to_vec());
+                vec![]
+            }
+
+            fn snapshot(&self) -> Vec<u8> {
+                vec![]
+            }
+
+            fn restore(&mut self, _snapshot: &[u8]) {}
+        }
+
+        // Give up on the apply while it's still running on the pool
+        let mut sm = BlockingStateMachine::new(Slow(Vec::new()));
+        let abandoned = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            sm.apply(b"SET a 1".to_vec()),
+        )
+        .await;
+        assert!(abandoned.is_err());
+
+        let mut sm = match sm.into_inner() {
+            Ok(_) => panic!("unwrapped while an apply was still running"),
+            Err(sm) => sm,
+        };
+        let slow = loop {
+            match sm.into_inner() {
+                Ok(slow) => break slow,
+                Err(back) => sm = back,
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        assert_eq!(slow.0, vec![b"SET a 1".to_vec()]);
+    }
+}
@@ -0,0 +1,207 @@
+//! Durable storage for Raft hard state
+//!
+//! The current term and vote must reach stable storage before a node acts on
+//! them: before answering an RPC, sending a vote request, or appending entries
+//! from a new term to its log. Otherwise a crash could let a node vote twice
+//! in one term or hold log entries from a term it has forgotten.
+//!
+//! Alongside it a backend can keep a commit hint: an index known to be
+//! committed, so a restarted node can apply what it already holds without
+//! waiting to hear from a leader.
+
+use crate::log::sync_dir;
+use crate::types::{LogIndex, NodeId, Term};
+use crate::{RaftError, Result};
+
+use parking_lot::Mutex;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// File holding the current term and vote
+const HARD_STATE_FILE: &str = "hard_state";
+
+/// File holding the commit hint
+const COMMIT_HINT_FILE: &str = "commit_hint";
+
+/// Trait for hard state storage backends
+///
+/// Implementations must ensure durability (fsync on write) before
+/// `save_hard_state` returns.
+pub trait StateStorage: Send + Sync {
+    /// Durably record the current term and vote
+    fn save_hard_state(&self, term: Term, voted_for: Option<NodeId>) -> Result<()>;
+
+    /// Load the last saved term and vote, if anything was ever saved
+    fn load_hard_state(&self) -> Result<Option<(Term, Option<NodeId>)>>;
+
+    /// Record an index known to be committed
+    ///
+    /// Needn't be synced: a lost hint only means a restarted node waits for
+    /// a leader before applying. The default keeps nothing.
+    fn save_commit_hint(&self, _commit_index: LogIndex) -> Result<()> {
+        Ok(())
+    }
+
+    /// Load the last saved commit hint, if any
+    fn load_commit_hint(&self) -> Result<Option<LogIndex>> {
+        Ok(None)
+    }
+}
+
+/// In-memory hard state storage (for testing and development)
+///
+/// Nothing survives a restart of the process.
+#[derive(Default)]
+pub struct MemoryStateStorage {
+    hard_state: Mutex<Option<(Term, Option<NodeId>)>>,
+    commit_hint: Mutex<Option<LogIndex>>,
+}
+
+impl MemoryStateStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StateStorage for MemoryStateStorage {
+    fn save_hard_state(&self, term: Term, voted_for: Option<NodeId>) -> Result<()> {
+        *self.hard_state.lock() = Some((term, voted_for));
+        Ok(())
+    }
+
+    fn load_hard_state(&self) -> Result<Option<(Term, Option<NodeId>)>> {
+        Ok(*self.hard_state.lock())
+    }
+
+    fn save_commit_hint(&self, commit_index: LogIndex) -> Result<()> {
+        *self.commit_hint.lock() = Some(commit_index);
+        Ok(())
+    }
+
+    fn load_commit_hint(&self) -> Result<Option<LogIndex>> {
+        Ok(*self.commit_hint.lock())
+    }
+}
+
+/// Hard state storage in a directory on disk
+///
+/// The term and vote live in one small file, replaced atomically and synced
+/// on every save. The commit hint is replaced the same way but never synced;
+/// one lost or torn in a crash reads back as no hint at all.
+pub struct FileStateStorage {
+    dir: PathBuf,
+
+    /// Serializes writers, which share the temporary file names
+    write_lock: Mutex<()>,
+}
+
+impl FileStateStorage {
+    /// Open the storage in `dir`, creating the directory if needed
+    ///
+    /// May share a directory with a [`FileLogStorage`](crate::FileLogStorage).
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// Replace `name` with `data`, writing aside and renaming so a crash
+    /// never leaves a half-written file behind
+    fn replace(&self, name: &str, data: &[u8], sync: bool) -> Result<()> {
+        let _guard = self.write_lock.lock();
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        if sync {
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, self.dir.join(name))?;
+        if sync {
+            sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// Contents of `name`, or `None` if it was never written
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.dir.join(name)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl StateStorage for FileStateStorage {
+    fn save_hard_state(&self, term: Term, voted_for: Option<NodeId>) -> Result<()> {
+        let data = bincode::serialize(&(term, voted_for))
+            .map_err(|e| RaftError::Internal(format!("cannot encode hard state: {}", e)))?;
+        self.replace(HARD_STATE_FILE, &data, true)
+    }
+
+    fn load_hard_state(&self) -> Result<Option<(Term, Option<NodeId>)>> {
+        let Some(data) = self.read(HARD_STATE_FILE)? else {
+            return Ok(None);
+        };
+        bincode::deserialize(&data)
+            .map(Some)
+            .map_err(|e| RaftError::CorruptLog(format!("undecodable hard state: {}", e)))
+    }
+
+    fn save_commit_hint(&self, commit_index: LogIndex) -> Result<()> {
+        let data = bincode::serialize(&commit_index)
+            .map_err(|e| RaftError::Internal(format!("cannot encode commit hint: {}", e)))?;
+        self.replace(COMMIT_HINT_FILE, &data, false)
+    }
+
+    fn load_commit_hint(&self) -> Result<Option<LogIndex>> {
+        Ok(self
+            .read(COMMIT_HINT_FILE)?
+            .and_then(|data| bincode::deserialize(&data).ok()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_state_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStateStorage::open(dir.path().join("state")).unwrap();
+        assert_eq!(storage.load_hard_state().unwrap(), None);
+        assert_eq!(storage.load_commit_hint().unwrap(), None);
+
+        storage.save_hard_state(Term(3), None).unwrap();
+        storage.save_hard_state(Term(4), Some(NodeId(2))).unwrap();
+        storage.save_commit_hint(LogIndex(17)).unwrap();
+        drop(storage);
+
+        let storage = FileStateStorage::open(dir.path().join("state")).unwrap();
+        assert_eq!(
+            storage.load_hard_state().unwrap(),
+            Some((Term(4), Some(NodeId(2))))
+        );
+        assert_eq!(storage.load_commit_hint().unwrap(), Some(LogIndex(17)));
+    }
+
+    #[test]
+    fn test_torn_commit_hint_reads_as_none() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStateStorage::open(dir.path()).unwrap();
+        storage.save_commit_hint(LogIndex(17)).unwrap();
+        fs::write(dir.path().join(COMMIT_HINT_FILE), [17, 0]).unwrap();
+        assert_eq!(storage.load_commit_hint().unwrap(), None);
+
+        // A damaged term and vote is an error, not a fresh start
+        fs::write(dir.path().join(HARD_STATE_FILE), [4]).unwrap();
+        assert!(matches!(
+            storage.load_hard_state(),
+            Err(RaftError::CorruptLog(_))
+        ));
+    }
+}
@@ -0,0 +1,267 @@
+//! Helpers for testing code built on top of Raft
+//!
+//! Only available with the `testing` feature.
+//!
+//! ```
+//! use objectbox_consensus::testing::TestCluster;
+//! use objectbox_consensus::StateMachine;
+//!
+//! #[derive(Default)]
+//! struct Echo;
+//!
+//! impl StateMachine for Echo {
+//!     fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+//!         command.to_vec()
+//!     }
+//!     fn snapshot(&self) -> Vec<u8> {
+//!         Vec::new()
+//!     }
+//!     fn restore(&mut self, _snapshot: &[u8]) {}
+//! }
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> objectbox_consensus::Result<()> {
+//! let cluster = TestCluster::builder(3).build(|_| Echo).await?;
+//! let result = cluster.propose_on_leader(b"hello".to_vec()).await?;
+//! assert_eq!(result, b"hello");
+//! cluster.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::config::{RaftConfig, RaftConfigBuilder};
+use crate::log::RaftLog;
+use crate::node::{apply_entry, RaftNode, RaftNodeBuilder, StateMachine};
+use crate::state::RaftRole;
+use crate::transport::{ChannelTransport, Transport};
+use crate::types::{EntryKind, LogIndex, NodeId};
+use crate::{RaftError, Result};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long the helpers wait for an election by default
+const DEFAULT_LEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Builds a [`TestCluster`]
+pub struct TestClusterBuilder {
+    size: u64,
+    config: RaftConfig,
+    leader_timeout: Duration,
+}
+
+impl TestClusterBuilder {
+    /// Config every node is built with
+    ///
+    /// Defaults to fast elections and heartbeats with snapshots disabled.
+    pub fn config(mut self, config: RaftConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// How long [`TestCluster::wait_for_leader`] and
+    /// [`TestCluster::propose_on_leader`] wait before giving up
+    pub fn leader_timeout(mut self, timeout: Duration) -> Self {
+        self.leader_timeout = timeout;
+        self
+    }
+
+    /// Start the nodes, each with a state machine made by
+    /// `make_state_machine`
+    ///
+    /// Returns as soon as every node is running; use
+    /// [`TestCluster::wait_for_leader`] to wait for an election.
+    pub async fn build<SM, F>(self, mut make_state_machine: F) -> Result<TestCluster>
+    where
+        SM: StateMachine,
+        F: FnMut(NodeId) -> SM,
+    {
+        let transport = ChannelTransport::new();
+        let ids: Vec<NodeId> = (1..=self.size).map(NodeId).collect();
+        for &id in &ids {
+            let node = RaftNodeBuilder::new(id, ids.clone(), make_state_machine(id))
+                .config(self.config.clone())
+                .transport(Arc::clone(&transport) as Arc<dyn Transport>)
+                .build()
+                .await?;
+            transport.register(Arc::new(node));
+        }
+
+        Ok(TestCluster {
+            transport,
+            ids,
+            leader_timeout: self.leader_timeout,
+        })
+    }
+}
+
+/// A cluster of in-process nodes wired together by a shared
+/// [`ChannelTransport`]
+///
+/// Nodes are numbered from 1. Call [`shutdown`](Self::shutdown) at the end of
+/// a test; dropping the cluster leaves the nodes running until the runtime
+/// stops.
+pub struct TestCluster {
+    transport: Arc<ChannelTransport>,
+    ids: Vec<NodeId>,
+    leader_timeout: Duration,
+}
+
+impl TestCluster {
+    /// Start building a cluster of `size` voters
+    pub fn builder(size: u64) -> TestClusterBuilder {
+        TestClusterBuilder {
+            size,
+            config: RaftConfigBuilder::new()
+                .election_timeout(Duration::from_millis(100), Duration::from_millis(500))
+                .heartbeat_interval(Duration::from_millis(10))
+                .snapshot_threshold(0)
+                .build(),
+            leader_timeout: DEFAULT_LEADER_TIMEOUT,
+        }
+    }
+
+    /// The transport connecting the nodes
+    pub fn transport(&self) -> &Arc<ChannelTransport> {
+        &self.transport
+    }
+
+    /// Ids of all nodes, in ascending order
+    pub fn ids(&self) -> &[NodeId] {
+        &self.ids
+    }
+
+    /// Handle of node `id`
+    ///
+    /// # Panics
+    ///
+    /// If `id` isn't part of the cluster.
+    pub fn node(&self, id: NodeId) -> Arc<RaftNode> {
+        self.transport
+            .node(id)
+            .unwrap_or_else(|| panic!("{} is not part of the cluster", id))
+    }
+
+    /// Handles of all nodes, in id order
+    pub fn nodes(&self) -> Vec<Arc<RaftNode>> {
+        self.ids.iter().map(|&id| self.node(id)).collect()
+    }
+
+    /// The current leader, if any
+    ///
+    /// Partitioned nodes are ignored, so a leader cut off from the cluster
+    /// isn't returned once it has been replaced. If several reachable nodes
+    /// believe they lead, the one with the highest term wins.
+    pub async fn leader(&self) -> Option<Arc<RaftNode>> {
+        let mut leader = None;
+        let mut leader_term = None;
+        for node in self.nodes() {
+            if self.transport.is_partitioned(node.id()) {
+                continue;
+            }
+            let Ok(metrics) = node.metrics().await else {
+                continue;
+            };
+            if metrics.role == RaftRole::Leader && Some(metrics.current_term) > leader_term {
+                leader_term = Some(metrics.current_term);
+                leader = Some(node);
+            }
+        }
+        leader
+    }
+
+    /// Wait until there is a [`leader`](Self::leader)
+    ///
+    /// Fails with [`RaftError::Timeout`] if none is elected within the
+    /// builder's leader timeout.
+    pub async fn wait_for_leader(&self) -> Result<Arc<RaftNode>> {
+        tokio::time::timeout(self.leader_timeout, async {
+            loop {
+                if let Some(leader) = self.leader().await {
+                    return leader;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .map_err(|_| RaftError::Timeout)
+    }
+
+    /// Propose `command` on the current leader and wait for its result
+    ///
+    /// Waits for a leader first, and tries again on the new leader if
+    /// leadership moves before the command is accepted.
+    pub async fn propose_on_leader(&self, command: Vec<u8>) -> Result<Vec<u8>> {
+        let deadline = tokio::time::Instant::now() + self.leader_timeout;
+        loop {
+            let leader = self.wait_for_leader().await?;
+            match leader.propose(command.clone()).await {
+                Err(RaftError::NotLeader(_)) if tokio::time::Instant::now() < deadline => {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Cut node `id` off from the rest of the cluster
+    pub fn partition(&self, id: NodeId) {
+        self.transport.partition(id);
+    }
+
+    /// Reconnect node `id` to the rest of the cluster
+    pub fn heal(&self, id: NodeId) {
+        self.transport.heal(id);
+    }
+
+    /// Stop every node
+    pub async fn shutdown(self) {
+        for &id in &self.ids {
+            if let Some(node) = self.transport.unregister(id) {
+                if let Ok(node) = Arc::try_unwrap(node) {
+                    node.shutdown().await;
+                }
+            }
+        }
+    }
+}
+
+/// Rebuild the state machine as it stood once every entry through `through`
+/// was applied, and return its snapshot
+///
+/// Starts from a fresh state machine made by `make_state_machine`, restores
+/// the log's snapshot if it has one, and applies the entries after it in
+/// order. No-ops and configuration changes are skipped, as on a node without
+/// `deliver_noops_to_state_machine`. Useful in post-mortems, or to check
+/// that two nodes' logs lead to the same state.
+///
+/// `through` should be committed; the log alone can't tell whether it is.
+/// Fails with [`RaftError::LogIndexOutOfRange`] if `through` lies before the
+/// log's snapshot or past its last entry.
+pub fn replay<SM, F>(log: &RaftLog, through: LogIndex, make_state_machine: F) -> Result<Vec<u8>>
+where
+    SM: StateMachine,
+    F: FnOnce() -> SM,
+{
+    if through > log.last_index() {
+        return Err(RaftError::LogIndexOutOfRange(through));
+    }
+
+    let mut machine = make_state_machine();
+    let mut applied = LogIndex::ZERO;
+    if let Some(snapshot) = log.get_snapshot() {
+        let base = snapshot.metadata.last_included_index;
+        if through < base {
+            return Err(RaftError::LogIndexOutOfRange(through));
+        }
+        machine.restore(&snapshot.data);
+        applied = base;
+    }
+
+    for entry in log.get_range(applied + 1, through + 1)? {
+        if entry.kind == EntryKind::Normal {
+            apply_entry(&mut machine, &entry);
+        }
+    }
+    Ok(machine.snapshot())
+}
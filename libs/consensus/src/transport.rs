@@ -0,0 +1,225 @@
+//! Network transport abstraction
+//!
+//! The node loop never talks to the network directly. Outbound RPCs go
+//! through a [`Transport`], so nodes can be wired together in-process for
+//! tests or over a real network in production.
+
+use crate::rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, ForwardRequest, ForwardResponse,
+    InstallSnapshotRequest, InstallSnapshotResponse, JoinRequest, JoinResponse, PingRequest,
+    PingResponse, RequestVoteRequest, RequestVoteResponse,
+};
+use crate::types::NodeId;
+use crate::{RaftError, Result};
+
+use async_trait::async_trait;
+use futures::stream::{BoxStream, FuturesUnordered, StreamExt};
+
+mod channel;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "grpc")]
+pub use grpc::{serve, GrpcTransport};
+#[cfg(feature = "tcp")]
+mod tcp;
+#[cfg(feature = "tcp")]
+pub use tcp::{serve_tcp, serve_tcp_with, TcpTransport, UnknownRpc};
+
+pub use channel::ChannelTransport;
+
+/// Sends Raft RPCs to other nodes
+///
+/// Implementations must be object-safe and cheap to share; the node holds an
+/// `Arc<dyn Transport>` and calls it from spawned tasks, never from the main
+/// loop itself.
+#[async_trait]
+pub trait Transport: Send + Sync + 'static {
+    /// Send a RequestVote RPC to `target` and wait for its response
+    async fn send_request_vote(
+        &self,
+        target: NodeId,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse>;
+
+    /// Send an AppendEntries RPC to `target` and wait for its response
+    async fn send_append_entries(
+        &self,
+        target: NodeId,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse>;
+
+    /// Send one chunk of an InstallSnapshot RPC to `target` and wait for its
+    /// response
+    async fn send_install_snapshot(
+        &self,
+        target: NodeId,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse>;
+
+    /// Send a Ping RPC to `target` and wait for its pong
+    ///
+    /// Used to probe liveness and measure round-trip time without going
+    /// through replication. Transports that don't support it report an error,
+    /// which the node treats like an unreachable peer.
+    async fn send_ping(&self, target: NodeId, request: PingRequest) -> Result<PingResponse> {
+        let _ = request;
+        Err(RaftError::Rpc(format!("ping to {} not supported", target)))
+    }
+
+    /// Ask `target` to add a new node to the cluster
+    ///
+    /// Only needed by nodes that join a running cluster with
+    /// `RaftNode::join`. Transports that don't support it report an error.
+    async fn send_join(&self, target: NodeId, request: JoinRequest) -> Result<JoinResponse> {
+        let _ = request;
+        Err(RaftError::Rpc(format!("join via {} not supported", target)))
+    }
+
+    /// Hand a client's proposal to the leader `target` and wait for the
+    /// outcome
+    ///
+    /// Only needed with `RaftConfig::forward_proposals`. Transports that
+    /// don't support it report an error.
+    async fn send_forward(
+        &self,
+        target: NodeId,
+        request: ForwardRequest,
+    ) -> Result<ForwardResponse> {
+        let _ = request;
+        Err(RaftError::Rpc(format!(
+            "forwarding to {} not supported",
+            target
+        )))
+    }
+
+    /// Largest payload one request may carry, in bytes, if the transport
+    /// has a limit
+    ///
+    /// Counts entry commands in an AppendEntries and snapshot data in an
+    /// InstallSnapshot chunk, so leave room for headers and framing. The
+    /// node sends no more than this in one request and refuses proposals
+    /// that wouldn't fit on their own.
+    fn max_message_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Send a RequestVote RPC to every peer concurrently
+    ///
+    /// Responses are yielded in the order they arrive rather than the order
+    /// of `peers`, so a candidate can act on a majority without waiting for
+    /// stragglers.
+    fn broadcast_request_vote(
+        &self,
+        peers: Vec<NodeId>,
+        request: RequestVoteRequest,
+    ) -> BoxStream<'_, (NodeId, Result<RequestVoteResponse>)> {
+        peers
+            .into_iter()
+            .map(|peer| {
+                let request = request.clone();
+                async move { (peer, self.send_request_vote(peer, request).await) }
+            })
+            .collect::<FuturesUnordered<_>>()
+            .boxed()
+    }
+}
+
+/// Transport that can't reach anyone
+///
+/// Every RPC fails immediately. Suits single-node clusters and tests that
+/// drive a node's RPCs by hand, and is what [`RaftNodeBuilder`](crate::RaftNodeBuilder)
+/// uses when given no transport.
+pub struct NoopTransport;
+
+#[async_trait]
+impl Transport for NoopTransport {
+    async fn send_request_vote(
+        &self,
+        target: NodeId,
+        _request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        Err(RaftError::Rpc(format!("no transport to reach {}", target)))
+    }
+
+    async fn send_append_entries(
+        &self,
+        target: NodeId,
+        _request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        Err(RaftError::Rpc(format!("no transport to reach {}", target)))
+    }
+
+    async fn send_install_snapshot(
+        &self,
+        target: NodeId,
+        _request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        Err(RaftError::Rpc(format!("no transport to reach {}", target)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{LogIndex, Term};
+    use std::time::Duration;
+
+    /// Grants every vote after a per-peer delay
+    struct DelayedTransport;
+
+    #[async_trait]
+    impl Transport for DelayedTransport {
+        async fn send_request_vote(
+            &self,
+            target: NodeId,
+            request: RequestVoteRequest,
+        ) -> Result<RequestVoteResponse> {
+            tokio::time::sleep(Duration::from_millis(10 * (5 - target.0))).await;
+            Ok(RequestVoteResponse {
+                term: request.term,
+                vote_granted: true,
+                denial_reason: None,
+            })
+        }
+
+        async fn send_append_entries(
+            &self,
+            target: NodeId,
+            _request: AppendEntriesRequest,
+        ) -> Result<AppendEntriesResponse> {
+            Err(RaftError::Rpc(format!("unreachable {}", target)))
+        }
+
+        async fn send_install_snapshot(
+            &self,
+            target: NodeId,
+            _request: InstallSnapshotRequest,
+        ) -> Result<InstallSnapshotResponse> {
+            Err(RaftError::Rpc(format!("unreachable {}", target)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_yields_in_arrival_order() {
+        let request = RequestVoteRequest {
+            term: Term(1),
+            candidate_id: NodeId(0),
+            last_log_index: LogIndex::ZERO,
+            last_log_term: Term(0),
+            pre_vote: false,
+        };
+
+        let transport = DelayedTransport;
+        let order: Vec<NodeId> = transport
+            .broadcast_request_vote(vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4)], request)
+            .map(|(peer, response)| {
+                assert!(response.unwrap().vote_granted);
+                peer
+            })
+            .collect()
+            .await;
+
+        // Higher ids answer faster
+        assert_eq!(order, vec![NodeId(4), NodeId(3), NodeId(2), NodeId(1)]);
+    }
+}
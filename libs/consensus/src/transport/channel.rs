@@ -0,0 +1,149 @@
+//! In-process transport
+//!
+//! Every RPC is handed straight to the target [`RaftNode`]'s command channel,
+//! so a whole cluster can run inside one process without sockets.
+
+use super::Transport;
+use crate::node::RaftNode;
+use crate::rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, ForwardRequest, ForwardResponse,
+    InstallSnapshotRequest, InstallSnapshotResponse, JoinRequest, JoinResponse, PingRequest,
+    PingResponse, RequestVoteRequest, RequestVoteResponse,
+};
+use crate::types::NodeId;
+use crate::{RaftError, Result};
+
+use async_trait::async_trait;
+use parking_lot::RwLock;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+/// Transport connecting nodes that live in the same process
+///
+/// One instance is shared by every node on the network: build the nodes with
+/// it, then [`register`](Self::register) them so RPCs can find them. The
+/// transport holds a handle to each registered node, so
+/// [`unregister`](Self::unregister) a node before shutting it down.
+///
+/// Nodes can be [`partition`](Self::partition)ed off: RPCs to and from them
+/// fail as if the network dropped them, until they're
+/// [`heal`](Self::heal)ed.
+#[derive(Default)]
+pub struct ChannelTransport {
+    nodes: RwLock<HashMap<NodeId, Arc<RaftNode>>>,
+
+    /// Nodes cut off from everyone else
+    partitioned: RwLock<BTreeSet<NodeId>>,
+}
+
+impl ChannelTransport {
+    /// Create a transport with no nodes on it
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// One transport handle per id in `ids`, in the same order
+    ///
+    /// The handles all lead onto the same network, so each node can be built
+    /// with its own and registered through it; a node registered through any
+    /// of them is reachable from all the others.
+    pub fn cluster(ids: &[NodeId]) -> Vec<Arc<Self>> {
+        let transport = Self::new();
+        ids.iter().map(|_| Arc::clone(&transport)).collect()
+    }
+
+    /// Make `node` reachable under its id
+    pub fn register(&self, node: Arc<RaftNode>) {
+        self.nodes.write().insert(node.id(), node);
+    }
+
+    /// Take `id` off the network, returning its handle
+    pub fn unregister(&self, id: NodeId) -> Option<Arc<RaftNode>> {
+        self.nodes.write().remove(&id)
+    }
+
+    /// Handle of a registered node
+    pub fn node(&self, id: NodeId) -> Option<Arc<RaftNode>> {
+        self.nodes.read().get(&id).cloned()
+    }
+
+    /// Ids of all registered nodes, in ascending order
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        let mut ids: Vec<_> = self.nodes.read().keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Drop every RPC to or from `id`
+    pub fn partition(&self, id: NodeId) {
+        self.partitioned.write().insert(id);
+    }
+
+    /// Deliver RPCs to and from `id` again
+    pub fn heal(&self, id: NodeId) {
+        self.partitioned.write().remove(&id);
+    }
+
+    /// Whether `id` is currently partitioned off
+    pub fn is_partitioned(&self, id: NodeId) -> bool {
+        self.partitioned.read().contains(&id)
+    }
+
+    /// Find the node an RPC from `from` to `target` should be delivered to
+    fn route(&self, from: NodeId, target: NodeId) -> Result<Arc<RaftNode>> {
+        if self.is_partitioned(from) || self.is_partitioned(target) {
+            return Err(RaftError::Rpc(format!("{} unreachable", target)));
+        }
+        self.node(target)
+            .ok_or_else(|| RaftError::Rpc(format!("unknown node {}", target)))
+    }
+}
+
+#[async_trait]
+impl Transport for ChannelTransport {
+    async fn send_request_vote(
+        &self,
+        target: NodeId,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        let node = self.route(request.candidate_id, target)?;
+        Ok(node.request_vote(request).await)
+    }
+
+    async fn send_append_entries(
+        &self,
+        target: NodeId,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        let node = self.route(request.leader_id, target)?;
+        Ok(node.append_entries(request).await)
+    }
+
+    async fn send_install_snapshot(
+        &self,
+        target: NodeId,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        let node = self.route(request.leader_id, target)?;
+        Ok(node.install_snapshot(request).await)
+    }
+
+    async fn send_ping(&self, target: NodeId, request: PingRequest) -> Result<PingResponse> {
+        let node = self.route(request.from, target)?;
+        Ok(node.ping(request).await)
+    }
+
+    async fn send_join(&self, target: NodeId, request: JoinRequest) -> Result<JoinResponse> {
+        let node = self.route(request.node, target)?;
+        Ok(node.handle_join(request).await)
+    }
+
+    async fn send_forward(
+        &self,
+        target: NodeId,
+        request: ForwardRequest,
+    ) -> Result<ForwardResponse> {
+        let node = self.route(request.from, target)?;
+        Ok(node.handle_forward(request).await)
+    }
+}
@@ -0,0 +1,727 @@
+//! gRPC transport built on tonic
+//!
+//! [`GrpcTransport`] sends RPCs to peers over gRPC, and [`serve`] answers
+//! them by handing each request to a local [`RaftNode`]. The wire format is
+//! defined in `proto/raft.proto`, so nodes can also talk to non-Rust peers.
+
+use crate::node::RaftNode;
+use crate::rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest, InstallSnapshotResponse,
+    PingRequest, PingResponse, RequestVoteRequest, RequestVoteResponse, VoteDenialReason,
+};
+use crate::transport::Transport;
+use crate::types::{ClusterConfig, Entry, EntryKind, LogIndex, NodeId, Term};
+use crate::{RaftError, Result};
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::transport::{Channel, Endpoint, Server};
+use tonic::{Request, Response, Status};
+
+use proto::raft_client::RaftClient;
+use proto::raft_server::{Raft, RaftServer};
+
+/// Wire messages, field for field as in `proto/raft.proto`
+#[allow(clippy::all)]
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Entry {
+        #[prost(uint64, tag = "1")]
+        pub term: u64,
+        #[prost(uint64, tag = "2")]
+        pub index: u64,
+        #[prost(bytes = "vec", tag = "3")]
+        pub command: Vec<u8>,
+        #[prost(enumeration = "EntryKind", tag = "4")]
+        pub kind: i32,
+        #[prost(bytes = "vec", optional, tag = "5")]
+        pub metadata: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum EntryKind {
+        Normal = 0,
+        Noop = 1,
+        ConfigChange = 2,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RequestVoteRequest {
+        #[prost(uint64, tag = "1")]
+        pub term: u64,
+        #[prost(uint64, tag = "2")]
+        pub candidate_id: u64,
+        #[prost(uint64, tag = "3")]
+        pub last_log_index: u64,
+        #[prost(uint64, tag = "4")]
+        pub last_log_term: u64,
+        #[prost(bool, tag = "5")]
+        pub pre_vote: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RequestVoteResponse {
+        #[prost(uint64, tag = "1")]
+        pub term: u64,
+        #[prost(bool, tag = "2")]
+        pub vote_granted: bool,
+        #[prost(enumeration = "VoteDenialReason", tag = "3")]
+        pub denial_reason: i32,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum VoteDenialReason {
+        Unspecified = 0,
+        StaleTerm = 1,
+        AlreadyVoted = 2,
+        LogNotUpToDate = 3,
+        NotAMember = 4,
+        RecentLeaderContact = 5,
+        TermGapExceeded = 6,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AppendEntriesRequest {
+        #[prost(uint64, tag = "1")]
+        pub term: u64,
+        #[prost(uint64, tag = "2")]
+        pub leader_id: u64,
+        #[prost(uint64, tag = "3")]
+        pub prev_log_index: u64,
+        #[prost(uint64, tag = "4")]
+        pub prev_log_term: u64,
+        #[prost(message, repeated, tag = "5")]
+        pub entries: Vec<Entry>,
+        #[prost(uint64, tag = "6")]
+        pub leader_commit: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AppendEntriesResponse {
+        #[prost(uint64, tag = "1")]
+        pub term: u64,
+        #[prost(bool, tag = "2")]
+        pub success: bool,
+        #[prost(uint64, optional, tag = "3")]
+        pub match_index: Option<u64>,
+        #[prost(uint64, tag = "4")]
+        pub commit_index: u64,
+        #[prost(uint64, tag = "5")]
+        pub last_applied: u64,
+        #[prost(uint64, optional, tag = "6")]
+        pub conflict_term: Option<u64>,
+        #[prost(uint64, optional, tag = "7")]
+        pub conflict_index: Option<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InstallSnapshotRequest {
+        #[prost(uint64, tag = "1")]
+        pub term: u64,
+        #[prost(uint64, tag = "2")]
+        pub leader_id: u64,
+        #[prost(uint64, tag = "3")]
+        pub last_included_index: u64,
+        #[prost(uint64, tag = "4")]
+        pub last_included_term: u64,
+        #[prost(uint64, tag = "5")]
+        pub offset: u64,
+        #[prost(bytes = "vec", tag = "6")]
+        pub data: Vec<u8>,
+        #[prost(bool, tag = "7")]
+        pub done: bool,
+        #[prost(message, optional, tag = "8")]
+        pub configuration: Option<ClusterConfig>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ClusterConfig {
+        #[prost(uint64, repeated, tag = "1")]
+        pub voters: Vec<u64>,
+        #[prost(uint64, repeated, tag = "2")]
+        pub learners: Vec<u64>,
+        #[prost(uint64, repeated, tag = "3")]
+        pub outgoing_voters: Vec<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InstallSnapshotResponse {
+        #[prost(uint64, tag = "1")]
+        pub term: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PingRequest {
+        #[prost(uint64, tag = "1")]
+        pub term: u64,
+        #[prost(uint64, tag = "2")]
+        pub from: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PingResponse {
+        #[prost(uint64, tag = "1")]
+        pub term: u64,
+        #[prost(uint64, tag = "2")]
+        pub from: u64,
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/objectbox.raft.Raft.rs"));
+}
+
+impl From<Entry> for proto::Entry {
+    fn from(entry: Entry) -> Self {
+        let kind = match entry.kind {
+            EntryKind::Normal => proto::EntryKind::Normal,
+            EntryKind::Noop => proto::EntryKind::Noop,
+            EntryKind::ConfigChange => proto::EntryKind::ConfigChange,
+        };
+        Self {
+            term: entry.term.0,
+            index: entry.index.0,
+            command: entry.command,
+            kind: kind as i32,
+            metadata: entry.metadata,
+        }
+    }
+}
+
+impl TryFrom<proto::Entry> for Entry {
+    type Error = RaftError;
+
+    fn try_from(entry: proto::Entry) -> Result<Self> {
+        let kind = match proto::EntryKind::try_from(entry.kind) {
+            Ok(proto::EntryKind::Normal) => EntryKind::Normal,
+            Ok(proto::EntryKind::Noop) => EntryKind::Noop,
+            Ok(proto::EntryKind::ConfigChange) => EntryKind::ConfigChange,
+            Err(_) => {
+                return Err(RaftError::InvalidEntry(format!(
+                    "unknown entry kind {} at index {}",
+                    entry.kind, entry.index
+                )))
+            }
+        };
+        Ok(Self {
+            term: Term(entry.term),
+            index: LogIndex(entry.index),
+            command: entry.command,
+            kind,
+            metadata: entry.metadata,
+        })
+    }
+}
+
+impl From<RequestVoteRequest> for proto::RequestVoteRequest {
+    fn from(req: RequestVoteRequest) -> Self {
+        Self {
+            term: req.term.0,
+            candidate_id: req.candidate_id.0,
+            last_log_index: req.last_log_index.0,
+            last_log_term: req.last_log_term.0,
+            pre_vote: req.pre_vote,
+        }
+    }
+}
+
+impl From<proto::RequestVoteRequest> for RequestVoteRequest {
+    fn from(req: proto::RequestVoteRequest) -> Self {
+        Self {
+            term: Term(req.term),
+            candidate_id: NodeId(req.candidate_id),
+            last_log_index: LogIndex(req.last_log_index),
+            last_log_term: Term(req.last_log_term),
+            pre_vote: req.pre_vote,
+        }
+    }
+}
+
+impl From<RequestVoteResponse> for proto::RequestVoteResponse {
+    fn from(resp: RequestVoteResponse) -> Self {
+        let denial_reason = match resp.denial_reason {
+            None => proto::VoteDenialReason::Unspecified,
+            Some(VoteDenialReason::StaleTerm) => proto::VoteDenialReason::StaleTerm,
+            Some(VoteDenialReason::AlreadyVoted) => proto::VoteDenialReason::AlreadyVoted,
+            Some(VoteDenialReason::LogNotUpToDate) => proto::VoteDenialReason::LogNotUpToDate,
+            Some(VoteDenialReason::NotAMember) => proto::VoteDenialReason::NotAMember,
+            Some(VoteDenialReason::RecentLeaderContact) => {
+                proto::VoteDenialReason::RecentLeaderContact
+            }
+            Some(VoteDenialReason::TermGapExceeded) => proto::VoteDenialReason::TermGapExceeded,
+        };
+        Self {
+            term: resp.term.0,
+            vote_granted: resp.vote_granted,
+            denial_reason: denial_reason as i32,
+        }
+    }
+}
+
+impl From<proto::RequestVoteResponse> for RequestVoteResponse {
+    fn from(resp: proto::RequestVoteResponse) -> Self {
+        // The reason is informational, so one we don't know is dropped
+        // rather than failing the response
+        let denial_reason = match proto::VoteDenialReason::try_from(resp.denial_reason) {
+            Ok(proto::VoteDenialReason::StaleTerm) => Some(VoteDenialReason::StaleTerm),
+            Ok(proto::VoteDenialReason::AlreadyVoted) => Some(VoteDenialReason::AlreadyVoted),
+            Ok(proto::VoteDenialReason::LogNotUpToDate) => Some(VoteDenialReason::LogNotUpToDate),
+            Ok(proto::VoteDenialReason::NotAMember) => Some(VoteDenialReason::NotAMember),
+            Ok(proto::VoteDenialReason::RecentLeaderContact) => {
+                Some(VoteDenialReason::RecentLeaderContact)
+            }
+            Ok(proto::VoteDenialReason::TermGapExceeded) => Some(VoteDenialReason::TermGapExceeded),
+            Ok(proto::VoteDenialReason::Unspecified) | Err(_) => None,
+        };
+        Self {
+            term: Term(resp.term),
+            vote_granted: resp.vote_granted,
+            denial_reason,
+        }
+    }
+}
+
+impl From<AppendEntriesRequest> for proto::AppendEntriesRequest {
+    fn from(req: AppendEntriesRequest) -> Self {
+        Self {
+            term: req.term.0,
+            leader_id: req.leader_id.0,
+            prev_log_index: req.prev_log_index.0,
+            prev_log_term: req.prev_log_term.0,
+            entries: req.entries.into_iter().map(Into::into).collect(),
+            leader_commit: req.leader_commit.0,
+        }
+    }
+}
+
+impl TryFrom<proto::AppendEntriesRequest> for AppendEntriesRequest {
+    type Error = RaftError;
+
+    fn try_from(req: proto::AppendEntriesRequest) -> Result<Self> {
+        Ok(Self {
+            term: Term(req.term),
+            leader_id: NodeId(req.leader_id),
+            prev_log_index: LogIndex(req.prev_log_index),
+            prev_log_term: Term(req.prev_log_term),
+            entries: req
+                .entries
+                .into_iter()
+                .map(Entry::try_from)
+                .collect::<Result<_>>()?,
+            leader_commit: LogIndex(req.leader_commit),
+        })
+    }
+}
+
+impl From<AppendEntriesResponse> for proto::AppendEntriesResponse {
+    fn from(resp: AppendEntriesResponse) -> Self {
+        Self {
+            term: resp.term.0,
+            success: resp.success,
+            match_index: resp.match_index.map(|i| i.0),
+            conflict_term: resp.conflict_term.map(|t| t.0),
+            conflict_index: resp.conflict_index.map(|i| i.0),
+            commit_index: resp.commit_index.0,
+            last_applied: resp.last_applied.0,
+        }
+    }
+}
+
+impl From<proto::AppendEntriesResponse> for AppendEntriesResponse {
+    fn from(resp: proto::AppendEntriesResponse) -> Self {
+        Self {
+            term: Term(resp.term),
+            success: resp.success,
+            match_index: resp.match_index.map(LogIndex),
+            conflict_term: resp.conflict_term.map(Term),
+            conflict_index: resp.conflict_index.map(LogIndex),
+            commit_index: LogIndex(resp.commit_index),
+            last_applied: LogIndex(resp.last_applied),
+        }
+    }
+}
+
+impl From<InstallSnapshotRequest> for proto::InstallSnapshotRequest {
+    fn from(req: InstallSnapshotRequest) -> Self {
+        Self {
+            term: req.term.0,
+            leader_id: req.leader_id.0,
+            last_included_index: req.last_included_index.0,
+            last_included_term: req.last_included_term.0,
+            offset: req.offset,
+            data: req.data,
+            done: req.done,
+            configuration: req.configuration.map(Into::into),
+        }
+    }
+}
+
+impl From<proto::InstallSnapshotRequest> for InstallSnapshotRequest {
+    fn from(req: proto::InstallSnapshotRequest) -> Self {
+        Self {
+            term: Term(req.term),
+            leader_id: NodeId(req.leader_id),
+            last_included_index: LogIndex(req.last_included_index),
+            last_included_term: Term(req.last_included_term),
+            offset: req.offset,
+            data: req.data,
+            done: req.done,
+            configuration: req.configuration.map(Into::into),
+        }
+    }
+}
+
+impl From<ClusterConfig> for proto::ClusterConfig {
+    fn from(config: ClusterConfig) -> Self {
+        Self {
+            voters: config.voters.into_iter().map(|id| id.0).collect(),
+            learners: config.learners.into_iter().map(|id| id.0).collect(),
+            outgoing_voters: config.outgoing_voters.into_iter().map(|id| id.0).collect(),
+        }
+    }
+}
+
+impl From<proto::ClusterConfig> for ClusterConfig {
+    fn from(config: proto::ClusterConfig) -> Self {
+        Self {
+            voters: config.voters.into_iter().map(NodeId).collect(),
+            learners: config.learners.into_iter().map(NodeId).collect(),
+            outgoing_voters: config.outgoing_voters.into_iter().map(NodeId).collect(),
+        }
+    }
+}
+
+impl From<PingRequest> for proto::PingRequest {
+    fn from(req: PingRequest) -> Self {
+        Self {
+            term: req.term.0,
+            from: req.from.0,
+        }
+    }
+}
+
+impl From<proto::PingRequest> for PingRequest {
+    fn from(req: proto::PingRequest) -> Self {
+        Self {
+            term: Term(req.term),
+            from: NodeId(req.from),
+        }
+    }
+}
+
+impl From<PingResponse> for proto::PingResponse {
+    fn from(resp: PingResponse) -> Self {
+        Self {
+            term: resp.term.0,
+            from: resp.from.0,
+        }
+    }
+}
+
+impl From<proto::PingResponse> for PingResponse {
+    fn from(resp: proto::PingResponse) -> Self {
+        Self {
+            term: Term(resp.term),
+            from: NodeId(resp.from),
+        }
+    }
+}
+
+/// Transport that reaches peers over gRPC
+///
+/// Connections are opened lazily on first use and re-established by tonic
+/// if they drop.
+pub struct GrpcTransport {
+    peers: HashMap<NodeId, RaftClient<Channel>>,
+}
+
+impl GrpcTransport {
+    /// Create a transport for peers at the given URIs, e.g.
+    /// `http://10.0.0.2:7000`
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn new(peers: impl IntoIterator<Item = (NodeId, String)>) -> Result<Self> {
+        let peers = peers
+            .into_iter()
+            .map(|(id, uri)| {
+                let endpoint = Endpoint::from_shared(uri)
+                    .map_err(|e| RaftError::Rpc(format!("bad address for {}: {}", id, e)))?;
+                Ok((id, RaftClient::new(endpoint.connect_lazy())))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { peers })
+    }
+
+    fn client(&self, target: NodeId) -> Result<RaftClient<Channel>> {
+        self.peers
+            .get(&target)
+            .cloned()
+            .ok_or_else(|| RaftError::Rpc(format!("no address for {}", target)))
+    }
+}
+
+fn rpc_error(target: NodeId, status: Status) -> RaftError {
+    RaftError::Rpc(format!("{} to {}", status, target))
+}
+
+/// Payload bytes per request, comfortably under the 4MB tonic accepts by
+/// default once entry headers and protobuf framing are added
+const MAX_PAYLOAD: usize = 3 * 1024 * 1024;
+
+#[async_trait]
+impl Transport for GrpcTransport {
+    async fn send_request_vote(
+        &self,
+        target: NodeId,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        let response = self
+            .client(target)?
+            .request_vote(proto::RequestVoteRequest::from(request))
+            .await
+            .map_err(|status| rpc_error(target, status))?;
+        Ok(response.into_inner().into())
+    }
+
+    async fn send_append_entries(
+        &self,
+        target: NodeId,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        let response = self
+            .client(target)?
+            .append_entries(proto::AppendEntriesRequest::from(request))
+            .await
+            .map_err(|status| rpc_error(target, status))?;
+        Ok(response.into_inner().into())
+    }
+
+    async fn send_install_snapshot(
+        &self,
+        target: NodeId,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        let response = self
+            .client(target)?
+            .install_snapshot(proto::InstallSnapshotRequest::from(request))
+            .await
+            .map_err(|status| rpc_error(target, status))?;
+        Ok(InstallSnapshotResponse {
+            term: Term(response.into_inner().term),
+        })
+    }
+
+    async fn send_ping(&self, target: NodeId, request: PingRequest) -> Result<PingResponse> {
+        let response = self
+            .client(target)?
+            .ping(proto::PingRequest::from(request))
+            .await
+            .map_err(|status| rpc_error(target, status))?;
+        Ok(response.into_inner().into())
+    }
+
+    fn max_message_size(&self) -> Option<usize> {
+        Some(MAX_PAYLOAD)
+    }
+}
+
+/// Answers gRPC requests on behalf of a local node
+struct RaftService {
+    node: Arc<RaftNode>,
+}
+
+#[async_trait]
+impl Raft for RaftService {
+    async fn request_vote(
+        &self,
+        request: Request<proto::RequestVoteRequest>,
+    ) -> std::result::Result<Response<proto::RequestVoteResponse>, Status> {
+        let response = self.node.request_vote(request.into_inner().into()).await;
+        Ok(Response::new(response.into()))
+    }
+
+    async fn append_entries(
+        &self,
+        request: Request<proto::AppendEntriesRequest>,
+    ) -> std::result::Result<Response<proto::AppendEntriesResponse>, Status> {
+        let request = AppendEntriesRequest::try_from(request.into_inner())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let response = self.node.append_entries(request).await;
+        Ok(Response::new(response.into()))
+    }
+
+    async fn install_snapshot(
+        &self,
+        request: Request<proto::InstallSnapshotRequest>,
+    ) -> std::result::Result<Response<proto::InstallSnapshotResponse>, Status> {
+        let response: InstallSnapshotResponse = self
+            .node
+            .install_snapshot(request.into_inner().into())
+            .await;
+        Ok(Response::new(proto::InstallSnapshotResponse {
+            term: response.term.0,
+        }))
+    }
+
+    async fn ping(
+        &self,
+        request: Request<proto::PingRequest>,
+    ) -> std::result::Result<Response<proto::PingResponse>, Status> {
+        let response = self.node.ping(request.into_inner().into()).await;
+        Ok(Response::new(response.into()))
+    }
+}
+
+/// Serve Raft RPCs for `node` on `addr` until the server fails
+///
+/// Run this in its own task alongside the node.
+pub async fn serve(node: Arc<RaftNode>, addr: SocketAddr) -> Result<()> {
+    Server::builder()
+        .add_service(RaftServer::new(RaftService { node }))
+        .serve(addr)
+        .await
+        .map_err(|e| RaftError::Rpc(format!("gRPC server on {} failed: {}", addr, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_entries_round_trip() {
+        let request = AppendEntriesRequest {
+            term: Term(3),
+            leader_id: NodeId(2),
+            prev_log_index: LogIndex(4),
+            prev_log_term: Term(2),
+            entries: vec![
+                Entry::noop(Term(3), LogIndex(5)),
+                Entry::new(Term(3), LogIndex(6), b"SET a 1".to_vec())
+                    .with_metadata(b"trace-1".to_vec()),
+            ],
+            leader_commit: LogIndex(4),
+        };
+
+        let decoded =
+            AppendEntriesRequest::try_from(proto::AppendEntriesRequest::from(request.clone()))
+                .unwrap();
+        assert_eq!(decoded.term, request.term);
+        assert_eq!(decoded.leader_id, request.leader_id);
+        assert_eq!(decoded.prev_log_index, request.prev_log_index);
+        assert_eq!(decoded.entries.len(), 2);
+        assert_eq!(decoded.entries[0].kind, EntryKind::Noop);
+        assert_eq!(decoded.entries[1].command, b"SET a 1");
+        assert_eq!(decoded.entries[0].metadata, None);
+        assert_eq!(
+            decoded.entries[1].metadata.as_deref(),
+            Some(&b"trace-1"[..])
+        );
+    }
+
+    #[test]
+    fn test_unknown_entry_kind_rejected() {
+        let entry = proto::Entry {
+            term: 1,
+            index: 1,
+            command: vec![],
+            kind: 42,
+            metadata: None,
+        };
+        assert!(matches!(
+            Entry::try_from(entry),
+            Err(RaftError::InvalidEntry(_))
+        ));
+    }
+
+    #[test]
+    fn test_vote_denial_reason_round_trip() {
+        for reason in [
+            None,
+            Some(VoteDenialReason::RecentLeaderContact),
+            Some(VoteDenialReason::TermGapExceeded),
+        ] {
+            let response = RequestVoteResponse {
+                term: Term(2),
+                vote_granted: false,
+                denial_reason: reason,
+            };
+            let decoded = RequestVoteResponse::from(proto::RequestVoteResponse::from(response));
+            assert_eq!(decoded.denial_reason, reason);
+        }
+
+        // A reason from a newer peer is dropped, not an error
+        let decoded = RequestVoteResponse::from(proto::RequestVoteResponse {
+            term: 2,
+            vote_granted: false,
+            denial_reason: 42,
+        });
+        assert_eq!(decoded.denial_reason, None);
+    }
+
+    #[tokio::test]
+    async fn test_vote_over_grpc() {
+        use crate::config::RaftConfig;
+        use crate::node::StateMachine;
+        use crate::transport::NoopTransport;
+
+        struct Nothing;
+
+        impl StateMachine for Nothing {
+            fn apply(&mut self, _command: &[u8]) -> Vec<u8> {
+                vec![]
+            }
+
+            fn snapshot(&self) -> Vec<u8> {
+                vec![]
+            }
+
+            fn restore(&mut self, _snapshot: &[u8]) {}
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let node = RaftNode::new(
+            NodeId(1),
+            vec![NodeId(1), NodeId(2)],
+            RaftConfig::default(),
+            Nothing,
+            Arc::new(NoopTransport),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(serve(Arc::new(node), addr));
+
+        let transport = GrpcTransport::new([(NodeId(1), format!("http://{}", addr))]).unwrap();
+        let request = RequestVoteRequest {
+            term: Term(5),
+            candidate_id: NodeId(2),
+            last_log_index: LogIndex::ZERO,
+            last_log_term: Term(0),
+            pre_vote: false,
+        };
+
+        // The server may take a moment to start listening
+        let mut response = None;
+        for _ in 0..50 {
+            match transport
+                .send_request_vote(NodeId(1), request.clone())
+                .await
+            {
+                Ok(r) => {
+                    response = Some(r);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(20)).await,
+            }
+        }
+
+        let response = response.expect("gRPC server never answered");
+        assert_eq!(response.term, Term(5));
+        assert!(response.vote_granted);
+    }
+}
@@ -0,0 +1,485 @@
+//! Length-prefixed TCP transport
+//!
+//! A lighter alternative to gRPC that needs nothing beyond tokio.
+//! [`TcpTransport`] sends each RPC as one frame, a 4-byte big-endian length
+//! followed by a bincode-encoded [`RaftMessage`], and waits for the
+//! [`RaftReply`] frame that answers it. [`serve_tcp`] accepts connections
+//! and hands every message it reads to a local [`RaftNode`].
+//!
+//! The encoding is not meant for other languages. Versions of this crate
+//! can be mixed only as far as rolling upgrades need: a request of a kind
+//! the server doesn't know is answered with an "unsupported" reply, as
+//! [`UnknownRpc`] describes, and the sender sees an RPC error.
+
+use crate::node::RaftNode;
+use crate::rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, ForwardRequest, ForwardResponse,
+    InstallSnapshotRequest, InstallSnapshotResponse, JoinRequest, JoinResponse, PingRequest,
+    PingResponse, RequestVoteRequest, RequestVoteResponse,
+};
+use crate::transport::Transport;
+use crate::types::{NodeId, Term};
+use crate::{RaftError, Result};
+
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// Largest frame either end reads or writes
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+/// Payload bytes per request, leaving room under [`MAX_FRAME`] for entry
+/// headers and the message encoding
+const MAX_PAYLOAD: usize = 8 * 1024 * 1024;
+
+/// How long to wait for a peer to accept a new connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Idle connections kept open to each peer
+///
+/// RPCs to the same peer run concurrently, each on its own connection, so a
+/// large snapshot chunk doesn't hold up heartbeats behind it.
+const MAX_IDLE: usize = 4;
+
+/// A request as it travels over the wire
+///
+/// New kinds of request go at the end, and [`MESSAGE_KINDS`] grows with
+/// them: servers tell a request they don't know from a damaged one by its
+/// variant index.
+#[derive(Serialize, Deserialize)]
+enum RaftMessage {
+    RequestVote(RequestVoteRequest),
+    AppendEntries(AppendEntriesRequest),
+    InstallSnapshot(InstallSnapshotRequest),
+    Ping(PingRequest),
+    Join(JoinRequest),
+    Forward(ForwardRequest),
+}
+
+/// Number of [`RaftMessage`] variants this version understands
+const MESSAGE_KINDS: u32 = 6;
+
+/// The answer to a [`RaftMessage`], always of the matching variant
+#[derive(Serialize, Deserialize)]
+enum RaftReply {
+    RequestVote(RequestVoteResponse),
+    AppendEntries(AppendEntriesResponse),
+    InstallSnapshot(InstallSnapshotResponse),
+    Ping(PingResponse),
+    Join(JoinResponse),
+    Forward(ForwardResponse),
+
+    /// The server doesn't know this kind of request, probably because it
+    /// runs an older version; `term` is its current term
+    Unsupported {
+        term: Term,
+    },
+}
+
+/// What [`serve_tcp_with`] does with a request of a kind it doesn't know
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownRpc {
+    /// Log a warning, answer with an "unsupported" reply carrying the
+    /// current term, and keep the connection open
+    #[default]
+    Reply,
+
+    /// Log a warning and close the connection, as for an undecodable frame
+    Disconnect,
+}
+
+type Connection = Framed<TcpStream, LengthDelimitedCodec>;
+
+fn framed(stream: TcpStream) -> Connection {
+    let codec = LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_FRAME)
+        .new_codec();
+    Framed::new(stream, codec)
+}
+
+/// Transport that reaches peers over plain TCP
+///
+/// Connections are opened on first use and reused afterwards. One that
+/// fails is dropped, and the next RPC to that peer connects afresh, so a
+/// restarted peer is picked up again without any action from the caller.
+pub struct TcpTransport {
+    peers: HashMap<NodeId, SocketAddr>,
+    idle: Mutex<HashMap<NodeId, Vec<Connection>>>,
+}
+
+impl TcpTransport {
+    /// Create a transport for peers listening at the given addresses
+    pub fn new(peers: impl IntoIterator<Item = (NodeId, SocketAddr)>) -> Self {
+        Self {
+            peers: peers.into_iter().collect(),
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn connect(&self, target: NodeId) -> Result<Connection> {
+        let addr = *self
+            .peers
+            .get(&target)
+            .ok_or_else(|| RaftError::Rpc(format!("no address for {}", target)))?;
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| RaftError::Rpc(format!("connecting to {} at {} timed out", target, addr)))?
+            .map_err(|e| RaftError::Rpc(format!("connecting to {} at {}: {}", target, addr, e)))?;
+        stream
+            .set_nodelay(true)
+            .map_err(|e| RaftError::Rpc(format!("connecting to {} at {}: {}", target, addr, e)))?;
+        Ok(framed(stream))
+    }
+
+    /// Send `message` to `target` and wait for its reply
+    async fn call(&self, target: NodeId, message: RaftMessage) -> Result<RaftReply> {
+        let frame = bincode::serialize(&message)
+            .map_err(|e| RaftError::Rpc(format!("encoding request to {}: {}", target, e)))?;
+        let idle = self.idle.lock().get_mut(&target).and_then(Vec::pop);
+        let mut conn = match idle {
+            Some(conn) => conn,
+            None => self.connect(target).await?,
+        };
+
+        conn.send(frame.into())
+            .await
+            .map_err(|e| RaftError::Rpc(format!("sending to {}: {}", target, e)))?;
+        let reply = conn
+            .next()
+            .await
+            .ok_or_else(|| RaftError::Rpc(format!("{} closed the connection", target)))?
+            .map_err(|e| RaftError::Rpc(format!("reading from {}: {}", target, e)))?;
+        let reply = bincode::deserialize(&reply)
+            .map_err(|e| RaftError::Rpc(format!("decoding reply from {}: {}", target, e)))?;
+        if let RaftReply::Unsupported { term } = reply {
+            // The connection is still good; only the request wasn't
+            self.release(target, conn);
+            return Err(RaftError::Rpc(format!(
+                "{} at term {} doesn't support this request",
+                target, term
+            )));
+        }
+
+        self.release(target, conn);
+        Ok(reply)
+    }
+
+    /// Return a connection that's done with its RPC to the idle pool
+    fn release(&self, target: NodeId, conn: Connection) {
+        let mut idle = self.idle.lock();
+        let pool = idle.entry(target).or_default();
+        if pool.len() < MAX_IDLE {
+            pool.push(conn);
+        }
+    }
+}
+
+fn unexpected_reply(target: NodeId) -> RaftError {
+    RaftError::Rpc(format!("{} answered with the wrong reply", target))
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn send_request_vote(
+        &self,
+        target: NodeId,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        match self.call(target, RaftMessage::RequestVote(request)).await? {
+            RaftReply::RequestVote(response) => Ok(response),
+            _ => Err(unexpected_reply(target)),
+        }
+    }
+
+    async fn send_append_entries(
+        &self,
+        target: NodeId,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        match self
+            .call(target, RaftMessage::AppendEntries(request))
+            .await?
+        {
+            RaftReply::AppendEntries(response) => Ok(response),
+            _ => Err(unexpected_reply(target)),
+        }
+    }
+
+    async fn send_install_snapshot(
+        &self,
+        target: NodeId,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        match self
+            .call(target, RaftMessage::InstallSnapshot(request))
+            .await?
+        {
+            RaftReply::InstallSnapshot(response) => Ok(response),
+            _ => Err(unexpected_reply(target)),
+        }
+    }
+
+    async fn send_ping(&self, target: NodeId, request: PingRequest) -> Result<PingResponse> {
+        match self.call(target, RaftMessage::Ping(request)).await? {
+            RaftReply::Ping(response) => Ok(response),
+            _ => Err(unexpected_reply(target)),
+        }
+    }
+
+    async fn send_join(&self, target: NodeId, request: JoinRequest) -> Result<JoinResponse> {
+        match self.call(target, RaftMessage::Join(request)).await? {
+            RaftReply::Join(response) => Ok(response),
+            _ => Err(unexpected_reply(target)),
+        }
+    }
+
+    async fn send_forward(
+        &self,
+        target: NodeId,
+        request: ForwardRequest,
+    ) -> Result<ForwardResponse> {
+        match self.call(target, RaftMessage::Forward(request)).await? {
+            RaftReply::Forward(response) => Ok(response),
+            _ => Err(unexpected_reply(target)),
+        }
+    }
+
+    fn max_message_size(&self) -> Option<usize> {
+        Some(MAX_PAYLOAD)
+    }
+}
+
+/// Hand one request to `node` and build the reply to send back
+async fn handle_message(node: &RaftNode, message: RaftMessage) -> RaftReply {
+    match message {
+        RaftMessage::RequestVote(req) => RaftReply::RequestVote(node.request_vote(req).await),
+        RaftMessage::AppendEntries(req) => RaftReply::AppendEntries(node.append_entries(req).await),
+        RaftMessage::InstallSnapshot(req) => {
+            RaftReply::InstallSnapshot(node.install_snapshot(req).await)
+        }
+        RaftMessage::Ping(req) => RaftReply::Ping(node.ping(req).await),
+        RaftMessage::Join(req) => RaftReply::Join(node.handle_join(req).await),
+        RaftMessage::Forward(req) => RaftReply::Forward(node.handle_forward(req).await),
+    }
+}
+
+/// Whether `frame` failed to decode because it holds a kind of request this
+/// version doesn't know, rather than because it's damaged
+fn is_unknown_kind(frame: &[u8]) -> bool {
+    matches!(bincode::deserialize::<u32>(frame), Ok(kind) if kind >= MESSAGE_KINDS)
+}
+
+/// Answer requests on one connection until the peer closes it
+async fn serve_connection(
+    node: Arc<RaftNode>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    on_unknown: UnknownRpc,
+) {
+    let _ = stream.set_nodelay(true);
+    let mut conn = framed(stream);
+    while let Some(frame) = conn.next().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                tracing::debug!("Dropping connection from {}: {}", peer, e);
+                return;
+            }
+        };
+        let reply = match bincode::deserialize(&frame) {
+            Ok(message) => handle_message(&node, message).await,
+            Err(_) if is_unknown_kind(&frame) => {
+                tracing::warn!("Unsupported kind of request from {}", peer);
+                if on_unknown == UnknownRpc::Disconnect {
+                    return;
+                }
+                let Ok(metrics) = node.metrics().await else {
+                    return;
+                };
+                RaftReply::Unsupported {
+                    term: metrics.current_term,
+                }
+            }
+            Err(e) => {
+                tracing::warn!("Undecodable request from {}: {}", peer, e);
+                return;
+            }
+        };
+        let reply = match bincode::serialize(&reply) {
+            Ok(reply) => reply,
+            Err(e) => {
+                tracing::warn!("Failed to encode reply to {}: {}", peer, e);
+                return;
+            }
+        };
+        if let Err(e) = conn.send(reply.into()).await {
+            tracing::debug!("Dropping connection from {}: {}", peer, e);
+            return;
+        }
+    }
+}
+
+/// Serve Raft RPCs for `node` on connections accepted from `listener` until
+/// accepting fails
+///
+/// Takes a bound listener rather than an address so the port is known, and
+/// can be handed to peers, before the server starts. Run this in its own
+/// task alongside the node.
+pub async fn serve_tcp(node: Arc<RaftNode>, listener: TcpListener) -> Result<()> {
+    serve_tcp_with(node, listener, UnknownRpc::default()).await
+}
+
+/// [`serve_tcp`], choosing what happens to requests of a kind this version
+/// doesn't know
+pub async fn serve_tcp_with(
+    node: Arc<RaftNode>,
+    listener: TcpListener,
+    on_unknown: UnknownRpc,
+) -> Result<()> {
+    loop {
+        let (stream, peer) = listener
+            .accept()
+            .await
+            .map_err(|e| RaftError::Rpc(format!("TCP server failed to accept: {}", e)))?;
+        tokio::spawn(serve_connection(
+            Arc::clone(&node),
+            stream,
+            peer,
+            on_unknown,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::{RaftNodeBuilder, StateMachine};
+
+    #[tokio::test]
+    async fn test_unreachable_peer_reports_rpc_error() {
+        // Bind and drop a listener to get a port nothing listens on
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let transport = TcpTransport::new([(NodeId(2), addr)]);
+        let request = PingRequest {
+            term: Term(1),
+            from: NodeId(1),
+        };
+
+        assert!(matches!(
+            transport.send_ping(NodeId(2), request.clone()).await,
+            Err(RaftError::Rpc(_))
+        ));
+        assert!(matches!(
+            transport.send_ping(NodeId(3), request).await,
+            Err(RaftError::Rpc(_))
+        ));
+    }
+
+    struct Noop;
+
+    impl StateMachine for Noop {
+        fn apply(&mut self, _command: &[u8]) -> Vec<u8> {
+            vec![]
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn restore(&mut self, _snapshot: &[u8]) {}
+    }
+
+    /// Serve a lone node on loopback, returning it and a raw connection to it
+    async fn serve_lone_node(on_unknown: UnknownRpc) -> (Arc<RaftNode>, Connection) {
+        let node = RaftNodeBuilder::new(NodeId(1), vec![NodeId(1)], Noop)
+            .build()
+            .await
+            .unwrap();
+        let node = Arc::new(node);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_tcp_with(Arc::clone(&node), listener, on_unknown));
+        let conn = framed(TcpStream::connect(addr).await.unwrap());
+        (node, conn)
+    }
+
+    /// A request as a newer version might send it: a variant index past
+    /// the ones this version knows, then its payload
+    fn future_request() -> Vec<u8> {
+        let mut frame = bincode::serialize(&MESSAGE_KINDS).unwrap();
+        frame.extend_from_slice(b"from the future");
+        frame
+    }
+
+    #[test]
+    fn test_message_kinds_matches_last_variant() {
+        let last = RaftMessage::Forward(ForwardRequest {
+            from: NodeId(2),
+            command: vec![],
+            metadata: None,
+        });
+        let frame = bincode::serialize(&last).unwrap();
+        assert_eq!(
+            bincode::deserialize::<u32>(&frame).unwrap(),
+            MESSAGE_KINDS - 1
+        );
+        assert!(!is_unknown_kind(&frame));
+        assert!(is_unknown_kind(&future_request()));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_request_answered_as_unsupported() {
+        let (node, mut conn) = serve_lone_node(UnknownRpc::Reply).await;
+        let term = node.metrics().await.unwrap().current_term;
+
+        conn.send(future_request().into()).await.unwrap();
+        let reply = conn.next().await.unwrap().unwrap();
+        match bincode::deserialize(&reply).unwrap() {
+            RaftReply::Unsupported { term: reported } => assert_eq!(reported, term),
+            _ => panic!("expected an unsupported reply"),
+        }
+
+        // The connection is still usable
+        let ping = RaftMessage::Ping(PingRequest {
+            term,
+            from: NodeId(2),
+        });
+        conn.send(bincode::serialize(&ping).unwrap().into())
+            .await
+            .unwrap();
+        let reply = conn.next().await.unwrap().unwrap();
+        assert!(matches!(
+            bincode::deserialize(&reply).unwrap(),
+            RaftReply::Ping(_)
+        ));
+
+        drop(conn);
+        if let Ok(node) = Arc::try_unwrap(node) {
+            node.shutdown().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_request_can_close_connection() {
+        let (node, mut conn) = serve_lone_node(UnknownRpc::Disconnect).await;
+
+        conn.send(future_request().into()).await.unwrap();
+        assert!(conn.next().await.is_none());
+
+        drop(conn);
+        if let Ok(node) = Arc::try_unwrap(node) {
+            node.shutdown().await;
+        }
+    }
+}
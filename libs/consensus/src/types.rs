@@ -1,10 +1,15 @@
 //! Core types used throughout the Raft implementation
 
+use crate::{RaftError, Result};
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// Unique identifier for a node in the cluster
+///
+/// Serialized as the bare inner `u64` in every format.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, PartialOrd, Ord)]
+#[serde(transparent)]
 pub struct NodeId(pub u64);
 
 impl fmt::Display for NodeId {
@@ -17,7 +22,9 @@ impl fmt::Display for NodeId {
 ///
 /// Terms are used to detect stale leaders and ensure safety.
 /// Each time a node starts an election, it increments its term.
+/// Serialized as the bare inner `u64` in every format.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default)]
+#[serde(transparent)]
 pub struct Term(pub u64);
 
 impl Term {
@@ -33,7 +40,10 @@ impl fmt::Display for Term {
 }
 
 /// Index into the Raft log
+///
+/// Serialized as the bare inner `u64` in every format.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default)]
+#[serde(transparent)]
 pub struct LogIndex(pub u64);
 
 impl LogIndex {
@@ -63,7 +73,7 @@ impl std::ops::Add<u64> for LogIndex {
     }
 }
 
-impl std::ops::Sub<u64> for LogI ndex {
+impl std::ops::Sub<u64> for LogIndex {
     type Output = LogIndex;
 
     fn sub(self, rhs: u64) -> Self::Output {
@@ -71,8 +81,83 @@ impl std::ops::Sub<u64> for LogI ndex {
     }
 }
 
+/// The position of an entry in the log: its term, then its index
+///
+/// Ordered by term first and index second, which is exactly Raft's "at least
+/// as up-to-date" comparison between two logs' last entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct LogPosition {
+    pub term: Term,
+    pub index: LogIndex,
+}
+
+impl LogPosition {
+    pub fn new(term: Term, index: LogIndex) -> Self {
+        Self { term, index }
+    }
+}
+
+impl fmt::Display for LogPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.index, self.term)
+    }
+}
+
+/// What a log entry carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum EntryKind {
+    /// An application command for the state machine
+    #[default]
+    Normal,
+
+    /// An empty entry a new leader appends to commit entries from earlier
+    /// terms; it carries no command
+    Noop,
+
+    /// A new cluster configuration, encoded in `command`
+    ConfigChange,
+}
+
+/// Cluster membership
+///
+/// Voters take part in elections and count toward quorum; learners only
+/// receive the replicated log.
+///
+/// While the voters change, the cluster passes through a joint
+/// configuration holding both the incoming `voters` and the
+/// `outgoing_voters` they replace. Every decision then needs a majority of
+/// each.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct ClusterConfig {
+    pub voters: Vec<NodeId>,
+    pub learners: Vec<NodeId>,
+
+    /// Voters of the configuration being left; empty unless joint
+    #[serde(default)]
+    pub outgoing_voters: Vec<NodeId>,
+}
+
+impl ClusterConfig {
+    /// Whether `node` votes in either half of the configuration
+    pub fn is_voter(&self, node: NodeId) -> bool {
+        self.voters.contains(&node) || self.outgoing_voters.contains(&node)
+    }
+
+    /// Whether this is the joint configuration of a change in progress
+    pub fn is_joint(&self) -> bool {
+        !self.outgoing_voters.is_empty()
+    }
+
+    pub fn is_learner(&self, node: NodeId) -> bool {
+        self.learners.contains(&node)
+    }
+}
+
 /// A single entry in the Raft log
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// `Debug` prints the command bytes unless payload redaction is on; see
+/// [`set_payload_redaction`].
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Entry {
     /// The term when this entry was created
     pub term: Term,
@@ -82,14 +167,175 @@ pub struct Entry {
 
     /// The command to apply to the state machine
     pub command: Vec<u8>,
+
+    /// What this entry carries
+    #[serde(default)]
+    pub kind: EntryKind,
+
+    /// Opaque bytes the proposer attached alongside the command, e.g. a
+    /// trace id; see [`RaftNode::propose_with_metadata`](crate::RaftNode::propose_with_metadata)
+    #[serde(default)]
+    pub metadata: Option<Vec<u8>>,
 }
 
 impl Entry {
+    /// Create a new entry without validation
+    ///
+    /// Log indices start at 1, so `index` must never be `LogIndex::ZERO`.
+    /// This is only checked in debug builds; use [`Entry::try_new`] when the
+    /// inputs aren't already known to be valid.
     pub fn new(term: Term, index: LogIndex, command: Vec<u8>) -> Self {
+        debug_assert!(index > LogIndex::ZERO, "Entry index must be at least 1");
         Self {
             term,
             index,
             command,
+            kind: EntryKind::Normal,
+            metadata: None,
+        }
+    }
+
+    /// Attach `metadata` to the entry
+    pub fn with_metadata(mut self, metadata: Vec<u8>) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Bytes of command and metadata the entry carries
+    pub fn payload_len(&self) -> usize {
+        self.command.len() + self.metadata.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Create a leader no-op entry
+    pub fn noop(term: Term, index: LogIndex) -> Self {
+        Self {
+            kind: EntryKind::Noop,
+            ..Self::new(term, index, vec![])
+        }
+    }
+
+    pub fn is_noop(&self) -> bool {
+        self.kind == EntryKind::Noop
+    }
+
+    /// Create an entry switching the cluster to `config`
+    pub fn config_change(term: Term, index: LogIndex, config: &ClusterConfig) -> Self {
+        let command = bincode::serialize(config).expect("cluster config is serializable");
+        Self {
+            kind: EntryKind::ConfigChange,
+            ..Self::new(term, index, command)
+        }
+    }
+
+    /// The configuration carried by a config-change entry
+    ///
+    /// Returns `None` for other kinds of entries.
+    pub fn config(&self) -> Result<Option<ClusterConfig>> {
+        if self.kind != EntryKind::ConfigChange {
+            return Ok(None);
+        }
+
+        bincode::deserialize(&self.command).map(Some).map_err(|e| {
+            RaftError::InvalidEntry(format!(
+                "undecodable configuration at {}: {}",
+                self.index, e
+            ))
+        })
+    }
+
+    /// Create a new entry, rejecting an invalid log position
+    pub fn try_new(term: Term, index: LogIndex, command: Vec<u8>) -> Result<Self> {
+        if index == LogIndex::ZERO {
+            return Err(RaftError::InvalidEntry(
+                "log index 0 is not a valid position (indices start at 1)".to_string(),
+            ));
+        }
+
+        Ok(Self::new(term, index, command))
+    }
+
+    /// Like [`Entry::try_new`], but also rejects an empty command
+    ///
+    /// Useful for callers whose state machine treats an empty command as a
+    /// programming error rather than a meaningful value.
+    pub fn try_new_strict(term: Term, index: LogIndex, command: Vec<u8>) -> Result<Self> {
+        if command.is_empty() {
+            return Err(RaftError::InvalidEntry(format!(
+                "empty command at {}",
+                index
+            )));
+        }
+
+        Self::try_new(term, index, command)
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Entry")
+            .field("term", &self.term)
+            .field("index", &self.index)
+            .field("command", &Payload(&self.command))
+            .field("kind", &self.kind)
+            .field("metadata", &self.metadata.as_deref().map(Payload))
+            .finish()
+    }
+}
+
+static REDACT_PAYLOADS: AtomicBool = AtomicBool::new(false);
+
+/// Hide command and snapshot bytes from `Debug` output, process-wide
+///
+/// With redaction on, [`Entry`], [`Snapshot`] and the RPCs carrying them
+/// print each payload as its length and hash, like [`Redacted`], instead of
+/// its contents. Only formatting changes; the bytes are replicated, stored
+/// and applied as usual. Off by default.
+pub fn set_payload_redaction(enabled: bool) {
+    REDACT_PAYLOADS.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether [`set_payload_redaction`] is on
+pub fn payload_redaction() -> bool {
+    REDACT_PAYLOADS.load(Ordering::Relaxed)
+}
+
+/// Formats a payload as its length and a hash, never its contents
+///
+/// The hash is 64-bit FNV-1a: stable across processes and versions, so the
+/// same command can be matched up in logs from different nodes, but not a
+/// cryptographic digest.
+pub struct Redacted<'a>(pub &'a [u8]);
+
+impl Redacted<'_> {
+    /// FNV-1a hash of the payload
+    pub fn hash(&self) -> u64 {
+        self.0.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} bytes, fnv1a {:016x}>", self.0.len(), self.hash())
+    }
+}
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Formats a payload as raw bytes, or [`Redacted`] if redaction is on
+pub(crate) struct Payload<'a>(pub(crate) &'a [u8]);
+
+impl fmt::Debug for Payload<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if payload_redaction() {
+            Redacted(self.0).fmt(f)
+        } else {
+            self.0.fmt(f)
         }
     }
 }
@@ -103,21 +349,175 @@ pub struct SnapshotMetadata {
     /// Term of the last entry included in the snapshot
     pub last_included_term: Term,
 
-    /// Cluster configuration at the time of the snapshot
-    pub configuration: Vec<NodeId>,
+    /// Cluster membership, voters and learners, as of `last_included_index`
+    pub configuration: ClusterConfig,
 }
 
 /// A complete snapshot of the state machine
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Snapshot {
     pub metadata: SnapshotMetadata,
     pub data: Vec<u8>,
 }
 
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("metadata", &self.metadata)
+            .field("data", &Payload(&self.data))
+            .finish()
+    }
+}
+
+/// Everything needed to start a new replica from a running node
+///
+/// Produced by `RaftNode::export_bundle` and loaded with
+/// `RaftNode::import_bundle`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateBundle {
+    /// The exporting node's latest snapshot, if it had taken one
+    pub snapshot: Option<Snapshot>,
+
+    /// Committed entries following the snapshot
+    pub entries: Vec<Entry>,
+
+    /// The committed cluster configuration
+    pub configuration: ClusterConfig,
+
+    /// Index of the last committed entry in the bundle
+    pub commit_index: LogIndex,
+}
+
+impl StateBundle {
+    /// Check that the snapshot and entries fit together
+    ///
+    /// Entries must follow on directly from the snapshot (or from the start
+    /// of the log) without gaps, and everything up to `commit_index` must be
+    /// included.
+    pub fn validate(&self) -> Result<()> {
+        let snapshot_index = self
+            .snapshot
+            .as_ref()
+            .map(|s| s.metadata.last_included_index)
+            .unwrap_or(LogIndex::ZERO);
+
+        let mut expected = snapshot_index + 1;
+        for entry in &self.entries {
+            if entry.index != expected {
+                return Err(RaftError::InvalidBundle(format!(
+                    "expected entry {} but found {}",
+                    expected, entry.index
+                )));
+            }
+            expected.increment();
+        }
+
+        let last_index = expected - 1;
+        if self.commit_index != last_index {
+            return Err(RaftError::InvalidBundle(format!(
+                "commit index {} but bundle ends at {}",
+                self.commit_index, last_index
+            )));
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_redacted_debug_hides_payload() {
+        let secret = b"ssn=078-05-1120".to_vec();
+        let entry = Entry::new(Term(2), LogIndex(7), secret.clone());
+        let raw = format!("{:?}", secret);
+
+        set_payload_redaction(true);
+        let redacted = format!("{:?}", entry);
+        let bundle = format!(
+            "{:?}",
+            Snapshot {
+                metadata: SnapshotMetadata {
+                    last_included_index: LogIndex(7),
+                    last_included_term: Term(2),
+                    configuration: ClusterConfig::default(),
+                },
+                data: secret.clone(),
+            }
+        );
+        set_payload_redaction(false);
+
+        let summary = format!("<15 bytes, fnv1a {:016x}>", Redacted(&secret).hash());
+        for output in [&redacted, &bundle] {
+            assert!(output.contains(&summary), "{}", output);
+            assert!(!output.contains(&raw), "{}", output);
+            assert!(!output.contains("078-05-1120"), "{}", output);
+        }
+        assert!(redacted.contains("index: LogIndex(7)"));
+
+        // Off again, and the bytes themselves were never touched
+        assert!(format!("{:?}", entry).contains(&raw));
+        assert_eq!(entry.command, secret);
+    }
+
+    #[test]
+    fn test_config_change_round_trip() {
+        let config = ClusterConfig {
+            voters: vec![NodeId(1), NodeId(2)],
+            learners: vec![NodeId(3)],
+            ..Default::default()
+        };
+        let entry = Entry::config_change(Term(2), LogIndex(4), &config);
+
+        assert_eq!(entry.kind, EntryKind::ConfigChange);
+        assert_eq!(entry.config().unwrap(), Some(config));
+
+        let joint = ClusterConfig {
+            voters: vec![NodeId(2), NodeId(3)],
+            learners: vec![],
+            outgoing_voters: vec![NodeId(1), NodeId(2)],
+        };
+        let entry = Entry::config_change(Term(2), LogIndex(5), &joint);
+        assert_eq!(entry.config().unwrap(), Some(joint));
+        assert_eq!(Entry::noop(Term(2), LogIndex(5)).config().unwrap(), None);
+    }
+
+    #[test]
+    fn test_bundle_validation() {
+        let snapshot = Snapshot {
+            metadata: SnapshotMetadata {
+                last_included_index: LogIndex(5),
+                last_included_term: Term(1),
+                configuration: ClusterConfig {
+                    voters: vec![NodeId(1)],
+                    ..Default::default()
+                },
+            },
+            data: vec![],
+        };
+        let mut bundle = StateBundle {
+            snapshot: Some(snapshot),
+            entries: vec![
+                Entry::new(Term(1), LogIndex(6), vec![1]),
+                Entry::new(Term(1), LogIndex(7), vec![2]),
+            ],
+            configuration: ClusterConfig::default(),
+            commit_index: LogIndex(7),
+        };
+        assert!(bundle.validate().is_ok());
+
+        bundle.commit_index = LogIndex(9);
+        assert!(bundle.validate().is_err());
+
+        // Entries overlapping the snapshot
+        bundle.commit_index = LogIndex(7);
+        bundle
+            .entries
+            .insert(0, Entry::new(Term(1), LogIndex(5), vec![0]));
+        assert!(bundle.validate().is_err());
+    }
+
     #[test]
     fn test_term_increment() {
         let mut term = Term(5);
@@ -143,4 +543,61 @@ mod tests {
         assert!(Term(1) < Term(2));
         assert!(Term(100) > Term(50));
     }
+
+    #[test]
+    fn test_log_position_ordering() {
+        let pos = |term, index| LogPosition::new(Term(term), LogIndex(index));
+
+        // A later term wins however short the log
+        assert!(pos(3, 1) > pos(2, 100));
+        // Within a term, the longer log wins
+        assert!(pos(2, 7) > pos(2, 6));
+        assert!(pos(2, 7) >= pos(2, 7));
+        assert_eq!(pos(2, 7), pos(2, 7));
+        assert!(pos(0, 0) < pos(1, 0));
+        assert_eq!(LogPosition::default(), pos(0, 0));
+    }
+
+    #[test]
+    fn test_entry_try_new_rejects_index_zero() {
+        let err = Entry::try_new(Term(1), LogIndex::ZERO, b"cmd".to_vec()).unwrap_err();
+        assert!(matches!(err, RaftError::InvalidEntry(_)));
+
+        let entry = Entry::try_new(Term(1), LogIndex(1), vec![]).unwrap();
+        assert_eq!(entry.index, LogIndex(1));
+    }
+
+    #[test]
+    fn test_entry_try_new_strict_rejects_empty_command() {
+        let err = Entry::try_new_strict(Term(1), LogIndex(1), vec![]).unwrap_err();
+        assert!(matches!(err, RaftError::InvalidEntry(_)));
+
+        assert!(Entry::try_new_strict(Term(1), LogIndex::ZERO, b"cmd".to_vec()).is_err());
+        assert!(Entry::try_new_strict(Term(1), LogIndex(1), b"cmd".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn test_newtypes_serialize_as_plain_integers() {
+        assert_eq!(serde_json::to_string(&Term(7)).unwrap(), "7");
+        assert_eq!(serde_json::to_string(&LogIndex(42)).unwrap(), "42");
+        assert_eq!(serde_json::to_string(&NodeId(3)).unwrap(), "3");
+
+        assert_eq!(
+            bincode::serialize(&Term(7)).unwrap(),
+            bincode::serialize(&7u64).unwrap()
+        );
+        assert_eq!(
+            bincode::serialize(&LogIndex(42)).unwrap(),
+            bincode::serialize(&42u64).unwrap()
+        );
+        assert_eq!(
+            bincode::serialize(&NodeId(3)).unwrap(),
+            bincode::serialize(&3u64).unwrap()
+        );
+
+        let term: Term = serde_json::from_str("7").unwrap();
+        assert_eq!(term, Term(7));
+        let index: LogIndex = bincode::deserialize(&bincode::serialize(&42u64).unwrap()).unwrap();
+        assert_eq!(index, LogIndex(42));
+    }
 }
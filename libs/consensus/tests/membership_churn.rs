@@ -0,0 +1,401 @@
+//! Stress: membership churns while clients keep proposing
+//!
+//! A cluster starts with three voters on a simulated network under tokio's
+//! paused clock. A seeded script keeps adding fresh nodes and removing
+//! voters, one `change_configuration` at a time, while a client proposes a
+//! command every few milliseconds. The cluster has to keep committing
+//! through every change, end with exactly the membership the script asked
+//! for, and hold every command it acknowledged on a majority of that
+//! membership.
+//!
+//! Every run is a pure function of its seed. Set `CHURN_SEED` to replay a
+//! single one.
+
+use async_trait::async_trait;
+use objectbox_consensus::{
+    AppendEntriesRequest, AppendEntriesResponse, Entry, InstallSnapshotRequest,
+    InstallSnapshotResponse, MemberRole, NodeId, PingRequest, PingResponse, RaftConfigBuilder,
+    RaftError, RaftNode, RaftNodeBuilder, RaftRole, RequestVoteRequest, RequestVoteResponse,
+    Result, StateMachine, Transport,
+};
+use parking_lot::{Mutex, RwLock};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const MIN_VOTERS: usize = 3;
+const MAX_VOTERS: usize = 5;
+const CHANGES: usize = 12;
+const SEEDS: [u64; 3] = [3, 99, 4096];
+
+/// How long the cluster may go without committing before quorum counts as
+/// lost
+const PROGRESS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// State machine that keeps nothing; the logs are what's checked
+struct Discard;
+
+impl StateMachine for Discard {
+    fn apply(&mut self, _command: &[u8]) -> Vec<u8> {
+        vec![]
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn restore(&mut self, _snapshot: &[u8]) {}
+}
+
+/// The wires between simulated nodes
+///
+/// Messages take a seeded random latency and are occasionally lost.
+struct SimNetwork {
+    nodes: RwLock<BTreeMap<NodeId, Arc<RaftNode>>>,
+    rng: Mutex<StdRng>,
+}
+
+impl SimNetwork {
+    /// Carry a message to `to` after a random delay, or lose it
+    async fn route(&self, from: NodeId, to: NodeId) -> Result<Arc<RaftNode>> {
+        let unreachable = || RaftError::Rpc(format!("{} unreachable from {}", to, from));
+        let latency = {
+            let mut rng = self.rng.lock();
+            if rng.gen_ratio(1, 50) {
+                return Err(unreachable());
+            }
+            Duration::from_millis(rng.gen_range(1..=5))
+        };
+        tokio::time::sleep(latency).await;
+
+        self.nodes.read().get(&to).cloned().ok_or_else(unreachable)
+    }
+}
+
+/// One node's view of the simulated network
+struct SimTransport {
+    from: NodeId,
+    network: Arc<SimNetwork>,
+}
+
+#[async_trait]
+impl Transport for SimTransport {
+    async fn send_request_vote(
+        &self,
+        target: NodeId,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        let node = self.network.route(self.from, target).await?;
+        Ok(node.request_vote(request).await)
+    }
+
+    async fn send_append_entries(
+        &self,
+        target: NodeId,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        let node = self.network.route(self.from, target).await?;
+        Ok(node.append_entries(request).await)
+    }
+
+    async fn send_install_snapshot(
+        &self,
+        target: NodeId,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        let node = self.network.route(self.from, target).await?;
+        Ok(node.install_snapshot(request).await)
+    }
+
+    async fn send_ping(&self, target: NodeId, request: PingRequest) -> Result<PingResponse> {
+        let node = self.network.route(self.from, target).await?;
+        Ok(node.ping(request).await)
+    }
+}
+
+/// Start node `id` knowing `peers`; a node added later knows nobody until
+/// the leader reaches it
+async fn start_node(network: &Arc<SimNetwork>, seed: u64, id: NodeId, peers: Vec<NodeId>) {
+    let config = RaftConfigBuilder::new()
+        .snapshot_threshold(0)
+        .partition_detection_timeouts(0)
+        .election_storm_threshold(0)
+        .random_seed(seed ^ id.0)
+        .build();
+    let transport = SimTransport {
+        from: id,
+        network: Arc::clone(network),
+    };
+
+    let node = RaftNodeBuilder::new(id, peers, Discard)
+        .config(config)
+        .transport(Arc::new(transport))
+        .build()
+        .await
+        .unwrap();
+    network.nodes.write().insert(id, Arc::new(node));
+}
+
+/// The leader with the highest term among `members`, once there is one
+async fn wait_for_leader(network: &SimNetwork, members: &BTreeSet<NodeId>) -> Arc<RaftNode> {
+    tokio::time::timeout(PROGRESS_TIMEOUT, async {
+        loop {
+            let nodes: Vec<_> = members
+                .iter()
+                .filter_map(|id| network.nodes.read().get(id).cloned())
+                .collect();
+            let mut leader = None;
+            for node in nodes {
+                let metrics = node.metrics().await.unwrap();
+                if metrics.role == RaftRole::Leader
+                    && leader
+                        .as_ref()
+                        .is_none_or(|(term, _)| metrics.current_term > *term)
+                {
+                    leader = Some((metrics.current_term, node));
+                }
+            }
+            if let Some((_, leader)) = leader {
+                return leader;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("quorum lost: no leader")
+}
+
+/// Propose a command every few milliseconds until `stop` is set, returning
+/// the commands a leader acknowledged
+///
+/// Fails if nothing commits for [`PROGRESS_TIMEOUT`].
+async fn propose_continuously(
+    network: Arc<SimNetwork>,
+    members: Arc<Mutex<BTreeSet<NodeId>>>,
+    stop: Arc<AtomicBool>,
+) -> Vec<Vec<u8>> {
+    let mut acknowledged = Vec::new();
+    let mut last_commit = tokio::time::Instant::now();
+    let mut next = 0;
+
+    while !stop.load(Ordering::SeqCst) {
+        let current = members.lock().clone();
+        let leader = wait_for_leader(&network, &current).await;
+        let command = format!("cmd-{}", next).into_bytes();
+        next += 1;
+
+        let proposal =
+            tokio::time::timeout(Duration::from_secs(1), leader.propose(command.clone()));
+        if let Ok(Ok(_)) = proposal.await {
+            acknowledged.push(command);
+            last_commit = tokio::time::Instant::now();
+        }
+        assert!(
+            last_commit.elapsed() < PROGRESS_TIMEOUT,
+            "quorum lost: nothing committed for {:?}",
+            PROGRESS_TIMEOUT
+        );
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    acknowledged
+}
+
+/// Drive the membership to `target`, retrying through leader changes
+async fn reconfigure(network: &SimNetwork, current: &BTreeSet<NodeId>, target: &BTreeSet<NodeId>) {
+    let voters: Vec<NodeId> = target.iter().copied().collect();
+    let deadline = tokio::time::Instant::now() + PROGRESS_TIMEOUT;
+
+    loop {
+        // Whichever of the old or new voters leads is the one to ask
+        let involved: BTreeSet<NodeId> = current.union(target).copied().collect();
+        let leader = wait_for_leader(network, &involved).await;
+        if !target.contains(&leader.id()) {
+            // Leadership moved to the voter being removed, which it can't do
+            // to itself; leave this change out
+            return;
+        }
+
+        let change = leader.change_configuration(voters.clone(), vec![]);
+        if let Ok(Ok(())) = tokio::time::timeout(Duration::from_secs(2), change).await {
+            return;
+        }
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "quorum lost: couldn't reconfigure to {:?}",
+            target
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Each member's committed log
+async fn committed_logs(
+    network: &SimNetwork,
+    members: &BTreeSet<NodeId>,
+) -> BTreeMap<NodeId, Vec<Entry>> {
+    let mut logs = BTreeMap::new();
+    for &id in members {
+        let node = network.nodes.read().get(&id).cloned().unwrap();
+        let bundle = node.export_bundle().await.unwrap();
+        assert!(bundle.snapshot.is_none(), "snapshots are disabled");
+        logs.insert(id, bundle.entries);
+    }
+    logs
+}
+
+async fn run_seed(seed: u64) {
+    let network = Arc::new(SimNetwork {
+        nodes: RwLock::new(BTreeMap::new()),
+        rng: Mutex::new(StdRng::seed_from_u64(seed)),
+    });
+    let initial: Vec<NodeId> = (1..=MIN_VOTERS as u64).map(NodeId).collect();
+    for &id in &initial {
+        start_node(&network, seed, id, initial.clone()).await;
+    }
+    let members = Arc::new(Mutex::new(initial.iter().copied().collect::<BTreeSet<_>>()));
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let proposer = tokio::spawn(propose_continuously(
+        Arc::clone(&network),
+        Arc::clone(&members),
+        Arc::clone(&stop),
+    ));
+
+    let mut script = StdRng::seed_from_u64(seed.rotate_left(32));
+    let mut next_id = MIN_VOTERS as u64 + 1;
+    for _ in 0..CHANGES {
+        let current = members.lock().clone();
+        let grow =
+            current.len() < MAX_VOTERS && (current.len() == MIN_VOTERS || script.gen_bool(0.5));
+        let mut target = current.clone();
+        if grow {
+            let id = NodeId(next_id);
+            next_id += 1;
+            start_node(&network, seed, id, vec![]).await;
+            target.insert(id);
+        } else {
+            // The leader can't remove itself
+            let leader = wait_for_leader(&network, &current).await;
+            let victims: Vec<NodeId> = current
+                .iter()
+                .copied()
+                .filter(|&id| id != leader.id())
+                .collect();
+            target.remove(&victims[script.gen_range(0..victims.len())]);
+        }
+
+        reconfigure(&network, &current, &target).await;
+        let involved: BTreeSet<NodeId> = current.union(&target).copied().collect();
+        let leader = wait_for_leader(&network, &involved).await;
+        let applied: BTreeSet<NodeId> = leader
+            .members()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|m| m.role == MemberRole::Voter)
+            .map(|m| m.id)
+            .collect();
+
+        // Retire nodes that left, and any newcomer that never made it in
+        for id in current.union(&target) {
+            if applied.contains(id) {
+                continue;
+            }
+            let retired = network.nodes.write().remove(id);
+            if let Some(Ok(node)) = retired.map(Arc::try_unwrap) {
+                node.shutdown().await;
+            }
+        }
+        *members.lock() = applied;
+
+        tokio::time::sleep(Duration::from_millis(script.gen_range(0..200))).await;
+    }
+
+    stop.store(true, Ordering::SeqCst);
+    let acknowledged = proposer.await.unwrap();
+    assert!(!acknowledged.is_empty(), "seed {}: nothing committed", seed);
+
+    // Let the last changes and entries reach everyone
+    let members = members.lock().clone();
+    let leader = wait_for_leader(&network, &members).await;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // Every member agrees on the final membership, which is what the
+    // script asked for
+    let mut expected = BTreeSet::new();
+    for member in leader.members().await.unwrap() {
+        assert_eq!(member.role, MemberRole::Voter, "seed {}", seed);
+        expected.insert(member.id);
+    }
+    assert_eq!(expected, members, "seed {}", seed);
+    for &id in &members {
+        let node = network.nodes.read().get(&id).cloned().unwrap();
+        let seen: BTreeSet<NodeId> = node.members().await.unwrap().iter().map(|m| m.id).collect();
+        assert_eq!(seen, members, "seed {}: {} disagrees", seed, id);
+    }
+
+    // Committed logs agree, and every acknowledged command is on a majority
+    // of the final members at the same index
+    let logs = committed_logs(&network, &members).await;
+    for (a, log_a) in &logs {
+        for (b, log_b) in &logs {
+            for (x, y) in log_a.iter().zip(log_b) {
+                assert!(
+                    x.index == y.index && x.term == y.term && x.command == y.command,
+                    "seed {}: nodes {} and {} committed different entries at {}",
+                    seed,
+                    a,
+                    b,
+                    x.index
+                );
+            }
+        }
+    }
+    for command in &acknowledged {
+        let positions: Vec<_> = logs
+            .values()
+            .filter_map(|log| log.iter().find(|e| &e.command == command))
+            .map(|e| e.index)
+            .collect();
+        let name = String::from_utf8_lossy(command);
+        assert!(
+            positions.len() > members.len() / 2,
+            "seed {}: acknowledged {} committed on only {} nodes",
+            seed,
+            name,
+            positions.len()
+        );
+        assert!(
+            positions.windows(2).all(|w| w[0] == w[1]),
+            "seed {}: acknowledged {} committed at different indices {:?}",
+            seed,
+            name,
+            positions
+        );
+    }
+
+    drop(leader);
+    let nodes = std::mem::take(&mut *network.nodes.write());
+    for (_, node) in nodes {
+        if let Ok(node) = Arc::try_unwrap(node) {
+            node.shutdown().await;
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn membership_churn_keeps_committed_entries() {
+    let seeds = match std::env::var("CHURN_SEED") {
+        Ok(seed) => vec![seed.parse().expect("CHURN_SEED must be a u64")],
+        Err(_) => SEEDS.to_vec(),
+    };
+
+    for seed in seeds {
+        run_seed(seed).await;
+    }
+}
@@ -0,0 +1,310 @@
+//! End-to-end safety: no committed entry is ever lost across leader changes
+//!
+//! A five-node cluster runs on a simulated network under tokio's paused
+//! clock. A seeded script proposes commands, cuts nodes off and forces leader
+//! changes. Once the network heals and the cluster settles, every command a
+//! leader acknowledged must sit at the same index on a majority of nodes, and
+//! the committed logs of all nodes must agree wherever they overlap.
+//!
+//! Every run is a pure function of its seed. Set `SAFETY_SEED` to replay a
+//! single one.
+
+use async_trait::async_trait;
+use objectbox_consensus::{
+    AppendEntriesRequest, AppendEntriesResponse, Entry, InstallSnapshotRequest,
+    InstallSnapshotResponse, NodeId, PingRequest, PingResponse, RaftConfigBuilder, RaftError,
+    RaftNode, RaftNodeBuilder, RaftRole, RequestVoteRequest, RequestVoteResponse, Result,
+    StateMachine, Transport,
+};
+use parking_lot::{Mutex, RwLock};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+const NODES: u64 = 5;
+const MAJORITY: usize = 3;
+const ROUNDS: usize = 30;
+const SEEDS: [u64; 4] = [1, 7, 42, 2024];
+
+/// State machine that keeps nothing; the logs are what's checked
+struct Discard;
+
+impl StateMachine for Discard {
+    fn apply(&mut self, _command: &[u8]) -> Vec<u8> {
+        vec![]
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn restore(&mut self, _snapshot: &[u8]) {}
+}
+
+/// The wires between simulated nodes
+///
+/// Messages take a seeded random latency and are occasionally lost. Isolated
+/// nodes can neither send nor receive.
+struct SimNetwork {
+    nodes: RwLock<BTreeMap<NodeId, Arc<RaftNode>>>,
+    isolated: Mutex<BTreeSet<NodeId>>,
+    rng: Mutex<StdRng>,
+}
+
+impl SimNetwork {
+    /// How long a message from `from` to `to` takes, or `None` if it's lost
+    fn latency(&self, from: NodeId, to: NodeId) -> Option<Duration> {
+        let isolated = self.isolated.lock();
+        if isolated.contains(&from) || isolated.contains(&to) {
+            return None;
+        }
+
+        let mut rng = self.rng.lock();
+        if rng.gen_ratio(1, 20) {
+            return None;
+        }
+        Some(Duration::from_millis(rng.gen_range(1..=10)))
+    }
+
+    /// Carry a message to `to`, failing like a real network would
+    async fn route(&self, from: NodeId, to: NodeId) -> Result<Arc<RaftNode>> {
+        let unreachable = || RaftError::Rpc(format!("{} unreachable from {}", to, from));
+        let latency = self.latency(from, to).ok_or_else(unreachable)?;
+        tokio::time::sleep(latency).await;
+
+        // The link may have been cut while the message was in flight
+        if self.isolated.lock().contains(&to) {
+            return Err(unreachable());
+        }
+        self.nodes.read().get(&to).cloned().ok_or_else(unreachable)
+    }
+}
+
+/// One node's view of the simulated network
+struct SimTransport {
+    from: NodeId,
+    network: Arc<SimNetwork>,
+}
+
+#[async_trait]
+impl Transport for SimTransport {
+    async fn send_request_vote(
+        &self,
+        target: NodeId,
+        request: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        let node = self.network.route(self.from, target).await?;
+        Ok(node.request_vote(request).await)
+    }
+
+    async fn send_append_entries(
+        &self,
+        target: NodeId,
+        request: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        let node = self.network.route(self.from, target).await?;
+        Ok(node.append_entries(request).await)
+    }
+
+    async fn send_install_snapshot(
+        &self,
+        target: NodeId,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        let node = self.network.route(self.from, target).await?;
+        Ok(node.install_snapshot(request).await)
+    }
+
+    async fn send_ping(&self, target: NodeId, request: PingRequest) -> Result<PingResponse> {
+        let node = self.network.route(self.from, target).await?;
+        Ok(node.ping(request).await)
+    }
+}
+
+async fn start_cluster(seed: u64) -> Arc<SimNetwork> {
+    let network = Arc::new(SimNetwork {
+        nodes: RwLock::new(BTreeMap::new()),
+        isolated: Mutex::new(BTreeSet::new()),
+        rng: Mutex::new(StdRng::seed_from_u64(seed)),
+    });
+    let ids: Vec<NodeId> = (1..=NODES).map(NodeId).collect();
+
+    for &id in &ids {
+        let config = RaftConfigBuilder::new()
+            .snapshot_threshold(0)
+            .partition_detection_timeouts(0)
+            .election_storm_threshold(0)
+            .random_seed(seed ^ id.0)
+            .build();
+        let transport = SimTransport {
+            from: id,
+            network: Arc::clone(&network),
+        };
+
+        let node = RaftNodeBuilder::new(id, ids.clone(), Discard)
+            .config(config)
+            .transport(Arc::new(transport))
+            .build()
+            .await
+            .unwrap();
+        network.nodes.write().insert(id, Arc::new(node));
+    }
+
+    network
+}
+
+/// The reachable leader with the highest term, once there is one
+async fn wait_for_leader(network: &SimNetwork) -> Arc<RaftNode> {
+    for _ in 0..200 {
+        let nodes: Vec<_> = network.nodes.read().values().cloned().collect();
+        let isolated = network.isolated.lock().clone();
+
+        let mut leader = None;
+        for node in nodes {
+            if isolated.contains(&node.id()) {
+                continue;
+            }
+            let metrics = node.metrics().await.unwrap();
+            if metrics.role == RaftRole::Leader
+                && leader
+                    .as_ref()
+                    .is_none_or(|(term, _)| metrics.current_term > *term)
+            {
+                leader = Some((metrics.current_term, node));
+            }
+        }
+        if let Some((_, leader)) = leader {
+            return leader;
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("no leader elected");
+}
+
+/// Drive the cluster through the script for `seed`, returning the commands
+/// leaders acknowledged
+async fn run_script(network: &SimNetwork, seed: u64) -> Vec<Vec<u8>> {
+    let mut script = StdRng::seed_from_u64(seed.rotate_left(32));
+    let mut acknowledged = Vec::new();
+
+    for round in 0..ROUNDS {
+        let leader = wait_for_leader(network).await;
+        let command = format!("cmd-{}", round).into_bytes();
+        let proposal =
+            tokio::time::timeout(Duration::from_secs(1), leader.propose(command.clone()));
+        if let Ok(Ok(_)) = proposal.await {
+            acknowledged.push(command);
+        }
+
+        // Disturb the cluster, never isolating more than a minority
+        {
+            let mut isolated = network.isolated.lock();
+            match script.gen_range(0..4) {
+                0 if isolated.len() < NODES as usize - MAJORITY => {
+                    isolated.insert(leader.id());
+                }
+                1 if isolated.len() < NODES as usize - MAJORITY => {
+                    isolated.insert(NodeId(script.gen_range(1..=NODES)));
+                }
+                2 => isolated.clear(),
+                _ => {}
+            }
+        }
+
+        tokio::time::sleep(Duration::from_millis(script.gen_range(0..500))).await;
+    }
+
+    acknowledged
+}
+
+/// Each node's committed log, keyed by node
+async fn committed_logs(network: &SimNetwork) -> BTreeMap<NodeId, Vec<Entry>> {
+    let nodes: Vec<_> = network.nodes.read().values().cloned().collect();
+    let mut logs = BTreeMap::new();
+    for node in nodes {
+        let bundle = node.export_bundle().await.unwrap();
+        assert!(bundle.snapshot.is_none(), "snapshots are disabled");
+        logs.insert(node.id(), bundle.entries);
+    }
+    logs
+}
+
+fn check_safety(seed: u64, acknowledged: &[Vec<u8>], logs: &BTreeMap<NodeId, Vec<Entry>>) {
+    // Committed logs never disagree at the same index
+    for (a, log_a) in logs {
+        for (b, log_b) in logs {
+            for (x, y) in log_a.iter().zip(log_b) {
+                assert!(
+                    x.index == y.index && x.term == y.term && x.command == y.command,
+                    "seed {}: nodes {} and {} committed different entries at {}",
+                    seed,
+                    a,
+                    b,
+                    x.index
+                );
+            }
+        }
+    }
+
+    // Every acknowledged command survived, at one index, on a majority
+    for command in acknowledged {
+        let mut positions = Vec::new();
+        for log in logs.values() {
+            if let Some(entry) = log.iter().find(|e| &e.command == command) {
+                positions.push(entry.index);
+            }
+        }
+
+        let name = String::from_utf8_lossy(command);
+        assert!(
+            positions.len() >= MAJORITY,
+            "seed {}: acknowledged {} committed on only {} nodes",
+            seed,
+            name,
+            positions.len()
+        );
+        assert!(
+            positions.windows(2).all(|w| w[0] == w[1]),
+            "seed {}: acknowledged {} committed at different indices {:?}",
+            seed,
+            name,
+            positions
+        );
+    }
+}
+
+async fn run_seed(seed: u64) {
+    let network = start_cluster(seed).await;
+    let acknowledged = run_script(&network, seed).await;
+    assert!(!acknowledged.is_empty(), "seed {}: nothing committed", seed);
+
+    // Heal everything and let replication catch up
+    network.isolated.lock().clear();
+    wait_for_leader(&network).await;
+    tokio::time::sleep(Duration::from_secs(5)).await;
+
+    let logs = committed_logs(&network).await;
+    check_safety(seed, &acknowledged, &logs);
+
+    let nodes = std::mem::take(&mut *network.nodes.write());
+    for (_, node) in nodes {
+        if let Ok(node) = Arc::try_unwrap(node) {
+            node.shutdown().await;
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn committed_entries_survive_leader_changes() {
+    let seeds = match std::env::var("SAFETY_SEED") {
+        Ok(seed) => vec![seed.parse().expect("SAFETY_SEED must be a u64")],
+        Err(_) => SEEDS.to_vec(),
+    };
+
+    for seed in seeds {
+        run_seed(seed).await;
+    }
+}
@@ -0,0 +1,175 @@
+//! End-to-end: a brand-new follower catches up from a snapshot plus the log
+//!
+//! A three-node cluster commits enough to snapshot and compact its log, then
+//! an empty node joins. The leader has to install its snapshot to get the
+//! newcomer past the compacted prefix and replicate the entries after it.
+//! Runs on an in-process transport under tokio's paused clock, with every
+//! node's randomness seeded.
+
+use objectbox_consensus::{
+    ChannelTransport, NodeId, RaftConfigBuilder, RaftNode, RaftNodeBuilder, RaftRole, RpcDirection,
+    RpcKind, RpcSummary, StateMachine, Transport,
+};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// `SET key value` store whose contents the test can inspect from outside
+struct Kv(Arc<Mutex<BTreeMap<String, String>>>);
+
+impl StateMachine for Kv {
+    fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+        let command = String::from_utf8_lossy(command);
+        let mut parts = command.splitn(3, ' ').skip(1);
+        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            self.0.lock().insert(key.to_string(), value.to_string());
+        }
+        vec![]
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(&*self.0.lock()).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        *self.0.lock() = serde_json::from_slice(snapshot).unwrap();
+    }
+}
+
+/// Start node `id` on `transport`, returning it and a view of its data
+async fn start_node(
+    transport: &Arc<ChannelTransport>,
+    id: NodeId,
+    peers: Vec<NodeId>,
+    observer: Option<Arc<Mutex<Vec<RpcSummary>>>>,
+) -> (Arc<RaftNode>, Arc<Mutex<BTreeMap<String, String>>>) {
+    let config = RaftConfigBuilder::new()
+        .election_timeout(Duration::from_millis(100), Duration::from_millis(500))
+        .heartbeat_interval(Duration::from_millis(10))
+        .snapshot_threshold(5)
+        .snapshot_trailing_logs(0)
+        .random_seed(id.0)
+        .build();
+    let data = Arc::new(Mutex::new(BTreeMap::new()));
+    let mut builder = RaftNodeBuilder::new(id, peers, Kv(Arc::clone(&data)))
+        .config(config)
+        .transport(Arc::clone(transport) as Arc<dyn Transport>);
+    if let Some(log) = observer {
+        builder = builder.rpc_observer(Arc::new(move |rpc: &RpcSummary| {
+            log.lock().push(rpc.clone())
+        }));
+    }
+
+    let node = Arc::new(builder.build().await.unwrap());
+    transport.register(Arc::clone(&node));
+    (node, data)
+}
+
+async fn wait_for_leader(nodes: &[Arc<RaftNode>]) -> Arc<RaftNode> {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            for node in nodes {
+                if node.metrics().await.unwrap().role == RaftRole::Leader {
+                    return Arc::clone(node);
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no leader elected")
+}
+
+#[tokio::test(start_paused = true)]
+async fn new_follower_catches_up_from_snapshot_and_log() {
+    let transport = ChannelTransport::new();
+    let voters = vec![NodeId(1), NodeId(2), NodeId(3)];
+    let mut nodes = Vec::new();
+    let mut data = Vec::new();
+    for &id in &voters {
+        let (node, view) = start_node(&transport, id, voters.clone(), None).await;
+        nodes.push(node);
+        data.push(view);
+    }
+    let leader = wait_for_leader(&nodes).await;
+
+    for i in 1..=12 {
+        leader
+            .propose(format!("SET k{} v{}", i, i).into_bytes())
+            .await
+            .unwrap();
+    }
+    let snapshot_index = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(index) = leader.metrics().await.unwrap().snapshot_index {
+                return index;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("leader never compacted its log");
+
+    // Leave entries behind the snapshot for the log to carry
+    for i in 13..=15 {
+        leader
+            .propose(format!("SET k{} v{}", i, i).into_bytes())
+            .await
+            .unwrap();
+    }
+    let leader_metrics = leader.metrics().await.unwrap();
+    assert!(leader_metrics.last_log_index > snapshot_index);
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let (newcomer, newcomer_data) =
+        start_node(&transport, NodeId(4), vec![], Some(Arc::clone(&received))).await;
+    tokio::time::timeout(Duration::from_secs(10), newcomer.join(vec![leader.id()]))
+        .await
+        .expect("join timed out")
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        for node in nodes.iter().chain([&newcomer]) {
+            while node.metrics().await.unwrap().last_applied < leader_metrics.commit_index {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    })
+    .await
+    .expect("a node never caught up");
+
+    // The snapshot arrived before any entries, and the entries it didn't
+    // cover came through AppendEntries
+    let received: Vec<_> = received
+        .lock()
+        .iter()
+        .filter(|rpc| rpc.direction == RpcDirection::Inbound)
+        .cloned()
+        .collect();
+    let installed = received
+        .iter()
+        .position(|rpc| rpc.kind == RpcKind::InstallSnapshot)
+        .expect("no snapshot was installed");
+    let first_entries = received
+        .iter()
+        .position(|rpc| rpc.kind == RpcKind::AppendEntries && rpc.entries.is_some())
+        .expect("no entries were replicated");
+    assert!(installed < first_entries);
+    let (first, _) = received[first_entries].entries.unwrap();
+    assert!(first > snapshot_index);
+
+    let expected = data[0].lock().clone();
+    assert_eq!(expected.len(), 15);
+    for view in &data[1..] {
+        assert_eq!(*view.lock(), expected);
+    }
+    assert_eq!(*newcomer_data.lock(), expected);
+
+    drop(leader);
+    for node in nodes.into_iter().chain([newcomer]) {
+        transport.unregister(node.id());
+        if let Ok(node) = Arc::try_unwrap(node) {
+            node.shutdown().await;
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! End-to-end: a three-node cluster elects a leader and replicates over TCP
+//!
+//! Every node listens on its own loopback port and reaches the others
+//! through a `TcpTransport`, so each RPC crosses a real socket. Runs on the
+//! wall clock, as sockets don't advance tokio's paused one.
+
+use objectbox_consensus::{
+    serve_tcp, NodeId, RaftConfigBuilder, RaftNode, RaftNodeBuilder, RaftRole, StateMachine,
+    TcpTransport,
+};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// `SET key value` store whose contents the test can inspect from outside
+struct Kv(Arc<Mutex<BTreeMap<String, String>>>);
+
+impl StateMachine for Kv {
+    fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+        let command = String::from_utf8_lossy(command);
+        let mut parts = command.splitn(3, ' ').skip(1);
+        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            self.0.lock().insert(key.to_string(), value.to_string());
+        }
+        vec![]
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(&*self.0.lock()).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        *self.0.lock() = serde_json::from_slice(snapshot).unwrap();
+    }
+}
+
+async fn wait_for_leader(nodes: &[Arc<RaftNode>]) -> Arc<RaftNode> {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            for node in nodes {
+                if node.metrics().await.unwrap().role == RaftRole::Leader {
+                    return Arc::clone(node);
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no leader elected")
+}
+
+#[tokio::test]
+async fn cluster_elects_and_replicates_over_tcp() {
+    // Bind first so every node knows its peers' ports before starting
+    let mut listeners = Vec::new();
+    for _ in 0..3 {
+        listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+    let addrs: Vec<(NodeId, SocketAddr)> = listeners
+        .iter()
+        .enumerate()
+        .map(|(i, listener)| (NodeId(i as u64 + 1), listener.local_addr().unwrap()))
+        .collect();
+    let voters: Vec<NodeId> = addrs.iter().map(|(id, _)| *id).collect();
+
+    let mut nodes = Vec::new();
+    let mut data = Vec::new();
+    let mut servers = Vec::new();
+    for (&(id, _), listener) in addrs.iter().zip(listeners) {
+        let peers = addrs.iter().copied().filter(|(peer, _)| *peer != id);
+        let config = RaftConfigBuilder::new()
+            .election_timeout(Duration::from_millis(150), Duration::from_millis(300))
+            .heartbeat_interval(Duration::from_millis(30))
+            .random_seed(id.0)
+            .build();
+        let view = Arc::new(Mutex::new(BTreeMap::new()));
+        let node = RaftNodeBuilder::new(id, voters.clone(), Kv(Arc::clone(&view)))
+            .config(config)
+            .transport(Arc::new(TcpTransport::new(peers)))
+            .build()
+            .await
+            .unwrap();
+        let node = Arc::new(node);
+        servers.push(tokio::spawn(serve_tcp(Arc::clone(&node), listener)));
+        nodes.push(node);
+        data.push(view);
+    }
+
+    let leader = wait_for_leader(&nodes).await;
+    for i in 1..=10 {
+        leader
+            .propose(format!("SET k{} v{}", i, i).into_bytes())
+            .await
+            .unwrap();
+    }
+
+    // Every node applies what the leader committed
+    let commit_index = leader.metrics().await.unwrap().commit_index;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        for node in &nodes {
+            while node.metrics().await.unwrap().last_applied < commit_index {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    })
+    .await
+    .expect("a follower never caught up");
+    let expected = data[0].lock().clone();
+    assert_eq!(expected.len(), 10);
+    for view in &data[1..] {
+        assert_eq!(*view.lock(), expected);
+    }
+
+    // All nodes agree on who leads
+    let term = leader.metrics().await.unwrap().current_term;
+    for node in &nodes {
+        let metrics = node.metrics().await.unwrap();
+        assert_eq!(metrics.current_term, term);
+        assert_eq!(metrics.current_leader, Some(leader.id()));
+    }
+
+    for server in servers {
+        server.abort();
+        let _ = server.await;
+    }
+    drop(leader);
+    for node in nodes {
+        if let Ok(node) = Arc::try_unwrap(node) {
+            node.shutdown().await;
+        }
+    }
+}
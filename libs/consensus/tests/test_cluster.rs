@@ -0,0 +1,155 @@
+//! The `testing::TestCluster` helpers, driven the way a downstream crate
+//! would use them
+
+use objectbox_consensus::testing::{replay, TestCluster};
+use objectbox_consensus::{
+    ClusterConfig, FileLogConfig, FileLogStorage, NodeId, RaftConfigBuilder, RaftError, RaftLog,
+    RaftNodeBuilder, RaftRole, Snapshot, SnapshotMetadata, StateMachine,
+};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Counts applied commands and returns the new count
+#[derive(Default)]
+struct Counter(u64);
+
+impl StateMachine for Counter {
+    fn apply(&mut self, _command: &[u8]) -> Vec<u8> {
+        self.0 += 1;
+        self.0.to_le_bytes().to_vec()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.0.to_le_bytes().to_vec()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        self.0 = u64::from_le_bytes(snapshot.try_into().unwrap());
+    }
+}
+
+/// `SET key value` store
+#[derive(Default)]
+struct Kv(BTreeMap<String, String>);
+
+impl StateMachine for Kv {
+    fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+        let command = String::from_utf8_lossy(command);
+        let mut parts = command.splitn(3, ' ').skip(1);
+        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            self.0.insert(key.to_string(), value.to_string());
+        }
+        vec![]
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.0).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        self.0 = serde_json::from_slice(snapshot).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_elect_and_commit() {
+    let cluster = TestCluster::builder(3)
+        .build(|_| Counter::default())
+        .await
+        .unwrap();
+
+    let result = cluster.propose_on_leader(b"inc".to_vec()).await.unwrap();
+    assert_eq!(result, 1u64.to_le_bytes());
+
+    cluster.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_partitioned_leader_is_replaced() {
+    let cluster = TestCluster::builder(3)
+        .build(|_| Counter::default())
+        .await
+        .unwrap();
+    let old = cluster.wait_for_leader().await.unwrap();
+
+    cluster.partition(old.id());
+    let new = cluster.wait_for_leader().await.unwrap();
+    assert_ne!(new.id(), old.id());
+
+    let result = cluster.propose_on_leader(b"inc".to_vec()).await.unwrap();
+    assert_eq!(result, 1u64.to_le_bytes());
+
+    cluster.heal(old.id());
+    cluster.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_replay_rebuilds_state_at_earlier_indexes() {
+    let dir = tempfile::tempdir().unwrap();
+    let open_log = || FileLogStorage::open(dir.path(), FileLogConfig::default()).unwrap();
+    let config = RaftConfigBuilder::new()
+        .election_timeout(Duration::from_millis(20), Duration::from_millis(40))
+        .heartbeat_interval(Duration::from_millis(10))
+        .build();
+    let node = RaftNodeBuilder::new(NodeId(1), vec![NodeId(1)], Kv::default())
+        .config(config)
+        .log_storage(Box::new(open_log()))
+        .build()
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while node.metrics().await.unwrap().role != RaftRole::Leader {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no leader elected");
+
+    // Keys are overwritten, so each state depends on how far the log got
+    let mut states = Vec::new();
+    for i in 1..=6 {
+        node.propose(format!("SET k{} v{}", i % 3, i).into_bytes())
+            .await
+            .unwrap();
+        let index = node.metrics().await.unwrap().last_applied;
+        let state = node
+            .linearizable_read(|kv: &Kv| kv.snapshot())
+            .await
+            .unwrap();
+        states.push((index, state));
+    }
+    node.shutdown().await;
+
+    let log = RaftLog::new(Box::new(open_log()));
+    for (index, state) in &states {
+        assert_eq!(&replay(&log, *index, Kv::default).unwrap(), state);
+    }
+
+    // Replaying starts from the log's snapshot once it has one
+    let (base, base_state) = states[2].clone();
+    log.set_snapshot(Snapshot {
+        metadata: SnapshotMetadata {
+            last_included_index: base,
+            last_included_term: log.get_term(base).unwrap().unwrap(),
+            configuration: ClusterConfig {
+                voters: vec![NodeId(1)],
+                ..Default::default()
+            },
+        },
+        data: base_state,
+    })
+    .unwrap();
+    log.compact(base).unwrap();
+    let (later, later_state) = &states[4];
+    assert_eq!(&replay(&log, *later, Kv::default).unwrap(), later_state);
+
+    // Nothing before the snapshot or past the log can be rebuilt
+    assert!(matches!(
+        replay(&log, states[1].0, Kv::default),
+        Err(RaftError::LogIndexOutOfRange(_))
+    ));
+    assert!(matches!(
+        replay(&log, log.last_index() + 1, Kv::default),
+        Err(RaftError::LogIndexOutOfRange(_))
+    ));
+}